@@ -21,12 +21,10 @@
 //! This module contains some utilities for algorithm support exchange.
 //!
 //! One important part of the SECIO handshake is negotiating algorithms. This is what this module
-//! helps you with.
+//! helps you with, alongside the `negotiation` module.
 
-use crate::error::SecioError;
 #[cfg(not(any(target_os = "emscripten", target_os = "unknown")))]
 use ring::digest;
-use std::cmp::Ordering;
 use crate::stream_cipher::Cipher;
 use crate::KeyAgreement;
 
@@ -45,6 +43,11 @@ pub(crate) const DEFAULT_AGREEMENTS_PROPOSITION: &str = "P-256,P-384";
 pub(crate) const DEFAULT_CIPHERS_PROPOSITION: &str = "AES-128,AES-256,TwofishCTR";
 pub(crate) const DEFAULT_DIGESTS_PROPOSITION: &str = "SHA256,SHA512";
 
+/// Key agreements, ciphers and digests that we know how to use.
+pub(crate) const KNOWN_AGREEMENTS: &[&str] = &[ECDH_P256, ECDH_P384];
+pub(crate) const KNOWN_CIPHERS: &[&str] = &[AES_128, AES_256, TWOFISH_CTR, NULL];
+pub(crate) const KNOWN_DIGESTS: &[&str] = &[SHA_256, SHA_512];
+
 /// Return a proposition string from the given sequence of `KeyAgreement` values.
 pub fn key_agreements_proposition<'a, I>(xchgs: I) -> String
 where
@@ -67,28 +70,17 @@ where
     s
 }
 
-/// Given two key agreement proposition strings try to figure out a match.
-///
-/// The `Ordering` parameter determines which argument is preferred. If `Less` or `Equal` we
-/// try for each of `theirs` every one of `ours`, for `Greater` it's the other way around.
-pub fn select_agreement(r: Ordering, ours: &str, theirs: &str) -> Result<KeyAgreement, SecioError> {
-    let (a, b) = match r {
-        Ordering::Less | Ordering::Equal => (theirs, ours),
-        Ordering::Greater =>  (ours, theirs)
-    };
-    for x in a.split(',') {
-        if b.split(',').any(|y| x == y) {
-            match x {
-                ECDH_P256 => return Ok(KeyAgreement::EcdhP256),
-                ECDH_P384 => return Ok(KeyAgreement::EcdhP384),
-                _ => continue
-            }
-        }
-    }
-    Err(SecioError::NoSupportIntersection)
+/// Parses a key agreement proposition string, skipping the algorithms we don't know of.
+pub fn parse_agreements(proposition: &str) -> Vec<KeyAgreement> {
+    proposition.split(',')
+        .filter_map(|x| match x {
+            ECDH_P256 => Some(KeyAgreement::EcdhP256),
+            ECDH_P384 => Some(KeyAgreement::EcdhP384),
+            _ => None
+        })
+        .collect()
 }
 
-
 /// Return a proposition string from the given sequence of `Cipher` values.
 pub fn ciphers_proposition<'a, I>(ciphers: I) -> String
 where
@@ -119,30 +111,19 @@ where
     s
 }
 
-/// Given two cipher proposition strings try to figure out a match.
-///
-/// The `Ordering` parameter determines which argument is preferred. If `Less` or `Equal` we
-/// try for each of `theirs` every one of `ours`, for `Greater` it's the other way around.
-pub fn select_cipher(r: Ordering, ours: &str, theirs: &str) -> Result<Cipher, SecioError> {
-    let (a, b) = match r {
-        Ordering::Less | Ordering::Equal => (theirs, ours),
-        Ordering::Greater =>  (ours, theirs)
-    };
-    for x in a.split(',') {
-        if b.split(',').any(|y| x == y) {
-            match x {
-                AES_128 => return Ok(Cipher::Aes128),
-                AES_256 => return Ok(Cipher::Aes256),
-                TWOFISH_CTR => return Ok(Cipher::TwofishCtr),
-                NULL => return Ok(Cipher::Null),
-                _ => continue
-            }
-        }
-    }
-    Err(SecioError::NoSupportIntersection)
+/// Parses a cipher proposition string, skipping the algorithms we don't know of.
+pub fn parse_ciphers(proposition: &str) -> Vec<Cipher> {
+    proposition.split(',')
+        .filter_map(|x| match x {
+            AES_128 => Some(Cipher::Aes128),
+            AES_256 => Some(Cipher::Aes256),
+            TWOFISH_CTR => Some(Cipher::TwofishCtr),
+            NULL => Some(Cipher::Null),
+            _ => None
+        })
+        .collect()
 }
 
-
 /// Possible digest algorithms.
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Digest {
@@ -183,25 +164,15 @@ where
     s
 }
 
-/// Given two digest proposition strings try to figure out a match.
-///
-/// The `Ordering` parameter determines which argument is preferred. If `Less` or `Equal` we
-/// try for each of `theirs` every one of `ours`, for `Greater` it's the other way around.
-pub fn select_digest(r: Ordering, ours: &str, theirs: &str) -> Result<Digest, SecioError> {
-    let (a, b) = match r {
-        Ordering::Less | Ordering::Equal => (theirs, ours),
-        Ordering::Greater =>  (ours, theirs)
-    };
-    for x in a.split(',') {
-        if b.split(',').any(|y| x == y) {
-            match x {
-                SHA_256 => return Ok(Digest::Sha256),
-                SHA_512 => return Ok(Digest::Sha512),
-                _ => continue
-            }
-        }
-    }
-    Err(SecioError::NoSupportIntersection)
+/// Parses a digest proposition string, skipping the algorithms we don't know of.
+pub fn parse_digests(proposition: &str) -> Vec<Digest> {
+    proposition.split(',')
+        .filter_map(|x| match x {
+            SHA_256 => Some(Digest::Sha256),
+            SHA_512 => Some(Digest::Sha512),
+            _ => None
+        })
+        .collect()
 }
 
 #[cfg(not(any(target_os = "emscripten", target_os = "unknown")))]
@@ -23,15 +23,17 @@ use crate::algo_support;
 use crate::codec::{full_codec, FullCodec, Hmac, LenPrefixCodec};
 use crate::error::SecioError;
 use crate::exchange;
+use crate::negotiation;
 use crate::stream_cipher::ctr;
+use crate::structs_ext::NONCE_SIZE;
 use crate::structs_proto::{Exchange, Propose};
 use futures::prelude::*;
 use libp2p_core::PublicKey;
 use log::{debug, trace};
 use prost::Message;
 use rand::{self, RngCore};
-use sha2::{Digest as ShaDigestTrait, Sha256};
-use std::{cmp::{self, Ordering}, io};
+use std::{cmp::{self, Ordering}, io, mem};
+use zeroize::Zeroizing;
 
 
 /// Performs a handshake on the given socket.
@@ -43,6 +45,9 @@ use std::{cmp::{self, Ordering}, io};
 /// On success, returns an object that implements the `Sink` and `Stream` trait whose items are
 /// buffers of data, plus the public key of the remote, plus the ephemeral public key used during
 /// negotiation.
+///
+/// The nonces, the handshake messages and the key material are wiped from memory once they are
+/// no longer needed, whether the handshake succeeds or not.
 pub async fn handshake<S>(socket: S, config: SecioConfig)
     -> Result<(FullCodec<S>, PublicKey, Vec<u8>), SecioError>
 where
@@ -51,54 +56,39 @@ where
     let mut socket = LenPrefixCodec::new(socket, config.max_frame_len);
 
     let local_nonce = {
-        let mut local_nonce = [0; 16];
+        let mut local_nonce = Zeroizing::new([0; NONCE_SIZE]);
         rand::thread_rng()
-            .try_fill_bytes(&mut local_nonce)
+            .try_fill_bytes(&mut local_nonce[..])
             .map_err(|_| SecioError::NonceGenerationFailed)?;
         local_nonce
     };
 
-    let local_public_key_encoded = config.key.public().into_protobuf_encoding();
-
     // Send our proposition with our nonce, public key and supported protocols.
-    let local_proposition = Propose {
-        rand: Some(local_nonce.to_vec()),
-        pubkey: Some(local_public_key_encoded.clone()),
-        exchanges: if let Some(ref p) = config.agreements_prop {
-            trace!("agreements proposition: {}", p);
-            Some(p.clone())
-        } else {
-            trace!("agreements proposition: {}", algo_support::DEFAULT_AGREEMENTS_PROPOSITION);
-            Some(algo_support::DEFAULT_AGREEMENTS_PROPOSITION.into())
-        },
-        ciphers: if let Some(ref p) = config.ciphers_prop {
-            trace!("ciphers proposition: {}", p);
-            Some(p.clone())
-        } else {
-            trace!("ciphers proposition: {}", algo_support::DEFAULT_CIPHERS_PROPOSITION);
-            Some(algo_support::DEFAULT_CIPHERS_PROPOSITION.into())
-        },
-        hashes: if let Some(ref p) = config.digests_prop {
-            trace!("digests proposition: {}", p);
-            Some(p.clone())
-        } else {
-            Some(algo_support::DEFAULT_DIGESTS_PROPOSITION.into())
-        }
-    };
+    let exchanges = config.agreements_prop.as_deref()
+        .unwrap_or(algo_support::DEFAULT_AGREEMENTS_PROPOSITION);
+    trace!("agreements proposition: {}", exchanges);
+    let ciphers = config.ciphers_prop.as_deref()
+        .unwrap_or(algo_support::DEFAULT_CIPHERS_PROPOSITION);
+    trace!("ciphers proposition: {}", ciphers);
+    let hashes = config.digests_prop.as_deref()
+        .unwrap_or(algo_support::DEFAULT_DIGESTS_PROPOSITION);
+    trace!("digests proposition: {}", hashes);
+    let local_proposition = Zeroizing::new(Propose::build(
+        local_nonce.to_vec(), &config.key.public(), exchanges, ciphers, hashes));
 
     let local_proposition_bytes = {
-        let mut buf = Vec::with_capacity(local_proposition.encoded_len());
-        local_proposition.encode(&mut buf).expect("Vec<u8> provides capacity as needed");
+        let mut buf = Zeroizing::new(Vec::with_capacity(local_proposition.encoded_len()));
+        local_proposition.encode(&mut *buf).expect("Vec<u8> provides capacity as needed");
         buf
     };
-    trace!("starting handshake; local nonce = {:?}", local_nonce);
+    trace!("starting handshake; local nonce = {:?}", &local_nonce[..]);
 
     trace!("sending proposition to remote");
-    socket.send(local_proposition_bytes.clone()).await?;
+    socket.send(local_proposition_bytes.to_vec()).await?;
 
     // Receive the remote's proposition.
     let remote_proposition_bytes = match socket.next().await {
-        Some(b) => b?,
+        Some(b) => Zeroizing::new(b?),
         None => {
             debug!("unexpected eof while waiting for remote's proposition");
             return Err(SecioError::IoError(io::ErrorKind::UnexpectedEof.into()))
@@ -106,15 +96,20 @@ where
     };
 
     let remote_proposition = match Propose::decode(&remote_proposition_bytes[..]) {
-        Ok(prop) => prop,
+        Ok(prop) => Zeroizing::new(prop),
         Err(_) => {
             debug!("failed to parse remote's proposition protobuf message");
             return Err(SecioError::HandshakeParsingFailure);
         }
     };
 
-    let remote_public_key_encoded = remote_proposition.pubkey.unwrap_or_default();
-    let remote_nonce = remote_proposition.rand.unwrap_or_default();
+    if let Err(err) = remote_proposition.validate() {
+        debug!("remote's proposition is invalid: {}", err);
+        return Err(err);
+    }
+
+    let remote_public_key_encoded = remote_proposition.pubkey.clone().unwrap_or_default();
+    let mut remote_nonce = Zeroizing::new(remote_proposition.rand.clone().unwrap_or_default());
 
     let remote_public_key = match PublicKey::from_protobuf_encoding(&remote_public_key_encoded) {
         Ok(p) => p,
@@ -124,75 +119,23 @@ where
         },
     };
     trace!("received proposition from remote; pubkey = {:?}; nonce = {:?}",
-        remote_public_key, remote_nonce);
+        remote_public_key, &remote_nonce[..]);
 
     // In order to determine which protocols to use, we compute two hashes and choose
     // based on which hash is larger.
-    let hashes_ordering = {
-        let oh1 = {
-            let mut ctx = Sha256::new();
-            ctx.input(&remote_public_key_encoded);
-            ctx.input(&local_nonce);
-            ctx.result()
-        };
-
-        let oh2 = {
-            let mut ctx = Sha256::new();
-            ctx.input(&local_public_key_encoded);
-            ctx.input(&remote_nonce);
-            ctx.result()
-        };
-
-        oh1.as_ref().cmp(&oh2.as_ref())
-    };
-
-    let chosen_exchange = {
-        let ours = config.agreements_prop.as_ref()
-            .map(|s| s.as_ref())
-            .unwrap_or(algo_support::DEFAULT_AGREEMENTS_PROPOSITION);
-        let theirs = &remote_proposition.exchanges.unwrap_or_default();
-        match algo_support::select_agreement(hashes_ordering, ours, theirs) {
-            Ok(a) => a,
-            Err(err) => {
-                debug!("failed to select an exchange protocol");
-                return Err(err);
-            }
-        }
-    };
-
-    let chosen_cipher = {
-        let ours = config.ciphers_prop.as_ref()
-            .map(|s| s.as_ref())
-            .unwrap_or(algo_support::DEFAULT_CIPHERS_PROPOSITION);
-        let theirs = &remote_proposition.ciphers.unwrap_or_default();
-        match algo_support::select_cipher(hashes_ordering, ours, theirs) {
-            Ok(a) => {
-                debug!("selected cipher: {:?}", a);
-                a
-            }
-            Err(err) => {
-                debug!("failed to select a cipher protocol");
-                return Err(err);
-            }
-        }
-    };
-
-    let chosen_hash = {
-        let ours = config.digests_prop.as_ref()
-            .map(|s| s.as_ref())
-            .unwrap_or(algo_support::DEFAULT_DIGESTS_PROPOSITION);
-        let theirs = &remote_proposition.hashes.unwrap_or_default();
-        match algo_support::select_digest(hashes_ordering, ours, theirs) {
-            Ok(a) => {
-                debug!("selected hash: {:?}", a);
-                a
-            }
-            Err(err) => {
-                debug!("failed to select a hash protocol");
-                return Err(err);
-            }
+    let negotiated = match negotiation::negotiate(&local_proposition, &remote_proposition) {
+        Ok(negotiated) => negotiated,
+        Err(err) => {
+            debug!("failed to select the algorithms of the connection");
+            return Err(err);
         }
     };
+    debug!("selected cipher: {:?}", negotiated.cipher);
+    debug!("selected hash: {:?}", negotiated.digest);
+    let hashes_ordering = negotiated.ordering;
+    let chosen_exchange = negotiated.agreement;
+    let chosen_cipher = negotiated.cipher;
+    let chosen_hash = negotiated.digest;
 
     // Generate an ephemeral key for the negotiation.
     let (tmp_priv_key, tmp_pub_key) = exchange::generate_agreement(chosen_exchange).await?;
@@ -200,16 +143,15 @@ where
     // Send the ephemeral pub key to the remote in an `Exchange` struct. The `Exchange` also
     // contains a signature of the two propositions encoded with our static public key.
     let local_exchange = {
-        let mut data_to_sign = local_proposition_bytes.clone();
+        let mut data_to_sign = Zeroizing::new(Vec::with_capacity(
+            local_proposition_bytes.len() + remote_proposition_bytes.len() + tmp_pub_key.len()));
+        data_to_sign.extend_from_slice(&local_proposition_bytes);
         data_to_sign.extend_from_slice(&remote_proposition_bytes);
         data_to_sign.extend_from_slice(&tmp_pub_key);
 
-        Exchange {
-            epubkey: Some(tmp_pub_key.clone()),
-            signature: match config.key.sign(&data_to_sign) {
-                Ok(sig) => Some(sig),
-                Err(_) => return Err(SecioError::SigningFailure)
-            }
+        match config.key.sign(&data_to_sign) {
+            Ok(signature) => Zeroizing::new(Exchange::build(tmp_pub_key.clone(), signature)),
+            Err(_) => return Err(SecioError::SigningFailure)
         }
     };
     let local_exch = {
@@ -225,7 +167,7 @@ where
     // Receive the remote's `Exchange`.
     let remote_exch = {
         let raw = match socket.next().await {
-            Some(r) => r?,
+            Some(r) => Zeroizing::new(r?),
             None => {
                 debug!("unexpected eof while waiting for remote's exchange");
                 return Err(SecioError::IoError(io::ErrorKind::UnexpectedEof.into()))
@@ -235,7 +177,7 @@ where
         match Exchange::decode(&raw[..]) {
             Ok(e) => {
                 trace!("received and decoded the remote's exchange");
-                e
+                Zeroizing::new(e)
             },
             Err(err) => {
                 debug!("failed to parse remote's exchange protobuf; {:?}", err);
@@ -248,11 +190,14 @@ where
     // the sender of its proposition, and that it is the owner of both its global and ephemeral
     // keys.
     {
-        let mut data_to_verify = remote_proposition_bytes.clone();
+        let remote_epubkey = remote_exch.epubkey.as_deref().unwrap_or_default();
+        let mut data_to_verify = Zeroizing::new(Vec::with_capacity(
+            remote_proposition_bytes.len() + local_proposition_bytes.len() + remote_epubkey.len()));
+        data_to_verify.extend_from_slice(&remote_proposition_bytes);
         data_to_verify.extend_from_slice(&local_proposition_bytes);
-        data_to_verify.extend_from_slice(remote_exch.epubkey.as_deref().unwrap_or_default());
+        data_to_verify.extend_from_slice(remote_epubkey);
 
-        if !remote_public_key.verify(&data_to_verify, &remote_exch.signature.unwrap_or_default()) {
+        if !remote_public_key.verify(&data_to_verify, remote_exch.signature.as_deref().unwrap_or_default()) {
             return Err(SecioError::SignatureVerificationFailed)
         }
 
@@ -261,12 +206,12 @@ where
 
     // Generate a key from the local ephemeral private key and the remote ephemeral public key,
     // derive from it a cipher key, an iv, and a hmac key, and build the encoder/decoder.
-    let key_material = exchange::agree(
+    let key_material = Zeroizing::new(exchange::agree(
         chosen_exchange,
         tmp_priv_key,
-        &remote_exch.epubkey.unwrap_or_default(),
+        remote_exch.epubkey.as_deref().unwrap_or_default(),
         chosen_hash.num_bytes()
-    ).await?;
+    ).await?);
 
     // Generate a key from the local ephemeral private key and the remote ephemeral public key,
     // derive from it a cipher key, an iv, and a hmac key, and build the encoder/decoder.
@@ -275,7 +220,7 @@ where
         let iv_size = chosen_cipher.iv_size();
 
         let key = Hmac::from_key(chosen_hash, &key_material);
-        let mut longer_key = vec![0u8; 2 * (iv_size + cipher_key_size + 20)];
+        let mut longer_key = Zeroizing::new(vec![0u8; 2 * (iv_size + cipher_key_size + 20)]);
         stretch_key(key, &mut longer_key);
 
         let (local_infos, remote_infos) = {
@@ -318,7 +263,9 @@ where
 
     // We send back their nonce to check if the connection works.
     trace!("checking encryption by sending back remote's nonce");
-    codec.send(remote_nonce).await?;
+    // The nonce is moved rather than copied, as the codec encrypts the buffer it is given in
+    // place, which leaves no copy of the nonce behind.
+    codec.send(mem::take(&mut *remote_nonce)).await?;
 
     Ok((codec, remote_public_key, tmp_pub_key))
 }
@@ -69,9 +69,11 @@ mod codec;
 mod error;
 mod exchange;
 mod handshake;
+mod negotiation;
 mod structs_proto {
     include!(concat!(env!("OUT_DIR"), "/spipe.pb.rs"));
 }
+mod structs_ext;
 mod stream_cipher;
 
 pub use crate::algo_support::Digest;
@@ -0,0 +1,234 @@
+// Copyright 2020 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Selection of the algorithms used by a connection, from the propositions of both sides.
+//!
+//! Each side proposes comma-separated lists of algorithms, ordered by preference. The
+//! preferences of the side whose public key hashed with the nonce of the other side is the
+//! largest win: that side is determined by comparing `SHA256(remote_pubkey || local_nonce)`
+//! with `SHA256(local_pubkey || remote_nonce)`, which both sides compute identically. This is
+//! the algorithm of the go and js implementations of secio.
+
+use crate::algo_support::{self, Digest};
+use crate::error::SecioError;
+use crate::exchange::KeyAgreement;
+use crate::stream_cipher::Cipher;
+use crate::structs_proto::Propose;
+use sha2::{Digest as ShaDigestTrait, Sha256};
+use std::cmp::Ordering;
+
+/// Algorithms agreed upon by both sides of a handshake.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Negotiated {
+    /// `Greater` if the preferences of the local side won, `Less` if those of the remote won.
+    ///
+    /// `Equal` if both sides have the same public key and nonce, in which case the remote's
+    /// preferences are used. The handshake can't go any further, as there is no way to tell the
+    /// two sides apart when deriving the keys.
+    pub ordering: Ordering,
+    /// Key agreement used to generate the shared secret.
+    pub agreement: KeyAgreement,
+    /// Cipher used to encrypt the data.
+    pub cipher: Cipher,
+    /// Digest used to authenticate the data.
+    pub digest: Digest,
+}
+
+/// Determines whose preferences win, from the propositions of both sides. See [`Negotiated`].
+pub fn ordering(local: &Propose, remote: &Propose) -> Ordering {
+    let oh1 = {
+        let mut ctx = Sha256::new();
+        ctx.input(remote.pubkey.as_deref().unwrap_or_default());
+        ctx.input(local.rand.as_deref().unwrap_or_default());
+        ctx.result()
+    };
+
+    let oh2 = {
+        let mut ctx = Sha256::new();
+        ctx.input(local.pubkey.as_deref().unwrap_or_default());
+        ctx.input(remote.rand.as_deref().unwrap_or_default());
+        ctx.result()
+    };
+
+    oh1.as_slice().cmp(oh2.as_slice())
+}
+
+/// Selects the algorithms to use from the propositions of both sides.
+///
+/// Algorithms we don't know of are ignored. Fails with [`SecioError::NoSupportIntersection`]
+/// if the propositions have no key agreement, cipher or digest in common.
+pub fn negotiate(local: &Propose, remote: &Propose) -> Result<Negotiated, SecioError> {
+    let ordering = ordering(local, remote);
+    let lists = |p: &Propose| (
+        p.exchanges.as_deref().unwrap_or_default(),
+        p.ciphers.as_deref().unwrap_or_default(),
+        p.hashes.as_deref().unwrap_or_default(),
+    );
+    let (ours, theirs) = (lists(local), lists(remote));
+
+    let agreement = select(
+        ordering,
+        &algo_support::parse_agreements(ours.0),
+        &algo_support::parse_agreements(theirs.0),
+    )?;
+    let cipher = select(
+        ordering,
+        &algo_support::parse_ciphers(ours.1),
+        &algo_support::parse_ciphers(theirs.1),
+    )?;
+    let digest = select(
+        ordering,
+        &algo_support::parse_digests(ours.2),
+        &algo_support::parse_digests(theirs.2),
+    )?;
+
+    Ok(Negotiated { ordering, agreement, cipher, digest })
+}
+
+/// Returns the first algorithm of the winning side that the other side supports as well.
+fn select<T: Copy + PartialEq>(ordering: Ordering, ours: &[T], theirs: &[T])
+    -> Result<T, SecioError>
+{
+    let (first, second) = match ordering {
+        Ordering::Less | Ordering::Equal => (theirs, ours),
+        Ordering::Greater => (ours, theirs)
+    };
+    first.iter()
+        .find(|x| second.contains(x))
+        .copied()
+        .ok_or(SecioError::NoSupportIntersection)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{negotiate, ordering, Negotiated};
+    use crate::algo_support::Digest;
+    use crate::error::SecioError;
+    use crate::exchange::KeyAgreement;
+    use crate::stream_cipher::Cipher;
+    use crate::structs_proto::Propose;
+    use std::cmp::Ordering;
+
+    fn proposition(seed: u8, exchanges: &str, ciphers: &str, hashes: &str) -> Propose {
+        Propose {
+            rand: Some(vec![seed + 1; 16]),
+            pubkey: Some(vec![seed; 4]),
+            exchanges: Some(exchanges.into()),
+            ciphers: Some(ciphers.into()),
+            hashes: Some(hashes.into()),
+        }
+    }
+
+    #[test]
+    fn ordering_vector() {
+        // SHA256([3; 4] || [2; 16]) = a7cbeb5d...
+        // SHA256([1; 4] || [4; 16]) = fecaf122...
+        let a = proposition(1, "", "", "");
+        let b = proposition(3, "", "", "");
+        assert_eq!(ordering(&a, &b), Ordering::Less);
+        assert_eq!(ordering(&b, &a), Ordering::Greater);
+        assert_eq!(ordering(&a, &a), Ordering::Equal);
+    }
+
+    #[test]
+    fn both_sides_agree() {
+        let a = proposition(1, "P-256,P-384", "AES-128,AES-256", "SHA256,SHA512");
+        let b = proposition(3, "P-384,P-256", "AES-256,AES-128", "SHA512,SHA256");
+        // The preferences of `b` win.
+        let expected = |ordering| Negotiated {
+            ordering,
+            agreement: KeyAgreement::EcdhP384,
+            cipher: Cipher::Aes256,
+            digest: Digest::Sha512,
+        };
+        assert_eq!(negotiate(&a, &b).unwrap(), expected(Ordering::Less));
+        assert_eq!(negotiate(&b, &a).unwrap(), expected(Ordering::Greater));
+    }
+
+    #[test]
+    fn encoded_keys_vector() {
+        // The protobuf-encoded Ed25519 and Secp256k1 public keys of the peer ID specification
+        // test vectors, with fixed nonces. go-libp2p-secio compares, from the Ed25519 side:
+        // SHA256(secp256k1 key || ed25519 nonce) = b441972c...
+        // SHA256(ed25519 key || secp256k1 nonce) = 65ac6ce9...
+        // and passes the positive result to `selectBest`, so the Ed25519 preferences win.
+        let ed25519 = Propose {
+            rand: Some(vec![
+                0x8f, 0x4e, 0x1c, 0x2a, 0x9b, 0x3d, 0x5e, 0x7f, 0x60, 0x71, 0x82, 0x93, 0xa4, 0xb5,
+                0xc6, 0xd7,
+            ]),
+            pubkey: Some(vec![
+                0x08, 0x01, 0x12, 0x20, 0x1e, 0xd1, 0xe8, 0xfa, 0xe2, 0xc4, 0xa1, 0x44, 0xb8, 0xbe,
+                0x8f, 0xd4, 0xb4, 0x7b, 0xf3, 0xd3, 0xb3, 0x4b, 0x87, 0x1c, 0x3c, 0xac, 0xf6, 0x01,
+                0x0f, 0x0e, 0x42, 0xd4, 0x74, 0xfc, 0xe2, 0x7e,
+            ]),
+            exchanges: Some("P-256,P-384".into()),
+            ciphers: Some("AES-128,AES-256".into()),
+            hashes: Some("SHA256,SHA512".into()),
+        };
+        let secp256k1 = Propose {
+            rand: Some(vec![
+                0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef, 0xfe, 0xdc, 0xba, 0x98, 0x76, 0x54,
+                0x32, 0x10,
+            ]),
+            pubkey: Some(vec![
+                0x08, 0x02, 0x12, 0x21, 0x03, 0x77, 0x77, 0xe9, 0x94, 0xe4, 0x52, 0xc2, 0x16, 0x04,
+                0xf9, 0x1d, 0xe0, 0x93, 0xce, 0x41, 0x5f, 0x54, 0x32, 0xf7, 0x01, 0xdd, 0x8c, 0xd1,
+                0xa7, 0xa6, 0xfe, 0xa0, 0xe6, 0x30, 0xbf, 0xca, 0x99,
+            ]),
+            exchanges: Some("P-384,P-256".into()),
+            ciphers: Some("AES-256,AES-128".into()),
+            hashes: Some("SHA512,SHA256".into()),
+        };
+        let expected = |ordering| Negotiated {
+            ordering,
+            agreement: KeyAgreement::EcdhP256,
+            cipher: Cipher::Aes128,
+            digest: Digest::Sha256,
+        };
+        assert_eq!(negotiate(&ed25519, &secp256k1).unwrap(), expected(Ordering::Greater));
+        assert_eq!(negotiate(&secp256k1, &ed25519).unwrap(), expected(Ordering::Less));
+    }
+
+    #[test]
+    fn unknown_algorithms_are_skipped() {
+        let a = proposition(1, "P-521,P-256", "Blowfish,TwofishCTR", "SHA1,SHA256");
+        let b = proposition(3, "P-521,P-384,P-256", "Blowfish,AES-128,TwofishCTR", "SHA256");
+        let negotiated = negotiate(&a, &b).unwrap();
+        assert_eq!(negotiated.agreement, KeyAgreement::EcdhP256);
+        assert_eq!(negotiated.cipher, Cipher::TwofishCtr);
+        assert_eq!(negotiated.digest, Digest::Sha256);
+    }
+
+    #[test]
+    fn no_common_algorithm() {
+        let a = proposition(1, "P-256", "AES-128", "SHA256");
+        for b in &[
+            proposition(3, "P-384", "AES-128", "SHA256"),
+            proposition(3, "P-256", "AES-256", "SHA256"),
+            proposition(3, "P-256", "AES-128", "SHA512"),
+        ] {
+            match negotiate(&a, b) {
+                Err(SecioError::NoSupportIntersection) => {}
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+    }
+}
@@ -26,7 +26,7 @@ use ctr::Ctr128;
 use twofish::Twofish;
 
 /// Possible encryption ciphers.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Cipher {
     Aes128,
     Aes256,
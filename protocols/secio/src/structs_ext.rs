@@ -0,0 +1,278 @@
+// Copyright 2020 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Hand-written additions to the messages generated from `structs.proto`.
+
+use crate::algo_support::{KNOWN_AGREEMENTS, KNOWN_CIPHERS, KNOWN_DIGESTS};
+use crate::error::SecioError;
+use crate::structs_proto::{Exchange, Propose};
+use libp2p_core::PublicKey;
+use zeroize::Zeroize;
+
+/// Length in bytes of the nonce in a `Propose` message.
+pub(crate) const NONCE_SIZE: usize = 16;
+
+impl Propose {
+    /// Builds a proposition with all of its fields set, the public key being encoded in its
+    /// protobuf representation.
+    pub(crate) fn build(
+        rand: Vec<u8>,
+        pubkey: &PublicKey,
+        exchanges: &str,
+        ciphers: &str,
+        hashes: &str,
+    ) -> Propose {
+        Propose {
+            rand: Some(rand),
+            pubkey: Some(pubkey.clone().into_protobuf_encoding()),
+            exchanges: Some(exchanges.to_owned()),
+            ciphers: Some(ciphers.to_owned()),
+            hashes: Some(hashes.to_owned()),
+        }
+    }
+
+    /// Checks that the proposition is well-formed: the nonce has the expected length, the public
+    /// key can be decoded, and each algorithm list is a non-empty comma-separated list containing
+    /// at least one algorithm that we know of.
+    ///
+    /// Unknown algorithms are allowed in the lists, as other implementations may support
+    /// algorithms that we don't. Whether the lists have an algorithm in common with ours is only
+    /// checked during the negotiation.
+    pub(crate) fn validate(&self) -> Result<(), SecioError> {
+        match &self.rand {
+            Some(rand) if rand.len() == NONCE_SIZE => {}
+            Some(_) => return Err(SecioError::InvalidProposition("nonce has an invalid length")),
+            None => return Err(SecioError::InvalidProposition("missing nonce")),
+        }
+        match &self.pubkey {
+            Some(pubkey) => {
+                if PublicKey::from_protobuf_encoding(pubkey).is_err() {
+                    return Err(SecioError::InvalidProposition("public key failed to decode"));
+                }
+            }
+            None => return Err(SecioError::InvalidProposition("missing public key")),
+        }
+        AGREEMENTS.validate(&self.exchanges)?;
+        CIPHERS.validate(&self.ciphers)?;
+        DIGESTS.validate(&self.hashes)?;
+        Ok(())
+    }
+}
+
+impl Exchange {
+    /// Builds an exchange message with all of its fields set.
+    pub(crate) fn build(epubkey: Vec<u8>, signature: Vec<u8>) -> Exchange {
+        Exchange {
+            epubkey: Some(epubkey),
+            signature: Some(signature),
+        }
+    }
+}
+
+/// Overwrites the nonce and public key with zeroes. The algorithm lists aren't sensitive and are
+/// left untouched.
+impl Zeroize for Propose {
+    fn zeroize(&mut self) {
+        for buf in [&mut self.rand, &mut self.pubkey].iter_mut() {
+            if let Some(buf) = buf {
+                buf.zeroize();
+            }
+        }
+    }
+}
+
+/// Overwrites the ephemeral public key and the signature with zeroes.
+impl Zeroize for Exchange {
+    fn zeroize(&mut self) {
+        for buf in [&mut self.epubkey, &mut self.signature].iter_mut() {
+            if let Some(buf) = buf {
+                buf.zeroize();
+            }
+        }
+    }
+}
+
+/// Describes one of the algorithm lists of a `Propose` message.
+struct AlgorithmList {
+    /// Algorithms that we know of.
+    known: &'static [&'static str],
+    /// Error message if the list is missing or empty.
+    empty: &'static str,
+    /// Error message if the list has an empty entry.
+    empty_entry: &'static str,
+    /// Error message if the list contains no algorithm that we know of.
+    unknown: &'static str,
+}
+
+const AGREEMENTS: AlgorithmList = AlgorithmList {
+    known: KNOWN_AGREEMENTS,
+    empty: "empty list of key agreements",
+    empty_entry: "empty entry in the list of key agreements",
+    unknown: "no known key agreement",
+};
+
+const CIPHERS: AlgorithmList = AlgorithmList {
+    known: KNOWN_CIPHERS,
+    empty: "empty list of ciphers",
+    empty_entry: "empty entry in the list of ciphers",
+    unknown: "no known cipher",
+};
+
+const DIGESTS: AlgorithmList = AlgorithmList {
+    known: KNOWN_DIGESTS,
+    empty: "empty list of digests",
+    empty_entry: "empty entry in the list of digests",
+    unknown: "no known digest",
+};
+
+impl AlgorithmList {
+    /// Checks that `list` is a non-empty comma-separated list of algorithms containing at least
+    /// one that we know of.
+    fn validate(&self, list: &Option<String>) -> Result<(), SecioError> {
+        let list = match list {
+            Some(list) if !list.is_empty() => list,
+            _ => return Err(SecioError::InvalidProposition(self.empty)),
+        };
+        if list.split(',').any(|algo| algo.is_empty()) {
+            return Err(SecioError::InvalidProposition(self.empty_entry));
+        }
+        if !list.split(',').any(|algo| self.known.contains(&algo)) {
+            return Err(SecioError::InvalidProposition(self.unknown));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NONCE_SIZE;
+    use crate::algo_support;
+    use crate::error::SecioError;
+    use crate::structs_proto::{Exchange, Propose};
+    use libp2p_core::{identity, PublicKey};
+    use zeroize::{Zeroize, Zeroizing};
+
+    fn proposition() -> Propose {
+        Propose::build(
+            vec![0; NONCE_SIZE],
+            &identity::Keypair::generate_ed25519().public(),
+            algo_support::DEFAULT_AGREEMENTS_PROPOSITION,
+            algo_support::DEFAULT_CIPHERS_PROPOSITION,
+            algo_support::DEFAULT_DIGESTS_PROPOSITION,
+        )
+    }
+
+    #[test]
+    fn build_sets_all_fields() {
+        let key = identity::Keypair::generate_ed25519().public();
+        let prop = Propose::build(vec![7; NONCE_SIZE], &key, "P-256", "AES-128", "SHA256");
+        assert_eq!(prop.rand, Some(vec![7; NONCE_SIZE]));
+        let pubkey = PublicKey::from_protobuf_encoding(prop.pubkey.as_ref().unwrap()).unwrap();
+        assert_eq!(pubkey, key);
+        assert_eq!(prop.exchanges.as_deref(), Some("P-256"));
+        assert_eq!(prop.ciphers.as_deref(), Some("AES-128"));
+        assert_eq!(prop.hashes.as_deref(), Some("SHA256"));
+
+        let exch = Exchange::build(vec![1, 2], vec![3, 4]);
+        assert_eq!(exch.epubkey, Some(vec![1, 2]));
+        assert_eq!(exch.signature, Some(vec![3, 4]));
+    }
+
+    fn invalid(prop: Propose) -> &'static str {
+        match prop.validate() {
+            Err(SecioError::InvalidProposition(msg)) => msg,
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    /// Lets a test inspect a message after the `Zeroizing` that wraps it has been dropped.
+    struct Inspect<'a, T>(&'a mut T);
+
+    impl<T: Zeroize> Zeroize for Inspect<'_, T> {
+        fn zeroize(&mut self) {
+            self.0.zeroize()
+        }
+    }
+
+    #[test]
+    fn zeroizing_wipes_key_material_on_drop() {
+        let mut prop = proposition();
+        prop.rand = Some(vec![0xff; NONCE_SIZE]);
+        drop(Zeroizing::new(Inspect(&mut prop)));
+        assert_eq!(prop.rand, Some(Vec::new()));
+        assert_eq!(prop.pubkey, Some(Vec::new()));
+        assert!(prop.exchanges.is_some());
+
+        let mut exch = Exchange::build(vec![0xff; 65], vec![0xff; 64]);
+        drop(Zeroizing::new(Inspect(&mut exch)));
+        assert_eq!(exch.epubkey, Some(Vec::new()));
+        assert_eq!(exch.signature, Some(Vec::new()));
+    }
+
+    #[test]
+    fn valid_proposition() {
+        assert!(proposition().validate().is_ok());
+        // Algorithms we don't know of are tolerated next to ones we do.
+        let prop = Propose { ciphers: Some("Blowfish,AES-128".into()), ..proposition() };
+        assert!(prop.validate().is_ok());
+    }
+
+    #[test]
+    fn invalid_nonce() {
+        assert_eq!(invalid(Propose { rand: None, ..proposition() }), "missing nonce");
+        assert_eq!(
+            invalid(Propose { rand: Some(vec![0; NONCE_SIZE - 1]), ..proposition() }),
+            "nonce has an invalid length"
+        );
+    }
+
+    #[test]
+    fn invalid_public_key() {
+        assert_eq!(invalid(Propose { pubkey: None, ..proposition() }), "missing public key");
+        assert_eq!(
+            invalid(Propose { pubkey: Some(vec![1, 2, 3]), ..proposition() }),
+            "public key failed to decode"
+        );
+    }
+
+    #[test]
+    fn invalid_algorithm_lists() {
+        assert_eq!(
+            invalid(Propose { exchanges: None, ..proposition() }),
+            "empty list of key agreements"
+        );
+        assert_eq!(
+            invalid(Propose { ciphers: Some(String::new()), ..proposition() }),
+            "empty list of ciphers"
+        );
+        assert_eq!(
+            invalid(Propose { hashes: Some("SHA256,".into()), ..proposition() }),
+            "empty entry in the list of digests"
+        );
+        assert_eq!(
+            invalid(Propose { exchanges: Some("P-521".into()), ..proposition() }),
+            "no known key agreement"
+        );
+        assert_eq!(
+            invalid(Propose { ciphers: Some("Blowfish".into()), ..proposition() }),
+            "no known cipher"
+        );
+    }
+}
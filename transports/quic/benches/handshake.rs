@@ -0,0 +1,74 @@
+// Copyright 2020 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use futures::prelude::*;
+use libp2p_core::transport::{ListenerEvent, Transport};
+use libp2p_quic::{Config, Endpoint, QuicTransport};
+
+/// Keypairs shared by all benchmarks, so that runs are comparable. Each side of a connection
+/// uses its own `seed`, as dialing ourselves fails.
+fn keypair(seed: u8) -> libp2p_core::identity::Keypair {
+    let mut secret = [seed; 32];
+    let secret = libp2p_core::identity::ed25519::SecretKey::from_bytes(&mut secret).unwrap();
+    libp2p_core::identity::Keypair::Ed25519(secret.into())
+}
+
+fn config(seed: u8) -> Config {
+    Config::new(&keypair(seed), "/ip4/127.0.0.1/udp/0/quic".parse().unwrap()).unwrap()
+}
+
+/// Measures the time it takes to establish a connection over the loopback interface, from the
+/// dial to both sides having completed the handshake.
+fn handshake_latency(c: &mut Criterion) {
+    let mut listener = QuicTransport(Endpoint::new(config(7)).unwrap())
+        .listen_on("/ip4/127.0.0.1/udp/0/quic".parse().unwrap())
+        .unwrap();
+    let addr = async_std::task::block_on(async {
+        match listener.next().await.unwrap().unwrap() {
+            ListenerEvent::NewAddress(addr) => addr,
+            _ => unreachable!(),
+        }
+    });
+    let dialer = QuicTransport(Endpoint::new(config(8)).unwrap());
+
+    c.bench_function("handshake", |b| {
+        b.iter(|| {
+            async_std::task::block_on(async {
+                let accept = async {
+                    loop {
+                        if let ListenerEvent::Upgrade { upgrade, .. } =
+                            listener.next().await.unwrap().unwrap()
+                        {
+                            break upgrade.await.unwrap();
+                        }
+                    }
+                };
+                let (accepted, dialed) =
+                    future::join(accept, dialer.clone().dial(addr.clone()).unwrap()).await;
+                dialed.unwrap();
+                accepted
+            })
+        })
+    });
+}
+
+criterion_group!(benches, handshake_latency);
+criterion_main!(benches);
@@ -0,0 +1,497 @@
+// Copyright 2020 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use futures::prelude::*;
+use libp2p_core::{
+    multiaddr::{Multiaddr, Protocol},
+    muxing::StreamMuxer,
+    transport::{ListenerEvent, Transport},
+};
+use libp2p_quic::{Config, Endpoint, QuicMuxer, QuicTransport};
+use std::{io::IoSlice, net::SocketAddr, sync::Arc};
+
+/// Keypairs shared by all benchmarks, so that runs are comparable. Each side of a connection
+/// uses its own `seed`, as dialing ourselves fails.
+fn keypair(seed: u8) -> libp2p_core::identity::Keypair {
+    let mut secret = [seed; 32];
+    let secret = libp2p_core::identity::ed25519::SecretKey::from_bytes(&mut secret).unwrap();
+    libp2p_core::identity::Keypair::Ed25519(secret.into())
+}
+
+fn config(seed: u8) -> Config {
+    Config::new(&keypair(seed), "/ip4/127.0.0.1/udp/0/quic".parse().unwrap()).unwrap()
+}
+
+/// Establishes a connection over the loopback interface and returns the listener's and the
+/// dialer's muxers, in that order.
+async fn connected_pair() -> (Arc<QuicMuxer>, Arc<QuicMuxer>) {
+    connected_pair_with(config(8), None).await
+}
+
+/// Like [`connected_pair`], with the dialer built from `dialer_config`. If `loss_interval` is
+/// set, the connection goes through a [`lossy_relay`].
+async fn connected_pair_with(
+    dialer_config: Config,
+    loss_interval: Option<usize>,
+) -> (Arc<QuicMuxer>, Arc<QuicMuxer>) {
+    let mut listener = QuicTransport(Endpoint::new(config(7)).unwrap())
+        .listen_on("/ip4/127.0.0.1/udp/0/quic".parse().unwrap())
+        .unwrap();
+    let addr = match listener.next().await.unwrap().unwrap() {
+        ListenerEvent::NewAddress(addr) => addr,
+        _ => unreachable!(),
+    };
+    let addr = match loss_interval {
+        Some(loss_interval) => lossy_relay(&addr, loss_interval).await,
+        None => addr,
+    };
+    let dialer = QuicTransport(Endpoint::new(dialer_config).unwrap());
+    let dial = async_std::task::spawn(dialer.dial(addr).unwrap());
+    let listener_muxer = loop {
+        if let ListenerEvent::Upgrade { upgrade, .. } = listener.next().await.unwrap().unwrap() {
+            break upgrade.await.unwrap().1;
+        }
+    };
+    let dialer_muxer = dial.await.unwrap().1;
+    (Arc::new(listener_muxer), Arc::new(dialer_muxer))
+}
+
+/// Spawns a UDP relay in front of the QUIC address `target`, which drops one datagram out of
+/// `loss_interval` in each direction, and returns the address to dial in order to go through it.
+async fn lossy_relay(target: &Multiaddr, loss_interval: usize) -> Multiaddr {
+    let target = match (target.iter().next(), target.iter().nth(1)) {
+        (Some(Protocol::Ip4(ip)), Some(Protocol::Udp(port))) => SocketAddr::new(ip.into(), port),
+        _ => unreachable!(),
+    };
+    let front = async_std::net::UdpSocket::bind("127.0.0.1:0")
+        .await
+        .unwrap();
+    let back = async_std::net::UdpSocket::bind("127.0.0.1:0")
+        .await
+        .unwrap();
+    let front_addr = front.local_addr().unwrap();
+
+    async_std::task::spawn(async move {
+        let mut dialer = None;
+        let mut front_buf = vec![0; 65536];
+        let mut back_buf = vec![0; 65536];
+        let (mut from_dialer, mut from_listener) = (0usize, 0usize);
+        loop {
+            futures::select! {
+                r = front.recv_from(&mut front_buf).fuse() => {
+                    if let Ok((n, from)) = r {
+                        dialer = Some(from);
+                        from_dialer += 1;
+                        if from_dialer % loss_interval != 0 {
+                            let _ = back.send_to(&front_buf[..n], target).await;
+                        }
+                    }
+                }
+                r = back.recv_from(&mut back_buf).fuse() => {
+                    if let (Ok((n, _)), Some(dialer)) = (r, dialer) {
+                        from_listener += 1;
+                        if from_listener % loss_interval != 0 {
+                            let _ = front.send_to(&back_buf[..n], dialer).await;
+                        }
+                    }
+                }
+            }
+        }
+    });
+
+    Multiaddr::empty()
+        .with(front_addr.ip().into())
+        .with(Protocol::Udp(front_addr.port()))
+        .with(Protocol::Quic)
+}
+
+async fn open(muxer: &QuicMuxer) -> quinn_proto::StreamId {
+    future::poll_fn(|cx| muxer.poll_outbound(cx, &mut ()))
+        .await
+        .unwrap()
+}
+
+async fn accept(muxer: &QuicMuxer) -> quinn_proto::StreamId {
+    future::poll_fn(|cx| muxer.poll_inbound(cx)).await.unwrap()
+}
+
+async fn write_all(muxer: &QuicMuxer, substream: &mut quinn_proto::StreamId, mut buf: &[u8]) {
+    while !buf.is_empty() {
+        let n = future::poll_fn(|cx| muxer.write_substream(cx, substream, buf))
+            .await
+            .unwrap();
+        buf = &buf[n..];
+    }
+}
+
+async fn read_exact(muxer: &QuicMuxer, substream: &mut quinn_proto::StreamId, mut buf: &mut [u8]) {
+    while !buf.is_empty() {
+        let n = future::poll_fn(|cx| muxer.read_substream(cx, substream, buf))
+            .await
+            .unwrap();
+        assert_ne!(n, 0, "premature end of file");
+        buf = &mut buf[n..];
+    }
+}
+
+/// Measures the throughput of a single substream over the loopback interface.
+fn single_substream_throughput(c: &mut Criterion) {
+    const LEN: usize = 4 * 1024 * 1024;
+
+    let (listener, dialer) = async_std::task::block_on(connected_pair());
+    let data = vec![0u8; LEN];
+
+    let mut group = c.benchmark_group("single substream");
+    group.throughput(Throughput::Bytes(LEN as u64));
+    group.sample_size(10);
+    group.bench_function("4 MiB", |b| {
+        b.iter(|| {
+            async_std::task::block_on(async {
+                let mut substream = open(&dialer).await;
+                let write = async {
+                    write_all(&dialer, &mut substream, &data).await;
+                    future::poll_fn(|cx| dialer.shutdown_substream(cx, &mut substream))
+                        .await
+                        .unwrap();
+                };
+                let read = async {
+                    let mut substream = accept(&listener).await;
+                    let mut buf = vec![0u8; 64 * 1024];
+                    while future::poll_fn(|cx| {
+                        listener.read_substream(cx, &mut substream, &mut buf)
+                    })
+                    .await
+                    .unwrap()
+                        != 0
+                    {}
+                    listener.destroy_substream(substream);
+                };
+                future::join(write, read).await;
+                dialer.destroy_substream(substream);
+            })
+        })
+    });
+    group.finish();
+}
+
+/// Measures how fast substreams carrying a single byte each are opened, used and closed.
+fn small_substreams_rate(c: &mut Criterion) {
+    let (listener, dialer) = async_std::task::block_on(connected_pair());
+
+    // The listener reads the byte and closes its side of each substream.
+    async_std::task::spawn({
+        let listener = listener.clone();
+        async move {
+            loop {
+                let mut substream = accept(&listener).await;
+                let mut buf = [0u8; 1];
+                read_exact(&listener, &mut substream, &mut buf).await;
+                future::poll_fn(|cx| listener.shutdown_substream(cx, &mut substream))
+                    .await
+                    .unwrap();
+                listener.destroy_substream(substream);
+            }
+        }
+    });
+
+    let mut group = c.benchmark_group("small substreams");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("open, write one byte, close", |b| {
+        b.iter(|| {
+            async_std::task::block_on(async {
+                let mut substream = open(&dialer).await;
+                write_all(&dialer, &mut substream, &[1]).await;
+                future::poll_fn(|cx| dialer.shutdown_substream(cx, &mut substream))
+                    .await
+                    .unwrap();
+                let mut buf = [0u8; 1];
+                let n = future::poll_fn(|cx| dialer.read_substream(cx, &mut substream, &mut buf))
+                    .await
+                    .unwrap();
+                assert_eq!(n, 0);
+                dialer.destroy_substream(substream);
+            })
+        })
+    });
+    group.finish();
+}
+
+/// Measures the round-trip time of a small message on one substream while 99 other substreams
+/// of the same connection are busy with bulk transfers.
+fn busy_connection_round_trip(c: &mut Criterion) {
+    const BUSY_SUBSTREAMS: usize = 99;
+
+    let (listener, dialer) = async_std::task::block_on(connected_pair());
+
+    // Bulk transfers on the busy substreams, which are drained by the listener.
+    for _ in 0..BUSY_SUBSTREAMS {
+        let dialer = dialer.clone();
+        async_std::task::spawn(async move {
+            let mut substream = open(&dialer).await;
+            let data = vec![0u8; 16 * 1024];
+            loop {
+                write_all(&dialer, &mut substream, &data).await;
+            }
+        });
+    }
+
+    // The listener echoes back everything received on the first substream and discards the
+    // data of all the others.
+    let probe = async_std::task::block_on(open(&dialer));
+    async_std::task::spawn({
+        let listener = listener.clone();
+        async move {
+            loop {
+                let mut substream = accept(&listener).await;
+                let listener = listener.clone();
+                async_std::task::spawn(async move {
+                    let mut buf = vec![0u8; 16 * 1024];
+                    loop {
+                        let n = future::poll_fn(|cx| {
+                            listener.read_substream(cx, &mut substream, &mut buf)
+                        })
+                        .await
+                        .unwrap();
+                        if n == 1 {
+                            write_all(&listener, &mut substream, &buf[..1]).await;
+                        }
+                    }
+                });
+            }
+        }
+    });
+
+    let mut probe = Some(probe);
+    c.bench_function("round trip with 100 active substreams", |b| {
+        b.iter(|| {
+            let substream = probe.as_mut().unwrap();
+            async_std::task::block_on(async {
+                write_all(&dialer, substream, &[1]).await;
+                let mut buf = [0u8; 1];
+                read_exact(&dialer, substream, &mut buf).await;
+            })
+        })
+    });
+}
+
+/// Measures the total throughput of a connection when many tasks concurrently write on their
+/// own substream.
+fn concurrent_writers_throughput(c: &mut Criterion) {
+    const WRITERS: usize = 16;
+    const LEN: usize = 256 * 1024;
+
+    let (listener, dialer) = async_std::task::block_on(connected_pair());
+
+    // The listener drains every substream, and reports each end of file.
+    let (finished_tx, finished_rx) = futures::channel::mpsc::unbounded();
+    async_std::task::spawn({
+        let listener = listener.clone();
+        async move {
+            loop {
+                let mut substream = accept(&listener).await;
+                let listener = listener.clone();
+                let finished_tx = finished_tx.clone();
+                async_std::task::spawn(async move {
+                    let mut buf = vec![0u8; 16 * 1024];
+                    loop {
+                        let n = future::poll_fn(|cx| {
+                            listener.read_substream(cx, &mut substream, &mut buf)
+                        })
+                        .await
+                        .unwrap();
+                        if n == 0 {
+                            break;
+                        }
+                    }
+                    listener.destroy_substream(substream);
+                    let _ = finished_tx.unbounded_send(());
+                });
+            }
+        }
+    });
+
+    let mut finished_rx = finished_rx;
+    let mut group = c.benchmark_group("concurrent writers");
+    group.throughput(Throughput::Bytes((WRITERS * LEN) as u64));
+    group.sample_size(10);
+    group.bench_function("16 substreams", |b| {
+        b.iter(|| {
+            async_std::task::block_on(async {
+                let writers = (0..WRITERS)
+                    .map(|_| {
+                        let dialer = dialer.clone();
+                        async_std::task::spawn(async move {
+                            let mut substream = open(&dialer).await;
+                            write_all(&dialer, &mut substream, &vec![0u8; LEN]).await;
+                            future::poll_fn(|cx| dialer.shutdown_substream(cx, &mut substream))
+                                .await
+                                .unwrap();
+                            dialer.destroy_substream(substream);
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                future::join_all(writers).await;
+                for _ in 0..WRITERS {
+                    finished_rx.next().await.unwrap();
+                }
+            })
+        })
+    });
+    group.finish();
+}
+
+/// Measures the throughput of a single substream over a link that loses 2% of the datagrams,
+/// with the default initial congestion window and a larger one. The congestion controller itself
+/// is always the NewReno of `quinn_proto`.
+fn lossy_link_initial_window(c: &mut Criterion) {
+    const LEN: usize = 1024 * 1024;
+    const LOSS_INTERVAL: usize = 50;
+
+    let mut group = c.benchmark_group("lossy link initial window");
+    group.throughput(Throughput::Bytes(LEN as u64));
+    group.sample_size(10);
+    for &(name, initial_window) in &[
+        ("default initial window", None),
+        ("initial window of 256 kiB", Some(256 * 1024)),
+    ] {
+        let dialer_config = match initial_window {
+            Some(bytes) => config(8).with_initial_window(bytes),
+            None => config(8),
+        };
+        let (listener, dialer) =
+            async_std::task::block_on(connected_pair_with(dialer_config, Some(LOSS_INTERVAL)));
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                async_std::task::block_on(async {
+                    let mut substream = open(&dialer).await;
+                    let write = async {
+                        write_all(&dialer, &mut substream, &vec![0u8; LEN]).await;
+                        future::poll_fn(|cx| dialer.shutdown_substream(cx, &mut substream))
+                            .await
+                            .unwrap();
+                    };
+                    let read = async {
+                        let mut substream = accept(&listener).await;
+                        let mut buf = vec![0u8; 16 * 1024];
+                        while future::poll_fn(|cx| {
+                            listener.read_substream(cx, &mut substream, &mut buf)
+                        })
+                        .await
+                        .unwrap()
+                            != 0
+                        {}
+                        listener.destroy_substream(substream);
+                    };
+                    future::join(write, read).await;
+                    dialer.destroy_substream(substream);
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Compares writing 64 KiB chunks, made of 16 slices of 4 KiB each, with one call per slice
+/// and with one vectored write per chunk.
+fn vectored_writes_throughput(c: &mut Criterion) {
+    const CHUNKS: usize = 16;
+    const SLICES: usize = 16;
+    const SLICE_LEN: usize = 4 * 1024;
+
+    let (listener, dialer) = async_std::task::block_on(connected_pair());
+    let slices = vec![vec![0u8; SLICE_LEN]; SLICES];
+
+    let mut group = c.benchmark_group("64 KiB chunks");
+    group.throughput(Throughput::Bytes((CHUNKS * SLICES * SLICE_LEN) as u64));
+    group.sample_size(10);
+    for &vectored in &[false, true] {
+        let name = if vectored {
+            "vectored writes"
+        } else {
+            "one write per slice"
+        };
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                async_std::task::block_on(async {
+                    let mut substream = open(&dialer).await;
+                    let write = async {
+                        for _ in 0..CHUNKS {
+                            if vectored {
+                                let mut bufs =
+                                    slices.iter().map(|s| IoSlice::new(s)).collect::<Vec<_>>();
+                                let mut bufs = &mut bufs[..];
+                                while !bufs.is_empty() {
+                                    let mut n = future::poll_fn(|cx| {
+                                        dialer.write_substream_vectored(cx, &mut substream, bufs)
+                                    })
+                                    .await
+                                    .unwrap();
+                                    while n > 0 && n >= bufs[0].len() {
+                                        n -= bufs[0].len();
+                                        bufs = &mut bufs[1..];
+                                    }
+                                    if n > 0 {
+                                        let rest = &slices[SLICES - bufs.len()]
+                                            [SLICE_LEN - bufs[0].len() + n..];
+                                        bufs[0] = IoSlice::new(rest);
+                                    }
+                                }
+                            } else {
+                                for slice in &slices {
+                                    write_all(&dialer, &mut substream, slice).await;
+                                }
+                            }
+                        }
+                        future::poll_fn(|cx| dialer.shutdown_substream(cx, &mut substream))
+                            .await
+                            .unwrap();
+                    };
+                    let read = async {
+                        let mut substream = accept(&listener).await;
+                        let mut buf = vec![0u8; 64 * 1024];
+                        while future::poll_fn(|cx| {
+                            listener.read_substream(cx, &mut substream, &mut buf)
+                        })
+                        .await
+                        .unwrap()
+                            != 0
+                        {}
+                        listener.destroy_substream(substream);
+                    };
+                    future::join(write, read).await;
+                    dialer.destroy_substream(substream);
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    single_substream_throughput,
+    small_substreams_rate,
+    busy_connection_round_trip,
+    concurrent_writers_throughput,
+    lossy_link_initial_window,
+    vectored_writes_throughput
+);
+criterion_main!(benches);
@@ -0,0 +1,58 @@
+// Copyright 2020 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use libp2p_quic::{Config, VerifierConfig};
+
+/// Keypairs shared by all benchmarks, so that runs are comparable.
+fn keypair(seed: u8) -> libp2p_core::identity::Keypair {
+    let mut secret = [seed; 32];
+    let secret = libp2p_core::identity::ed25519::SecretKey::from_bytes(&mut secret).unwrap();
+    libp2p_core::identity::Keypair::Ed25519(secret.into())
+}
+
+/// A certificate of the peer with the given `seed`, as presented during handshakes.
+fn certificate(seed: u8) -> rustls::Certificate {
+    let config = Config::new(&keypair(seed), "/ip4/127.0.0.1/udp/0/quic".parse().unwrap());
+    rustls::Certificate(config.unwrap().certificate_der().to_vec())
+}
+
+/// Measures the verification of the certificate of a peer that has already been seen, with and
+/// without a cache, as happens when a small set of peers reconnects repeatedly.
+fn certificate_verification(c: &mut Criterion) {
+    let certificates: Vec<_> = (0..8).map(|seed| [certificate(seed)]).collect();
+    let mut group = c.benchmark_group("certificate_verification");
+    for (name, config) in vec![
+        ("uncached", VerifierConfig::default()),
+        ("cached", VerifierConfig::default().with_cache(16)),
+    ] {
+        group.bench_function(name, |b| {
+            let mut certificates = certificates.iter().cycle();
+            b.iter(|| {
+                let certificate = certificates.next().unwrap();
+                config.verify_presented_certs(certificate).unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, certificate_verification);
+criterion_main!(benches);
@@ -0,0 +1,37 @@
+// Copyright 2020 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Feeds arbitrary bytes to the parsing of QUIC multiaddresses. Run with
+//! `cargo +nightly fuzz run multiaddr` from the `transports/quic` directory.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use libp2p_core::Multiaddr;
+use libp2p_quic::addr::{multiaddr_to_socketaddr, socketaddr_to_multiaddr};
+use std::convert::TryFrom;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(multiaddr) = Multiaddr::try_from(data.to_vec()) {
+        if let Ok(socket_addr) = multiaddr_to_socketaddr(&multiaddr) {
+            assert_eq!(socketaddr_to_multiaddr(&socket_addr), multiaddr);
+        }
+    }
+});
@@ -0,0 +1,193 @@
+// Copyright 2020 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Conversions between QUIC multiaddresses and UDP socket addresses.
+//!
+//! A QUIC multiaddress is made of exactly three components: an IPv4 or IPv6 address, a UDP
+//! port, and `/quic`, such as `/ip4/127.0.0.1/udp/4001/quic`. Host names, `/p2p` suffixes and
+//! anything else are rejected, and must be handled by the caller beforehand.
+
+use libp2p_core::multiaddr::{Multiaddr, Protocol};
+use std::net::SocketAddr;
+use thiserror::Error;
+
+/// Reason why a multiaddress isn't a QUIC multiaddress.
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The multiaddress has fewer than three components.
+    #[error("The multiaddress is missing components")]
+    MissingComponents,
+    /// The multiaddress has components after `/quic`, such as `/p2p`.
+    #[error("The multiaddress has components after `/quic`")]
+    TrailingComponents,
+    /// The first component isn't an IP address, for example a host name.
+    #[error("The multiaddress doesn't start with an IP address")]
+    NotIp,
+    /// The second component isn't a UDP port.
+    #[error("The multiaddress doesn't have a UDP port after the IP address")]
+    NotUdp,
+    /// The third component isn't `/quic`.
+    #[error("The multiaddress doesn't end with `/quic`")]
+    NotQuic,
+}
+
+/// Turns a QUIC multiaddress into a UDP [`SocketAddr`].
+// TODO: also accept `/quic-v1` once `quinn_proto` speaks RFC 9000 QUIC on the wire
+pub fn multiaddr_to_socketaddr(addr: &Multiaddr) -> Result<SocketAddr, Error> {
+    let mut iter = addr.iter();
+    let proto1 = iter.next().ok_or(Error::MissingComponents)?;
+    let proto2 = iter.next().ok_or(Error::MissingComponents)?;
+    let proto3 = iter.next().ok_or(Error::MissingComponents)?;
+
+    if iter.next().is_some() {
+        return Err(Error::TrailingComponents);
+    }
+
+    let ip = match proto1 {
+        Protocol::Ip4(ip) => ip.into(),
+        Protocol::Ip6(ip) => ip.into(),
+        _ => return Err(Error::NotIp),
+    };
+    let port = match proto2 {
+        Protocol::Udp(port) => port,
+        _ => return Err(Error::NotUdp),
+    };
+    if proto3 != Protocol::Quic {
+        return Err(Error::NotQuic);
+    }
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// Turns an IP address and port into the corresponding `/quic` multiaddress.
+pub fn socketaddr_to_multiaddr(socket_addr: &SocketAddr) -> Multiaddr {
+    Multiaddr::empty()
+        .with(socket_addr.ip().into())
+        .with(Protocol::Udp(socket_addr.port()))
+        .with(Protocol::Quic)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck::quickcheck;
+    use std::convert::TryFrom;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    fn parse(addr: &str) -> Result<SocketAddr, Error> {
+        multiaddr_to_socketaddr(&addr.parse::<Multiaddr>().unwrap())
+    }
+
+    #[test]
+    fn multiaddr_to_udp_conversion() {
+        assert_eq!(
+            parse("/ip4/127.0.0.1/udp/12345/quic"),
+            Ok(SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                12345
+            ))
+        );
+        assert_eq!(
+            parse("/ip4/255.255.255.255/udp/8080/quic"),
+            Ok(SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::new(255, 255, 255, 255)),
+                8080
+            ))
+        );
+        assert_eq!(
+            parse("/ip6/::1/udp/12345/quic"),
+            Ok(SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)),
+                12345
+            ))
+        );
+        assert_eq!(
+            parse("/ip6/ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff/udp/8080/quic"),
+            Ok(SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::new(
+                    65535, 65535, 65535, 65535, 65535, 65535, 65535, 65535,
+                )),
+                8080,
+            ))
+        );
+    }
+
+    #[test]
+    fn malformed_multiaddrs() {
+        let peer_id = libp2p_core::PeerId::random();
+        let cases = [
+            (String::new(), Error::MissingComponents),
+            ("/ip4/127.0.0.1".into(), Error::MissingComponents),
+            ("/ip4/127.0.0.1/udp/1234".into(), Error::MissingComponents),
+            ("/ip4/127.0.0.1/tcp/1234".into(), Error::MissingComponents),
+            ("/ip4/127.0.0.1/tcp/1234/quic".into(), Error::NotUdp),
+            ("/ip4/127.0.0.1/udp/1234/udt".into(), Error::NotQuic),
+            (
+                "/ip4/127.0.0.1/udp/1234/quic/ws".into(),
+                Error::TrailingComponents,
+            ),
+            ("/dns4/example.com/udp/1234/quic".into(), Error::NotIp),
+            ("/dns6/example.com/udp/1234/quic".into(), Error::NotIp),
+            ("/dns/example.com/udp/1234/quic".into(), Error::NotIp),
+            ("/udp/1234/ip4/127.0.0.1/quic".into(), Error::NotIp),
+            ("/ip4/127.0.0.1/quic/udp/1234".into(), Error::NotUdp),
+            (
+                format!("/ip4/127.0.0.1/udp/1234/quic/p2p/{}", peer_id),
+                Error::TrailingComponents,
+            ),
+            (
+                format!("/p2p/{}/ip4/127.0.0.1/udp/1234/quic", peer_id),
+                Error::TrailingComponents,
+            ),
+            (
+                format!("/ip4/127.0.0.1/p2p/{}/udp/1234", peer_id),
+                Error::NotUdp,
+            ),
+        ];
+        for (addr, error) in cases.iter() {
+            assert_eq!(parse(addr), Err(*error), "{}", addr);
+        }
+    }
+
+    #[test]
+    fn socketaddr_round_trip() {
+        fn prop(socket_addr: SocketAddr) -> bool {
+            // Multiaddresses don't carry the flow information and scope identifier of IPv6
+            // socket addresses.
+            let socket_addr = SocketAddr::new(socket_addr.ip(), socket_addr.port());
+            let multiaddr = socketaddr_to_multiaddr(&socket_addr);
+            multiaddr_to_socketaddr(&multiaddr) == Ok(socket_addr)
+        }
+        quickcheck(prop as fn(_) -> _);
+    }
+
+    #[test]
+    fn arbitrary_bytes_never_panic() {
+        fn prop(bytes: Vec<u8>) -> bool {
+            match Multiaddr::try_from(bytes) {
+                Ok(multiaddr) => match multiaddr_to_socketaddr(&multiaddr) {
+                    Ok(socket_addr) => socketaddr_to_multiaddr(&socket_addr) == multiaddr,
+                    Err(_) => true,
+                },
+                Err(_) => true,
+            }
+        }
+        quickcheck(prop as fn(_) -> _);
+    }
+}
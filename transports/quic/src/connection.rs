@@ -26,16 +26,26 @@
 //! All interactions with a QUIC connection should be done through this struct.
 // TODO: docs
 
-use crate::endpoint::Endpoint;
+use crate::{
+    endpoint::Endpoint,
+    metrics::{Direction, Metrics, TransportMetrics},
+    muxer::MuxerConfig,
+    transport, x509,
+};
 
+use bytes::{Bytes, BytesMut};
 use futures::{channel::mpsc, prelude::*};
+use libp2p_core::PeerId;
 use std::{
+    collections::HashMap,
     fmt,
+    io::{IoSlice, IoSliceMut},
+    mem,
     net::SocketAddr,
     pin::Pin,
     sync::Arc,
     task::{Context, Poll},
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 /// Underlying structure for both [`crate::QuicMuxer`] and [`crate::Upgrade`].
@@ -49,7 +59,7 @@ pub(crate) struct Connection {
     pending_to_endpoint: Option<Pin<Box<dyn Future<Output = ()> + Send + Sync>>>,
     /// Events that the endpoint will send in destination to our local [`quinn_proto::Connection`].
     /// Passed at initialization.
-    from_endpoint: mpsc::Receiver<quinn_proto::ConnectionEvent>,
+    from_endpoint: mpsc::Receiver<FromEndpoint>,
 
     /// The QUIC state machine for this specific connection.
     connection: quinn_proto::Connection,
@@ -69,6 +79,110 @@ pub(crate) struct Connection {
     /// Contains `None` if it is still open.
     /// Contains `Some` if and only if a `ConnectionLost` event has been emitted.
     closed: Option<Error>,
+    /// Counters reported by [`Connection::stats`]. The `rtt` field is filled on demand.
+    stats: ConnectionStats,
+    /// End of the substreams reached by [`Connection::read_substream_vectored`] after it had
+    /// already read some data, which is reported by the next read instead: `None` if the remote
+    /// has finished the substream, or the error otherwise.
+    deferred_read_ends: HashMap<quinn_proto::StreamId, Option<quinn_proto::ReadError>>,
+    /// Whether this is an incoming connection counted by [`Endpoint::pending_connections`],
+    /// until its handshake is over.
+    pending: bool,
+    /// Whether the handshake has completed, in which case the connection has been reported as
+    /// opened to the metrics.
+    established: bool,
+    /// Span that the events concerning this connection are emitted in.
+    span: tracing::Span,
+    /// Identity of the remote, once it has been reported as established to the metrics.
+    peer_id: Option<PeerId>,
+    /// QUIC version of the packets sent on the connection, known once one with a long header
+    /// has been sent.
+    version: Option<u32>,
+    /// Versions listed by the remote in a version negotiation packet, if it has sent one.
+    peer_versions: Option<Vec<u32>>,
+    /// For an incoming connection whose remote isn't allowed to migrate, the address that the
+    /// connection has been established with. `None` once a migration has been refused.
+    pinned_remote: Option<SocketAddr>,
+}
+
+/// Maximum number of bytes processed by a single read or write on a substream.
+///
+/// A bulk transfer thus only holds the lock of the connection for a bounded time, and can't use
+/// up the flow control window of the whole connection in one go, which leaves room for the
+/// other substreams in between.
+const MAX_SUBSTREAM_BYTES_PER_CALL: usize = 256 * 1024;
+
+/// Size of the buffer that [`Connection::read_chunk`] reads into first, on the stack, in order to
+/// only allocate a chunk once data is available.
+const CHUNK_PROBE_LEN: usize = 1024;
+
+/// Bit of the first byte of a QUIC packet that is set if the packet has a long header.
+const LONG_HEADER_FORM: u8 = 0x80;
+
+/// Returns the version field of the first packet of `datagram` if it has a long header. The
+/// version of version negotiation packets is 0.
+pub(crate) fn long_header_version(datagram: &[u8]) -> Option<u32> {
+    if datagram.len() < 5 || datagram[0] & LONG_HEADER_FORM == 0 {
+        return None;
+    }
+    let mut version = [0; 4];
+    version.copy_from_slice(&datagram[1..5]);
+    Some(u32::from_be_bytes(version))
+}
+
+/// Returns the versions supported by the remote if `datagram` is a version negotiation packet.
+pub(crate) fn negotiation_versions(datagram: &[u8]) -> Option<Vec<u32>> {
+    if long_header_version(datagram)? != 0 {
+        return None;
+    }
+    // The version is followed by the length and value of the destination and of the source
+    // connection IDs, then by the list of versions.
+    let src_cid_at = 6 + usize::from(*datagram.get(5)?);
+    let versions_at = src_cid_at + 1 + usize::from(*datagram.get(src_cid_at)?);
+    let versions = datagram.get(versions_at..)?;
+    Some(
+        versions
+            .chunks_exact(4)
+            .map(|v| u32::from_be_bytes([v[0], v[1], v[2], v[3]]))
+            .collect(),
+    )
+}
+
+/// Statistics about a connection, as returned by [`crate::QuicMuxer::stats`].
+// TODO: quinn_proto 0.6 doesn't expose the congestion window or the number of lost packets;
+//       add them once it does
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ConnectionStats {
+    /// Current estimate of the round-trip time to the remote.
+    pub rtt: Duration,
+    /// Number of bytes of substream data written to the connection.
+    pub bytes_sent: u64,
+    /// Number of bytes of substream data read from the connection.
+    pub bytes_received: u64,
+    /// Number of UDP datagrams sent by the connection, including retransmissions and control
+    /// packets.
+    pub datagrams_sent: u64,
+    /// Number of bytes of UDP payload sent by the connection.
+    pub udp_bytes_sent: u64,
+}
+
+/// What was negotiated during the handshake of a connection, as returned by
+/// [`crate::QuicMuxer::handshake_info`].
+// TODO: rustls 0.17 doesn't tell whether a session was resumed; add a `resumed` field once it
+//       does
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandshakeInfo {
+    /// ALPN protocol negotiated with the remote, or `None` if the remote didn't take part in the
+    /// negotiation.
+    pub alpn_protocol: Option<Vec<u8>>,
+    /// TLS cipher suite protecting the connection, or `None` if the handshake hasn't progressed
+    /// far enough to pick one.
+    pub cipher_suite: Option<rustls::CipherSuite>,
+    /// Whether the remote has accepted the data sent before the handshake completed.
+    pub accepted_0rtt: bool,
+    /// QUIC version spoken on the wire, or `None` if no handshake packet has been sent yet.
+    pub quic_version: Option<u32>,
 }
 
 /// Error on the connection as a whole.
@@ -80,6 +194,14 @@ pub enum Error {
     /// Error in the inner state machine.
     #[error("{0}")]
     Quinn(#[from] quinn_proto::ConnectionError),
+    /// The remote doesn't support the QUIC version we offered.
+    #[error("The remote supports QUIC versions {peer_supported:?}, but we offered {offered:?}")]
+    VersionNegotiationFailed {
+        /// Versions we offered. Empty if the connection hadn't sent any packet yet.
+        offered: Vec<u32>,
+        /// Versions listed by the version negotiation packet of the remote.
+        peer_supported: Vec<u32>,
+    },
 }
 
 impl Connection {
@@ -102,7 +224,7 @@ impl Connection {
         endpoint: Arc<Endpoint>,
         connection: quinn_proto::Connection,
         connection_id: quinn_proto::ConnectionHandle,
-        from_endpoint: mpsc::Receiver<quinn_proto::ConnectionEvent>,
+        from_endpoint: mpsc::Receiver<FromEndpoint>,
     ) -> Self {
         // As the documentation mention, one is not supposed to call any of the methods on the
         // `quinn_proto::Connection` before entering this function, and consequently, even if the
@@ -111,6 +233,19 @@ impl Connection {
         assert!(!connection.is_closed());
 
         let is_handshaking = connection.is_handshaking();
+        let span = tracing::debug_span!(
+            "connection",
+            remote = %connection.remote_address(),
+            direction = ?Direction::from_side(connection.side()),
+            peer = tracing::field::Empty,
+        );
+
+        let pinned_remote =
+            if connection.side() == quinn_proto::Side::Server && !endpoint.migration() {
+                Some(connection.remote_address())
+            } else {
+                None
+            };
 
         Connection {
             endpoint,
@@ -121,24 +256,112 @@ impl Connection {
             connection_id,
             is_handshaking,
             closed: None,
+            stats: Default::default(),
+            deferred_read_ends: HashMap::new(),
+            pending: false,
+            established: false,
+            span,
+            peer_id: None,
+            version: None,
+            peer_versions: None,
+            pinned_remote,
         }
     }
 
+    /// Counts this incoming connection as pending in the endpoint, until its handshake has
+    /// completed or the connection is dropped.
+    pub(crate) fn set_pending(&mut self) {
+        debug_assert!(!self.pending);
+        self.pending = true;
+        self.endpoint.add_pending_connection();
+    }
+
+    /// Reports the end of the handshake to the endpoint if the connection is pending.
+    fn end_pending(&mut self) {
+        if mem::replace(&mut self.pending, false) {
+            self.endpoint.report_handshake_done_non_block();
+        }
+    }
+
+    /// Returns the configuration of the muxer to build on top of this connection.
+    pub(crate) fn muxer_config(&self) -> &MuxerConfig {
+        self.endpoint.muxer_config()
+    }
+
+    /// Returns whether the handshake of this connection should be traced. See
+    /// [`crate::Config::with_handshake_trace`].
+    pub(crate) fn handshake_trace(&self) -> bool {
+        self.endpoint.handshake_trace()
+    }
+
     /// Returns the connection’s side (client or server)
     pub(crate) fn side(&self) -> quinn_proto::Side {
         self.connection.side()
     }
 
-    /// Returns the certificates sent by the remote through the underlying TLS session.
-    /// Returns `None` if the connection is still handshaking.
-    // TODO: it seems to happen that is_handshaking is false but this returns None
-    pub(crate) fn peer_certificates(
-        &self,
-    ) -> Option<impl Iterator<Item = quinn_proto::Certificate>> {
+    /// Returns the span that the events concerning this connection are emitted in.
+    pub(crate) fn span(&self) -> &tracing::Span {
+        &self.span
+    }
+
+    /// Records the identity of the remote in the span of the connection, and reports the
+    /// connection as established to the metrics.
+    pub(crate) fn record_peer_id(&mut self, peer_id: &PeerId) {
+        self.span.record("peer", &tracing::field::display(peer_id));
+        let remote_addr = crate::addr::socketaddr_to_multiaddr(&self.remote_addr());
+        self.metrics().connection_established(
+            peer_id,
+            &remote_addr,
+            Direction::from_side(self.side()),
+        );
+        self.peer_id = Some(peer_id.clone());
+    }
+
+    /// Returns the metrics that the activity of the connection is reported to.
+    pub(crate) fn metrics(&self) -> &Metrics {
+        &self.endpoint.muxer_config().metrics
+    }
+
+    /// Returns the [`PeerId`] of the remote, extracted from the certificate it presented during
+    /// the handshake. Fails if the connection is still handshaking.
+    ///
+    /// If sessions can be resumed, the certificate may come from the session that was resumed
+    /// instead, in which case it hasn't been verified during this handshake. It is then verified
+    /// again, which binds the [`PeerId`] to this connection as well.
+    pub(crate) fn remote_peer_id(&self) -> Result<PeerId, transport::Error> {
+        let certificates = self.connection.crypto_session().get_peer_certificates();
+        match (certificates, self.endpoint.session_verifier()) {
+            (Some(ref certificates), Some(verifier)) if !certificates.is_empty() => verifier
+                .verify_presented_certs(certificates)
+                .map_err(transport::Error::CertificateRejected),
+            (Some(ref certificates), None) if !certificates.is_empty() => {
+                x509::extract_peerid(certificates[0].as_ref())
+                    .map_err(transport::Error::InvalidCertificate)
+            }
+            _ => Err(transport::Error::MissingCertificate),
+        }
+    }
+
+    /// Returns the ALPN protocol negotiated with the remote, if any.
+    pub(crate) fn alpn_protocol(&self) -> Option<Vec<u8>> {
         self.connection
             .crypto_session()
-            .get_peer_certificates()
-            .map(|l| l.into_iter().map(|l| l.into()))
+            .get_alpn_protocol()
+            .map(|protocol| protocol.to_vec())
+    }
+
+    /// Returns what was negotiated during the handshake.
+    pub(crate) fn handshake_info(&self) -> HandshakeInfo {
+        HandshakeInfo {
+            alpn_protocol: self.alpn_protocol(),
+            cipher_suite: self
+                .connection
+                .crypto_session()
+                .get_negotiated_ciphersuite()
+                .map(|suite| suite.suite),
+            accepted_0rtt: self.accepted_0rtt(),
+            quic_version: self.version,
+        }
     }
 
     /// Returns the address of the node we're connected to.
@@ -147,28 +370,57 @@ impl Connection {
         self.connection.remote_address()
     }
 
+    /// Returns statistics about the connection.
+    pub(crate) fn stats(&self) -> ConnectionStats {
+        ConnectionStats {
+            rtt: self.connection.rtt(),
+            ..self.stats.clone()
+        }
+    }
+
+    /// Sends a PING frame to the remote, which results in a new RTT sample once it is
+    /// acknowledged.
+    pub(crate) fn ping(&mut self) {
+        self.connection.ping();
+    }
+
     /// Returns `true` if this connection is still pending. Returns `false` if we are connected to
     /// the remote or if the connection is closed.
     pub(crate) fn is_handshaking(&self) -> bool {
         self.is_handshaking
     }
 
-    /// If the connection is closed, returns why. If the connection is open, returns `None`.
+    /// Returns `true` if the connection is closing or closed, in which case no more data can be
+    /// exchanged with the remote.
+    pub(crate) fn is_closed(&self) -> bool {
+        self.connection.is_closed()
+    }
+
+    /// If the connection is closed, returns why. If the connection is open, returns `None`,
+    /// which is also the case while a 0-RTT connection is still handshaking.
     ///
     /// > **Note**: This method is also the main way to determine whether a connection is closed.
     pub(crate) fn close_reason(&self) -> Option<&Error> {
-        assert!(!self.is_handshaking);
         self.closed.as_ref()
     }
 
-    /// Start closing the connection. A [`ConnectionEvent::ConnectionLost`] event will be
-    /// produced in the future.
-    pub(crate) fn close(&mut self) {
+    /// Returns `true` if data can be sent before the handshake has completed, which is the case
+    /// for outgoing connections that resume a session in which the remote allowed 0-RTT.
+    pub(crate) fn has_0rtt(&self) -> bool {
+        self.connection.has_0rtt()
+    }
+
+    /// Returns `true` if the remote has accepted the data sent before the handshake completed.
+    pub(crate) fn accepted_0rtt(&self) -> bool {
+        self.connection.accepted_0rtt()
+    }
+
+    /// Start closing the connection, reporting `error_code` and `reason` to the remote. A
+    /// [`ConnectionEvent::ConnectionLost`] event will be produced in the future.
+    pub(crate) fn close(&mut self, error_code: quinn_proto::VarInt, reason: &[u8]) {
         // TODO: what if the user calls this multiple times?
-        // We send a dummy `0` error code with no message, as the API of StreamMuxer doesn't
-        // support this.
         self.connection
-            .close(Instant::now(), From::from(0u32), Default::default());
+            .close(Instant::now(), error_code, reason.to_vec().into());
     }
 
     /// Pops a new substream opened by the remote.
@@ -190,13 +442,107 @@ impl Connection {
         self.connection.open(quinn_proto::Dir::Bi)
     }
 
-    // TODO: better API
+    /// Reads data from a substream into `buf`. Returns `None` if the remote has finished the
+    /// substream and all of its data has already been read, after which the substream is
+    /// unknown to the connection.
     pub(crate) fn read_substream(
         &mut self,
         id: quinn_proto::StreamId,
         buf: &mut [u8],
-    ) -> Result<usize, quinn_proto::ReadError> {
-        self.connection.read(id, buf).map(|n| n.unwrap_or(0))
+    ) -> Result<Option<usize>, quinn_proto::ReadError> {
+        if let Some(end) = self.deferred_read_ends.remove(&id) {
+            return end.map_or(Ok(None), Err);
+        }
+        let len = buf.len().min(MAX_SUBSTREAM_BYTES_PER_CALL);
+        let read = self.connection.read(id, &mut buf[..len])?;
+        self.stats.bytes_received += read.unwrap_or(0) as u64;
+        self.metrics().bytes_received(read.unwrap_or(0) as u64);
+        Ok(read)
+    }
+
+    /// Reads data from a substream into `bufs`, in order, until one of them isn't filled
+    /// entirely. Returns `None` like [`Connection::read_substream`] if nothing could be read
+    /// because the substream has ended. Only fails if nothing could be read: the end of the
+    /// substream is reported by the next read otherwise.
+    pub(crate) fn read_substream_vectored(
+        &mut self,
+        id: quinn_proto::StreamId,
+        bufs: &mut [IoSliceMut<'_>],
+    ) -> Result<Option<usize>, quinn_proto::ReadError> {
+        if let Some(end) = self.deferred_read_ends.remove(&id) {
+            return end.map_or(Ok(None), Err);
+        }
+        let mut read = 0;
+        for buf in bufs.iter_mut().filter(|buf| !buf.is_empty()) {
+            let len = buf.len().min(MAX_SUBSTREAM_BYTES_PER_CALL - read);
+            if len == 0 {
+                break;
+            }
+            match self.connection.read(id, &mut buf[..len]) {
+                Ok(Some(n)) => {
+                    read += n;
+                    if n < len {
+                        break;
+                    }
+                }
+                Ok(None) if read == 0 => return Ok(None),
+                Err(err) if read == 0 => return Err(err),
+                // The data read so far is returned first. `Blocked` isn't an end, as more data
+                // can arrive.
+                Err(quinn_proto::ReadError::Blocked) => break,
+                end => {
+                    self.deferred_read_ends.insert(id, end.err());
+                    break;
+                }
+            }
+        }
+        self.stats.bytes_received += read as u64;
+        self.metrics().bytes_received(read as u64);
+        Ok(Some(read))
+    }
+
+    /// Reads the next chunk of data of a substream, of at most `max_len` bytes, or at most
+    /// [`MAX_SUBSTREAM_BYTES_PER_CALL`] bytes. Returns `None` like
+    /// [`Connection::read_substream`]. `max_len` must not be 0.
+    ///
+    /// No memory is allocated unless data is available.
+    // TODO: quinn_proto 0.6 only reads into a caller buffer in order; hand its `Bytes` over
+    //       without copying once it exposes them
+    pub(crate) fn read_chunk(
+        &mut self,
+        id: quinn_proto::StreamId,
+        max_len: usize,
+    ) -> Result<Option<Bytes>, quinn_proto::ReadError> {
+        debug_assert_ne!(max_len, 0);
+        let max_len = max_len.min(MAX_SUBSTREAM_BYTES_PER_CALL);
+        let mut probe = [0; CHUNK_PROBE_LEN];
+        let probe_len = max_len.min(CHUNK_PROBE_LEN);
+        let first = match self
+            .read_substream_vectored(id, &mut [IoSliceMut::new(&mut probe[..probe_len])])?
+        {
+            Some(n) => n,
+            None => return Ok(None),
+        };
+        if first < probe_len || first == max_len {
+            return Ok(Some(Bytes::copy_from_slice(&probe[..first])));
+        }
+
+        // More data might be available, which is read directly into the chunk.
+        let mut chunk = BytesMut::with_capacity(max_len);
+        chunk.extend_from_slice(&probe[..first]);
+        chunk.resize(max_len, 0);
+        let rest =
+            match self.read_substream_vectored(id, &mut [IoSliceMut::new(&mut chunk[first..])]) {
+                Ok(Some(n)) => n,
+                Err(quinn_proto::ReadError::Blocked) => 0,
+                // The data read so far is returned first, and the end by the next read.
+                end => {
+                    self.deferred_read_ends.insert(id, end.err());
+                    0
+                }
+            };
+        chunk.truncate(first + rest);
+        Ok(Some(chunk.freeze()))
     }
 
     pub(crate) fn write_substream(
@@ -204,7 +550,62 @@ impl Connection {
         id: quinn_proto::StreamId,
         buf: &[u8],
     ) -> Result<usize, quinn_proto::WriteError> {
-        self.connection.write(id, buf)
+        let len = buf.len().min(MAX_SUBSTREAM_BYTES_PER_CALL);
+        let written = self.connection.write(id, &buf[..len])?;
+        self.stats.bytes_sent += written as u64;
+        self.metrics().bytes_sent(written as u64);
+        Ok(written)
+    }
+
+    /// Writes the data of `bufs` to a substream, in order, until one of them isn't accepted
+    /// entirely. Only fails if nothing could be written, as the error is reported again by the
+    /// next write otherwise.
+    pub(crate) fn write_substream_vectored(
+        &mut self,
+        id: quinn_proto::StreamId,
+        bufs: &[IoSlice<'_>],
+    ) -> Result<usize, quinn_proto::WriteError> {
+        let mut written = 0;
+        for buf in bufs.iter().filter(|buf| !buf.is_empty()) {
+            let len = buf.len().min(MAX_SUBSTREAM_BYTES_PER_CALL - written);
+            if len == 0 {
+                break;
+            }
+            match self.connection.write(id, &buf[..len]) {
+                Ok(n) => {
+                    written += n;
+                    if n < len {
+                        break;
+                    }
+                }
+                Err(_) if written > 0 => break,
+                Err(err) => return Err(err),
+            }
+        }
+        self.stats.bytes_sent += written as u64;
+        self.metrics().bytes_sent(written as u64);
+        Ok(written)
+    }
+
+    /// Returns `true` if the remote accepts datagrams.
+    pub(crate) fn datagrams_supported(&self) -> bool {
+        self.connection.max_datagram_size().is_some()
+    }
+
+    /// Queues a datagram for sending to the remote.
+    pub(crate) fn send_datagram(
+        &mut self,
+        data: Vec<u8>,
+    ) -> Result<(), quinn_proto::SendDatagramError> {
+        self.connection.send_datagram(data.into())
+    }
+
+    /// Pops a datagram received from the remote.
+    ///
+    /// If `None` is returned, then a [`ConnectionEvent::DatagramReceived`] event will later be
+    /// produced when a datagram is available.
+    pub(crate) fn recv_datagram(&mut self) -> Option<Vec<u8>> {
+        self.connection.recv_datagram().map(|data| data.to_vec())
     }
 
     pub(crate) fn is_drained(&self) -> bool {
@@ -218,6 +619,32 @@ impl Connection {
         self.connection.finish(id)
     }
 
+    /// Abruptly stops sending data on a substream. The remote is told the `error_code`.
+    ///
+    /// Has no effect if the substream is unknown, for example because the remote has already
+    /// acknowledged all of its data.
+    pub(crate) fn reset_substream(
+        &mut self,
+        id: quinn_proto::StreamId,
+        error_code: quinn_proto::VarInt,
+    ) {
+        let _ = self.connection.reset(id, error_code);
+    }
+
+    /// Asks the remote to stop sending data on a substream, and discards the data it has
+    /// already sent. The remote is told the `error_code`.
+    ///
+    /// Has no effect if the substream is unknown, for example because all of its data has
+    /// already been read.
+    pub(crate) fn stop_sending_substream(
+        &mut self,
+        id: quinn_proto::StreamId,
+        error_code: quinn_proto::VarInt,
+    ) {
+        self.deferred_read_ends.remove(&id);
+        let _ = self.connection.stop_sending(id, error_code);
+    }
+
     /// Polls the connection for an event that happend on it.
     pub(crate) fn poll_event(&mut self, cx: &mut Context<'_>) -> Poll<ConnectionEvent> {
         // Nothing more can be done if the connection is drained.
@@ -230,7 +657,27 @@ impl Connection {
         // Process events that the endpoint has sent to us.
         loop {
             match Pin::new(&mut self.from_endpoint).poll_next(cx) {
-                Poll::Ready(Some(event)) => self.connection.handle_event(event),
+                Poll::Ready(Some(FromEndpoint::Event(event))) => {
+                    self.connection.handle_event(event);
+                    // `quinn_proto` only moves the connection to the address of a packet once
+                    // it has authenticated it, so spoofed packets never get here.
+                    let from = self.connection.remote_address();
+                    if self.pinned_remote.map_or(false, |remote| remote != from) {
+                        self.pinned_remote = None;
+                        tracing::debug!(from = %from, "remote attempted to migrate, closing");
+                        self.close(
+                            MIGRATION_REFUSED_ERROR_CODE.into(),
+                            b"connection migration is disabled",
+                        );
+                        return Poll::Ready(ConnectionEvent::MigrationRefused(from));
+                    }
+                }
+                Poll::Ready(Some(FromEndpoint::Close { error_code, reason })) => {
+                    self.close(error_code, &reason)
+                }
+                Poll::Ready(Some(FromEndpoint::VersionNegotiation { peer_supported })) => {
+                    self.peer_versions = Some(peer_supported);
+                }
                 Poll::Ready(None) => {
                     assert!(self.closed.is_none());
                     let err = Error::ClosedChannel;
@@ -264,6 +711,12 @@ impl Connection {
             // Poll the connection for packets to send on the UDP socket and try to send them on
             // `to_endpoint`.
             while let Some(transmit) = self.connection.poll_transmit(now) {
+                self.stats.datagrams_sent += 1;
+                self.stats.udp_bytes_sent += transmit.contents.len() as u64;
+                if self.version.is_none() {
+                    self.version =
+                        long_header_version(&transmit.contents).filter(|&version| version != 0);
+                }
                 let endpoint = self.endpoint.clone();
                 assert!(self.pending_to_endpoint.is_none());
                 self.pending_to_endpoint = Some(Box::pin(async move {
@@ -318,15 +771,21 @@ impl Connection {
                 match event {
                     quinn_proto::Event::Stream(quinn_proto::StreamEvent::Opened {
                         dir: quinn_proto::Dir::Uni,
-                    })
-                    | quinn_proto::Event::Stream(quinn_proto::StreamEvent::Available {
+                    }) => {
+                        // We don't use unidirectional streams. The remote can only open them if
+                        // they are allowed by the configuration, in which case we reject them
+                        // one by one.
+                        while let Some(id) = self.connection.accept(quinn_proto::Dir::Uni) {
+                            let _ = self.connection.stop_sending(id, From::from(0u32));
+                        }
+                    }
+                    quinn_proto::Event::Stream(quinn_proto::StreamEvent::Available {
                         dir: quinn_proto::Dir::Uni,
-                    })
-                    | quinn_proto::Event::DatagramReceived => {
-                        // We don't use datagrams or unidirectional streams. If these events
-                        // happen, it is by some code not compatible with libp2p-quic.
-                        self.connection
-                            .close(Instant::now(), From::from(0u32), Default::default());
+                    }) => {
+                        // We never open unidirectional streams.
+                    }
+                    quinn_proto::Event::DatagramReceived => {
+                        return Poll::Ready(ConnectionEvent::DatagramReceived);
                     }
                     quinn_proto::Event::Stream(quinn_proto::StreamEvent::Readable { id }) => {
                         return Poll::Ready(ConnectionEvent::StreamReadable(id));
@@ -334,12 +793,14 @@ impl Connection {
                     quinn_proto::Event::Stream(quinn_proto::StreamEvent::Writable { id }) => {
                         return Poll::Ready(ConnectionEvent::StreamWritable(id));
                     }
-                    quinn_proto::Event::Stream(quinn_proto::StreamEvent::Available {
+                    // Note that `quinn_proto` calls "opened" the streams opened by the remote, and
+                    // "available" the local ability to open new streams.
+                    quinn_proto::Event::Stream(quinn_proto::StreamEvent::Opened {
                         dir: quinn_proto::Dir::Bi,
                     }) => {
                         return Poll::Ready(ConnectionEvent::StreamAvailable);
                     }
-                    quinn_proto::Event::Stream(quinn_proto::StreamEvent::Opened {
+                    quinn_proto::Event::Stream(quinn_proto::StreamEvent::Available {
                         dir: quinn_proto::Dir::Bi,
                     }) => {
                         return Poll::Ready(ConnectionEvent::StreamOpened);
@@ -347,7 +808,16 @@ impl Connection {
                     quinn_proto::Event::ConnectionLost { reason } => {
                         assert!(self.closed.is_none());
                         self.is_handshaking = false;
-                        let err = Error::Quinn(reason);
+                        let err = match (reason, self.peer_versions.take()) {
+                            (
+                                quinn_proto::ConnectionError::VersionMismatch,
+                                Some(peer_supported),
+                            ) => Error::VersionNegotiationFailed {
+                                offered: self.version.into_iter().collect(),
+                                peer_supported,
+                            },
+                            (reason, _) => Error::Quinn(reason),
+                        };
                         self.closed = Some(err.clone());
                         return Poll::Ready(ConnectionEvent::ConnectionLost(err));
                     }
@@ -362,6 +832,10 @@ impl Connection {
                         assert!(self.is_handshaking);
                         assert!(!self.connection.is_handshaking());
                         self.is_handshaking = false;
+                        self.end_pending();
+                        self.established = true;
+                        self.metrics()
+                            .connection_opened(Direction::from_side(self.side()));
                         return Poll::Ready(ConnectionEvent::Connected);
                     }
                 }
@@ -382,6 +856,16 @@ impl fmt::Debug for Connection {
 
 impl Drop for Connection {
     fn drop(&mut self) {
+        self.end_pending();
+        if self.established {
+            self.metrics()
+                .connection_closed(Direction::from_side(self.side()));
+        }
+        if let Some(peer_id) = self.peer_id.take() {
+            let reason = self.closed.clone().map(transport::Error::from);
+            self.metrics()
+                .connection_terminated(&peer_id, reason.as_ref());
+        }
         // TODO: don't do that if already drained
         // We send a message to the endpoint.
         self.endpoint.report_quinn_event_non_block(
@@ -391,6 +875,26 @@ impl Drop for Connection {
     }
 }
 
+/// Message sent by the endpoint to a [`Connection`].
+#[derive(Debug)]
+pub(crate) enum FromEndpoint {
+    /// Event to feed to the `quinn_proto` connection.
+    Event(quinn_proto::ConnectionEvent),
+    /// The endpoint is closing. The connection must be closed, reporting `error_code` and
+    /// `reason` to the remote.
+    Close {
+        error_code: quinn_proto::VarInt,
+        reason: Vec<u8>,
+    },
+    /// The remote has sent a version negotiation packet listing `peer_supported`. Sent right
+    /// before the [`FromEndpoint::Event`] of the packet itself.
+    VersionNegotiation { peer_supported: Vec<u32> },
+}
+
+/// Application error code with which connections are closed when their remote attempts to
+/// migrate while migration is disabled.
+pub(crate) const MIGRATION_REFUSED_ERROR_CODE: u32 = 1;
+
 /// Event generated by the [`Connection`].
 #[derive(Debug)]
 pub(crate) enum ConnectionEvent {
@@ -405,10 +909,43 @@ pub(crate) enum ConnectionEvent {
     /// `None`. After this event has been generated, this method is guaranteed to return `Some`.
     StreamAvailable,
     /// Generated after [`Connection::pop_outgoing_substream`] has been called and has returned
-    /// `None`. After this event has been generated, this method is guaranteed to return `Some`.
+    /// `None`, once the remote has granted us the right to open more substreams. After this
+    /// event has been generated, this method is guaranteed to return `Some`.
     StreamOpened,
 
     StreamReadable(quinn_proto::StreamId),
     StreamWritable(quinn_proto::StreamId),
     StreamFinished(quinn_proto::StreamId),
+
+    /// A datagram has been received and can be retrieved with [`Connection::recv_datagram`].
+    DatagramReceived,
+
+    /// The remote has attempted to migrate to `from` while migration is disabled. The
+    /// connection is being closed, and a [`ConnectionEvent::ConnectionLost`] follows.
+    MigrationRefused(SocketAddr),
+}
+
+#[cfg(test)]
+#[test]
+fn version_negotiation_packets_are_parsed() {
+    // Form and fixed bits, version 0, an empty destination connection ID, a source connection
+    // ID of two bytes, and two versions.
+    let negotiation = [
+        0xc0, 0, 0, 0, 0, 0, 2, 0xab, 0xcd, 0, 0, 0, 1, 0x1a, 0x2a, 0x3a, 0x4a,
+    ];
+    assert_eq!(long_header_version(&negotiation), Some(0));
+    assert_eq!(
+        negotiation_versions(&negotiation),
+        Some(vec![1, 0x1a2a_3a4a])
+    );
+
+    // The source connection ID is longer than the packet.
+    assert_eq!(negotiation_versions(&negotiation[..8]), None);
+
+    // An initial packet, and a packet with a short header.
+    let initial = [0xc3, 0xff, 0, 0, 0x1b, 0, 0];
+    assert_eq!(long_header_version(&initial), Some(0xff00_001b));
+    assert_eq!(negotiation_versions(&initial), None);
+    assert_eq!(long_header_version(&[0x43, 0xff, 0, 0, 0x1b]), None);
+    assert_eq!(negotiation_versions(&[0x43, 0xff, 0, 0, 0x1b]), None);
 }
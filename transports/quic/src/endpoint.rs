@@ -28,69 +28,1010 @@
 //! the rest of the code only happens through channels. See the documentation of the
 //! [`background_task`] for a thorough description.
 
-use crate::{connection::Connection, x509};
+use crate::{
+    connection::{self, Connection, FromEndpoint},
+    error::Error,
+    metrics::{Metrics, TransportMetrics},
+    muxer::{MuxerConfig, MAX_CLOSE_REASON_LEN},
+    x509,
+};
 
 use async_std::net::SocketAddr;
-use either::Either;
 use futures::{
     channel::{mpsc, oneshot},
+    future::{BoxFuture, Fuse},
     lock::Mutex,
     prelude::*,
 };
+use futures_timer::Delay;
+use ipnet::IpNet;
 use libp2p_core::{
     multiaddr::{host_addresses, Multiaddr, Protocol},
     transport::TransportError,
+    Executor, PeerId,
 };
+use socket2::{Domain, Socket, Type};
 use std::{
     collections::{HashMap, VecDeque},
-    fmt, io,
-    sync::{Arc, Weak},
-    task::Poll,
+    fmt, io, mem,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    panic::AssertUnwindSafe,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Weak,
+    },
+    task::{Context, Poll},
     time::{Duration, Instant},
 };
 use tracing::{info, warn};
 
+/// Interval at which an endpoint listening on all interfaces checks for changes in the set of
+/// interfaces.
+const INTERFACES_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Policy for attempting dials again when their handshake times out. See
+/// [`Config::with_dial_retry`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DialRetry {
+    /// Maximum number of attempts, the first one included. At least one attempt is made.
+    pub max_attempts: u32,
+    /// Delay between the failure of the first attempt and the start of the second one.
+    pub initial_delay: Duration,
+    /// Factor that the delay is multiplied by after each subsequent failure.
+    pub multiplier: u32,
+}
+
+impl DialRetry {
+    /// Returns the delays to wait for before each attempt after the first one.
+    pub(crate) fn delays(self) -> impl Iterator<Item = Duration> {
+        let multiplier = self.multiplier;
+        // The delay stops growing rather than overflowing.
+        std::iter::successors(Some(self.initial_delay), move |delay| {
+            Some(delay.checked_mul(multiplier).unwrap_or(*delay))
+        })
+        .take(self.max_attempts.saturating_sub(1) as usize)
+    }
+}
+
+/// Sides of connections that an [`Endpoint`] can be.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    /// The endpoint both dials and accepts connections.
+    Both,
+    /// The endpoint only dials. See [`Config::client_only`].
+    Client,
+    /// The endpoint only accepts connections. See [`Config::server_only`].
+    Server,
+}
+
+/// Runs the background tasks of an [`Endpoint`] and of its connections.
+#[derive(Clone, Default)]
+pub(crate) struct Spawner(Option<Arc<dyn Executor + Send + Sync>>);
+
+impl Spawner {
+    /// Runs `future` in the background, on the executor passed to [`Config::with_executor`] if
+    /// any. Otherwise, it runs on the current `tokio` runtime if the `tokio` feature is enabled
+    /// and there is one, or on the `async-std` global executor.
+    pub(crate) fn spawn(&self, future: impl Future<Output = ()> + Send + 'static) {
+        if let Some(executor) = &self.0 {
+            executor.exec(Box::pin(future));
+            return;
+        }
+        #[cfg(feature = "tokio")]
+        {
+            if let Ok(handle) = tokio_crate::runtime::Handle::try_current() {
+                handle.spawn(future);
+                return;
+            }
+        }
+        async_std::task::spawn(future);
+    }
+
+    /// Runs `future` in the background like [`Spawner::spawn`]. The returned receiver is sent
+    /// `true` once `future` has completed, or `false` if it has panicked. It is canceled if the
+    /// executor drops `future` before completion.
+    pub(crate) fn spawn_joinable(
+        &self,
+        future: impl Future<Output = ()> + Send + 'static,
+    ) -> oneshot::Receiver<bool> {
+        let (tx, rx) = oneshot::channel();
+        self.spawn(async move {
+            let completed = AssertUnwindSafe(future).catch_unwind().await.is_ok();
+            let _ = tx.send(completed);
+        });
+        rx
+    }
+}
+
+impl fmt::Debug for Spawner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = if self.0.is_some() {
+            "custom"
+        } else {
+            "default"
+        };
+        f.debug_tuple("Spawner").field(&name).finish()
+    }
+}
+
+/// Background task of an [`Endpoint`], returned by [`Endpoint::new_with_driver`].
+///
+/// Resolves once the endpoint has been closed or dropped.
+#[must_use = "the endpoint doesn't do anything unless its driver is polled"]
+pub struct EndpointDriver(Pin<Box<dyn Future<Output = ()> + Send>>);
+
+impl Future for EndpointDriver {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        self.0.as_mut().poll(cx)
+    }
+}
+
+impl fmt::Debug for EndpointDriver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("EndpointDriver").finish()
+    }
+}
+
 /// Represents the configuration for the [`Endpoint`].
-#[derive(Debug, Clone)]
+///
+/// # Resource limits
+///
+/// The amount of memory that a remote can make us use is bounded by the following settings,
+/// which servers accepting connections from untrusted remotes should review:
+///
+/// - [`Config::with_max_concurrent_bidi_streams`] and
+///   [`Config::with_max_concurrent_uni_streams`] limit the number of substreams. Remotes that
+///   want to open more have to wait for existing ones to be closed. A few hundred bidirectional
+///   substreams and no unidirectional ones are reasonable for a server.
+/// - [`Config::with_stream_receive_window`] limits the unread data of a single substream, and
+///   [`Config::with_receive_window`] the unread data of a connection, all substreams combined.
+///   The latter is the hard cap on buffered data. A few hundred kiB per substream and a few MiB
+///   per connection are reasonable for a server. Substreams are never read unless the user
+///   does so, and remotes whose data isn't read are blocked once they reach either window,
+///   regardless of the number of substreams they spread it over.
+/// - [`Config::with_datagram_receive_buffer_size`] limits the unread datagrams.
+/// - [`Config::with_max_pending_connections`] limits the incoming connections whose handshake
+///   hasn't completed yet, including the ones the listener hasn't reported or the application
+///   hasn't upgraded.
+#[derive(Clone)]
 pub struct Config {
-    /// The client configuration to pass to `quinn_proto`.
-    client_config: quinn_proto::ClientConfig,
-    /// The server configuration to pass to `quinn_proto`.
-    server_config: Arc<quinn_proto::ServerConfig>,
+    /// The certificate presented to remotes.
+    certificate: rustls::Certificate,
+    /// The private key of [`Config::certificate`].
+    private_key: rustls::PrivateKey,
+    /// How the certificates presented by remotes are verified.
+    verifier: x509::VerifierConfig,
+    /// ALPN protocols offered during the handshake, in order of preference. Empty for the
+    /// default.
+    alpn_protocols: Vec<Vec<u8>>,
+    /// TLS configurations provided by the user, used instead of the ones built from the fields
+    /// above.
+    custom_tls: Option<(Arc<rustls::ClientConfig>, Arc<rustls::ServerConfig>)>,
     /// The endpoint configuration to pass to `quinn_proto`.
     endpoint_config: Arc<quinn_proto::EndpointConfig>,
     /// The [`Multiaddr`] to use to spawn the UDP socket.
     multiaddr: Multiaddr,
+    /// Whether remotes are allowed to migrate their connections to a new network path.
+    migration: bool,
+    /// Whether remotes have to prove that they own their address before a connection is
+    /// accepted.
+    use_retry: bool,
+    /// Maximum number of incoming connections whose handshake hasn't completed yet.
+    max_pending_connections: u32,
+    /// Maximum number of bidirectional substreams that remotes may have open at the same time.
+    /// `None` to use the `quinn_proto` default.
+    max_concurrent_bidi_streams: Option<u64>,
+    /// Maximum number of unidirectional substreams that remotes may have open at the same time.
+    max_concurrent_uni_streams: u64,
+    /// Number of bytes that remotes may send on a substream before it is read. `None` to use
+    /// the `quinn_proto` default.
+    stream_receive_window: Option<u64>,
+    /// Number of bytes that remotes may send on a connection before it is read. `None` to use
+    /// the `quinn_proto` default.
+    receive_window: Option<u64>,
+    /// Whether opening an outbound substream waits for the remote to grant us the right to do
+    /// so, as opposed to failing.
+    queue_outbound: bool,
+    /// Whether to emit an event for each step of the handshake of the connections.
+    handshake_trace: bool,
+    /// Whether an IPv6 socket only accepts IPv6 traffic, as opposed to IPv4-mapped traffic as
+    /// well.
+    ipv6_only: bool,
+    /// Whether an endpoint listening on an unspecified address reports the addresses of the
+    /// loopback interface.
+    loopback_addresses: bool,
+    /// How long to stop receiving packets after an error on the UDP socket.
+    sleep_on_error: Duration,
+    /// Whether remotes are allowed to send unreliable datagrams.
+    datagrams: bool,
+    /// Number of bytes of received datagrams that are buffered before older ones are dropped.
+    /// `None` to use the `quinn_proto` default.
+    datagram_receive_buffer: Option<usize>,
+    /// Maximum duration of the handshake of outgoing connections.
+    handshake_timeout: Option<Duration>,
+    /// How to attempt dials again when their handshake times out, if at all.
+    dial_retry: Option<DialRetry>,
+    /// Duration of inactivity after which a connection is closed, or `None` for no limit.
+    idle_timeout: Option<Duration>,
+    /// Interval at which packets are sent on otherwise idle connections, or `None` to not send
+    /// any.
+    keep_alive_interval: Option<Duration>,
+    /// Initial congestion window in bytes, or `None` for the `quinn_proto` default.
+    initial_window: Option<u64>,
+    /// Runs the background tasks of the endpoint and of its connections.
+    spawner: Spawner,
+    /// Receives the events worth counting.
+    metrics: Metrics,
+    /// Error code sent to the remote for the substreams that are destroyed before being
+    /// closed.
+    cancel_error_code: u32,
+    /// [`PeerId`] of the keypair the configuration has been created with.
+    local_peer_id: PeerId,
+    /// Whether the endpoint dials, accepts connections, or both.
+    role: Role,
+    /// Whether data may be sent and accepted before the handshake has completed.
+    early_data: bool,
+    /// Whether TLS sessions can be resumed.
+    session_resumption: bool,
+    /// How long [`Endpoint::close`] waits for the connections to be drained.
+    close_timeout: Duration,
 }
 
 impl Config {
     /// Creates a new configuration object with default values.
+    ///
+    /// This generates the certificate that authenticates us to the remotes, which never panics:
+    /// failing to sign it with `keypair` is reported as [`ConfigError::SigningError`], and
+    /// failing to build or encode it as [`ConfigError::RcgenError`].
+    ///
+    /// [`ConfigError::SigningError`]: crate::ConfigError::SigningError
+    /// [`ConfigError::RcgenError`]: crate::ConfigError::RcgenError
     pub fn new(
         keypair: &libp2p_core::identity::Keypair,
         multiaddr: Multiaddr,
     ) -> Result<Self, x509::ConfigError> {
-        let mut transport = quinn_proto::TransportConfig::default();
-        transport.stream_window_uni(0);
-        transport.datagram_receive_buffer_size(None);
-        transport.keep_alive_interval(Some(Duration::from_millis(10)));
-        let transport = Arc::new(transport);
-        let (client_tls_config, server_tls_config) = x509::make_tls_config(keypair)?;
-        let mut server_config = quinn_proto::ServerConfig::default();
-        server_config.transport = transport.clone();
-        server_config.crypto = Arc::new(server_tls_config);
-        let mut client_config = quinn_proto::ClientConfig::default();
-        client_config.transport = transport;
-        client_config.crypto = Arc::new(client_tls_config);
+        let (certificate, private_key) = x509::make_certificate(keypair, &Default::default())?;
         Ok(Self {
-            client_config,
-            server_config: Arc::new(server_config),
+            certificate,
+            private_key,
+            verifier: Default::default(),
+            alpn_protocols: Vec::new(),
+            custom_tls: None,
             endpoint_config: Default::default(),
-            multiaddr: multiaddr,
+            multiaddr,
+            migration: true,
+            use_retry: false,
+            max_pending_connections: 1024,
+            max_concurrent_bidi_streams: None,
+            max_concurrent_uni_streams: 0,
+            stream_receive_window: None,
+            receive_window: None,
+            queue_outbound: true,
+            handshake_trace: false,
+            ipv6_only: true,
+            loopback_addresses: true,
+            sleep_on_error: Duration::from_millis(100),
+            datagrams: false,
+            datagram_receive_buffer: None,
+            handshake_timeout: None,
+            dial_retry: None,
+            idle_timeout: Some(Duration::from_secs(10)),
+            keep_alive_interval: Some(Duration::from_millis(10)),
+            initial_window: None,
+            spawner: Spawner::default(),
+            metrics: Metrics::default(),
+            cancel_error_code: 0,
+            local_peer_id: keypair.public().into_peer_id(),
+            role: Role::Both,
+            early_data: false,
+            session_resumption: false,
+            close_timeout: Duration::from_secs(3),
         })
     }
+
+    /// Creates a configuration for an endpoint that only dials, with default values otherwise.
+    ///
+    /// No TLS server configuration is built, and incoming connections are refused. Listening
+    /// with a [`QuicTransport`](crate::QuicTransport) using such an endpoint fails with
+    /// [`Error::ClientOnly`](crate::transport::Error::ClientOnly). The socket is still bound to
+    /// `multiaddr`, which is typically an unspecified address with port 0.
+    pub fn client_only(
+        keypair: &libp2p_core::identity::Keypair,
+        multiaddr: Multiaddr,
+    ) -> Result<Self, x509::ConfigError> {
+        let mut config = Self::new(keypair, multiaddr)?;
+        config.role = Role::Client;
+        Ok(config)
+    }
+
+    /// Creates a configuration for an endpoint that only accepts connections, with default
+    /// values otherwise.
+    ///
+    /// No TLS client configuration is built. Dialing with a
+    /// [`QuicTransport`](crate::QuicTransport) using such an endpoint fails with
+    /// [`Error::ServerOnly`](crate::transport::Error::ServerOnly).
+    pub fn server_only(
+        keypair: &libp2p_core::identity::Keypair,
+        multiaddr: Multiaddr,
+    ) -> Result<Self, x509::ConfigError> {
+        let mut config = Self::new(keypair, multiaddr)?;
+        config.role = Role::Server;
+        Ok(config)
+    }
+
+    /// Creates a configuration that listens on a random port of all the IPv4 interfaces, with
+    /// default values otherwise.
+    ///
+    /// This is the simplest way to get a working transport: remotes are authenticated with their
+    /// libp2p certificate, over TLS 1.3.
+    pub fn from_keypair(
+        keypair: &libp2p_core::identity::Keypair,
+    ) -> Result<Self, x509::ConfigError> {
+        let multiaddr = Multiaddr::empty()
+            .with(Protocol::Ip4(Ipv4Addr::UNSPECIFIED))
+            .with(Protocol::Udp(0))
+            .with(Protocol::Quic);
+        Self::new(keypair, multiaddr)
+    }
+
+    /// Creates a configuration like [`Config::new`], from a DER-encoded identity key of the
+    /// given type instead of a keypair.
+    ///
+    /// Fails with [`ConfigError::KeyDecodingError`] or [`ConfigError::MalformedPkcs8`] if the
+    /// key can't be decoded.
+    ///
+    /// [`ConfigError::KeyDecodingError`]: crate::ConfigError::KeyDecodingError
+    /// [`ConfigError::MalformedPkcs8`]: crate::ConfigError::MalformedPkcs8
+    pub fn from_der(
+        key_bytes: &[u8],
+        key_type: x509::KeyType,
+        multiaddr: Multiaddr,
+    ) -> Result<Self, x509::ConfigError> {
+        Self::new(&x509::keypair_from_der(key_bytes, key_type)?, multiaddr)
+    }
+
+    /// Returns the DER encoding of the certificate presented to remotes.
+    ///
+    /// The certificate isn't used if TLS has been configured with [`Config::with_tls`].
+    pub fn certificate_der(&self) -> &[u8] {
+        &self.certificate.0
+    }
+
+    /// Returns the DER-encoded SubjectPublicKeyInfo of [`Config::certificate_der`], which can
+    /// be published and compared against [`subject_public_key_info`] of the certificate that
+    /// a remote presented.
+    ///
+    /// [`subject_public_key_info`]: crate::subject_public_key_info
+    pub fn subject_public_key_info(&self) -> Vec<u8> {
+        x509::subject_public_key_info(&self.certificate.0)
+            .expect("the certificate has been generated by make_certificate; qed")
+    }
+
+    /// Sets whether remotes are allowed to migrate their connections to a new network path.
+    ///
+    /// When `false`, a connection whose remote sends an authenticated packet from a different
+    /// address than the one the connection was established with is closed, with the application
+    /// error code `1` and the reason `connection migration is disabled`. Packets from another
+    /// address that can't be authenticated, such as spoofed ones, are dropped without affecting
+    /// the connection.
+    ///
+    /// Defaults to `true`.
+    pub fn with_migration(mut self, migration: bool) -> Self {
+        self.migration = migration;
+        self
+    }
+
+    /// Sets whether remotes have to prove that they own their address before the endpoint
+    /// accepts their connection.
+    ///
+    /// When enabled, the endpoint answers the first packet of a remote with a Retry packet
+    /// containing a token, which the remote has to send back. This protects against
+    /// amplification attacks and attacks using spoofed addresses, at the cost of an additional
+    /// round trip for every incoming connection.
+    ///
+    /// Defaults to `false`.
+    pub fn with_retry(mut self, use_retry: bool) -> Self {
+        self.use_retry = use_retry;
+        self
+    }
+
+    /// Sets the maximum number of incoming connections whose handshake hasn't completed yet.
+    ///
+    /// A connection is pending from its first packet until the upgrade produced by the listener
+    /// has completed or has been dropped, which includes the time the listener and the
+    /// application take to process it. Beyond the limit, new connections are refused with a
+    /// `CONNECTION_REFUSED` error, which the remote reports as a failed dial. The current number
+    /// is returned by [`Endpoint::pending_connections`]. The value must be non-zero, otherwise
+    /// creating the [`Endpoint`] fails.
+    ///
+    /// Defaults to `1024`.
+    pub fn with_max_pending_connections(mut self, max: u32) -> Self {
+        self.max_pending_connections = max;
+        self
+    }
+
+    /// Sets the maximum number of bidirectional substreams that remotes may have open at the
+    /// same time. Remotes that try to open more substreams have to wait for existing ones to
+    /// be closed.
+    pub fn with_max_concurrent_bidi_streams(mut self, max: u64) -> Self {
+        self.max_concurrent_bidi_streams = Some(max);
+        self
+    }
+
+    /// Sets the maximum number of unidirectional streams that remotes may have open at the same
+    /// time.
+    ///
+    /// libp2p only uses bidirectional substreams, and the unidirectional streams opened by
+    /// remotes are immediately rejected. A non-zero value merely avoids closing the connection
+    /// of remotes that open them anyway. Defaults to `0`.
+    pub fn with_max_concurrent_uni_streams(mut self, max: u64) -> Self {
+        self.max_concurrent_uni_streams = max;
+        self
+    }
+
+    /// Sets the number of bytes that remotes may send on a substream before it has been read.
+    ///
+    /// Larger windows increase the throughput of a substream on links with a large
+    /// bandwidth-delay product, at the cost of potentially buffering more data. The value must
+    /// be non-zero and fit in a QUIC variable-length integer, otherwise creating the
+    /// [`Endpoint`] fails.
+    pub fn with_stream_receive_window(mut self, bytes: u64) -> Self {
+        self.stream_receive_window = Some(bytes);
+        self
+    }
+
+    /// Sets the number of bytes that remotes may send on a connection, all substreams
+    /// combined, before it has been read.
+    ///
+    /// This bounds the memory used to buffer the unread data of a connection: once it is
+    /// reached, no more credit is granted to the remote until some data has been read. The same
+    /// constraints as for [`Config::with_stream_receive_window`] apply.
+    // TODO: quinn_proto 0.6 doesn't report the amount of unread data of a connection; once it
+    //       does, optionally close the connections of remotes that keep it at the limit
+    pub fn with_receive_window(mut self, bytes: u64) -> Self {
+        self.receive_window = Some(bytes);
+        self
+    }
+
+    /// Sets the application error code sent to the remote when a substream is destroyed before
+    /// being closed, in which case it is reset and the remote is asked to stop sending.
+    ///
+    /// Defaults to `0`.
+    pub fn with_cancel_error_code(mut self, error_code: u32) -> Self {
+        self.cancel_error_code = error_code;
+        self
+    }
+
+    /// Sets what happens when opening an outbound substream while the limit of concurrent
+    /// substreams set by the remote is reached.
+    ///
+    /// If `true`, [`StreamMuxer::poll_outbound`](libp2p_core::StreamMuxer::poll_outbound)
+    /// returns `Pending` until the remote allows more substreams. If `false`, it immediately
+    /// fails with [`Error::StreamsExhausted`](crate::Error::StreamsExhausted).
+    ///
+    /// Defaults to `true`.
+    pub fn with_outbound_queueing(mut self, queue: bool) -> Self {
+        self.queue_outbound = queue;
+        self
+    }
+
+    /// Sets how the certificates presented by remotes are verified.
+    ///
+    /// The same configuration applies to both incoming and outgoing connections.
+    pub fn with_verifier(mut self, verifier: x509::VerifierConfig) -> Self {
+        self.verifier = verifier;
+        self
+    }
+
+    /// Sets whether an endpoint listening on an IPv6 address only accepts IPv6 traffic.
+    ///
+    /// When `false`, an endpoint listening on `::` also accepts connections from IPv4 remotes,
+    /// and reports the addresses of the IPv4 interfaces as listen addresses. The addresses of
+    /// these remotes are reported as `/ip4` multiaddresses. Has no effect on endpoints listening
+    /// on an IPv4 address.
+    ///
+    /// Defaults to `true`.
+    pub fn with_ipv6_only(mut self, ipv6_only: bool) -> Self {
+        self.ipv6_only = ipv6_only;
+        self
+    }
+
+    /// Sets whether an endpoint listening on an unspecified address, such as `0.0.0.0` or `::`,
+    /// reports the addresses of the loopback interface as listen addresses.
+    ///
+    /// Loopback addresses are only reachable from the local host, and are therefore useless to
+    /// advertise to remotes. Endpoints listening on a loopback address explicitly always report
+    /// it.
+    ///
+    /// Defaults to `true`.
+    pub fn with_loopback_addresses(mut self, loopback: bool) -> Self {
+        self.loopback_addresses = loopback;
+        self
+    }
+
+    /// Sets whether 0-RTT is enabled, in other words whether data can be sent before the
+    /// handshake has completed when reconnecting to a remote.
+    ///
+    /// When enabled, accepted connections issue session tickets that allow the remote to resume
+    /// the session later on, as with [`Config::with_session_resumption`], and to send data along
+    /// with its first packets, which saves a round-trip. Such data is only sent by the muxers of
+    /// [`QuicTransport::dial_0rtt`](crate::QuicTransport::dial_0rtt), and is accepted by
+    /// listeners that enable 0-RTT as well.
+    ///
+    /// # Replay safety
+    ///
+    /// 0-RTT data is not protected against replays: an attacker that captures the first packets
+    /// of a connection can send them again, and the listener then processes the 0-RTT data a
+    /// second time. Only enable this if the protocols that run first on new connections are
+    /// idempotent, which is why this method has the `unchecked` suffix. The data is not
+    /// forward-secret either, as it is encrypted with keys derived from the resumed session.
+    ///
+    /// Has no effect on the TLS configurations set with [`Config::with_tls`], which are used
+    /// as they are.
+    ///
+    /// Defaults to `false`.
+    pub fn with_0rtt_unchecked(mut self, enabled: bool) -> Self {
+        self.early_data = enabled;
+        self
+    }
+
+    /// Sets whether TLS sessions can be resumed when reconnecting to a remote.
+    ///
+    /// When enabled, accepted connections issue session tickets, and dialing a remote that
+    /// issued one resumes the session, which skips the exchange and verification of the
+    /// certificates during the handshake. The remote must enable session resumption as well.
+    /// The [`PeerId`] of the remote is still extracted from the certificate it presented when
+    /// the session was established, which is verified again according to
+    /// [`Config::with_verifier`].
+    ///
+    /// Enabling 0-RTT with [`Config::with_0rtt_unchecked`] implies session resumption.
+    ///
+    /// Has no effect on the TLS configurations set with [`Config::with_tls`], which are used
+    /// as they are.
+    ///
+    /// Defaults to `false`.
+    pub fn with_session_resumption(mut self, enabled: bool) -> Self {
+        self.session_resumption = enabled;
+        self
+    }
+
+    /// Returns `true` if the TLS configurations built from this configuration resume sessions.
+    fn resumes_sessions(&self) -> bool {
+        self.custom_tls.is_none() && (self.session_resumption || self.early_data)
+    }
+
+    /// Sets whether remotes are allowed to send unreliable datagrams, which can then be received
+    /// with [`QuicMuxer::poll_datagram`](crate::QuicMuxer::poll_datagram).
+    ///
+    /// Sending datagrams is only possible if the remote has enabled them, which can be checked
+    /// with [`QuicMuxer::datagrams_supported`](crate::QuicMuxer::datagrams_supported).
+    ///
+    /// Defaults to `false`.
+    pub fn with_datagrams(mut self, enabled: bool) -> Self {
+        self.datagrams = enabled;
+        self
+    }
+
+    /// Sets the number of bytes of datagrams that are buffered until they are received with
+    /// [`QuicMuxer::poll_datagram`](crate::QuicMuxer::poll_datagram). Once the buffer is full,
+    /// the oldest datagrams are dropped to make room for the new ones.
+    ///
+    /// Only relevant if datagrams are enabled with [`Config::with_datagrams`]. The value must be
+    /// non-zero, otherwise creating the [`Endpoint`] fails.
+    pub fn with_datagram_receive_buffer_size(mut self, bytes: usize) -> Self {
+        self.datagram_receive_buffer = Some(bytes);
+        self
+    }
+
+    /// Sets how long the endpoint stops receiving packets after an error on its UDP socket.
+    ///
+    /// Such errors are reported to the listener, which continues to produce events afterwards.
+    /// Pausing avoids spinning if the error persists.
+    ///
+    /// Defaults to 100ms.
+    pub fn with_sleep_on_error(mut self, duration: Duration) -> Self {
+        self.sleep_on_error = duration;
+        self
+    }
+
+    /// Sets how long [`Endpoint::close`] waits for the connections of the endpoint to be
+    /// drained before stopping it anyway.
+    ///
+    /// Defaults to 3 seconds.
+    pub fn with_close_timeout(mut self, timeout: Duration) -> Self {
+        self.close_timeout = timeout;
+        self
+    }
+
+    /// Sets the ALPN protocols offered during the handshake, in order of preference.
+    ///
+    /// As the server selects the first protocol of its own list that the client supports, the
+    /// order only matters for incoming connections. An empty list, the default, offers only
+    /// `b"libp2p"`.
+    // TODO: rustls 0.17 selects the protocol in the order of `ServerConfig::alpn_protocols`, with
+    // no hook to reorder it per ClientHello; follow the preference of the client once it has one
+    pub fn with_alpn_protocols(mut self, protocols: Vec<Vec<u8>>) -> Self {
+        self.alpn_protocols = protocols;
+        self
+    }
+
+    /// Sets the maximum duration of the handshake when dialing. If the handshake doesn't
+    /// complete in time, the dial fails with
+    /// [`transport::Error::HandshakeTimeout`](crate::transport::Error::HandshakeTimeout).
+    ///
+    /// This doesn't affect established connections. By default, dialing only fails once the
+    /// connection times out for lack of response from the remote.
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets how dials are attempted again when their handshake times out, which happens when
+    /// the first packets are lost on a congested network.
+    ///
+    /// The attempts are made from the same endpoint, and thus from the same UDP port. Only
+    /// timeouts are retried: [`transport::Error::HandshakeTimeout`], and
+    /// [`transport::Error::TimedOut`] if no handshake timeout is set. Other errors, such as a
+    /// rejected certificate or a [`transport::Error::PeerIdMismatch`], fail the dial right
+    /// away. Doesn't apply to [`QuicTransport::dial_as_listener`], whose remote does the
+    /// dialing.
+    ///
+    /// [`transport::Error::HandshakeTimeout`]: crate::transport::Error::HandshakeTimeout
+    /// [`transport::Error::TimedOut`]: crate::transport::Error::TimedOut
+    /// [`transport::Error::PeerIdMismatch`]: crate::transport::Error::PeerIdMismatch
+    /// [`QuicTransport::dial_as_listener`]: crate::QuicTransport::dial_as_listener
+    ///
+    /// Defaults to making a single attempt.
+    pub fn with_dial_retry(mut self, retry: DialRetry) -> Self {
+        self.dial_retry = Some(retry);
+        self
+    }
+
+    /// Sets how long a connection can stay without receiving any packet before it is closed, or
+    /// `None` to never close idle connections.
+    ///
+    /// The remote can ask for a shorter timeout, in which case its value is used. Defaults to
+    /// 10 seconds.
+    pub fn with_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Sets the interval at which a packet is sent on connections that would otherwise be
+    /// idle, or `None` to not send any.
+    ///
+    /// This prevents idle connections from timing out, and keeps the mappings of NATs between
+    /// the endpoints alive. The interval must be shorter than the idle timeout, otherwise
+    /// creating the [`Endpoint`] fails. Defaults to 10 milliseconds.
+    pub fn with_keep_alive_interval(mut self, interval: Option<Duration>) -> Self {
+        self.keep_alive_interval = interval;
+        self
+    }
+
+    /// Sets whether to emit a trace event for each step of the handshake of every connection,
+    /// which helps finding out why a connection with another implementation fails.
+    ///
+    /// The events are emitted with the `libp2p_quic::handshake` target and the `info` level.
+    /// Each carries a `milestone` field, which is successively `started`, `connected`,
+    /// `certificate_received` and `peer_identified` for a successful handshake, or `failed`,
+    /// and an `elapsed` field with the time since the handshake started.
+    ///
+    /// Defaults to `false`.
+    pub fn with_handshake_trace(mut self, enabled: bool) -> Self {
+        self.handshake_trace = enabled;
+        self
+    }
+
+    /// Sets the initial congestion window of incoming and outgoing connections, in bytes.
+    ///
+    /// A larger window lets new connections, and connections recovering from persistent
+    /// congestion, send more data before receiving acknowledgements, at the risk of causing
+    /// losses on paths that can't absorb it. Must not be 0.
+    ///
+    /// Defaults to the `quinn_proto` default of 14720 bytes.
+    pub fn with_initial_window(mut self, bytes: u64) -> Self {
+        self.initial_window = Some(bytes);
+        self
+    }
+
+    /// Sets the executor that runs the background tasks of the endpoint and of its connections.
+    ///
+    /// The endpoint has one background task driving its UDP socket, and each connection has
+    /// one driving its timers. These tasks don't depend on a specific runtime, and any executor
+    /// can be used.
+    ///
+    /// By default, the tasks are spawned on the runtime that creates them if the `tokio`
+    /// feature is enabled and it is a `tokio` runtime, and on the global `async-std` executor,
+    /// which starts its own threads if needed, otherwise.
+    pub fn with_executor(mut self, executor: impl Executor + Send + Sync + 'static) -> Self {
+        self.spawner = Spawner(Some(Arc::new(executor)));
+        self
+    }
+
+    /// Sets the object that the endpoint and its connections report their activity to, for
+    /// instance a `PrometheusMetrics` with the `metrics` feature. See the
+    /// [`metrics`](crate::metrics) module.
+    ///
+    /// Defaults to no metrics at all.
+    pub fn with_metrics(mut self, metrics: Arc<dyn TransportMetrics>) -> Self {
+        self.metrics = Metrics::new(metrics);
+        self
+    }
+
+    /// Shortcut for [`Config::with_executor`] with an executor that calls the given closure.
+    pub fn with_executor_fn(
+        self,
+        f: impl Fn(Pin<Box<dyn Future<Output = ()> + Send>>) + Send + Sync + 'static,
+    ) -> Self {
+        struct FnExecutor<F>(F);
+        impl<F: Fn(Pin<Box<dyn Future<Output = ()> + Send>>)> Executor for FnExecutor<F> {
+            fn exec(&self, future: Pin<Box<dyn Future<Output = ()> + Send>>) {
+                (self.0)(future)
+            }
+        }
+        self.with_executor(FnExecutor(f))
+    }
+
+    /// Uses the given TLS configurations for outgoing and incoming connections respectively,
+    /// instead of the ones generated from the keypair passed to [`Config::new`].
+    ///
+    /// This overrides [`Config::with_verifier`] and [`Config::with_alpn_protocols`]. The
+    /// configurations must be compatible with libp2p:
+    ///
+    /// - Only TLS 1.3 can be used.
+    /// - The ALPN protocols must include `b"libp2p"`.
+    /// - The certificate must be a libp2p certificate, as generated by [`make_tls_config`].
+    /// - The verifiers must only accept libp2p certificates, as the [`PeerId`] of the remote
+    ///   is extracted from its certificate once the handshake is complete.
+    ///
+    /// [`make_tls_config`]: crate::make_tls_config
+    /// [`PeerId`]: libp2p_core::PeerId
+    pub fn with_tls(mut self, client: rustls::ClientConfig, server: rustls::ServerConfig) -> Self {
+        self.custom_tls = Some((Arc::new(client), Arc::new(server)));
+        self
+    }
+
+    /// Checks that an endpoint could listen on `addr` with this configuration, and returns the
+    /// UDP socket address that would be bound.
+    ///
+    /// This performs the same checks as [`Endpoint::new`], except that no socket is created.
+    /// Binding the socket can still fail, for example if the port is already in use.
+    pub fn validate_listen(
+        &self,
+        addr: &Multiaddr,
+    ) -> Result<SocketAddr, TransportError<io::Error>> {
+        let socket_addr = match crate::addr::multiaddr_to_socketaddr(addr) {
+            Ok(socket_addr) => socket_addr,
+            Err(_) => return Err(TransportError::MultiaddrNotSupported(addr.clone())),
+        };
+
+        let limits = self
+            .max_concurrent_bidi_streams
+            .into_iter()
+            .chain(Some(self.max_concurrent_uni_streams));
+        for max in limits {
+            if quinn_proto::VarInt::from_u64(max).is_err() {
+                let msg = format!(
+                    "maximum number of concurrent substreams {} is too large",
+                    max
+                );
+                return Err(TransportError::Other(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    msg,
+                )));
+            }
+        }
+
+        if let Some(timeout) = self.idle_timeout {
+            if quinn_proto::VarInt::from_u64(timeout.as_millis() as u64).is_err() {
+                let msg = format!("idle timeout {:?} is too large", timeout);
+                return Err(TransportError::Other(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    msg,
+                )));
+            }
+            match self.keep_alive_interval {
+                Some(interval) if interval >= timeout => {
+                    let msg = format!(
+                        "keep-alive interval {:?} isn't shorter than the idle timeout {:?}",
+                        interval, timeout
+                    );
+                    return Err(TransportError::Other(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        msg,
+                    )));
+                }
+                _ => {}
+            }
+        }
+
+        let windows = self
+            .stream_receive_window
+            .into_iter()
+            .chain(self.receive_window);
+        for window in windows {
+            if window == 0 || quinn_proto::VarInt::from_u64(window).is_err() {
+                let msg = format!("receive window of {} bytes is out of bounds", window);
+                return Err(TransportError::Other(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    msg,
+                )));
+            }
+        }
+
+        if self.max_pending_connections == 0 {
+            return Err(TransportError::Other(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the maximum number of pending connections must not be 0",
+            )));
+        }
+
+        if self.datagram_receive_buffer == Some(0) {
+            return Err(TransportError::Other(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "datagram receive buffer must not be empty",
+            )));
+        }
+
+        if self.initial_window == Some(0) {
+            return Err(TransportError::Other(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "initial congestion window must not be empty",
+            )));
+        }
+
+        Ok(socket_addr)
+    }
+
+    /// Builds the transport configuration shared by incoming and outgoing connections.
+    fn transport_config(&self) -> Arc<quinn_proto::TransportConfig> {
+        let mut transport = quinn_proto::TransportConfig::default();
+        transport.stream_window_uni(self.max_concurrent_uni_streams);
+        if let Some(max) = self.max_concurrent_bidi_streams {
+            transport.stream_window_bidi(max);
+        }
+        if let Some(window) = self.stream_receive_window {
+            transport
+                .stream_receive_window(window)
+                .expect("checked by `validate_listen`; qed");
+        }
+        if let Some(window) = self.receive_window {
+            transport
+                .receive_window(window)
+                .expect("checked by `validate_listen`; qed");
+        }
+        if !self.datagrams {
+            transport.datagram_receive_buffer_size(None);
+        } else if let Some(bytes) = self.datagram_receive_buffer {
+            transport.datagram_receive_buffer_size(Some(bytes));
+        }
+        transport
+            .idle_timeout(self.idle_timeout)
+            .expect("checked by `validate_listen`; qed");
+        transport.keep_alive_interval(self.keep_alive_interval);
+        // TODO: quinn_proto 0.6 only implements NewReno; let the congestion controller be chosen
+        //       once it supports others
+        if let Some(bytes) = self.initial_window {
+            transport.initial_window(bytes);
+        }
+        Arc::new(transport)
+    }
+
+    /// Builds the configuration of the muxers of the connections.
+    fn muxer_config(&self) -> MuxerConfig {
+        MuxerConfig {
+            queue_outbound: self.queue_outbound,
+            spawner: self.spawner.clone(),
+            metrics: self.metrics.clone(),
+            cancel_error_code: self.cancel_error_code.into(),
+        }
+    }
+
+    /// Builds the `quinn_proto` configuration used when dialing.
+    fn client_config(&self) -> quinn_proto::ClientConfig {
+        let mut client_config = quinn_proto::ClientConfig::default();
+        client_config.transport = self.transport_config();
+        if let Some((client, _)) = &self.custom_tls {
+            client_config.crypto = client.clone();
+            return client_config;
+        }
+        let mut crypto = x509::make_client_config(
+            self.certificate.clone(),
+            self.private_key.clone(),
+            self.verifier.clone(),
+            &self.alpn_protocols,
+            self.resumes_sessions(),
+        )
+        .expect("the certificate and its key were generated by `make_certificate`; qed");
+        crypto.enable_early_data = self.early_data;
+        client_config.crypto = Arc::new(crypto);
+        client_config
+    }
+
+    /// Builds the `quinn_proto` configuration used when accepting connections.
+    fn server_config(&self) -> Arc<quinn_proto::ServerConfig> {
+        let mut server_config = quinn_proto::ServerConfig::default();
+        server_config.transport = self.transport_config();
+        // `quinn_proto` drops the packets of a remote that migrates while migration is disabled,
+        // without authenticating them first. Migration is therefore allowed here, and refused by
+        // the `Connection` once a packet from the new path has been authenticated.
+        server_config.migration = true;
+        server_config.use_stateless_retry = self.use_retry;
+        server_config.accept_buffer = self.max_pending_connections;
+        if let Some((_, server)) = &self.custom_tls {
+            server_config.crypto = server.clone();
+            return Arc::new(server_config);
+        }
+        let mut crypto = x509::make_server_config(
+            self.certificate.clone(),
+            self.private_key.clone(),
+            self.verifier.clone(),
+            &self.alpn_protocols,
+            self.resumes_sessions(),
+        )
+        .expect("the certificate and its key were generated by `make_certificate`; qed");
+        if self.early_data {
+            // QUIC requires the limit to be either 0 or `u32::max_value()`, as the amount of
+            // 0-RTT data is limited by the flow control of the connection instead.
+            crypto.max_early_data_size = u32::max_value();
+        }
+        server_config.crypto = Arc::new(crypto);
+        Arc::new(server_config)
+    }
+}
+
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Config")
+            .field("local_peer_id", &self.local_peer_id)
+            .field("role", &self.role)
+            .field("early_data", &self.early_data)
+            .field("session_resumption", &self.session_resumption)
+            .field("close_timeout", &self.close_timeout)
+            .field("multiaddr", &self.multiaddr)
+            .field("migration", &self.migration)
+            .field("use_retry", &self.use_retry)
+            .field("max_pending_connections", &self.max_pending_connections)
+            .field(
+                "max_concurrent_bidi_streams",
+                &self.max_concurrent_bidi_streams,
+            )
+            .field(
+                "max_concurrent_uni_streams",
+                &self.max_concurrent_uni_streams,
+            )
+            .field("stream_receive_window", &self.stream_receive_window)
+            .field("receive_window", &self.receive_window)
+            .field("queue_outbound", &self.queue_outbound)
+            .field("cancel_error_code", &self.cancel_error_code)
+            .field("verifier", &self.verifier)
+            .field("alpn_protocols", &self.alpn_protocols)
+            .field("custom_tls", &self.custom_tls.is_some())
+            .field("handshake_trace", &self.handshake_trace)
+            .field("ipv6_only", &self.ipv6_only)
+            .field("loopback_addresses", &self.loopback_addresses)
+            .field("sleep_on_error", &self.sleep_on_error)
+            .field("datagrams", &self.datagrams)
+            .field("datagram_receive_buffer", &self.datagram_receive_buffer)
+            .field("initial_window", &self.initial_window)
+            .field("spawner", &self.spawner)
+            .field("metrics", &self.metrics)
+            .field("handshake_timeout", &self.handshake_timeout)
+            .field("dial_retry", &self.dial_retry)
+            .field("idle_timeout", &self.idle_timeout)
+            .field("keep_alive_interval", &self.keep_alive_interval)
+            .finish()
+    }
 }
 
 /// Object containing all the QUIC resources shared between all connections.
+///
+/// Dropping the last reference to the `Endpoint` closes its UDP socket right away, so that its
+/// port can be bound again, and instructs its background task to stop, which happens the next
+/// time the task is polled.
 // TODO: expand docs
 // TODO: Debug trait
 // TODO: remove useless fields
@@ -104,117 +1045,522 @@ pub struct Endpoint {
     /// done in two steps: locking this mutex, and grabbing the next element on the `Receiver`.
     /// The only consequence of this `Mutex` is that multiple simultaneous calls to
     /// [`Endpoint::next_incoming`] are serialized.
-    new_connections: Mutex<mpsc::Receiver<Either<Connection, Multiaddr>>>,
+    new_connections: Mutex<mpsc::Receiver<Incoming>>,
 
     /// Copy of [`Endpoint::to_endpoint`], except not behind a `Mutex`. Used if we want to be guaranteed a
     /// slot in the messages buffer.
     to_endpoint2: mpsc::Sender<ToEndpoint>,
+
+    /// Resolves once the background task has finished. Awaited by [`Endpoint::close_now`] and
+    /// [`Endpoint::close`], by as many callers as there are.
+    background_task: future::Shared<oneshot::Receiver<()>>,
+
+    /// Configuration of the muxers of the connections of this endpoint.
+    muxer_config: MuxerConfig,
+
+    /// See [`Config::with_handshake_trace`].
+    handshake_trace: bool,
+
+    /// See [`Config::with_handshake_timeout`].
+    handshake_timeout: Option<Duration>,
+
+    /// See [`Config::with_dial_retry`].
+    dial_retry: Option<DialRetry>,
+
+    /// Verifier of the certificates of resumed sessions, which aren't verified during the TLS
+    /// handshake. `None` if sessions are never resumed.
+    session_verifier: Option<x509::VerifierConfig>,
+
+    /// UDP socket of the endpoint, shared with the background task. Emptied when the endpoint
+    /// is dropped.
+    socket: SharedSocket,
+
+    /// Address the UDP socket is bound to. Its port is never 0, but its IP address can be
+    /// unspecified.
+    local_addr: SocketAddr,
+
+    /// Configuration the endpoint has been built from. Used to build the endpoints of the
+    /// additional listeners, see [`Endpoint::listener_endpoint`].
+    config: Config,
+
+    /// Whether a listener reports the incoming connections of this endpoint.
+    listening: AtomicBool,
+
+    /// Endpoints of the additional listeners. They are kept alive by their listener and their
+    /// connections rather than by this endpoint.
+    listener_endpoints: parking_lot::Mutex<Vec<Weak<Endpoint>>>,
+
+    /// Number of incoming connections whose handshake hasn't completed yet. See
+    /// [`Config::with_max_pending_connections`].
+    pending_connections: AtomicUsize,
+
+    /// Addresses the endpoint is reachable at, as reported to its listener. Kept up to date by
+    /// the background task as the interfaces change.
+    listen_addrs: parking_lot::Mutex<Vec<Multiaddr>>,
 }
 
 impl Endpoint {
     /// Builds a new `Endpoint`.
+    ///
+    /// The background task of the endpoint is spawned as described in
+    /// [`Config::with_executor`].
     pub fn new(config: Config) -> Result<Arc<Endpoint>, TransportError<io::Error>> {
-        let mut multiaddr = config.multiaddr.clone();
-        let local_socket_addr = match crate::transport::multiaddr_to_socketaddr(&config.multiaddr) {
-            Ok(a) => a,
-            Err(()) => return Err(TransportError::MultiaddrNotSupported(multiaddr)),
-        };
+        let spawner = config.spawner.clone();
+        let (endpoint, driver) = Endpoint::new_with_driver(config)?;
+        spawner.spawn(driver);
+        Ok(endpoint)
+    }
 
-        // NOT blocking, as per man:bind(2), as we pass an IP address.
-        let socket =
-            std::net::UdpSocket::bind(&local_socket_addr).map_err(TransportError::Other)?;
-        // TODO:
-        let port_is_zero = local_socket_addr.port() == 0;
-        let local_socket_addr = socket.local_addr().map_err(TransportError::Other)?;
-        if port_is_zero {
-            assert_ne!(local_socket_addr.port(), 0);
-            assert_eq!(multiaddr.pop(), Some(Protocol::Quic));
-            assert_eq!(multiaddr.pop(), Some(Protocol::Udp(0)));
-            multiaddr.push(Protocol::Udp(local_socket_addr.port()));
-            multiaddr.push(Protocol::Quic);
+    /// Builds a new `Endpoint` that only dials, bound to an ephemeral port.
+    ///
+    /// This is the same as building an endpoint from [`Config::client_only`], except that the
+    /// port of the multiaddr of `config` is ignored. No TLS server configuration is built, the
+    /// handshakes that remotes initiate with the socket are ignored, and listening with a
+    /// [`QuicTransport`](crate::QuicTransport) using the endpoint fails with
+    /// [`Error::ClientOnly`](crate::transport::Error::ClientOnly).
+    pub fn client(mut config: Config) -> Result<Arc<Endpoint>, TransportError<io::Error>> {
+        config.role = Role::Client;
+        config.multiaddr = config
+            .multiaddr
+            .iter()
+            .map(|protocol| match protocol {
+                Protocol::Udp(_) => Protocol::Udp(0),
+                protocol => protocol,
+            })
+            .collect();
+        Endpoint::new(config)
+    }
+
+    /// Builds a new `Endpoint`, and returns its background task instead of spawning it.
+    ///
+    /// The endpoint doesn't send or receive any packet until the [`EndpointDriver`] is
+    /// polled, typically by spawning it on the executor of the application. The background
+    /// tasks of the connections are still spawned as described in [`Config::with_executor`].
+    pub fn new_with_driver(
+        config: Config,
+    ) -> Result<(Arc<Endpoint>, EndpointDriver), TransportError<io::Error>> {
+        Endpoint::build(config, None)
+    }
+
+    /// Builds a new `Endpoint` that uses `socket` rather than binding a UDP socket itself.
+    ///
+    /// This is meant for sockets obtained from elsewhere, such as with systemd socket
+    /// activation or before dropping privileges. The endpoint listens on the address that
+    /// `socket` is bound to, which must match the multiaddr of `config`: port 0 matches any
+    /// port, and an unspecified IP address matches any address of the same family. Otherwise,
+    /// or if the socket is connected, this fails with [`io::ErrorKind::InvalidInput`].
+    /// [`Config::with_ipv6_only`] doesn't apply to the socket. The endpoints of additional
+    /// listeners bind their own sockets, as usual.
+    ///
+    /// The background task of the endpoint is spawned as described in
+    /// [`Config::with_executor`].
+    pub fn from_socket(
+        config: Config,
+        socket: std::net::UdpSocket,
+    ) -> Result<Arc<Endpoint>, TransportError<io::Error>> {
+        if let Ok(peer) = socket.peer_addr() {
+            let msg = format!("the UDP socket is connected to {}", peer);
+            return Err(TransportError::Other(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                msg,
+            )));
+        }
+        let requested = config.validate_listen(&config.multiaddr)?;
+        let bound = socket.local_addr().map_err(TransportError::Other)?;
+        if !is_bound_to(requested, bound) {
+            let msg = format!(
+                "the UDP socket is bound to {}, which doesn't match {}",
+                bound, config.multiaddr
+            );
+            return Err(TransportError::Other(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                msg,
+            )));
         }
 
+        let spawner = config.spawner.clone();
+        let (endpoint, driver) = Endpoint::build(config, Some(socket))?;
+        spawner.spawn(driver);
+        Ok(endpoint)
+    }
+
+    /// Builds a new `Endpoint` around `socket`, or around a newly bound socket if `None`, and
+    /// returns its background task.
+    fn build(
+        config: Config,
+        socket: Option<std::net::UdpSocket>,
+    ) -> Result<(Arc<Endpoint>, EndpointDriver), TransportError<io::Error>> {
+        let local_socket_addr = config.validate_listen(&config.multiaddr)?;
+
+        let socket = match socket {
+            Some(socket) => socket,
+            // NOT blocking, as per man:bind(2), as we pass an IP address.
+            None => {
+                bind_socket(&local_socket_addr, config.ipv6_only).map_err(TransportError::Other)?
+            }
+        };
+        // The actual port if port 0 has been requested.
+        let local_socket_addr = socket.local_addr().map_err(TransportError::Other)?;
+        let multiaddr = crate::addr::socketaddr_to_multiaddr(&local_socket_addr);
+
         let (to_endpoint_tx, to_endpoint_rx) = mpsc::channel(32);
         let to_endpoint2 = to_endpoint_tx.clone();
         let (new_connections_tx, new_connections_rx) = mpsc::channel(500);
+        let (task_finished_tx, task_finished_rx) = oneshot::channel();
+        let socket = Arc::new(parking_lot::Mutex::new(Some(SocketOps {
+            socket: Arc::new(async_std::net::UdpSocket::from(socket)),
+            send: None,
+            recv: None,
+        })));
 
         let endpoint = Arc::new(Endpoint {
             to_endpoint: Mutex::new(to_endpoint_tx),
             to_endpoint2,
             new_connections: Mutex::new(new_connections_rx),
+            background_task: task_finished_rx.shared(),
+            muxer_config: config.muxer_config(),
+            handshake_trace: config.handshake_trace,
+            handshake_timeout: config.handshake_timeout,
+            dial_retry: config.dial_retry,
+            session_verifier: if config.resumes_sessions() {
+                Some(config.verifier.clone())
+            } else {
+                None
+            },
+            socket: socket.clone(),
+            local_addr: local_socket_addr,
+            config: config.clone(),
+            listening: AtomicBool::new(false),
+            listener_endpoints: Default::default(),
+            pending_connections: AtomicUsize::new(0),
+            listen_addrs: Default::default(),
         });
 
         let send_addr = |e| {
             new_connections_tx
                 .clone()
-                .try_send(Either::Right(e))
+                .try_send(Incoming::NewAddress(e))
                 .expect("we just cloned this, so we have capacity; qed")
         };
 
-        // TODO: IP address stuff
-        if local_socket_addr.ip().is_unspecified() {
+        // Determine all our listen addresses, which is either a single local IP address or (if a
+        // wildcard IP address was used) the addresses of all our interfaces.
+        let addresses = if local_socket_addr.ip().is_unspecified() {
             tracing::info!("returning all local IPs for unspecified address");
-            let suffixes = [Protocol::Udp(local_socket_addr.port()), Protocol::Quic];
-            let local_addresses = host_addresses(&suffixes).map_err(TransportError::Other)?;
-            for (_, _, address) in local_addresses {
-                tracing::info!("sending address {:?}", address);
-                send_addr(address)
-            }
+            let addrs =
+                interface_addresses(&local_socket_addr, &config).map_err(TransportError::Other)?;
+            Addresses::Many(InterfacesWatch::new(addrs))
         } else {
-            tracing::info!("sending address {:?}", multiaddr);
-            send_addr(multiaddr)
+            Addresses::One(multiaddr)
+        };
+        let listen_addrs = addresses.multiaddrs();
+        for address in &listen_addrs {
+            tracing::info!("sending address {:?}", address);
+            send_addr(address.clone())
         }
+        *endpoint.listen_addrs.lock() = listen_addrs;
 
-        // TODO: just for testing, do proper task spawning
-        async_std::task::spawn(background_task(
+        let mut failure_tx = new_connections_tx.clone();
+        let task = background_task(
             config.clone(),
             Arc::downgrade(&endpoint),
-            async_std::net::UdpSocket::from(socket),
+            socket.clone(),
+            local_socket_addr,
+            addresses,
             new_connections_tx,
             to_endpoint_rx.fuse(),
-        ));
+        );
+        let driver = EndpointDriver(Box::pin(async move {
+            // If the task panics, its sender is dropped and the stream of the listener ends as
+            // if the endpoint had been closed. An error is sent with our own sender, for which
+            // the channel always has room, to tell both cases apart.
+            if AssertUnwindSafe(task).catch_unwind().await.is_err() {
+                let _ = failure_tx.try_send(Incoming::DriverFailed);
+            }
+            // The socket is closed before reporting the task as finished, as the endpoint
+            // itself may be kept alive.
+            socket.lock().take();
+            let _ = task_finished_tx.send(());
+        }));
+
+        Ok((endpoint, driver))
+    }
 
-        // let endpoint = EndpointRef { reference, channel };
-        // let join_handle = spawn(endpoint.clone());
-        // Ok((Self(endpoint), join_handle))
+    /// Stops the background task of the endpoint and waits for it to have finished, at which
+    /// point the UDP socket has been closed and its port can be bound again.
+    ///
+    /// Connections that are still alive can no longer communicate with the remote, and further
+    /// attempts at dialing fail.
+    pub async fn close_now(&self) {
+        // Cloning the sender guarantees us a slot in the buffer. If sending fails, the task has
+        // already stopped.
+        let _ = self.to_endpoint2.clone().try_send(ToEndpoint::Shutdown);
+        // An error means that the executor dropped the task, which is equally finished.
+        let _ = self.background_task.clone().await;
+    }
 
+    /// Closes the endpoint gracefully, and waits for its background task to have finished.
+    ///
+    /// The endpoint stops accepting connections, and all of its connections are closed,
+    /// reporting `error_code` and `reason` to the remotes as an application close. The returned
+    /// future resolves once the connections have been drained, or once the timeout set with
+    /// [`Config::with_close_timeout`] has elapsed, at which point the UDP socket has been closed.
+    /// Connections are only drained while their [`QuicMuxer`](crate::QuicMuxer) or
+    /// [`Upgrade`](crate::Upgrade) is alive, as that is what drives them.
+    ///
+    /// Dialing fails with
+    /// [`transport::Error::EndpointClosed`](crate::transport::Error::EndpointClosed) as soon as
+    /// this has been called.
+    ///
+    /// Fails with [`Error::ErrorCodeTooLarge`] or [`Error::CloseReasonTooLong`] if the error
+    /// code or reason can't be sent, in which case the endpoint is left untouched.
+    pub async fn close(&self, error_code: u64, reason: &[u8]) -> Result<(), Error> {
+        let error_code = check_close_frame(error_code, reason)?;
+        // Cloning the sender guarantees us a slot in the buffer. If sending fails, the task has
+        // already stopped.
+        let _ = self.to_endpoint2.clone().try_send(ToEndpoint::Close {
+            error_code,
+            reason: reason.to_vec(),
+        });
+        let _ = self.background_task.clone().await;
+        Ok(())
+    }
+
+    /// Closes all the connections of the endpoint, reporting `error_code` and `reason` to the
+    /// remotes as an application close, and waits for the endpoint to be idle.
+    ///
+    /// Unlike [`Endpoint::close`], the endpoint keeps accepting and dialing connections. The
+    /// returned future resolves once the endpoint has no connection left, which includes the
+    /// connections established in the meantime. Connections are only drained while their
+    /// [`QuicMuxer`](crate::QuicMuxer) or [`Upgrade`](crate::Upgrade) is alive, as that is what
+    /// drives them.
+    ///
+    /// Fails with [`Error::ErrorCodeTooLarge`] or [`Error::CloseReasonTooLong`] if the error
+    /// code or reason can't be sent, in which case no connection is closed. Resolves
+    /// immediately if the background task of the endpoint has stopped.
+    pub async fn close_all(&self, error_code: u64, reason: &[u8]) -> Result<(), Error> {
+        let error_code = check_close_frame(error_code, reason)?;
+        let (tx, rx) = oneshot::channel();
+        let message = ToEndpoint::CloseAll {
+            error_code,
+            reason: reason.to_vec(),
+            idle: tx,
+        };
+        // Sending the message or receiving the response can only fail if the background task
+        // has stopped, in which case there is no connection left either.
+        if self.to_endpoint.lock().await.send(message).await.is_ok() {
+            let _ = rx.await;
+        }
+        Ok(())
+    }
+
+    /// Returns the configuration of the muxers of the connections of this endpoint.
+    pub(crate) fn muxer_config(&self) -> &MuxerConfig {
+        &self.muxer_config
+    }
+
+    /// Returns the maximum duration of the handshake of outgoing connections.
+    pub(crate) fn handshake_timeout(&self) -> Option<Duration> {
+        self.handshake_timeout
+    }
+
+    /// Returns how dials are attempted again when their handshake times out.
+    pub(crate) fn dial_retry(&self) -> Option<DialRetry> {
+        self.dial_retry
+    }
+
+    /// Returns the address the UDP socket of this endpoint is bound to.
+    pub(crate) fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Returns the endpoint whose incoming connections a new listener on `addr` should report.
+    ///
+    /// This is the endpoint itself if `addr` is the address it has been configured with or is
+    /// bound to, and no other listener is using it. Otherwise, a new endpoint bound to `addr` is
+    /// built from the same configuration, and thus with the same certificate and TLS
+    /// configuration. Binding an address that is already in use fails with
+    /// [`io::ErrorKind::AddrInUse`].
+    ///
+    /// The listener must call [`Endpoint::stop_listening`] on the returned endpoint once it is
+    /// dropped.
+    pub(crate) fn listener_endpoint(
+        self: &Arc<Self>,
+        addr: &Multiaddr,
+    ) -> Result<Arc<Endpoint>, TransportError<io::Error>> {
+        let is_own_addr = *addr == self.config.multiaddr
+            || crate::addr::multiaddr_to_socketaddr(addr)
+                .map_or(false, |socket_addr| socket_addr == self.local_addr);
+        if is_own_addr && !self.listening.swap(true, Ordering::SeqCst) {
+            return Ok(self.clone());
+        }
+
+        let mut config = self.config.clone();
+        config.multiaddr = addr.clone();
+        let endpoint = Endpoint::new(config)?;
+        endpoint.listening.store(true, Ordering::SeqCst);
+        let mut listener_endpoints = self.listener_endpoints.lock();
+        listener_endpoints.retain(|e| e.strong_count() > 0);
+        listener_endpoints.push(Arc::downgrade(&endpoint));
         Ok(endpoint)
     }
 
+    /// Returns the addresses that the listeners of the endpoint are currently reachable at.
+    ///
+    /// These are the addresses reported with `NewAddress` and not yet with `AddressExpired`
+    /// by the listeners created with [`QuicTransport`](crate::QuicTransport), including the
+    /// listeners on other addresses than the one the endpoint has been configured with. Listening
+    /// on an unspecified IP address yields the addresses of the matching interfaces, which are
+    /// kept up to date as interfaces come and go. The addresses contain the actual port and end
+    /// with the QUIC component. An endpoint without any listener has no listen address.
+    pub fn listen_addrs(&self) -> Vec<Multiaddr> {
+        let mut addrs = Vec::new();
+        if self.listening.load(Ordering::SeqCst) {
+            addrs.extend(self.listen_addrs.lock().iter().cloned());
+        }
+        for endpoint in self
+            .listener_endpoints
+            .lock()
+            .iter()
+            .filter_map(Weak::upgrade)
+        {
+            if endpoint.listening.load(Ordering::SeqCst) {
+                addrs.extend(endpoint.listen_addrs.lock().iter().cloned());
+            }
+        }
+        addrs
+    }
+
+    /// Indicates that the listener of this endpoint has been dropped, so that a new one can
+    /// be created with [`Endpoint::listener_endpoint`].
+    pub(crate) fn stop_listening(&self) {
+        self.listening.store(false, Ordering::SeqCst);
+    }
+
+    /// Returns the endpoint that should be used to dial `addr`.
+    ///
+    /// This is the endpoint itself, unless its socket can't reach `addr` because of its address
+    /// family and the endpoint of one of the additional listeners can.
+    pub(crate) fn dialer_endpoint(self: &Arc<Self>, addr: &SocketAddr) -> Arc<Endpoint> {
+        if self.can_reach(addr) {
+            return self.clone();
+        }
+        self.listener_endpoints
+            .lock()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .find(|e| e.can_reach(addr))
+            .unwrap_or_else(|| self.clone())
+    }
+
+    /// Returns `true` if the endpoint accepts incoming connections, in other words if it
+    /// hasn't been built with [`Config::client_only`].
+    pub(crate) fn can_listen(&self) -> bool {
+        self.config.role != Role::Client
+    }
+
+    /// Returns `true` if the endpoint can dial, in other words if it hasn't been built with
+    /// [`Config::server_only`].
+    pub(crate) fn can_dial(&self) -> bool {
+        self.config.role != Role::Server
+    }
+
+    /// Returns the [`PeerId`] of the keypair the endpoint has been configured with.
+    pub(crate) fn local_peer_id(&self) -> &PeerId {
+        &self.config.local_peer_id
+    }
+
+    /// Returns `true` if `addr` is the address of the UDP socket of this endpoint or of the
+    /// endpoint of one of its additional listeners, in which case dialing it would connect us
+    /// to ourselves.
+    pub(crate) fn is_local_addr(self: &Arc<Self>, addr: &SocketAddr) -> bool {
+        let is_own_addr = |endpoint: &Endpoint| {
+            endpoint.can_reach(addr)
+                && local_addr_towards(endpoint.local_addr, addr).ok() == Some(*addr)
+        };
+        is_own_addr(self)
+            || self
+                .listener_endpoints
+                .lock()
+                .iter()
+                .filter_map(Weak::upgrade)
+                .any(|e| is_own_addr(&e))
+    }
+
+    /// Returns `true` if the UDP socket of this endpoint can send packets to `addr`.
+    fn can_reach(&self, addr: &SocketAddr) -> bool {
+        match (self.local_addr, addr) {
+            (SocketAddr::V4(_), SocketAddr::V4(_)) | (SocketAddr::V6(_), SocketAddr::V6(_)) => true,
+            (SocketAddr::V6(_), SocketAddr::V4(_)) => !self.config.ipv6_only,
+            (SocketAddr::V4(_), SocketAddr::V6(_)) => false,
+        }
+    }
+
+    /// Returns whether the handshakes of the connections of this endpoint should be traced.
+    pub(crate) fn handshake_trace(&self) -> bool {
+        self.handshake_trace
+    }
+
+    /// Returns whether the remotes of incoming connections may migrate them to a new path. See
+    /// [`Config::with_migration`].
+    pub(crate) fn migration(&self) -> bool {
+        self.config.migration
+    }
+
+    /// Returns the verifier of the certificates of resumed sessions, if sessions can be resumed.
+    pub(crate) fn session_verifier(&self) -> Option<&x509::VerifierConfig> {
+        self.session_verifier.as_ref()
+    }
+
     /// Asks the endpoint to start dialing the given address.
     ///
     /// Note that this method only *starts* the dialing. `Ok` is returned as soon as possible, even
     /// when the remote might end up being unreachable.
+    ///
+    /// Returns [`quinn_proto::ConnectError::EndpointStopping`] if the endpoint has been closed.
     pub(crate) async fn dial(
         &self,
         addr: SocketAddr,
     ) -> Result<Connection, quinn_proto::ConnectError> {
-        // The two `expect`s below can panic if the background task has stopped. The background
-        // task can stop only if the `Endpoint` is destroyed or if the task itself panics. In other
-        // words, we panic here iff a panic has already happened somewhere else, which is a
-        // reasonable thing to do.
+        // Sending the message or receiving the response can only fail if the background task
+        // has stopped, either because of `close_now` or because of a panic.
         let (tx, rx) = oneshot::channel();
         self.to_endpoint
             .lock()
             .await
             .send(ToEndpoint::Dial { addr, result: tx })
             .await
-            .expect("background task has crashed");
+            .map_err(|_| quinn_proto::ConnectError::EndpointStopping)?;
         info!("Sent dial message, awaiting response");
-        rx.await.expect("background task has crashed")
+        rx.await
+            .map_err(|_| quinn_proto::ConnectError::EndpointStopping)?
+    }
+
+    /// Asks the endpoint to hand over the next incoming connection from `addr` to the returned
+    /// receiver, instead of reporting it to the listener.
+    ///
+    /// Returns [`quinn_proto::ConnectError::EndpointStopping`] if the endpoint has been closed.
+    /// The receiver is canceled if the endpoint is closed while waiting.
+    pub(crate) async fn expect_connection(
+        &self,
+        addr: SocketAddr,
+    ) -> Result<oneshot::Receiver<Connection>, quinn_proto::ConnectError> {
+        let (tx, rx) = oneshot::channel();
+        self.to_endpoint
+            .lock()
+            .await
+            .send(ToEndpoint::ExpectConnection { addr, result: tx })
+            .await
+            .map_err(|_| quinn_proto::ConnectError::EndpointStopping)?;
+        Ok(rx)
     }
 
     /// Tries to pop a new incoming connection from the queue.
-    pub(crate) async fn next_incoming(&self) -> Either<Connection, Multiaddr> {
-        // The `expect` below can panic if the background task has stopped. The background task
-        // can stop only if the `Endpoint` is destroyed or if the task itself panics. In other
-        // words, we panic here iff a panic has already happened somewhere else, which is a
-        // reasonable thing to do.
+    ///
+    /// Returns `None` if the background task has stopped.
+    pub(crate) async fn next_incoming(&self) -> Option<Incoming> {
         let mut new_connections = self.new_connections.lock().await;
-        new_connections
-            .next()
-            .await
-            .expect("background task has crashed")
+        new_connections.next().await
     }
 
     /// Asks the endpoint to send a UDP packet.
@@ -249,15 +1595,45 @@ impl Endpoint {
         connection_id: quinn_proto::ConnectionHandle,
         event: quinn_proto::EndpointEvent,
     ) {
-        self.to_endpoint
+        // An error means that the background task has stopped, in which case the event is
+        // irrelevant anyway.
+        let _ = self
+            .to_endpoint
             .lock()
             .await
             .send(ToEndpoint::ProcessConnectionEvent {
                 connection_id,
                 event,
             })
-            .await
-            .expect("background task has crashed");
+            .await;
+    }
+
+    /// Returns the number of incoming connections whose handshake hasn't completed yet, which
+    /// is at most [`Config::with_max_pending_connections`].
+    ///
+    /// The connections handed over to the dialer as part of a simultaneous open are not
+    /// included.
+    pub fn pending_connections(&self) -> usize {
+        self.pending_connections.load(Ordering::Relaxed)
+    }
+
+    /// Counts a new incoming connection as pending, until
+    /// [`Endpoint::report_handshake_done_non_block`] is called for it.
+    pub(crate) fn add_pending_connection(&self) {
+        self.pending_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Reports that the handshake of an incoming connection counted as pending has completed,
+    /// or has been abandoned, which allows the endpoint to accept a new one.
+    ///
+    /// Like [`Endpoint::report_quinn_event_non_block`], this never waits.
+    pub(crate) fn report_handshake_done_non_block(&self) {
+        self.pending_connections.fetch_sub(1, Ordering::Relaxed);
+        let result = self
+            .to_endpoint2
+            .clone()
+            .try_send(ToEndpoint::IncomingHandshakeDone);
+        assert!(result.is_ok() || result.unwrap_err().is_disconnected());
     }
 
     /// Similar to [`Endpoint::report_quinn_event`], except that the message sending is guaranteed
@@ -271,7 +1647,8 @@ impl Endpoint {
         event: quinn_proto::EndpointEvent,
     ) {
         // We implement this by cloning the `mpsc::Sender`. Since each sender is guaranteed a slot
-        // in the buffer, cloning the sender reserves the slot and sending thus always succeeds.
+        // in the buffer, cloning the sender reserves the slot and sending thus always succeeds,
+        // unless the background task has stopped.
         let result = self
             .to_endpoint2
             .clone()
@@ -279,8 +1656,22 @@ impl Endpoint {
                 connection_id,
                 event,
             });
-        assert!(result.is_ok());
+        assert!(result.is_ok() || result.unwrap_err().is_disconnected());
+    }
+}
+
+/// Checks that `error_code` and `reason` fit in a `CONNECTION_CLOSE` frame, and returns the
+/// error code as a `VarInt`.
+pub(crate) fn check_close_frame(
+    error_code: u64,
+    reason: &[u8],
+) -> Result<quinn_proto::VarInt, Error> {
+    let error_code = quinn_proto::VarInt::from_u64(error_code)
+        .map_err(|_| Error::ErrorCodeTooLarge(error_code))?;
+    if reason.len() > MAX_CLOSE_REASON_LEN {
+        return Err(Error::CloseReasonTooLong(reason.len()));
     }
+    Ok(error_code)
 }
 
 /// Message sent to the endpoint background task.
@@ -300,6 +1691,17 @@ enum ToEndpoint {
         connection_id: quinn_proto::ConnectionHandle,
         event: quinn_proto::EndpointEvent,
     },
+    /// Instruct the endpoint to hand over the next incoming connection from the given address
+    /// instead of reporting it to the listener.
+    ExpectConnection {
+        /// UDP address the connection is expected from.
+        addr: SocketAddr,
+        /// Channel to send the connection to.
+        result: oneshot::Sender<Connection>,
+    },
+    /// Sent once the handshake of an incoming connection reported to the listener has completed
+    /// or has been abandoned, which frees a slot of the accept buffer.
+    IncomingHandshakeDone,
     /// Instruct the endpoint to send a packet of data on its UDP socket.
     SendUdpPacket {
         /// Destination of the UDP packet.
@@ -307,6 +1709,26 @@ enum ToEndpoint {
         /// Packet of data to send.
         data: Box<[u8]>,
     },
+    /// Instruct the endpoint to stop its background task and close the UDP socket.
+    Shutdown,
+    /// Instruct the endpoint to close all of its connections, and to report when it no longer
+    /// has any.
+    CloseAll {
+        /// Error code reported to the remotes.
+        error_code: quinn_proto::VarInt,
+        /// Reason reported to the remotes.
+        reason: Vec<u8>,
+        /// Channel to notify once the endpoint has no connection left.
+        idle: oneshot::Sender<()>,
+    },
+    /// Instruct the endpoint to stop accepting connections, close all of its connections, and
+    /// stop its background task once they have been drained.
+    Close {
+        /// Error code reported to the remotes.
+        error_code: quinn_proto::VarInt,
+        /// Reason reported to the remotes.
+        reason: Vec<u8>,
+    },
 }
 
 /// Task that runs in the background for as long as the endpont is alive. Responsible for
@@ -351,17 +1773,20 @@ enum ToEndpoint {
 /// ## Back-pressure on `new_connections`
 ///
 /// The [`quinn_proto::Endpoint`] object contains an accept buffer, in other words a buffer of the
-/// incoming connections waiting to be accepted. When a new connection is signalled, we send this
+/// incoming connections waiting to be accepted, whose size is
+/// [`Config::with_max_pending_connections`]. When a new connection is signalled, we send this
 /// new connection on the `new_connections` channel in an asynchronous way, and we only free a slot
-/// in the accept buffer once the element has actually been enqueued on `new_connections`. There
-/// are therefore in total three buffers in play: the `new_connections` channel itself, the queue
-/// of elements being sent on `new_connections`, and the accept buffer of the
-/// [`quinn_proto::Endpoint`].
+/// in the accept buffer once its handshake has completed, or has been abandoned, which the
+/// [`Connection`] reports with a [`ToEndpoint::IncomingHandshakeDone`] message. The accept buffer
+/// thus bounds the connections in the three places they can be in the meantime: the queue of
+/// elements being sent on `new_connections`, the `new_connections` channel itself, and the
+/// upgrades produced by the listener.
 ///
-/// Unfortunately, this design has the consequence that, on the network layer, we will accept a
-/// certain number of incoming connections even if [`Endpoint::next_incoming`] is never even
-/// called. The `quinn-proto` library doesn't provide any way to not accept incoming connections
-/// apart from filling the accept buffer.
+/// Once the accept buffer is full, `quinn_proto` refuses new connections. Unfortunately, this
+/// design has the consequence that, on the network layer, we will accept a certain number of
+/// incoming connections even if [`Endpoint::next_incoming`] is never even called. The
+/// `quinn-proto` library doesn't provide any way to not accept incoming connections apart from
+/// filling the accept buffer.
 ///
 /// ## Back-pressure on connections
 ///
@@ -388,7 +1813,12 @@ enum ToEndpoint {
 /// # Shutdown
 ///
 /// The background task shuts down if `endpoint_weak`, `receiver` or `new_connections` become
-/// disconnected/invalid. This corresponds to the lifetime of the associated [`Endpoint`].
+/// disconnected/invalid, or once the [`Endpoint`] has closed `udp_socket` when dropped. This
+/// corresponds to the lifetime of the associated [`Endpoint`].
+/// It also shuts down when receiving a [`ToEndpoint::Shutdown`] message, which is sent by
+/// [`Endpoint::close_now`], and once all connections have been drained or
+/// [`Config::with_close_timeout`] has elapsed after a [`ToEndpoint::Close`] message, which is
+/// sent by [`Endpoint::close`].
 ///
 /// Keep in mind that we pass an `Arc<Endpoint>` whenever we create a new connection, which
 /// guarantees that the [`Endpoint`], and therefore the background task, is properly kept alive
@@ -399,15 +1829,27 @@ enum Void {}
 async fn background_task(
     config: Config,
     endpoint_weak: Weak<Endpoint>,
-    udp_socket: async_std::net::UdpSocket,
-    mut new_connections: mpsc::Sender<Either<Connection, Multiaddr>>,
+    udp_socket: SharedSocket,
+    local_socket_addr: SocketAddr,
+    mut addresses: Addresses,
+    mut new_connections: mpsc::Sender<Incoming>,
     mut receiver: stream::Fuse<mpsc::Receiver<ToEndpoint>>,
 ) {
-    // The actual QUIC state machine.
-    let mut endpoint = quinn_proto::Endpoint::new(
-        config.endpoint_config.clone(),
-        Some(config.server_config.clone()),
-    );
+    // The actual QUIC state machine. Without a server configuration, `quinn_proto` refuses
+    // incoming connections.
+    let server_config = if config.role != Role::Client {
+        Some(config.server_config())
+    } else {
+        None
+    };
+    let mut endpoint = quinn_proto::Endpoint::new(config.endpoint_config.clone(), server_config);
+
+    // Configuration passed to `quinn_proto` for each outgoing connection.
+    let client_config = if config.role != Role::Server {
+        Some(config.client_config())
+    } else {
+        None
+    };
 
     // List of all active connections, with a sender to notify them of events.
     let mut alive_connections = HashMap::<quinn_proto::ConnectionHandle, mpsc::Sender<_>>::new();
@@ -417,8 +1859,8 @@ async fn background_task(
 
     // The quinn_proto endpoint can give us new connections for as long as its accept buffer
     // isn't full. This buffer is used to push these new connections while we are waiting to
-    // send them on the `new_connections` channel. We only call `endpoint.accept()` when we remove
-    // an element from this list, which guarantees that it doesn't grow unbounded.
+    // send them on the `new_connections` channel. We only call `endpoint.accept()` once the
+    // handshake of a connection has completed, which guarantees that it doesn't grow unbounded.
     // TODO: with_capacity?
     let mut queued_new_connections = VecDeque::new();
 
@@ -427,6 +1869,29 @@ async fn background_task(
     // code below.
     let mut next_packet_out: Option<(SocketAddr, Box<[u8]>)> = None;
 
+    // Timer after which we check for changes in the network interfaces. Only relevant if we
+    // listen on all interfaces.
+    let mut interfaces_check = match addresses {
+        Addresses::One(_) => Fuse::terminated(),
+        Addresses::Many(_) => Delay::new(INTERFACES_CHECK_INTERVAL).fuse(),
+    };
+
+    // Pause before receiving the next packet, after an error on the UDP socket.
+    let mut recv_pause: Option<Delay> = None;
+
+    // Set by `ToEndpoint::Close`. Once it is, the task stops as soon as all connections have
+    // been drained, or when `close_deadline` fires.
+    let mut closing = false;
+    let mut close_deadline: Fuse<Delay> = Fuse::terminated();
+
+    // Senders of `ToEndpoint::CloseAll`, notified once `alive_connections` is empty.
+    let mut idle_waiters: Vec<oneshot::Sender<()>> = Vec::new();
+
+    // Incoming connections that are expected from a specific address, as part of a simultaneous
+    // open. These connections are sent on the channel rather than being reported to the
+    // listener.
+    let mut expected_connections: HashMap<SocketAddr, oneshot::Sender<Connection>> = HashMap::new();
+
     // Main loop of the task.
     loop {
         // Start by flushing `next_packet_out`.
@@ -437,9 +1902,17 @@ async fn background_task(
             // channels.
             // TODO: set ECN bits; there is no support for them in the ecosystem right now
             // TODO: use a circular buffer instead.
-            match udp_socket.send_to(&data, destination).await {
-                Ok(n) if n == data.len() => {}
-                Ok(_) => tracing::error!(
+            // TODO: padding small packets to a minimum size, in order to make control messages
+            // harder to fingerprint by their length, isn't supported. Bytes appended here would
+            // be considered part of the last packet of the datagram, as short-header packets
+            // extend until the end of the datagram, and `quinn_proto` doesn't provide a way to
+            // request PADDING frames.
+            let len = data.len();
+            match send_to(&udp_socket, data, destination).await {
+                // The endpoint has been dropped and has closed the socket.
+                None => return,
+                Some(Ok(n)) if n == len => {}
+                Some(Ok(_)) => tracing::error!(
                     "QUIC UDP socket violated expectation that packets are always fully \
                     transferred"
                 ),
@@ -448,7 +1921,9 @@ async fn background_task(
                 // printing a log message. The packet gets discarded in case of error, but we are
                 // robust to packet losses and it is consequently not a logic error to process with
                 // normal operations.
-                Err(err) => tracing::error!("Error while sending on QUIC UDP socket: {:?}", err),
+                Some(Err(err)) => {
+                    tracing::error!("Error while sending on QUIC UDP socket: {:?}", err)
+                }
             }
         }
 
@@ -461,6 +1936,16 @@ async fn background_task(
             continue;
         }
 
+        if alive_connections.is_empty() {
+            for idle in idle_waiters.drain(..) {
+                let _ = idle.send(());
+            }
+            if closing {
+                tracing::debug!("all connections drained, closing");
+                return;
+            }
+        }
+
         futures::select! {
             message = receiver.next() => {
                 // Received a message from a different part of the code requesting us to
@@ -468,16 +1953,22 @@ async fn background_task(
                 span!("message received");
                 match message {
                     // Shut down if the endpoint has shut down.
-                    None => return,
+                    None | Some(ToEndpoint::Shutdown) => return,
 
                     Some(ToEndpoint::Dial { addr, result }) => {
                         span!("dialing", addr = display(addr), side = debug(quinn_proto::Side::Client));
                         info!("received dialout request");
-                        // This `"l"` seems necessary because an empty string is an invalid domain
-                        // name. While we don't use domain names, the underlying rustls library
-                        // is based upon the assumption that we do.
+                        if closing {
+                            let _ = result.send(Err(quinn_proto::ConnectError::EndpointStopping));
+                            continue;
+                        }
+                        // While we don't use domain names, the underlying rustls library is
+                        // based upon the assumption that we do, and wants a valid one.
+                        let client_config = client_config
+                            .clone()
+                            .expect("server-only endpoints are never asked to dial; qed");
                         let (connection_id, connection) =
-                            match endpoint.connect(config.client_config.clone(), addr, "l") {
+                            match endpoint.connect(client_config, addr, x509::SERVER_NAME) {
                                 Ok(c) => c,
                                 Err(err) => {
                                     let _ = result.send(Err(err));
@@ -516,27 +2007,67 @@ async fn background_task(
                         if let Some(event_back) = endpoint.handle_event(connection_id, event) {
                             assert!(!is_drained_event);
                             // TODO: don't await here /!\
-                            let _ =  alive_connections.get_mut(&connection_id).unwrap().clone().try_send(event_back);
+                            let _ = alive_connections
+                                .get_mut(&connection_id)
+                                .unwrap()
+                                .clone()
+                                .try_send(FromEndpoint::Event(event_back));
                         }
                     }
 
+                    // The handshake of a connection reported to the listener is over, allowing
+                    // the QUIC state machine to feed a new incoming connection to us.
+                    Some(ToEndpoint::IncomingHandshakeDone) => endpoint.accept(),
+
+                    Some(ToEndpoint::ExpectConnection { addr, result }) => {
+                        expected_connections.retain(|_, result| !result.is_canceled());
+                        expected_connections.insert(addr, result);
+                    }
+
                     // Data needs to be sent on the UDP socket.
                     Some(ToEndpoint::SendUdpPacket { destination, data }) => {
                         assert!(next_packet_out.is_none());
                         next_packet_out = Some((destination, data));
                         continue;
                     }
+
+                    Some(ToEndpoint::CloseAll { error_code, reason, idle }) => {
+                        for sender in alive_connections.values() {
+                            send_close(sender, error_code, &reason);
+                        }
+                        idle_waiters.push(idle);
+                    }
+
+                    Some(ToEndpoint::Close { error_code, reason }) => {
+                        if closing {
+                            continue;
+                        }
+                        closing = true;
+                        endpoint.reject_new_connections();
+                        // Nobody is interested in the connections that haven't been handed over
+                        // yet. Dropping them reports them as drained.
+                        queued_new_connections.retain(|i| !matches!(i, Incoming::Connection(_)));
+                        expected_connections.clear();
+                        for sender in alive_connections.values() {
+                            send_close(sender, error_code, &reason);
+                        }
+                        close_deadline = Delay::new(config.close_timeout).fuse();
+                    }
                 }
             }
 
+            _ = close_deadline => {
+                tracing::debug!("connections not drained in time, closing anyway");
+                return;
+            }
+
             // The future we create here wakes up if two conditions are fulfilled:
             //
             // - The `new_connections` channel is ready to accept a new element.
             // - `queued_new_connections` is not empty.
             //
-            // When this happens, we pop an element from `queued_new_connections`, put it on the
-            // channel, and call `endpoint.accept()`, thereby allowing the QUIC state machine to
-            // feed a new incoming connection to us.
+            // When this happens, we pop an element from `queued_new_connections` and put it on the
+            // channel.
             readiness = {
                 let active = !queued_new_connections.is_empty();
                 let new_connections = &mut new_connections;
@@ -555,16 +2086,46 @@ async fn background_task(
                     .expect("if queue is empty, the future above is always Pending; qed");
                 new_connections.start_send(elem)
                     .expect("future is waken up only if poll_ready returned Ready; qed");
-                endpoint.accept();
             }
 
-            result = udp_socket.recv_from(&mut socket_recv_buffer).fuse() => {
+            _ = interfaces_check => {
+                if let Addresses::Many(ref mut watch) = addresses {
+                    let addrs = interface_addresses(&local_socket_addr, &config);
+                    match addrs {
+                        Ok(addrs) => {
+                            watch.update(addrs, &mut queued_new_connections);
+                            if let Some(endpoint) = endpoint_weak.upgrade() {
+                                *endpoint.listen_addrs.lock() = addresses.multiaddrs();
+                            }
+                        }
+                        Err(err) => {
+                            tracing::warn!("Failed to enumerate network interfaces: {:?}", err)
+                        }
+                    }
+                }
+                interfaces_check = Delay::new(INTERFACES_CHECK_INTERVAL).fuse();
+            }
+
+            result = async {
+                if let Some(pause) = recv_pause.as_mut() {
+                    pause.await;
+                }
+                recv_from(&udp_socket, &mut socket_recv_buffer).await
+            }.fuse() => {
+                recv_pause = None;
                 let (packet_len, packet_src) = match result {
-                    Ok(v) => v,
-                    // Errors on the socket are expected to never happen, and we handle them by
-                    // simply printing a log message.
-                    Err(err) => {
+                    // The endpoint has been dropped and has closed the socket.
+                    None => return,
+                    Some(Ok(v)) => v,
+                    // Errors on the socket are expected to be transient. They are reported to the
+                    // listener, unless one is already waiting to be delivered, and we stop
+                    // receiving for a while in case the error persists.
+                    Some(Err(err)) => {
                         tracing::error!("Error while receive on QUIC UDP socket: {:?}", err);
+                        if !matches!(queued_new_connections.back(), Some(Incoming::Error(_))) {
+                            queued_new_connections.push_back(Incoming::Error(err));
+                        }
+                        recv_pause = Some(Delay::new(config.sleep_on_error));
                         continue;
                     },
                 };
@@ -573,6 +2134,10 @@ async fn background_task(
 
                 // Received a UDP packet from the socket.
                 assert!(packet_len <= socket_recv_buffer.len());
+                // `quinn_proto` only reports that the remote doesn't support our version, and
+                // the connection is told which versions it does support.
+                let peer_versions =
+                    connection::negotiation_versions(&socket_recv_buffer[..packet_len]);
                 let packet = From::from(&socket_recv_buffer[..packet_len]);
                 // TODO: ECN bits aren't handled
                 match endpoint.handle(Instant::now(), packet_src, None, packet) {
@@ -580,7 +2145,11 @@ async fn background_task(
                     Some((connec_id, quinn_proto::DatagramEvent::ConnectionEvent(event))) => {
                         // Event to send to an existing connection.
                         if let Some(sender) = alive_connections.get_mut(&connec_id) {
-                            let _ = sender.clone().try_send(event);
+                            if let Some(peer_supported) = peer_versions {
+                                let message = FromEndpoint::VersionNegotiation { peer_supported };
+                                let _ = sender.clone().try_send(message);
+                            }
+                            let _ = sender.clone().try_send(FromEndpoint::Event(event));
                         } else {
                             tracing::error!("State mismatch: event for closed connection");
                         }
@@ -600,11 +2169,26 @@ async fn background_task(
                         };
                         let connection = Connection::from_quinn_connection(endpoint_arc, connec, connec_id, rx);
 
+                        // Connections that are expected as part of a simultaneous open don't go
+                        // through the listener. `endpoint.accept()` can therefore be called
+                        // immediately.
+                        let mut connection = match expected_connections.remove(&packet_src) {
+                            Some(result) => match result.send(connection) {
+                                Ok(()) => {
+                                    endpoint.accept();
+                                    continue;
+                                }
+                                Err(connection) => connection,
+                            },
+                            None => connection,
+                        };
+
                         // As explained in the documentation, we put this new connection in an
                         // intermediary buffer. At the next loop iteration we will try to move it
                         // to the `new_connections` channel. We call `endpoint.accept()` only once
-                        // the element has successfully been sent on `new_connections`.
-                        queued_new_connections.push_back(Either::Left(connection));
+                        // its handshake is over.
+                        connection.set_pending();
+                        queued_new_connections.push_back(Incoming::Connection(connection));
                         tracing::trace!("connection queued");
                     },
                 }
@@ -614,8 +2198,416 @@ async fn background_task(
     }
 }
 
+/// Asks the connection behind `sender` to close, reporting `error_code` and `reason` to the
+/// remote.
+///
+/// The message is never dropped for lack of room: cloning the sender guarantees us a slot in
+/// the buffer, however far behind the connection is. Sending can therefore only fail if the
+/// connection is gone, in which case it reports itself as drained when dropped.
+fn send_close(sender: &mpsc::Sender<FromEndpoint>, error_code: quinn_proto::VarInt, reason: &[u8]) {
+    let message = FromEndpoint::Close {
+        error_code,
+        reason: reason.to_vec(),
+    };
+    if let Err(err) = sender.clone().try_send(message) {
+        debug_assert!(
+            err.is_disconnected(),
+            "a cloned sender always has a slot; qed"
+        );
+    }
+}
+
+/// UDP socket of an endpoint, shared between the [`Endpoint`] and its background task.
+///
+/// The background task only locks it while polling, and the `Endpoint` empties it when dropped,
+/// which closes the socket without waiting for the task to run.
+type SharedSocket = Arc<parking_lot::Mutex<Option<SocketOps>>>;
+
+/// UDP socket of an endpoint, along with the operations in progress on it. These keep their
+/// own reference to the socket, which is therefore closed as soon as this is dropped.
+struct SocketOps {
+    socket: Arc<async_std::net::UdpSocket>,
+    send: Option<BoxFuture<'static, io::Result<usize>>>,
+    recv: Option<BoxFuture<'static, (Vec<u8>, io::Result<(usize, SocketAddr)>)>>,
+}
+
+/// Sends `data` to `destination` on `socket`. Returns `None` if the socket has been closed.
+async fn send_to(
+    socket: &SharedSocket,
+    data: Box<[u8]>,
+    destination: SocketAddr,
+) -> Option<io::Result<usize>> {
+    let mut data = Some(data);
+    future::poll_fn(move |cx| {
+        let mut ops = socket.lock();
+        let ops = match ops.as_mut() {
+            Some(ops) => ops,
+            None => return Poll::Ready(None),
+        };
+        if let Some(data) = data.take() {
+            let socket = ops.socket.clone();
+            ops.send = Some(async move { socket.send_to(&data, destination).await }.boxed());
+        }
+        let send = ops.send.as_mut().expect("set by the first poll; qed");
+        let result = futures::ready!(send.poll_unpin(cx));
+        ops.send = None;
+        Poll::Ready(Some(result))
+    })
+    .await
+}
+
+/// Receives a packet in `buffer` from `socket`. Returns `None` if the socket has been closed.
+///
+/// The reception carries on if the returned future is dropped, and the packet is returned by
+/// the next call, with `buffer` left empty in the meantime.
+async fn recv_from(
+    socket: &SharedSocket,
+    buffer: &mut Vec<u8>,
+) -> Option<io::Result<(usize, SocketAddr)>> {
+    future::poll_fn(|cx| {
+        let mut ops = socket.lock();
+        let ops = match ops.as_mut() {
+            Some(ops) => ops,
+            None => return Poll::Ready(None),
+        };
+        if ops.recv.is_none() {
+            let socket = ops.socket.clone();
+            let mut buffer = mem::take(buffer);
+            ops.recv = Some(
+                async move {
+                    let result = socket.recv_from(&mut buffer).await;
+                    (buffer, result)
+                }
+                .boxed(),
+            );
+        }
+        let recv = ops.recv.as_mut().expect("set above if missing; qed");
+        let (filled, result) = futures::ready!(recv.poll_unpin(cx));
+        ops.recv = None;
+        *buffer = filled;
+        Poll::Ready(Some(result))
+    })
+    .await
+}
+
+/// Item produced by the background task and yielded by [`Endpoint::next_incoming`].
+pub(crate) enum Incoming {
+    /// A new connection has been received. Its handshake is still in progress.
+    Connection(Connection),
+    /// The endpoint is now reachable at the given address.
+    NewAddress(Multiaddr),
+    /// The endpoint is no longer reachable at the given address.
+    AddressExpired(Multiaddr),
+    /// A non-fatal error happened on the UDP socket.
+    Error(io::Error),
+    /// The background task stopped unexpectedly. This is always the last item.
+    DriverFailed,
+}
+
+/// Listen address information.
+#[derive(Debug)]
+enum Addresses {
+    /// A specific address is used to listen.
+    One(Multiaddr),
+    /// A set of addresses is used to listen.
+    Many(InterfacesWatch),
+}
+
+impl Addresses {
+    /// Returns the addresses that have been reported to the listener.
+    fn multiaddrs(&self) -> Vec<Multiaddr> {
+        match self {
+            Addresses::One(address) => vec![address.clone()],
+            Addresses::Many(watch) => watch.addrs.iter().map(|(.., ma)| ma.clone()).collect(),
+        }
+    }
+}
+
+/// Returns the addresses of the local interfaces that a socket bound to the unspecified address
+/// `socket_addr` is reachable at.
+///
+/// A socket bound to `0.0.0.0` only accepts IPv4 traffic, while a socket bound to `::` also
+/// accepts IPv4 traffic unless it is restricted to IPv6. Loopback addresses are only included
+/// if [`Config::with_loopback_addresses`] allows it.
+fn interface_addresses(
+    socket_addr: &SocketAddr,
+    config: &Config,
+) -> io::Result<Vec<(IpAddr, IpNet, Multiaddr)>> {
+    let suffixes = [Protocol::Udp(socket_addr.port()), Protocol::Quic];
+    let mut addrs = host_addresses(&suffixes)?;
+    addrs.retain(|(ip, ..)| {
+        let family = match socket_addr {
+            SocketAddr::V4(_) => ip.is_ipv4(),
+            SocketAddr::V6(_) => ip.is_ipv6() || !config.ipv6_only,
+        };
+        family && (config.loopback_addresses || !ip.is_loopback())
+    });
+    Ok(addrs)
+}
+
+/// Returns the address that `remote` reaches a socket bound to `local_socket_addr` at.
+///
+/// If `local_socket_addr` is an unspecified address, the IP address is the one the kernel picks
+/// to send packets to `remote`, which is found by connecting a throwaway UDP socket. Connecting a
+/// UDP socket only looks up the route and doesn't send anything.
+pub(crate) fn local_addr_towards(
+    local_socket_addr: SocketAddr,
+    remote: &SocketAddr,
+) -> io::Result<SocketAddr> {
+    if !local_socket_addr.ip().is_unspecified() {
+        return Ok(local_socket_addr);
+    }
+    let unspecified: IpAddr = match remote {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = std::net::UdpSocket::bind(SocketAddr::new(unspecified, 0))?;
+    socket.connect(remote)?;
+    let ip = socket.local_addr()?.ip();
+    Ok(SocketAddr::new(ip, local_socket_addr.port()))
+}
+
+/// Binds a UDP socket to `socket_addr`. If it is an IPv6 address, `ipv6_only` determines whether
+/// the socket also accepts IPv4 traffic.
+fn bind_socket(socket_addr: &SocketAddr, ipv6_only: bool) -> io::Result<std::net::UdpSocket> {
+    let socket = if socket_addr.is_ipv4() {
+        Socket::new(
+            Domain::ipv4(),
+            Type::dgram(),
+            Some(socket2::Protocol::udp()),
+        )?
+    } else {
+        let socket = Socket::new(
+            Domain::ipv6(),
+            Type::dgram(),
+            Some(socket2::Protocol::udp()),
+        )?;
+        socket.set_only_v6(ipv6_only)?;
+        socket
+    };
+    socket.bind(&(*socket_addr).into())?;
+    Ok(socket.into_udp_socket())
+}
+
+/// Returns `true` if binding `requested` could have yielded a socket bound to `bound`, in other
+/// words if they are equal once port 0 and the unspecified IP address in `requested` have been
+/// resolved.
+fn is_bound_to(requested: SocketAddr, bound: SocketAddr) -> bool {
+    let ip_matches = requested.ip() == bound.ip()
+        || (requested.ip().is_unspecified() && requested.is_ipv4() == bound.is_ipv4());
+    let port_matches = requested.port() == 0 || requested.port() == bound.port();
+    ip_matches && port_matches
+}
+
+/// Tracks the addresses of the local interfaces that an endpoint listening on all interfaces
+/// is reachable at.
+///
+/// A change is only reported once two consecutive checks have found the same set of addresses,
+/// so that interfaces going up and down in quick succession don't produce a flurry of events.
+#[derive(Debug)]
+struct InterfacesWatch {
+    /// Addresses reported to the listener.
+    addrs: Vec<(IpAddr, IpNet, Multiaddr)>,
+    /// Addresses found by the last check, if they differ from `addrs`.
+    unconfirmed: Option<Vec<(IpAddr, IpNet, Multiaddr)>>,
+}
+
+impl InterfacesWatch {
+    fn new(addrs: Vec<(IpAddr, IpNet, Multiaddr)>) -> Self {
+        InterfacesWatch {
+            addrs,
+            unconfirmed: None,
+        }
+    }
+
+    /// Processes the result of a new enumeration of the local interfaces, and pushes the
+    /// new and expired listen addresses to `pending` if the change is confirmed.
+    fn update(
+        &mut self,
+        current: Vec<(IpAddr, IpNet, Multiaddr)>,
+        pending: &mut VecDeque<Incoming>,
+    ) {
+        if same_ips(&current, &self.addrs) {
+            self.unconfirmed = None;
+            return;
+        }
+        match self.unconfirmed.take() {
+            Some(previous) if same_ips(&previous, &current) => {}
+            _ => {
+                tracing::debug!("Network interfaces changed, waiting for confirmation");
+                self.unconfirmed = Some(current);
+                return;
+            }
+        }
+
+        let old_listen_addrs = mem::replace(&mut self.addrs, current);
+
+        // Check for addresses no longer in use.
+        for (ip, _, ma) in old_listen_addrs.iter() {
+            if self.addrs.iter().find(|(i, ..)| i == ip).is_none() {
+                tracing::debug!("Expired listen address: {}", ma);
+                pending.push_back(Incoming::AddressExpired(ma.clone()));
+            }
+        }
+
+        // Check for new addresses.
+        for (ip, _, ma) in self.addrs.iter() {
+            if old_listen_addrs.iter().find(|(i, ..)| i == ip).is_none() {
+                tracing::debug!("New listen address: {}", ma);
+                pending.push_back(Incoming::NewAddress(ma.clone()));
+            }
+        }
+    }
+}
+
+/// Returns `true` if `a` and `b` contain the same IP addresses.
+fn same_ips(a: &[(IpAddr, IpNet, Multiaddr)], b: &[(IpAddr, IpNet, Multiaddr)]) -> bool {
+    a.len() == b.len() && a.iter().all(|(ip, ..)| b.iter().any(|(i, ..)| i == ip))
+}
+
 impl fmt::Debug for Endpoint {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_tuple("Endpoint").finish()
     }
 }
+
+impl Drop for Endpoint {
+    fn drop(&mut self) {
+        // Closes the socket, along with the operations in progress on it, without waiting for
+        // the background task to run.
+        self.socket.lock().take();
+        // Senders of the channel can outlive the endpoint, for example in a pending message of a
+        // connection. Shutting down explicitly guarantees that the background task stops as soon
+        // as it next runs, like `close_now` does. Cloning the sender guarantees us a slot in the
+        // buffer.
+        let _ = self.to_endpoint2.clone().try_send(ToEndpoint::Shutdown);
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn local_addr_towards_resolves_wildcards() {
+    let bound: SocketAddr = "127.0.0.1:4001".parse().unwrap();
+    let remote: SocketAddr = "127.0.0.1:5001".parse().unwrap();
+    assert_eq!(local_addr_towards(bound, &remote).unwrap(), bound);
+
+    let wildcard: SocketAddr = "0.0.0.0:4001".parse().unwrap();
+    assert_eq!(local_addr_towards(wildcard, &remote).unwrap(), bound);
+}
+
+#[cfg(test)]
+#[test]
+fn interface_changes_are_debounced() {
+    let addr = |ip: &str| {
+        let ip: IpAddr = ip.parse().unwrap();
+        let multiaddr = Multiaddr::from(ip)
+            .with(Protocol::Udp(1234))
+            .with(Protocol::Quic);
+        (ip, IpNet::from(ip), multiaddr)
+    };
+    let events = |pending: &mut VecDeque<Incoming>| {
+        pending
+            .drain(..)
+            .map(|event| match event {
+                Incoming::NewAddress(a) => format!("+{}", a),
+                Incoming::AddressExpired(a) => format!("-{}", a),
+                _ => unreachable!(),
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let mut pending = VecDeque::new();
+    let mut watch = InterfacesWatch::new(vec![addr("127.0.0.1"), addr("10.0.0.1")]);
+
+    // Unchanged interfaces.
+    watch.update(vec![addr("10.0.0.1"), addr("127.0.0.1")], &mut pending);
+    assert!(pending.is_empty());
+
+    // An interface that goes down and comes back up before the next check isn't reported.
+    watch.update(vec![addr("127.0.0.1")], &mut pending);
+    watch.update(vec![addr("127.0.0.1"), addr("10.0.0.1")], &mut pending);
+    watch.update(vec![addr("127.0.0.1"), addr("10.0.0.1")], &mut pending);
+    assert!(pending.is_empty());
+
+    // Changes are reported once confirmed.
+    watch.update(vec![addr("127.0.0.1"), addr("10.0.0.2")], &mut pending);
+    assert!(pending.is_empty());
+    watch.update(vec![addr("127.0.0.1"), addr("10.0.0.2")], &mut pending);
+    assert_eq!(
+        events(&mut pending),
+        vec![
+            "-/ip4/10.0.0.1/udp/1234/quic".to_owned(),
+            "+/ip4/10.0.0.2/udp/1234/quic".to_owned(),
+        ]
+    );
+
+    // Successive different changes restart the confirmation.
+    watch.update(vec![addr("127.0.0.1")], &mut pending);
+    watch.update(vec![addr("127.0.0.1"), addr("10.0.0.3")], &mut pending);
+    assert!(pending.is_empty());
+    watch.update(vec![addr("127.0.0.1"), addr("10.0.0.3")], &mut pending);
+    assert_eq!(
+        events(&mut pending),
+        vec![
+            "-/ip4/10.0.0.2/udp/1234/quic".to_owned(),
+            "+/ip4/10.0.0.3/udp/1234/quic".to_owned(),
+        ]
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn dial_retry_delays() {
+    let retry = |max_attempts| DialRetry {
+        max_attempts,
+        initial_delay: Duration::from_millis(100),
+        multiplier: 3,
+    };
+    let delays = |max_attempts| retry(max_attempts).delays().collect::<Vec<_>>();
+    assert!(delays(0).is_empty());
+    assert!(delays(1).is_empty());
+    assert_eq!(
+        delays(4),
+        vec![
+            Duration::from_millis(100),
+            Duration::from_millis(300),
+            Duration::from_millis(900),
+        ]
+    );
+
+    // The delay stops growing once multiplying it would overflow.
+    let retry = DialRetry {
+        max_attempts: 3,
+        initial_delay: Duration::from_secs(u64::MAX / 2),
+        multiplier: 4,
+    };
+    let delays = retry.delays().collect::<Vec<_>>();
+    assert_eq!(delays, vec![Duration::from_secs(u64::MAX / 2); 2]);
+}
+
+#[cfg(test)]
+#[test]
+fn close_is_sent_to_lagging_connections() {
+    let (mut sender, receiver) = mpsc::channel(1);
+    let event = || FromEndpoint::VersionNegotiation {
+        peer_supported: Vec::new(),
+    };
+    while sender.try_send(event()).is_ok() {}
+
+    let error_code = quinn_proto::VarInt::from_u32(7);
+    send_close(&sender, error_code, b"bye");
+    send_close(&sender, error_code, b"bye");
+    drop(sender);
+    let messages = futures::executor::block_on(receiver.collect::<Vec<_>>());
+    let closes = messages
+        .iter()
+        .filter(|message| match message {
+            FromEndpoint::Close {
+                error_code: code,
+                reason,
+            } => *code == error_code && reason == b"bye",
+            _ => false,
+        })
+        .count();
+    assert_eq!(closes, 2);
+}
@@ -18,6 +18,8 @@
 // FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
 // DEALINGS IN THE SOFTWARE.
 
+//! Errors that can be returned by libp2p-quic.
+
 use futures::channel::mpsc::SendError;
 use io::ErrorKind;
 use std::io;
@@ -53,21 +55,72 @@ pub enum Error {
     /// Connection already being closed
     #[error("Connection already being closed")]
     ConnectionClosing,
+    /// Application error code doesn't fit in a QUIC variable-length integer
+    #[error("Application error code {0} is too large")]
+    ErrorCodeTooLarge(u64),
+    /// Reason for closing a connection is longer than
+    /// [`MAX_CLOSE_REASON_LEN`](crate::muxer::MAX_CLOSE_REASON_LEN) bytes
+    #[error("Close reason of {0} bytes is too long")]
+    CloseReasonTooLong(usize),
+    /// Sending a datagram failed
+    #[error("Error sending datagram: {0}")]
+    SendDatagram(#[from] quinn_proto::SendDatagramError),
+    /// The remote doesn't allow opening more substreams at the moment
+    #[error("No more substreams can be opened at the moment")]
+    StreamsExhausted,
+    /// The background task driving the connection stopped unexpectedly
+    #[error("Background task of the connection stopped unexpectedly")]
+    DriverFailed,
+    /// Writing to a substream that has been closed for writing
+    #[error("Substream has been closed for writing")]
+    WriteAfterClose,
 }
 
+/// The [`Error`] is kept as the source of the [`io::Error`], which preserves details such as
+/// the application error codes.
+///
+/// The kinds are chosen so that protocols can decide whether to retry:
+///
+/// - [`ErrorKind::ConnectionReset`] if the remote closed the connection or reset the substream.
+/// - [`ErrorKind::ConnectionAborted`] if the connection was closed locally or lost.
+/// - [`ErrorKind::TimedOut`] if the connection timed out for lack of activity.
+/// - [`ErrorKind::BrokenPipe`] if writing isn't possible because the remote stopped reading or
+///   the substream has been closed for writing.
 impl From<Error> for io::Error {
     fn from(e: Error) -> Self {
         match e {
             Error::IO(e) => io::Error::new(e.kind(), Error::IO(e)),
-            Error::ConnectionError(e) => e.into(),
-            e @ Error::ConnectionClosing | e @ Error::ConnectError(_) => {
+            Error::ConnectionError(e) => {
+                use quinn_proto::ConnectionError::*;
+                let kind = match e {
+                    TimedOut => ErrorKind::TimedOut,
+                    Reset | ConnectionClosed(_) | ApplicationClosed(_) => {
+                        ErrorKind::ConnectionReset
+                    }
+                    LocallyClosed => ErrorKind::ConnectionAborted,
+                    VersionMismatch | TransportError(_) => ErrorKind::InvalidData,
+                };
+                io::Error::new(kind, Error::ConnectionError(e))
+            }
+            e @ Error::Reset(_) => io::Error::new(ErrorKind::ConnectionReset, e),
+            e @ Error::ConnectionClosing | e @ Error::ConnectError(_) | e @ Error::DriverFailed => {
                 io::Error::new(ErrorKind::Other, e)
             }
-            e @ Error::Stopped(_) | e @ Error::Reset(_) | e @ Error::ConnectionLost => {
-                io::Error::new(ErrorKind::ConnectionAborted, e)
+            e @ Error::ConnectionLost => io::Error::new(ErrorKind::ConnectionAborted, e),
+            e @ Error::Stopped(_) | e @ Error::Finish(_) | e @ Error::WriteAfterClose => {
+                io::Error::new(ErrorKind::BrokenPipe, e)
             }
-            e @ Error::Finish(_) => io::Error::new(ErrorKind::BrokenPipe, e),
             e @ Error::AlreadyListening => io::Error::new(ErrorKind::AddrInUse, e),
+            e @ Error::ErrorCodeTooLarge(_) | e @ Error::CloseReasonTooLong(_) => {
+                io::Error::new(ErrorKind::InvalidInput, e)
+            }
+            Error::SendDatagram(quinn_proto::SendDatagramError::TooLarge) => io::Error::new(
+                ErrorKind::InvalidInput,
+                Error::SendDatagram(quinn_proto::SendDatagramError::TooLarge),
+            ),
+            e @ Error::SendDatagram(_) | e @ Error::StreamsExhausted => {
+                io::Error::new(ErrorKind::Other, e)
+            }
         }
     }
 }
@@ -27,17 +27,28 @@
 //! Example:
 //!
 //! ```
-//! use libp2p_quic::{Config, Endpoint};
-//! use libp2p_core::Multiaddr;
+//! use libp2p_core::{Multiaddr, Transport};
+//! use libp2p_quic::{Config, Endpoint, QuicTransport};
 //!
 //! let keypair = libp2p_core::identity::Keypair::generate_ed25519();
-//! let addr = "/ip4/127.0.0.1/udp/12345/quic".parse().expect("bad address?");
-//! let quic_config = Config::new(&keypair, addr).expect("could not make config");
+//! let addr: Multiaddr = "/ip4/127.0.0.1/udp/0/quic".parse().expect("bad address?");
+//! let quic_config = Config::new(&keypair, addr.clone()).expect("could not make config");
 //! let quic_endpoint = Endpoint::new(quic_config).expect("I/O error");
+//! let listener = QuicTransport(quic_endpoint)
+//!     .listen_on(addr)
+//!     .expect("could not listen");
 //! ```
 //!
-//! The `Endpoint` struct implements the `Transport` trait of the `core` library. See the
-//! documentation of `core` and of libp2p in general to learn how to use the `Transport` trait.
+//! The [`QuicTransport`] struct wraps an `Endpoint` and implements the `Transport` trait of the
+//! `core` library. Its output is the [`PeerId`](libp2p_core::PeerId) of the remote together with
+//! the [`QuicMuxer`] of the connection. See the documentation of `core` and of libp2p in general
+//! to learn how to use the `Transport` trait.
+//!
+//! The entry points of the crate are [`Config`], [`Endpoint`], [`QuicTransport`] and
+//! [`QuicMuxer`], which are all most applications need. The rest of the public API serves more
+//! specialised uses: the modules listed below, and the TLS helpers re-exported at the root of the
+//! crate, such as [`make_tls_config`] and [`verify_libp2p_certificate`], for applications that
+//! build or check libp2p certificates themselves.
 //!
 //! Note that QUIC provides transport, security, and multiplexing in a single protocol.  Therefore,
 //! QUIC connections do not need to be upgraded. You will get a compile-time error if you try.
@@ -49,7 +60,29 @@
 //! should generally have one of these per process.
 //!
 //! `Endpoint` manages a background task that processes all incoming packets.  Each
-//! `QuicConnection` also manages a background task, which handles socket output and timer polling.
+//! `QuicMuxer` also manages a background task, which handles socket output and timer polling.
+//!
+//! These tasks don't depend on a specific runtime. By default, they are spawned on the current
+//! `tokio` runtime if the `tokio` feature is enabled and there is one, and on the global
+//! `async-std` executor otherwise. Applications can pick the executor with
+//! [`Config::with_executor`], or spawn the background task of the endpoint themselves with
+//! [`Endpoint::new_with_driver`].
+//!
+//! The crate is organized as follows:
+//!
+//! - [`endpoint`] contains the [`Endpoint`] and its [`Config`].
+//! - [`transport`] implements the `Transport` trait on top of an [`Endpoint`].
+//! - [`addr`] converts between QUIC multiaddresses and UDP socket addresses.
+//! - [`muxer`] contains the [`QuicMuxer`] that every successful connection yields.
+//! - [`metrics`] contains the hooks reporting the activity of the above to a metrics system.
+//! - [`error`] contains the errors that can be returned by the above.
+//!
+//! # Features
+//!
+//! - `tokio`: spawns the background tasks on the current `tokio` runtime by default, when there
+//!   is one, as described above.
+//! - `metrics`: provides `PrometheusMetrics`, which exports the metrics of the transport to
+//!   Prometheus.
 
 #![deny(unsafe_code)]
 
@@ -61,16 +94,27 @@ macro_rules! span {
 }
 
 mod connection;
-mod endpoint;
-mod error;
-mod muxer;
 mod upgrade;
 mod x509;
 
+pub mod addr;
+pub mod endpoint;
+pub mod error;
+pub mod metrics;
+pub mod muxer;
 pub mod transport;
 
-pub use endpoint::{Config, Endpoint};
+pub use endpoint::{Config, DialRetry, Endpoint, EndpointDriver};
 pub use error::Error;
-pub use muxer::QuicMuxer;
+#[cfg(feature = "metrics")]
+pub use metrics::PrometheusMetrics;
+pub use metrics::{Direction, TransportMetrics};
+pub use muxer::{ConnectionStats, ErrorEvent, HandshakeInfo, QuicMuxer, Substream};
 pub use transport::QuicTransport;
 pub use upgrade::Upgrade;
+pub use x509::{
+    make_certificate_from_der, make_tls_config, make_tls_config_split, make_tls_config_with_alpn,
+    subject_public_key_info, verify_libp2p_certificate, CertificateParams, ChainMode, ConfigError,
+    KeyType, PeerPolicy, TimeMode, VerifiedCertificate, VerifierConfig,
+    DEFAULT_MAX_CERTIFICATE_SIZE,
+};
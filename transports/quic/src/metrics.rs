@@ -0,0 +1,339 @@
+// Copyright 2020 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Hooks reporting the activity of the transport to a metrics system.
+//!
+//! The endpoint calls the [`TransportMetrics`] set with
+//! [`Config::with_metrics`](crate::Config::with_metrics) as connections and substreams come and
+//! go. The trait isn't tied to any metrics library: all its methods do nothing by default, and
+//! implementations only override the ones they are interested in. With the `metrics` feature,
+//! [`PrometheusMetrics`] records everything in a `prometheus` registry.
+
+use crate::transport::Error;
+use libp2p_core::{Multiaddr, PeerId};
+use std::{fmt, sync::Arc, time::Duration};
+
+/// Which side initiated a connection.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Direction {
+    /// The remote dialed us.
+    Inbound,
+    /// We dialed the remote.
+    Outbound,
+}
+
+impl Direction {
+    /// Returns the direction of a connection on the given side.
+    pub(crate) fn from_side(side: quinn_proto::Side) -> Self {
+        match side {
+            quinn_proto::Side::Client => Direction::Outbound,
+            quinn_proto::Side::Server => Direction::Inbound,
+        }
+    }
+}
+
+/// Receives the events of an endpoint that are worth counting.
+///
+/// The methods are called synchronously, from the tasks driving the connections, and should
+/// therefore return quickly. The same object is shared by all the connections of an endpoint,
+/// including the endpoints of additional listeners.
+pub trait TransportMetrics: Send + Sync {
+    /// A connection has completed its handshake.
+    fn connection_opened(&self, _direction: Direction) {}
+
+    /// A connection reported by [`TransportMetrics::connection_opened`] has been dropped.
+    fn connection_closed(&self, _direction: Direction) {}
+
+    /// The remote of a connection has been identified as `peer`, once the handshake has
+    /// completed. `remote_addr` is the `/quic` address of the remote.
+    fn connection_established(
+        &self,
+        _peer: &PeerId,
+        _remote_addr: &Multiaddr,
+        _direction: Direction,
+    ) {
+    }
+
+    /// A connection reported by [`TransportMetrics::connection_established`] has been dropped.
+    /// `reason` is the error that closed the connection, or `None` if it was still open.
+    fn connection_terminated(&self, _peer: &PeerId, _reason: Option<&Error>) {}
+
+    /// The handshake of a connection has failed with `error`, or has timed out.
+    fn handshake_failed(&self, _direction: Direction, _error: &Error) {}
+
+    /// A substream has been opened, by either side.
+    fn substream_opened(&self) {}
+
+    /// A substream reported by [`TransportMetrics::substream_opened`] has been destroyed, or its
+    /// connection has been dropped.
+    fn substream_closed(&self) {}
+
+    /// Substream data has been written to a connection.
+    fn bytes_sent(&self, _bytes: u64) {}
+
+    /// Substream data has been read from a connection.
+    fn bytes_received(&self, _bytes: u64) {}
+
+    /// Dialing has succeeded after `latency`, which includes the handshake.
+    fn dial_succeeded(&self, _latency: Duration) {}
+}
+
+/// The [`TransportMetrics`] of an endpoint, if any.
+#[derive(Clone, Default)]
+pub(crate) struct Metrics(Option<Arc<dyn TransportMetrics>>);
+
+impl Metrics {
+    pub(crate) fn new(metrics: Arc<dyn TransportMetrics>) -> Self {
+        Metrics(Some(metrics))
+    }
+}
+
+impl TransportMetrics for Metrics {
+    fn connection_opened(&self, direction: Direction) {
+        if let Some(metrics) = &self.0 {
+            metrics.connection_opened(direction)
+        }
+    }
+
+    fn connection_closed(&self, direction: Direction) {
+        if let Some(metrics) = &self.0 {
+            metrics.connection_closed(direction)
+        }
+    }
+
+    fn connection_established(&self, peer: &PeerId, remote_addr: &Multiaddr, direction: Direction) {
+        if let Some(metrics) = &self.0 {
+            metrics.connection_established(peer, remote_addr, direction)
+        }
+    }
+
+    fn connection_terminated(&self, peer: &PeerId, reason: Option<&Error>) {
+        if let Some(metrics) = &self.0 {
+            metrics.connection_terminated(peer, reason)
+        }
+    }
+
+    fn handshake_failed(&self, direction: Direction, error: &Error) {
+        if let Some(metrics) = &self.0 {
+            metrics.handshake_failed(direction, error)
+        }
+    }
+
+    fn substream_opened(&self) {
+        if let Some(metrics) = &self.0 {
+            metrics.substream_opened()
+        }
+    }
+
+    fn substream_closed(&self) {
+        if let Some(metrics) = &self.0 {
+            metrics.substream_closed()
+        }
+    }
+
+    fn bytes_sent(&self, bytes: u64) {
+        if let Some(metrics) = &self.0 {
+            metrics.bytes_sent(bytes)
+        }
+    }
+
+    fn bytes_received(&self, bytes: u64) {
+        if let Some(metrics) = &self.0 {
+            metrics.bytes_received(bytes)
+        }
+    }
+
+    fn dial_succeeded(&self, latency: Duration) {
+        if let Some(metrics) = &self.0 {
+            metrics.dial_succeeded(latency)
+        }
+    }
+}
+
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = if self.0.is_some() { "custom" } else { "none" };
+        f.debug_tuple("Metrics").field(&name).finish()
+    }
+}
+
+#[cfg(feature = "metrics")]
+pub use self::prometheus_metrics::PrometheusMetrics;
+
+#[cfg(feature = "metrics")]
+mod prometheus_metrics {
+    use super::{Direction, Error, TransportMetrics};
+    use prometheus::{
+        Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    };
+    use std::time::Duration;
+
+    /// [`TransportMetrics`] recorded in a `prometheus` registry.
+    ///
+    /// The metrics are prefixed with `libp2p_quic_`. Connections are labelled with their
+    /// `direction`, `inbound` or `outbound`, and handshake failures with their `cause` as well.
+    #[derive(Debug, Clone)]
+    pub struct PrometheusMetrics {
+        connections_opened: IntCounterVec,
+        connections_closed: IntCounterVec,
+        handshake_failures: IntCounterVec,
+        open_substreams: IntGauge,
+        bytes_sent: IntCounter,
+        bytes_received: IntCounter,
+        dial_latency: Histogram,
+    }
+
+    impl PrometheusMetrics {
+        /// Creates the metrics and registers them in `registry`.
+        ///
+        /// Fails if metrics with the same names are already registered.
+        pub fn new(registry: &Registry) -> Result<Self, prometheus::Error> {
+            let metrics = PrometheusMetrics {
+                connections_opened: IntCounterVec::new(
+                    Opts::new(
+                        "libp2p_quic_connections_opened_total",
+                        "Number of connections whose handshake has completed",
+                    ),
+                    &["direction"],
+                )?,
+                connections_closed: IntCounterVec::new(
+                    Opts::new(
+                        "libp2p_quic_connections_closed_total",
+                        "Number of connections that have been closed after their handshake",
+                    ),
+                    &["direction"],
+                )?,
+                handshake_failures: IntCounterVec::new(
+                    Opts::new(
+                        "libp2p_quic_handshake_failures_total",
+                        "Number of handshakes that have failed",
+                    ),
+                    &["direction", "cause"],
+                )?,
+                open_substreams: IntGauge::new(
+                    "libp2p_quic_open_substreams",
+                    "Number of substreams currently open",
+                )?,
+                bytes_sent: IntCounter::new(
+                    "libp2p_quic_sent_bytes_total",
+                    "Number of bytes of substream data sent",
+                )?,
+                bytes_received: IntCounter::new(
+                    "libp2p_quic_received_bytes_total",
+                    "Number of bytes of substream data received",
+                )?,
+                dial_latency: Histogram::with_opts(HistogramOpts::new(
+                    "libp2p_quic_dial_latency_seconds",
+                    "Time taken by successful dials, handshake included",
+                ))?,
+            };
+            registry.register(Box::new(metrics.connections_opened.clone()))?;
+            registry.register(Box::new(metrics.connections_closed.clone()))?;
+            registry.register(Box::new(metrics.handshake_failures.clone()))?;
+            registry.register(Box::new(metrics.open_substreams.clone()))?;
+            registry.register(Box::new(metrics.bytes_sent.clone()))?;
+            registry.register(Box::new(metrics.bytes_received.clone()))?;
+            registry.register(Box::new(metrics.dial_latency.clone()))?;
+            Ok(metrics)
+        }
+    }
+
+    fn direction_label(direction: Direction) -> &'static str {
+        match direction {
+            Direction::Inbound => "inbound",
+            Direction::Outbound => "outbound",
+        }
+    }
+
+    fn cause_label(error: &Error) -> &'static str {
+        match error {
+            Error::Reach(_) => "unreachable",
+            Error::TimedOut => "timed_out",
+            Error::HandshakeTimeout => "handshake_timeout",
+            Error::VersionNegotiationFailed { .. } => "version_negotiation_failed",
+            Error::TransportError { .. } => "protocol_violation",
+            Error::PeerIdMismatch { .. } => "peer_id_mismatch",
+            Error::MissingCertificate
+            | Error::InvalidCertificate(_)
+            | Error::CertificateRejected(_) => "certificate",
+            Error::DialToSelf => "dial_to_self",
+            _ => "other",
+        }
+    }
+
+    impl TransportMetrics for PrometheusMetrics {
+        fn connection_opened(&self, direction: Direction) {
+            self.connections_opened
+                .with_label_values(&[direction_label(direction)])
+                .inc()
+        }
+
+        fn connection_closed(&self, direction: Direction) {
+            self.connections_closed
+                .with_label_values(&[direction_label(direction)])
+                .inc()
+        }
+
+        fn handshake_failed(&self, direction: Direction, error: &Error) {
+            self.handshake_failures
+                .with_label_values(&[direction_label(direction), cause_label(error)])
+                .inc()
+        }
+
+        fn substream_opened(&self) {
+            self.open_substreams.inc()
+        }
+
+        fn substream_closed(&self) {
+            self.open_substreams.dec()
+        }
+
+        fn bytes_sent(&self, bytes: u64) {
+            self.bytes_sent.inc_by(bytes)
+        }
+
+        fn bytes_received(&self, bytes: u64) {
+            self.bytes_received.inc_by(bytes)
+        }
+
+        fn dial_succeeded(&self, latency: Duration) {
+            self.dial_latency.observe(latency.as_secs_f64())
+        }
+    }
+
+    #[cfg(test)]
+    #[test]
+    fn metrics_are_registered() {
+        let registry = Registry::new();
+        let metrics = PrometheusMetrics::new(&registry).unwrap();
+        metrics.connection_opened(Direction::Inbound);
+        metrics.handshake_failed(Direction::Outbound, &Error::HandshakeTimeout);
+        metrics.bytes_sent(42);
+        metrics.dial_succeeded(Duration::from_millis(3));
+
+        let families = registry.gather();
+        let sent = families
+            .iter()
+            .find(|family| family.get_name() == "libp2p_quic_sent_bytes_total")
+            .unwrap();
+        assert_eq!(sent.get_metric()[0].get_counter().get_value(), 42.0);
+        assert!(PrometheusMetrics::new(&registry).is_err());
+    }
+}
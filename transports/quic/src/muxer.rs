@@ -18,36 +18,157 @@
 // FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
 // DEALINGS IN THE SOFTWARE.
 
-use crate::connection::{Connection, ConnectionEvent};
+//! Implementation of the [`StreamMuxer`] trait on top of a QUIC connection.
+//!
+//! Every substream of a [`QuicMuxer`] is a bidirectional QUIC stream.
+//!
+//! Substreams can be half-closed: once [`StreamMuxer::shutdown_substream`] has been called,
+//! writing fails with [`Error::WriteAfterClose`], while reading continues to work until the
+//! remote closes its own side.
+
+use crate::connection::{self, Connection, ConnectionEvent};
+use crate::endpoint::{self, Spawner};
 use crate::error::Error;
+use crate::metrics::{Metrics, TransportMetrics};
 
-use libp2p_core::StreamMuxer;
+use bytes::Bytes;
+use futures::{channel::oneshot, prelude::*};
+use libp2p_core::{PeerId, StreamMuxer};
 use parking_lot::Mutex;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     fmt,
+    io::{IoSlice, IoSliceMut},
+    mem,
+    net::SocketAddr,
+    sync::Arc,
     task::{Context, Poll, Waker},
 };
 
+pub use crate::connection::{ConnectionStats, HandshakeInfo};
+
+/// A substream of a [`QuicMuxer`], identified by its QUIC stream ID.
+pub type Substream = quinn_proto::StreamId;
+
+/// Maximum length, in bytes, of the reason passed to [`QuicMuxer::close_with`].
+///
+/// The frame that closes a connection has to fit in a single packet, and the QUIC
+/// implementation would otherwise silently truncate longer reasons.
+pub const MAX_CLOSE_REASON_LEN: usize = 1024;
+
+/// Maximum number of entries returned by [`QuicMuxer::recent_errors`].
+const ERROR_HISTORY_LEN: usize = 32;
+
+/// Number of inbound substreams that [`StreamMuxer::poll_inbound`] returns in a row before
+/// yielding, so that a remote opening substreams in a tight loop doesn't starve the other tasks
+/// using the connection.
+const INBOUND_SUBSTREAMS_BEFORE_YIELD: usize = 16;
+
+/// Recoverable error that happened on a connection. See [`QuicMuxer::recent_errors`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorEvent {
+    /// The remote has reset a substream.
+    StreamReset {
+        substream: quinn_proto::StreamId,
+        error_code: quinn_proto::VarInt,
+    },
+    /// The remote has stopped receiving data on a substream.
+    StreamStopped {
+        substream: quinn_proto::StreamId,
+        error_code: quinn_proto::VarInt,
+    },
+    /// Writing on a substream had to wait because of flow control.
+    FlowControlBlocked { substream: quinn_proto::StreamId },
+    /// The remote has attempted to migrate the connection to `from` while migration is
+    /// disabled, and the connection has been closed. See
+    /// [`Config::with_migration`](crate::Config::with_migration).
+    MigrationRefused { from: SocketAddr },
+}
+
 /// State for a single opened QUIC connection.
-// TODO: the inner `Mutex` should theoretically be a `futures::lock::Mutex` (or something similar),
-// in order to sleep the current task if concurrent access to the connection is required
+///
+/// The connection is driven by a background task spawned when the [`QuicMuxer`] is created.
+/// This task only holds the lock of the connection for the duration of polling a single event,
+/// which means that the substream operations of the [`StreamMuxer`] implementation never wait
+/// for more than that.
+// TODO: the inner `Mutex`es should theoretically be `futures::lock::Mutex`es (or something
+// similar), in order to sleep the current task if concurrent access to the connection is required
 pub struct QuicMuxer {
-    inner: Mutex<QuicMuxerInner>,
+    inner: Arc<QuicMuxerInner>,
+    /// Resolves once the background task driving the connection has stopped. See
+    /// [`QuicMuxer::join_driver`].
+    driver: future::Shared<oneshot::Receiver<bool>>,
+}
+
+/// Options of a [`QuicMuxer`], derived from the [`Config`](crate::Config) of the endpoint.
+#[derive(Debug, Clone)]
+pub(crate) struct MuxerConfig {
+    /// If `false`, [`StreamMuxer::poll_outbound`] fails instead of waiting when the remote
+    /// doesn't allow opening more substreams.
+    pub(crate) queue_outbound: bool,
+    /// Runs the background task of the connection.
+    pub(crate) spawner: Spawner,
+    /// Error code sent to the remote for the substreams that are destroyed before being
+    /// closed.
+    pub(crate) cancel_error_code: quinn_proto::VarInt,
+    /// Receives the events worth counting.
+    pub(crate) metrics: Metrics,
 }
 
-/// Mutex-protected fields of [`QuicMuxer`].
+/// Fields shared between a [`QuicMuxer`] and its background task.
+///
+/// The state is split in two so that registering wakers and waking up tasks doesn't require
+/// access to the connection. If both locks need to be held at the same time, `connection` must
+/// always be locked first.
 struct QuicMuxerInner {
+    /// Options of the muxer.
+    config: MuxerConfig,
+    /// For a muxer built before the end of the handshake in order to send 0-RTT data, the
+    /// remote that is expected. The connection is closed if the certificate of the remote turns
+    /// out to belong to another peer.
+    early_peer_id: Option<PeerId>,
+    /// Connection and everything that has to be kept in sync with it.
+    connection: Mutex<ConnectionState>,
+    /// Bookkeeping of the muxer.
+    state: Mutex<MuxerState>,
+    /// Span of the connection. Can be entered without locking the connection.
+    span: tracing::Span,
+}
+
+/// Fields protected by [`QuicMuxerInner::connection`].
+struct ConnectionState {
     /// Inner connection object that yields events.
     connection: Connection,
+    /// Waker of the background task driving the connection. Must be woken up after any
+    /// operation that might require the connection to send out packets.
+    driver_waker: Option<Waker>,
+    /// `true` if the [`QuicMuxer`] has been destroyed, in which case the connection has been
+    /// closed and the driver only keeps running until it is drained.
+    muxer_dropped: bool,
+}
+
+/// Fields protected by [`QuicMuxerInner::state`].
+struct MuxerState {
+    /// Waker to wake if a new inbound substream is available.
+    poll_inbound_waker: Option<Waker>,
+    /// Number of inbound substreams that can still be returned before
+    /// [`StreamMuxer::poll_inbound`] yields. See [`INBOUND_SUBSTREAMS_BEFORE_YIELD`].
+    inbound_budget: usize,
     /// State of all the substreams that the muxer reports as open.
     substreams: HashMap<quinn_proto::StreamId, SubstreamState>,
     /// Waker to wake if a new outgoing substream is opened.
     poll_substream_opened_waker: Option<Waker>,
+    /// `true` if the last attempt at opening an outbound substream failed because the limit
+    /// set by the remote was reached.
+    outbound_blocked: bool,
     /// Waker to wake if the connection is closed.
     poll_close_waker: Option<Waker>,
+    /// Waker to wake if a datagram is received.
+    poll_datagram_waker: Option<Waker>,
     /// Count of active (writable) substreams.
     writable_substreams: usize,
+    /// The last [`ERROR_HISTORY_LEN`] recoverable errors, oldest first.
+    error_history: VecDeque<ErrorEvent>,
 }
 
 /// State of a single substream.
@@ -59,10 +180,18 @@ struct SubstreamState {
     write_waker: Option<Waker>,
     /// Waker to wake if the substream becomes closed.
     finished_waker: Option<Waker>,
-    /// `true` if and only if the substream has been closed for reading.
+    /// `true` if and only if the remote has finished the substream and all of its data has
+    /// been read.
     read_closed: bool,
+    /// Error code of the reset of the substream by the remote, if it happened.
+    read_reset: Option<quinn_proto::VarInt>,
     /// `true` if and only if the substream has been closed for writing.
     write_closed: bool,
+    /// `true` if closing the substream for writing has been requested. Writing is no longer
+    /// allowed, even if the remote hasn't acknowledged the closure yet.
+    write_shutdown: bool,
+    /// `true` if the last write attempt was blocked by flow control.
+    write_blocked: bool,
 }
 
 impl QuicMuxer {
@@ -73,94 +202,527 @@ impl QuicMuxer {
     /// Panics if `connection.is_handshaking()` returns `true`.
     pub(crate) fn from_connection(connection: Connection) -> Self {
         assert!(!connection.is_handshaking());
+        Self::new(connection, None)
+    }
+
+    /// Crate-internal function that builds a [`QuicMuxer`] from an outgoing connection whose
+    /// handshake is still in progress, so that 0-RTT data can be sent to `expected`.
+    ///
+    /// # Panic
+    ///
+    /// Panics if `connection.has_0rtt()` returns `false`.
+    pub(crate) fn from_0rtt_connection(connection: Connection, expected: PeerId) -> Self {
+        assert!(connection.has_0rtt());
+        Self::new(connection, Some(expected))
+    }
 
-        QuicMuxer {
-            inner: Mutex::new(QuicMuxerInner {
+    fn new(connection: Connection, early_peer_id: Option<PeerId>) -> Self {
+        let inner = Arc::new(QuicMuxerInner {
+            config: connection.muxer_config().clone(),
+            early_peer_id,
+            span: connection.span().clone(),
+            connection: Mutex::new(ConnectionState {
                 connection,
+                driver_waker: None,
+                muxer_dropped: false,
+            }),
+            state: Mutex::new(MuxerState {
+                poll_inbound_waker: None,
+                inbound_budget: INBOUND_SUBSTREAMS_BEFORE_YIELD,
                 substreams: Default::default(),
                 poll_substream_opened_waker: None,
+                outbound_blocked: false,
                 poll_close_waker: None,
+                poll_datagram_waker: None,
                 writable_substreams: 0,
+                error_history: VecDeque::with_capacity(ERROR_HISTORY_LEN),
             }),
+        });
+
+        let driver = inner
+            .config
+            .spawner
+            .spawn_joinable(connection_driver(inner.clone()))
+            .shared();
+
+        QuicMuxer { inner, driver }
+    }
+
+    /// Returns the most recent recoverable errors that happened on this connection, oldest
+    /// first.
+    ///
+    /// Only the last few errors are kept, which makes it possible to investigate a flaky
+    /// connection after the fact without continuously logging.
+    pub fn recent_errors(&self) -> Vec<ErrorEvent> {
+        self.inner
+            .state
+            .lock()
+            .error_history
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Returns a future that resolves once the background task driving the connection has
+    /// stopped, which happens when the connection has been closed.
+    ///
+    /// Fails with [`Error::DriverFailed`] if the task stopped unexpectedly, for example because
+    /// it panicked or because the executor dropped it, in which case the connection can no
+    /// longer make progress.
+    pub fn join_driver(&self) -> impl Future<Output = Result<(), Error>> + Send + 'static {
+        self.driver.clone().map(|result| match result {
+            Ok(true) => Ok(()),
+            Ok(false) | Err(oneshot::Canceled) => Err(Error::DriverFailed),
+        })
+    }
+
+    /// Abruptly stops sending data on a substream, and tells the remote the reason with an
+    /// application-defined `error_code`.
+    ///
+    /// Data that has been written but not yet received by the remote may be lost. On the side
+    /// of the remote, reading from the substream fails with [`Error::Reset`] carrying
+    /// `error_code`. Writing to the substream afterwards fails with [`Error::WriteAfterClose`],
+    /// while reading keeps working.
+    pub fn reset_substream(&self, substream: &mut Substream, error_code: u32) {
+        self.inner
+            .substream_span(*substream)
+            .in_scope(|| tracing::debug!(error_code, "resetting substream"));
+        let mut connection = self.inner.connection.lock();
+        connection
+            .connection
+            .reset_substream(*substream, error_code.into());
+        connection.wake_driver();
+        drop(connection);
+
+        let mut state = self.inner.state.lock();
+        let was_closed = match state.substreams.get_mut(substream) {
+            Some(substream) => {
+                substream.write_shutdown = true;
+                // A reset substream never becomes finished.
+                mem::replace(&mut substream.write_closed, true)
+            }
+            None => true,
+        };
+        if !was_closed {
+            state.writable_substreams -= 1;
         }
     }
-}
 
-impl StreamMuxer for QuicMuxer {
-    type OutboundSubstream = ();
-    type Substream = quinn_proto::StreamId;
-    type Error = Error;
+    /// Returns `true` if the last attempt at opening an outbound substream hit the limit set by
+    /// the remote, and the remote hasn't raised it since.
+    ///
+    /// Protocols can use this to throttle themselves until the limit is raised again.
+    // TODO: `quinn_proto` 0.6 doesn't expose how many more substreams the remote allows; report
+    //       the remaining stream credit instead once it does.
+    pub fn is_stream_limit_reached(&self) -> bool {
+        self.inner.state.lock().outbound_blocked
+    }
 
-    fn poll_inbound(&self, cx: &mut Context<'_>) -> Poll<Result<Self::Substream, Self::Error>> {
-        // We use `poll_inbound` to perform the background processing of the entire connection.
-        let mut inner = self.inner.lock();
-        span!("poll_inbound", side = debug(inner.connection.side()));
-        tracing::trace!("poll_inbound called");
+    /// Returns the ALPN protocol that was negotiated with the remote during the handshake, or
+    /// `None` if the remote didn't take part in the negotiation.
+    ///
+    /// See [`Config::with_alpn_protocols`](crate::Config::with_alpn_protocols).
+    pub fn alpn_protocol(&self) -> Option<Vec<u8>> {
+        self.inner.connection.lock().connection.alpn_protocol()
+    }
+
+    /// Returns what was negotiated with the remote during the handshake: the ALPN protocol, the
+    /// TLS cipher suite, and whether 0-RTT data was accepted.
+    ///
+    /// For muxers returned by [`QuicTransport::dial_0rtt`](crate::QuicTransport::dial_0rtt), this
+    /// is only final once the handshake has completed.
+    pub fn handshake_info(&self) -> HandshakeInfo {
+        self.inner.connection.lock().connection.handshake_info()
+    }
+
+    /// Returns `true` if the remote has accepted the data sent before the handshake completed,
+    /// which is only the case for muxers returned by
+    /// [`QuicTransport::dial_0rtt`](crate::QuicTransport::dial_0rtt) once the handshake has
+    /// completed.
+    ///
+    /// If the remote rejects the 0-RTT data, the substreams opened before the end of the
+    /// handshake fail.
+    pub fn accepted_0rtt(&self) -> bool {
+        self.inner.connection.lock().connection.accepted_0rtt()
+    }
+
+    /// Returns `true` if the remote accepts datagrams, in which case they can be sent with
+    /// [`QuicMuxer::send_datagram`].
+    pub fn datagrams_supported(&self) -> bool {
+        self.inner
+            .connection
+            .lock()
+            .connection
+            .datagrams_supported()
+    }
+
+    /// Sends an unreliable datagram to the remote.
+    ///
+    /// Fails with [`Error::SendDatagram`] if the remote doesn't accept datagrams or if `data`
+    /// doesn't fit in a single packet.
+    pub fn send_datagram(&self, data: Vec<u8>) -> Result<(), Error> {
+        let mut connection = self.inner.connection.lock();
+        connection.connection.send_datagram(data)?;
+        connection.wake_driver();
+        Ok(())
+    }
+
+    /// Polls for the next datagram sent by the remote.
+    ///
+    /// Datagrams are only received if they are enabled with
+    /// [`Config::with_datagrams`](crate::Config::with_datagrams).
+    pub fn poll_datagram(&self, cx: &mut Context<'_>) -> Poll<Result<Vec<u8>, Error>> {
+        register(&mut self.inner.state.lock().poll_datagram_waker, cx.waker());
+
+        let mut connection = self.inner.connection.lock();
+        if let Some(data) = connection.connection.recv_datagram() {
+            Poll::Ready(Ok(data))
+        } else if connection.connection.is_drained() {
+            Poll::Ready(Err(match connection.connection.close_reason() {
+                Some(connection::Error::Quinn(err)) => Error::from(err.clone()),
+                _ => Error::ConnectionLost,
+            }))
+        } else {
+            Poll::Pending
+        }
+    }
+
+    /// Returns statistics about the connection, such as the estimated round-trip time and the
+    /// amount of data exchanged so far.
+    pub fn stats(&self) -> ConnectionStats {
+        self.inner.connection.lock().connection.stats()
+    }
 
-        while let Poll::Ready(event) = inner.connection.poll_event(cx) {
-            match event {
-                ConnectionEvent::Connected => {
-                    log::error!("Unexpected Connected event on established QUIC connection");
+    /// Sends a ping to the remote in order to obtain a fresh round-trip time sample, which is
+    /// reflected by [`QuicMuxer::stats`] once the remote has acknowledged it.
+    ///
+    /// This works on idle connections as well, without opening a substream. Pinging doesn't
+    /// enable keep-alive: like any other exchange with the remote, it only defers the idle
+    /// timeout of the connection once.
+    // TODO: `quinn_proto` 0.6 doesn't report when a given packet is acknowledged; add a
+    //       `measure_rtt` resolving on the acknowledgement of the PING frame once it does.
+    pub fn ping(&self) {
+        let mut connection = self.inner.connection.lock();
+        connection.connection.ping();
+        connection.wake_driver();
+    }
+
+    /// Writes the data of `bufs` to a substream, in order, like [`StreamMuxer::write_substream`]
+    /// would if they were concatenated.
+    ///
+    /// Writing stops at the first buffer that the flow control of the substream or of the
+    /// connection doesn't accept entirely, and the number of bytes written is returned. This
+    /// saves taking the locks of the muxer and waking its background task for each buffer.
+    pub fn write_substream_vectored(
+        &self,
+        cx: &mut Context<'_>,
+        substream: &mut Substream,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<Result<usize, Error>> {
+        let id = *substream;
+        self.poll_write(cx, substream, |connection| {
+            connection.write_substream_vectored(id, bufs)
+        })
+    }
+
+    /// Writes data to a substream with `write`, which is passed the connection and returns
+    /// the number of bytes written, and handles its result like
+    /// [`StreamMuxer::write_substream`].
+    fn poll_write(
+        &self,
+        cx: &mut Context<'_>,
+        substream: &Substream,
+        write: impl FnOnce(&mut Connection) -> Result<usize, quinn_proto::WriteError>,
+    ) -> Poll<Result<usize, Error>> {
+        {
+            let mut state = self.inner.state.lock();
+            if let Some(state) = state.substreams.get(substream) {
+                if state.write_shutdown || state.write_closed {
+                    return Poll::Ready(Err(Error::WriteAfterClose));
                 }
-                ConnectionEvent::ConnectionLost(_) => {
-                    if let Some(waker) = inner.poll_close_waker.take() {
-                        waker.wake();
+            }
+            state.register_substream_waker(substream, cx.waker(), |s| &mut s.write_waker);
+        }
+
+        let result = {
+            let mut connection = self.inner.connection.lock();
+            let result = write(&mut connection.connection);
+            if result.is_ok() {
+                connection.wake_driver();
+            }
+            result
+        };
+
+        let span = match result {
+            Ok(_) => tracing::Span::none(),
+            Err(_) => self.inner.substream_span(*substream),
+        };
+        let _enter = span.enter();
+        let mut state = self.inner.state.lock();
+        match result {
+            Ok(bytes) => {
+                if let Some(substream) = state.substreams.get_mut(substream) {
+                    substream.write_blocked = false;
+                }
+                Poll::Ready(Ok(bytes))
+            }
+            Err(quinn_proto::WriteError::Stopped(error_code)) => {
+                state.record_error(ErrorEvent::StreamStopped {
+                    substream: *substream,
+                    error_code,
+                });
+                Poll::Ready(Err(Error::Stopped(error_code)))
+            }
+            Err(quinn_proto::WriteError::Blocked) => {
+                // The waker registered above is woken by `ConnectionEvent::StreamWritable` once
+                // the remote grants more credit with `MAX_STREAM_DATA` or `MAX_DATA`.
+                let MuxerState {
+                    ref mut substreams,
+                    ref mut error_history,
+                    ..
+                } = &mut *state;
+                if let Some(state) = substreams.get_mut(substream) {
+                    if !state.write_blocked {
+                        state.write_blocked = true;
+                        let event = ErrorEvent::FlowControlBlocked {
+                            substream: *substream,
+                        };
+                        push_error(error_history, event);
                     }
                 }
+                Poll::Pending
+            }
+            Err(quinn_proto::WriteError::UnknownStream) => {
+                tracing::error!(
+                    "The application used a connection that is already being \
+                    closed. This is a bug in the application or in libp2p."
+                );
+                Poll::Pending
+            }
+        }
+    }
 
-                ConnectionEvent::StreamOpened => {
-                    if let Some(waker) = inner.poll_substream_opened_waker.take() {
-                        waker.wake();
-                    }
+    /// Reads data from a substream into `bufs`, in order, like [`StreamMuxer::read_substream`]
+    /// would into their concatenation.
+    ///
+    /// Reading stops at the first buffer that isn't filled entirely, and the number of bytes
+    /// read is returned. Returns `Ok(0)` at the end of the substream if `bufs` isn't empty.
+    pub fn read_substream_vectored(
+        &self,
+        cx: &mut Context<'_>,
+        substream: &mut Substream,
+        bufs: &mut [IoSliceMut<'_>],
+    ) -> Poll<Result<usize, Error>> {
+        let id = *substream;
+        self.poll_read(cx, substream, |connection| {
+            connection.read_substream_vectored(id, bufs)
+        })
+    }
+
+    /// Reads the next chunk of data of a substream, of at most `max_len` bytes, or `None` at
+    /// the end of the substream.
+    ///
+    /// Chunks are returned in order and can be interleaved with the other reads on the same
+    /// substream. A single chunk holds at most 256 kiB whatever `max_len`, and is only allocated
+    /// once data is available. Errors are reported like [`StreamMuxer::read_substream`].
+    pub fn read_chunk(
+        &self,
+        cx: &mut Context<'_>,
+        substream: &mut Substream,
+        max_len: usize,
+    ) -> Poll<Result<Option<Bytes>, Error>> {
+        if max_len == 0 {
+            return Poll::Ready(Ok(Some(Bytes::new())));
+        }
+        let id = *substream;
+        let mut chunk = None;
+        let read = self.poll_read(cx, substream, |connection| {
+            let read = connection.read_chunk(id, max_len)?;
+            let len = read.as_ref().map(Bytes::len);
+            chunk = read;
+            Ok(len)
+        });
+        read.map_ok(|n| if n == 0 { None } else { chunk })
+    }
+
+    /// Reads data from a substream with `read`, which is passed the connection and returns the
+    /// number of bytes read, or `None` at the end of the substream, and handles its result like
+    /// [`StreamMuxer::read_substream`].
+    fn poll_read(
+        &self,
+        cx: &mut Context<'_>,
+        substream: &Substream,
+        read: impl FnOnce(&mut Connection) -> Result<Option<usize>, quinn_proto::ReadError>,
+    ) -> Poll<Result<usize, Error>> {
+        {
+            let mut state = self.inner.state.lock();
+            // The connection forgets about the substream once its end has been reported, which
+            // therefore has to be remembered here.
+            if let Some(state) = state.substreams.get(substream) {
+                if let Some(error_code) = state.read_reset {
+                    return Poll::Ready(Err(Error::Reset(error_code)));
                 }
-                ConnectionEvent::StreamReadable(substream) => {
-                    if let Some(substream) = inner.substreams.get_mut(&substream) {
-                        if let Some(waker) = substream.read_waker.take() {
-                            waker.wake();
-                        }
-                    }
+                if state.read_closed {
+                    return Poll::Ready(Ok(0));
                 }
-                ConnectionEvent::StreamWritable(substream) => {
-                    if let Some(substream) = inner.substreams.get_mut(&substream) {
-                        if let Some(waker) = substream.write_waker.take() {
-                            waker.wake();
-                        }
-                    }
+            }
+            state.register_substream_waker(substream, cx.waker(), |s| &mut s.read_waker);
+        }
+
+        let result = {
+            let mut connection = self.inner.connection.lock();
+            let result = read(&mut connection.connection);
+            if result.is_ok() {
+                // Reading can open up the flow control window of the remote.
+                connection.wake_driver();
+            }
+            result
+        };
+
+        let span = match result {
+            Ok(_) => tracing::Span::none(),
+            Err(_) => self.inner.substream_span(*substream),
+        };
+        let _enter = span.enter();
+        match result {
+            Ok(Some(bytes)) => Poll::Ready(Ok(bytes)),
+            Ok(None) => {
+                if let Some(state) = self.inner.state.lock().substreams.get_mut(substream) {
+                    state.read_closed = true;
                 }
-                ConnectionEvent::StreamFinished(substream) => {
-                    if let Some(substream) = inner.substreams.get_mut(&substream) {
-                        if let Some(waker) = substream.read_waker.take() {
-                            waker.wake();
-                        }
-                        if let Some(waker) = substream.write_waker.take() {
-                            waker.wake();
-                        }
-                        if let Some(waker) = substream.finished_waker.take() {
-                            waker.wake();
-                        }
-                        substream.write_closed = true
-                    }
-                    inner.writable_substreams -= 1;
+                Poll::Ready(Ok(0)) // EOF
+            }
+            Err(quinn_proto::ReadError::Reset(error_code)) => {
+                let mut state = self.inner.state.lock();
+                if let Some(state) = state.substreams.get_mut(substream) {
+                    state.read_reset = Some(error_code);
                 }
+                state.record_error(ErrorEvent::StreamReset {
+                    substream: *substream,
+                    error_code,
+                });
+                Poll::Ready(Err(Error::Reset(error_code)))
+            }
+            Err(quinn_proto::ReadError::Blocked) => Poll::Pending,
+            Err(quinn_proto::ReadError::UnknownStream) => {
+                tracing::error!(
+                    "The application used a connection that is already being \
+                    closed. This is a bug in the application or in libp2p."
+                );
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Closes the connection, reporting `error_code` and `reason` to the remote.
+    ///
+    /// Similar to [`StreamMuxer::close`], which uses an error code of `0` and an empty reason.
+    /// The remote observes the closure as a
+    /// [`ConnectionError::ApplicationClosed`](quinn_proto::ConnectionError::ApplicationClosed)
+    /// carrying these values. Returns `Ready` once the connection is closed.
+    ///
+    /// Fails with [`Error::CloseReasonTooLong`] if `reason` is longer than
+    /// [`MAX_CLOSE_REASON_LEN`], in which case the connection is left untouched.
+    pub fn close_with(
+        &self,
+        cx: &mut Context<'_>,
+        error_code: u64,
+        reason: &[u8],
+    ) -> Poll<Result<(), Error>> {
+        let error_code = endpoint::check_close_frame(error_code, reason)?;
+
+        // StreamMuxer's `close` documentation mentions that it automatically implies `flush_all`.
+        if let Poll::Pending = StreamMuxer::flush_all(self, cx)? {
+            return Poll::Pending;
+        }
+
+        // TODO: poll if closed or something
 
-                // Do nothing as this is handled below.
-                ConnectionEvent::StreamAvailable => {}
+        let mut connection = self.inner.connection.lock();
+        if connection.connection.close_reason().is_some() || connection.connection.is_drained() {
+            return Poll::Ready(Ok(()));
+        }
+        let _enter = self.inner.span.enter();
+        connection.wake_driver();
+
+        // The driver can't process the closing of the connection while we hold the lock of the
+        // connection, so the waker registered below can't be missed.
+        let mut state = self.inner.state.lock();
+        if state.writable_substreams == 0 {
+            tracing::debug!("closing connection");
+            connection.connection.close(error_code, reason);
+        } else {
+            tracing::debug!("shutting down pending substreams");
+            for (stream_id, waker) in state.substreams.iter_mut() {
+                tracing::debug!("shutting down substream {:?}", stream_id);
+                connection.connection.shutdown_substream(*stream_id);
+                if let Some(w) = waker.write_waker.take() {
+                    w.wake()
+                }
+                waker.write_waker = Some(cx.waker().clone());
+                if let Some(w) = waker.finished_waker.take() {
+                    w.wake()
+                }
+                waker.finished_waker = Some(cx.waker().clone());
             }
         }
 
-        if let Some(substream) = inner.connection.pop_incoming_substream() {
-            inner.substreams.insert(substream, Default::default());
-            inner.writable_substreams += 1;
-            tracing::trace!("New substream");
-            Poll::Ready(Ok(substream))
-        } else if inner.connection.is_drained() {
-            if let Some(w) = inner.poll_close_waker.take() {
-                tracing::trace!("Inner connection is drained, waking close waker");
-                w.wake()
+        // Register `cx.waker()` as being woken up if the connection closes.
+        register(&mut state.poll_close_waker, cx.waker());
+        Poll::Pending
+    }
+}
+
+// In the methods below, wakers are registered *before* accessing the connection. The driver
+// only wakes up tasks after having polled an event out of the connection, so if the connection
+// isn't ready the waker is guaranteed to be in place by the time the relevant event is
+// processed, without having to hold both locks at the same time.
+impl StreamMuxer for QuicMuxer {
+    type OutboundSubstream = ();
+    type Substream = Substream;
+    type Error = Error;
+
+    fn poll_inbound(&self, cx: &mut Context<'_>) -> Poll<Result<Self::Substream, Self::Error>> {
+        let _enter = self.inner.span.enter();
+        {
+            let mut state = self.inner.state.lock();
+            if state.inbound_budget == 0 {
+                // Let the other tasks lock the connection before coming back right away.
+                state.inbound_budget = INBOUND_SUBSTREAMS_BEFORE_YIELD;
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
             }
-            Poll::Ready(Err(Error::ConnectionLost))
+            // Register `cx.waker()` as having to be woken up once a substream is available.
+            register(&mut state.poll_inbound_waker, cx.waker());
+        }
+
+        let mut connection = self.inner.connection.lock();
+        tracing::trace!("poll_inbound called");
+
+        if let Some(substream) = connection.connection.pop_incoming_substream() {
+            // Accepting a substream can grant the remote the right to open a new one.
+            connection.wake_driver();
+            drop(connection);
+            let mut state = self.inner.state.lock();
+            state.inbound_budget -= 1;
+            state.add_substream(substream);
+            drop(state);
+            self.inner.config.metrics.substream_opened();
+            self.inner
+                .substream_span(substream)
+                .in_scope(|| tracing::debug!("inbound substream opened"));
+            Poll::Ready(Ok(substream))
         } else {
-            Poll::Pending
+            // Nothing is returned in a row anymore.
+            self.inner.state.lock().inbound_budget = INBOUND_SUBSTREAMS_BEFORE_YIELD;
+            if connection.connection.is_drained() {
+                Poll::Ready(Err(match connection.connection.close_reason() {
+                    Some(connection::Error::Quinn(err)) => Error::from(err.clone()),
+                    _ => Error::ConnectionLost,
+                }))
+            } else {
+                Poll::Pending
+            }
         }
     }
 
@@ -175,20 +737,33 @@ impl StreamMuxer for QuicMuxer {
         // over and over again and get new substreams. Using the API this way is invalid and would
         // normally result in a panic, but we decide to just ignore this question.
 
-        let mut inner = self.inner.lock();
-        if let Some(substream) = inner.connection.pop_outgoing_substream() {
-            inner.substreams.insert(substream, Default::default());
-            inner.writable_substreams += 1;
+        let _enter = self.inner.span.enter();
+        // Register `cx.waker()` as having to be woken up once a substream is available.
+        register(
+            &mut self.inner.state.lock().poll_substream_opened_waker,
+            cx.waker(),
+        );
+
+        let mut connection = self.inner.connection.lock();
+        if let Some(substream) = connection.connection.pop_outgoing_substream() {
+            connection.wake_driver();
+            drop(connection);
+            let mut state = self.inner.state.lock();
+            state.outbound_blocked = false;
+            state.add_substream(substream);
+            self.inner.config.metrics.substream_opened();
+            self.inner
+                .substream_span(substream)
+                .in_scope(|| tracing::debug!("outbound substream opened"));
             return Poll::Ready(Ok(substream));
         }
 
-        // Register `cx.waker()` as having to be woken up once a substream is available.
-        if !inner
-            .poll_substream_opened_waker
-            .as_ref()
-            .map_or(false, |w| w.will_wake(cx.waker()))
-        {
-            inner.poll_substream_opened_waker = Some(cx.waker().clone());
+        // The connection is still locked, so that the driver can't process a `StreamOpened`
+        // event before the flag is set.
+        self.inner.state.lock().outbound_blocked = true;
+        drop(connection);
+        if !self.inner.config.queue_outbound {
+            return Poll::Ready(Err(Error::StreamsExhausted));
         }
         Poll::Pending
     }
@@ -196,76 +771,45 @@ impl StreamMuxer for QuicMuxer {
     fn destroy_outbound(&self, _: Self::OutboundSubstream) {}
 
     fn is_remote_acknowledged(&self) -> bool {
-        // TODO: stub
-        true
+        // Apart from 0-RTT muxers, a `QuicMuxer` is only ever built once the handshake has
+        // completed, at which point the remote has acknowledged our keys and, if it is a client,
+        // has had its address validated. This stops being true as soon as the connection starts
+        // closing or draining.
+        let connection = self.inner.connection.lock();
+        !connection.connection.is_handshaking()
+            && connection.connection.close_reason().is_none()
+            && !connection.connection.is_closed()
     }
 
+    // TODO: `quinn_proto` 0.6 only accepts slices, which it copies; add a `write_chunk` taking
+    //       `Bytes` once it can take ownership of them without copying.
     fn write_substream(
         &self,
         cx: &mut Context<'_>,
         substream: &mut Self::Substream,
         buf: &[u8],
     ) -> Poll<Result<usize, Self::Error>> {
-        let mut inner = self.inner.lock();
-
-        match inner.connection.write_substream(*substream, buf) {
-            Ok(bytes) => Poll::Ready(Ok(bytes)),
-            Err(quinn_proto::WriteError::Stopped(_)) => Poll::Ready(Ok(0)), // EOF
-            Err(quinn_proto::WriteError::Blocked) => {
-                if let Some(substream) = inner.substreams.get_mut(substream) {
-                    if !substream
-                        .write_waker
-                        .as_ref()
-                        .map_or(false, |w| w.will_wake(cx.waker()))
-                    {
-                        substream.write_waker = Some(cx.waker().clone());
-                    }
-                }
-                Poll::Pending
-            }
-            Err(quinn_proto::WriteError::UnknownStream) => {
-                log::error!(
-                    "The application used a connection that is already being \
-                    closed. This is a bug in the application or in libp2p."
-                );
-                Poll::Pending
-            }
-        }
+        let id = *substream;
+        self.poll_write(cx, substream, |connection| {
+            connection.write_substream(id, buf)
+        })
     }
 
-    /// Try to read from a substream. This will return an error if the substream has
-    /// not yet been written to.
+    /// Reads data from a substream.
+    ///
+    /// Returns `Ok(0)` once the remote has finished the substream and all of its data has been
+    /// read, and keeps doing so on subsequent calls. If the remote has reset the substream,
+    /// fails with [`Error::Reset`] instead, on this and subsequent calls.
     fn read_substream(
         &self,
         cx: &mut Context<'_>,
         substream: &mut Self::Substream,
         buf: &mut [u8],
     ) -> Poll<Result<usize, Self::Error>> {
-        let mut inner = self.inner.lock();
-
-        match inner.connection.read_substream(*substream, buf) {
-            Ok(bytes) => Poll::Ready(Ok(bytes)),
-            Err(quinn_proto::ReadError::Reset(_)) => Poll::Ready(Ok(0)), // EOF
-            Err(quinn_proto::ReadError::Blocked) => {
-                if let Some(substream) = inner.substreams.get_mut(substream) {
-                    if !substream
-                        .read_waker
-                        .as_ref()
-                        .map_or(false, |w| w.will_wake(cx.waker()))
-                    {
-                        substream.read_waker = Some(cx.waker().clone());
-                    }
-                }
-                Poll::Pending
-            }
-            Err(quinn_proto::ReadError::UnknownStream) => {
-                log::error!(
-                    "The application used a connection that is already being \
-                    closed. This is a bug in the application or in libp2p."
-                );
-                Poll::Pending
-            }
-        }
+        let id = *substream;
+        self.poll_read(cx, substream, |connection| {
+            connection.read_substream(id, buf)
+        })
     }
 
     fn shutdown_substream(
@@ -273,37 +817,84 @@ impl StreamMuxer for QuicMuxer {
         cx: &mut Context<'_>,
         substream_id: &mut Self::Substream,
     ) -> Poll<Result<(), Self::Error>> {
-        let mut inner = self.inner.lock();
-        let QuicMuxerInner {
-            ref mut substreams,
-            ref mut connection,
-            ..
-        } = &mut *inner;
-
-        let substream = substreams
-            .get_mut(substream_id)
-            .expect("using a destroyed substream");
-
-        if substream.write_closed {
-            return Poll::Ready(Ok(()));
+        let span = self.inner.substream_span(*substream_id);
+        let _enter = span.enter();
+        {
+            let mut state = self.inner.state.lock();
+            let substream = state
+                .substreams
+                .get_mut(substream_id)
+                .expect("using a destroyed substream");
+
+            if substream.write_closed {
+                return Poll::Ready(Ok(()));
+            }
+            if !substream.write_shutdown {
+                tracing::debug!("closing substream for writing");
+            }
+            substream.write_shutdown = true;
+
+            let waker = cx.waker();
+            match substream.finished_waker.as_mut() {
+                None => substream.finished_waker = Some(waker.clone()),
+                Some(w) if w.will_wake(waker) && false => {}
+                Some(w) => std::mem::replace(w, waker.clone()).wake(),
+            }
         }
 
-        if connection.shutdown_substream(*substream_id).is_err() {
-            substream.write_closed = true;
+        let result = {
+            let mut connection = self.inner.connection.lock();
+            connection.wake_driver();
+            connection.connection.shutdown_substream(*substream_id)
+        };
+
+        if result.is_err() {
+            let mut state = self.inner.state.lock();
+            let was_closed = match state.substreams.get_mut(substream_id) {
+                Some(substream) => mem::replace(&mut substream.write_closed, true),
+                None => true,
+            };
+            if !was_closed {
+                state.writable_substreams -= 1;
+            }
             return Poll::Ready(Ok(()));
         }
-        let waker = cx.waker();
-        match substream.finished_waker.as_mut() {
-            None => substream.finished_waker = Some(waker.clone()),
-            Some(w) if w.will_wake(waker) && false => {}
-            Some(w) => std::mem::replace(w, waker.clone()).wake(),
-        }
         Poll::Pending
     }
 
+    /// Stops tracking the substream. Unless the substream has been closed for writing, it is
+    /// reset, and the remote is asked to stop sending in any case, so that neither side keeps
+    /// buffering data or holding flow control credit for it.
     fn destroy_substream(&self, substream: Self::Substream) {
-        let mut inner = self.inner.lock();
-        inner.substreams.remove(&substream);
+        let span = self.inner.substream_span(substream);
+        let _enter = span.enter();
+        let reset = {
+            let mut state = self.inner.state.lock();
+            let removed = state.substreams.remove(&substream);
+            if removed.is_some() {
+                self.inner.config.metrics.substream_closed();
+            }
+            match removed {
+                // The substream can no longer become finished once reset, which must be
+                // accounted for right away.
+                Some(substream) if !substream.write_closed => {
+                    state.writable_substreams -= 1;
+                    !substream.write_shutdown
+                }
+                _ => false,
+            }
+        };
+
+        tracing::debug!(reset, "substream destroyed");
+        let error_code = self.inner.config.cancel_error_code;
+        let mut connection = self.inner.connection.lock();
+        if reset {
+            connection.connection.reset_substream(substream, error_code);
+        }
+        connection
+            .connection
+            .stop_sending_substream(substream, error_code);
+        connection.wake_driver();
     }
 
     fn flush_substream(
@@ -320,51 +911,240 @@ impl StreamMuxer for QuicMuxer {
     }
 
     fn close(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        // StreamMuxer's `close` documentation mentions that it automatically implies `flush_all`.
-        if let Poll::Pending = self.flush_all(cx)? {
-            return Poll::Pending;
+        self.close_with(cx, 0, &[])
+    }
+}
+
+impl QuicMuxerInner {
+    /// Returns a span for the events concerning `substream`, nested in the span of the
+    /// connection.
+    fn substream_span(&self, substream: quinn_proto::StreamId) -> tracing::Span {
+        tracing::debug_span!(parent: &self.span, "substream", id = ?substream)
+    }
+}
+
+impl ConnectionState {
+    /// Wakes up the background task driving the connection.
+    fn wake_driver(&mut self) {
+        if let Some(waker) = self.driver_waker.take() {
+            waker.wake();
         }
+    }
+}
 
-        // TODO: poll if closed or something
+impl MuxerState {
+    /// Starts tracking a substream that has just been returned by the connection.
+    fn add_substream(&mut self, substream: quinn_proto::StreamId) {
+        self.substreams.insert(substream, Default::default());
+        self.writable_substreams += 1;
+    }
 
-        let mut inner = self.inner.lock();
-        if inner.connection.close_reason().is_some() || inner.connection.is_drained() {
-            return Poll::Ready(Ok(()));
+    /// Registers `waker` in the slot of `substream` returned by `slot`, if the substream is
+    /// still tracked.
+    fn register_substream_waker(
+        &mut self,
+        substream: &quinn_proto::StreamId,
+        waker: &Waker,
+        slot: impl FnOnce(&mut SubstreamState) -> &mut Option<Waker>,
+    ) {
+        if let Some(substream) = self.substreams.get_mut(substream) {
+            register(slot(substream), waker);
         }
-        span!("closing", side = debug(inner.connection.side()));
-        if inner.writable_substreams == 0 {
-            tracing::debug!("closing connection");
-            inner.connection.close();
-        } else {
-            tracing::debug!("shutting down pending substreams");
-            let QuicMuxerInner {
-                ref mut substreams,
-                ref mut connection,
-                ..
-            } = &mut *inner;
-            for (stream_id, waker) in substreams.iter_mut() {
-                tracing::debug!("shutting down substream {:?}", stream_id);
-                connection.shutdown_substream(*stream_id);
-                if let Some(w) = waker.write_waker.take() {
-                    w.wake()
+    }
+
+    /// Adds an entry to the error history.
+    fn record_error(&mut self, event: ErrorEvent) {
+        push_error(&mut self.error_history, event)
+    }
+
+    /// Wakes up all the tasks waiting for something to happen on the connection.
+    fn wake_all(&mut self) {
+        let wakers = self
+            .poll_inbound_waker
+            .take()
+            .into_iter()
+            .chain(self.poll_substream_opened_waker.take())
+            .chain(self.poll_close_waker.take())
+            .chain(self.poll_datagram_waker.take());
+        for waker in wakers {
+            waker.wake();
+        }
+        for substream in self.substreams.values_mut() {
+            let wakers = substream
+                .read_waker
+                .take()
+                .into_iter()
+                .chain(substream.write_waker.take())
+                .chain(substream.finished_waker.take());
+            for waker in wakers {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Processes an event generated by the connection.
+    fn handle_event(&mut self, event: ConnectionEvent) {
+        match event {
+            // Handled by `connection_driver`.
+            ConnectionEvent::Connected => {}
+            ConnectionEvent::ConnectionLost(_) => self.wake_all(),
+
+            ConnectionEvent::StreamAvailable => {
+                if let Some(waker) = self.poll_inbound_waker.take() {
+                    waker.wake();
                 }
-                waker.write_waker = Some(cx.waker().clone());
-                if let Some(w) = waker.finished_waker.take() {
-                    w.wake()
+            }
+            ConnectionEvent::StreamOpened => {
+                self.outbound_blocked = false;
+                if let Some(waker) = self.poll_substream_opened_waker.take() {
+                    waker.wake();
+                }
+            }
+            ConnectionEvent::DatagramReceived => {
+                if let Some(waker) = self.poll_datagram_waker.take() {
+                    waker.wake();
+                }
+            }
+            // The closure itself is reported by the `ConnectionLost` that follows.
+            ConnectionEvent::MigrationRefused(from) => {
+                self.record_error(ErrorEvent::MigrationRefused { from })
+            }
+            ConnectionEvent::StreamReadable(substream) => {
+                if let Some(substream) = self.substreams.get_mut(&substream) {
+                    if let Some(waker) = substream.read_waker.take() {
+                        waker.wake();
+                    }
+                }
+            }
+            ConnectionEvent::StreamWritable(substream) => {
+                if let Some(substream) = self.substreams.get_mut(&substream) {
+                    if let Some(waker) = substream.write_waker.take() {
+                        waker.wake();
+                    }
+                }
+            }
+            ConnectionEvent::StreamFinished(substream) => {
+                if let Some(substream) = self.substreams.get_mut(&substream) {
+                    if let Some(waker) = substream.read_waker.take() {
+                        waker.wake();
+                    }
+                    if let Some(waker) = substream.write_waker.take() {
+                        waker.wake();
+                    }
+                    if let Some(waker) = substream.finished_waker.take() {
+                        waker.wake();
+                    }
+                    // Substreams that are no longer tracked have been accounted for by
+                    // `destroy_substream`.
+                    if !substream.write_closed {
+                        substream.write_closed = true;
+                        self.writable_substreams -= 1;
+                    }
                 }
-                waker.finished_waker = Some(cx.waker().clone());
             }
         }
+    }
+}
 
-        // Register `cx.waker()` as being woken up if the connection closes.
-        if !inner
-            .poll_close_waker
-            .as_ref()
-            .map_or(false, |w| w.will_wake(cx.waker()))
-        {
-            inner.poll_close_waker = Some(cx.waker().clone());
+/// Stores `waker` in `slot`, unless the waker already in there would wake up the same task.
+fn register(slot: &mut Option<Waker>, waker: &Waker) {
+    if !slot.as_ref().map_or(false, |w| w.will_wake(waker)) {
+        *slot = Some(waker.clone());
+    }
+}
+
+/// Adds `event` to `history`, evicting the oldest entry if the history is full.
+fn push_error(history: &mut VecDeque<ErrorEvent>, event: ErrorEvent) {
+    tracing::debug!("recoverable error: {:?}", event);
+    if history.len() == ERROR_HISTORY_LEN {
+        history.pop_front();
+    }
+    history.push_back(event);
+}
+
+/// Background task driving the connection of a [`QuicMuxer`].
+///
+/// This task processes the events of the connection and wakes up the tasks interested in them.
+/// The lock of the connection is released before waking up tasks, and the task yields after each
+/// event, so that a burst of incoming packets doesn't block the substream operations of other
+/// tasks.
+///
+/// The task stops once the connection is drained or the [`QuicMuxer`] is destroyed.
+async fn connection_driver(inner: Arc<QuicMuxerInner>) {
+    future::poll_fn(move |cx| {
+        let _enter = inner.span.enter();
+        let mut connection = inner.connection.lock();
+
+        // Once the muxer is gone, the connection is only driven in order to tell the remote
+        // that it has been closed, which is pointless if it has been lost already.
+        if connection.muxer_dropped && connection.connection.close_reason().is_some() {
+            return Poll::Ready(());
         }
-        Poll::Pending
+        if connection.connection.is_drained() {
+            tracing::trace!("Inner connection is drained, stopping driver");
+            drop(connection);
+            inner.state.lock().wake_all();
+            return Poll::Ready(());
+        }
+
+        register(&mut connection.driver_waker, cx.waker());
+
+        match connection.connection.poll_event(cx) {
+            Poll::Ready(ConnectionEvent::Connected) => {
+                // Only 0-RTT muxers exist while the handshake is in progress. Their remote has
+                // only been identified now that its certificate has been verified.
+                if let Some(expected) = &inner.early_peer_id {
+                    match connection.connection.remote_peer_id() {
+                        Ok(actual) if actual == *expected => {
+                            connection.connection.record_peer_id(&actual)
+                        }
+                        actual => {
+                            tracing::warn!("0-RTT remote isn't {}: {:?}", expected, actual);
+                            connection
+                                .connection
+                                .close(0u32.into(), b"unexpected remote peer");
+                        }
+                    }
+                }
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+            Poll::Ready(event) => {
+                drop(connection);
+                inner.state.lock().handle_event(event);
+                // Yield in order to give other tasks a chance to grab the lock before we process
+                // the next event.
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+            Poll::Pending if connection.connection.is_drained() => {
+                drop(connection);
+                inner.state.lock().wake_all();
+                Poll::Ready(())
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    })
+    .await
+}
+
+impl Drop for QuicMuxer {
+    fn drop(&mut self) {
+        // The substreams that haven't been destroyed are gone as well.
+        for _ in 0..self.inner.state.lock().substreams.len() {
+            self.inner.config.metrics.substream_closed();
+        }
+        // Close the connection so that the remote is notified rather than left waiting for the
+        // idle timeout, and wake up the driver so that it sends the close and stops once the
+        // connection is drained.
+        let mut connection = self.inner.connection.lock();
+        if connection.connection.close_reason().is_none() && !connection.connection.is_drained() {
+            let _enter = self.inner.span.enter();
+            tracing::debug!("muxer dropped, closing connection");
+            connection.connection.close(0u32.into(), b"");
+        }
+        connection.muxer_dropped = true;
+        connection.wake_driver();
     }
 }
 
@@ -22,16 +22,29 @@
 //!
 //! Combines all the objects in the other modules to implement the trait.
 
-use crate::{endpoint::Endpoint, muxer::QuicMuxer, upgrade::Upgrade};
+use crate::{
+    addr::{multiaddr_to_socketaddr, socketaddr_to_multiaddr},
+    endpoint::{self, DialRetry, Endpoint, Incoming},
+    metrics::{Direction, Metrics, TransportMetrics},
+    muxer::QuicMuxer,
+    upgrade::Upgrade,
+};
 
-use either::{Left, Right};
 use futures::prelude::*;
 use libp2p_core::{
     multiaddr::{Multiaddr, Protocol},
     transport::{ListenerEvent, TransportError},
     PeerId, Transport,
 };
-use std::{net::SocketAddr, pin::Pin, sync::Arc};
+use ring::rand::SecureRandom;
+use std::{
+    fmt, io,
+    net::{Ipv4Addr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
 
 // We reexport the errors that are exposed in the API.
 // All of these types use one another.
@@ -43,156 +56,774 @@ pub use quinn_proto::{
 
 /// Wraps around an `Arc<Endpoint>` and implements the [`Transport`] trait.
 ///
+/// Dialing and listening produce a [`PeerId`] alongside the [`QuicMuxer`] of the connection.
+/// The [`PeerId`] is extracted from the certificate of the remote once it has been verified
+/// during the handshake, so it can be trusted without further checks.
+///
 /// > **Note**: This type is necessary because Rust unfortunately forbids implementing the
 /// >           `Transport` trait directly on `Arc<Endpoint>`.
 #[derive(Debug, Clone)]
 pub struct QuicTransport(pub Arc<Endpoint>);
 
 /// Error that can happen on the transport.
+///
+/// Addresses that aren't QUIC addresses, as well as QUIC addresses that can only be listened on
+/// because their IP address is unspecified or their port is 0, are reported with
+/// [`TransportError::MultiaddrNotSupported`] rather than with this type. Other transports might
+/// be able to dial them, which isn't the case of a remote refusing the connection.
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     /// Error while trying to reach a remote.
     #[error("{0}")]
     Reach(ConnectError),
-    /// Error after the remote has been reached.
+    /// Error after the remote has been reached, not covered by the more specific variants below.
     #[error("{0}")]
     Established(Libp2pQuicConnectionError),
+    /// The connection timed out for lack of activity.
+    #[error("Connection timed out")]
+    TimedOut,
+    /// The remote doesn't support the QUIC version we offered on the wire.
+    #[error("The remote supports QUIC versions {peer_supported:?}, but we offered {offered:?}")]
+    VersionNegotiationFailed {
+        /// Versions we offered.
+        offered: Vec<u32>,
+        /// Versions the remote supports, as listed by its version negotiation packet.
+        peer_supported: Vec<u32>,
+    },
+    /// The QUIC protocol was violated, by the remote or by ourselves.
+    #[error("QUIC transport error {code}: {reason}")]
+    TransportError {
+        /// Transport error code of the violation.
+        code: TransportErrorCode,
+        /// Human-readable description of the violation.
+        reason: String,
+    },
+    /// The handshake with the remote didn't complete in time.
+    #[error("Handshake with the remote timed out")]
+    HandshakeTimeout,
+    /// The remote isn't the peer designated by the `/p2p` component of the dialed address.
+    #[error("Expected to reach {expected}, but reached {actual}")]
+    PeerIdMismatch {
+        /// Peer designated by the dialed address.
+        expected: PeerId,
+        /// Peer that was actually reached.
+        actual: PeerId,
+    },
+    /// The remote didn't present a certificate during the handshake.
+    #[error("The remote didn't present a certificate")]
+    MissingCertificate,
+    /// The certificate presented by the remote doesn't contain a valid libp2p extension.
+    #[error("Invalid certificate presented by the remote: {0:?}")]
+    InvalidCertificate(webpki::Error),
+    /// The certificate of a resumed session was rejected by the verifier set with
+    /// [`Config::with_verifier`](crate::Config::with_verifier).
+    #[error("Certificate of the resumed session rejected: {0}")]
+    CertificateRejected(rustls::TLSError),
+    /// The dialed address is one of our own listen addresses, or the remote turned out to have
+    /// our own [`PeerId`].
+    #[error("Cannot dial ourselves")]
+    DialToSelf,
+    /// Tried to listen with an endpoint built with
+    /// [`Config::client_only`](crate::Config::client_only).
+    #[error("Cannot listen: the endpoint has been configured as a client only")]
+    ClientOnly,
+    /// Tried to dial with an endpoint built with
+    /// [`Config::server_only`](crate::Config::server_only).
+    #[error("Cannot dial: the endpoint has been configured as a server only")]
+    ServerOnly,
+    /// The endpoint has been closed.
+    #[error("The endpoint has been closed")]
+    EndpointClosed,
+    /// The background task of the endpoint stopped unexpectedly. This is the last item
+    /// produced by the listener.
+    #[error("The background task of the endpoint stopped unexpectedly")]
+    DriverFailed,
+    /// Non-fatal error on the UDP socket of a listener.
+    #[error("{0}")]
+    Io(io::Error),
+}
+
+impl From<ConnectError> for Error {
+    fn from(err: ConnectError) -> Self {
+        match err {
+            ConnectError::EndpointStopping => Error::EndpointClosed,
+            err => Error::Reach(err),
+        }
+    }
+}
+
+impl From<Libp2pQuicConnectionError> for Error {
+    fn from(err: Libp2pQuicConnectionError) -> Self {
+        match err {
+            Libp2pQuicConnectionError::Quinn(ConnectionError::TimedOut) => Error::TimedOut,
+            Libp2pQuicConnectionError::VersionNegotiationFailed {
+                offered,
+                peer_supported,
+            } => Error::VersionNegotiationFailed {
+                offered,
+                peer_supported,
+            },
+            // The version negotiation packet always reaches the connection first. The versions
+            // are only unknown if the connection was lost in between.
+            Libp2pQuicConnectionError::Quinn(ConnectionError::VersionMismatch) => {
+                Error::VersionNegotiationFailed {
+                    offered: Vec::new(),
+                    peer_supported: Vec::new(),
+                }
+            }
+            Libp2pQuicConnectionError::Quinn(ConnectionError::TransportError(e)) => {
+                Error::TransportError {
+                    code: e.code,
+                    reason: e.reason,
+                }
+            }
+            err => Error::Established(err),
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Io(e) => io::Error::new(e.kind(), Error::Io(e)),
+            Error::Established(Libp2pQuicConnectionError::Quinn(e)) => e.into(),
+            e @ Error::Established(_) => io::Error::new(io::ErrorKind::ConnectionAborted, e),
+            e @ Error::Reach(ConnectError::InvalidRemoteAddress(_))
+            | e @ Error::Reach(ConnectError::InvalidDnsName(_))
+            | e @ Error::DialToSelf
+            | e @ Error::ClientOnly
+            | e @ Error::ServerOnly => io::Error::new(io::ErrorKind::InvalidInput, e),
+            e @ Error::Reach(_) | e @ Error::DriverFailed => {
+                io::Error::new(io::ErrorKind::Other, e)
+            }
+            e @ Error::HandshakeTimeout | e @ Error::TimedOut => {
+                io::Error::new(io::ErrorKind::TimedOut, e)
+            }
+            e @ Error::VersionNegotiationFailed { .. } | e @ Error::TransportError { .. } => {
+                io::Error::new(io::ErrorKind::InvalidData, e)
+            }
+            e @ Error::PeerIdMismatch { .. }
+            | e @ Error::MissingCertificate
+            | e @ Error::InvalidCertificate(_)
+            | e @ Error::CertificateRejected(_) => io::Error::new(io::ErrorKind::InvalidData, e),
+            e @ Error::EndpointClosed => io::Error::new(io::ErrorKind::NotConnected, e),
+        }
+    }
+}
+
+/// Stream of events of a listening [`QuicTransport`].
+///
+/// The first listener on the address the [`Endpoint`] of the transport has been configured with
+/// uses that endpoint. Listeners on other addresses, such as an IPv6 listener next to an IPv4
+/// one, get an endpoint of their own, built from the same configuration.
+///
+/// The stream ends once the endpoint has been closed, either with [`Listener::close`] or
+/// [`Endpoint::close_now`]. Merely dropping the listener doesn't stop the endpoint, which
+/// keeps accepting connections as long as the transport is alive.
+///
+/// Errors on the UDP socket are produced as [`ListenerEvent::Error`] and the listener keeps
+/// accepting connections. Failures that only concern a single connection, such as a remote
+/// presenting an invalid certificate, are reported by the [`Upgrade`] of the connection alone.
+/// Packets that don't lead to a connection at all, such as malformed initial packets or packets
+/// of an unsupported version of QUIC, are dropped by the endpoint without being reported. The
+/// only `Err` item is [`Error::DriverFailed`], which ends the stream.
+pub struct Listener {
+    /// Endpoint the events are coming from.
+    endpoint: Arc<Endpoint>,
+    /// Events of the endpoint, turned into listener events.
+    events: Pin<Box<dyn Stream<Item = Result<ListenerEvent<Upgrade, Error>, Error>> + Send>>,
+}
+
+impl Listener {
+    /// Stops accepting connections, closes the endpoint, and waits for its background task to
+    /// have finished, at which point the UDP socket has been closed.
+    ///
+    /// This also terminates the connections of the endpoint. If the endpoint is the one the
+    /// transport has been built with, further attempts at dialing fail as well.
+    pub async fn close(self) {
+        self.endpoint.close_now().await
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.endpoint.stop_listening();
+    }
+}
+
+impl Stream for Listener {
+    type Item = Result<ListenerEvent<Upgrade, Error>, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.events.poll_next_unpin(cx)
+    }
+}
+
+impl fmt::Debug for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Listener")
+            .field("endpoint", &self.endpoint)
+            .finish()
+    }
 }
 
 impl Transport for QuicTransport {
     type Output = (PeerId, QuicMuxer);
     type Error = Error;
-    type Listener = Pin<
-        Box<dyn Stream<Item = Result<ListenerEvent<Upgrade, Self::Error>, Self::Error>> + Send>,
-    >;
+    type Listener = Listener;
     type ListenerUpgrade = Upgrade;
     type Dial = Pin<Box<dyn Future<Output = Result<Self::Output, Self::Error>> + Send>>;
 
     fn listen_on(self, addr: Multiaddr) -> Result<Self::Listener, TransportError<Self::Error>> {
-        // TODO: check address correctness
+        let span = tracing::debug_span!(
+            "listen_on",
+            %addr,
+            local = tracing::field::Empty,
+            outcome = tracing::field::Empty,
+            error = tracing::field::Empty,
+        );
+        let _enter = span.enter();
+        let result = self.listen_on_inner(addr);
+        if let Ok(listener) = &result {
+            span.record(
+                "local",
+                &tracing::field::display(listener.endpoint.local_addr()),
+            );
+        }
+        record_outcome(&span, &result);
+        result
+    }
+
+    fn dial(self, addr: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
+        self.dial_with(addr, false)
+    }
+}
 
-        // TODO: report the locally opened addresses
+impl QuicTransport {
+    /// Implementation of [`Transport::listen_on`], without the span.
+    fn listen_on_inner(self, addr: Multiaddr) -> Result<Listener, TransportError<Error>> {
+        // Checked first, so that other transports get a chance to listen on the address.
+        if multiaddr_to_socketaddr(&addr).is_err() {
+            return Err(TransportError::MultiaddrNotSupported(addr));
+        }
+        if !self.0.can_listen() {
+            return Err(TransportError::Other(Error::ClientOnly));
+        }
+        let endpoint = self
+            .0
+            .listener_endpoint(&addr)
+            .map_err(|err| err.map(Error::Io))?;
+        let events = listener_events(incoming(endpoint.clone()), endpoint.local_addr());
+        Ok(Listener {
+            events: events.boxed(),
+            endpoint,
+        })
+    }
+
+    /// Similar to [`Transport::dial`], except that the returned future resolves before the
+    /// handshake has completed when resuming a session in which the remote allowed 0-RTT.
+    ///
+    /// Data written on the muxer is then sent to the remote alongside the handshake. This
+    /// requires [`Config::with_0rtt_unchecked`](crate::Config::with_0rtt_unchecked), whose
+    /// documentation explains the caveats of 0-RTT data, and a previous connection to the
+    /// same remote. [`QuicMuxer::accepted_0rtt`] tells whether the remote has accepted the data.
+    ///
+    /// As the remote can't be identified before the handshake has completed, 0-RTT is only
+    /// attempted if `addr` ends with a `/p2p` component. The muxer closes the connection if the
+    /// certificate of the remote later turns out to belong to another peer. Otherwise, this
+    /// behaves like [`Transport::dial`].
+    pub fn dial_0rtt(
+        self,
+        addr: Multiaddr,
+    ) -> Result<<Self as Transport>::Dial, TransportError<Error>> {
+        self.dial_with(addr, true)
+    }
 
-        Ok(stream::unfold((), move |()| {
-            let endpoint = self.0.clone();
-            let addr = addr.clone();
+    /// Implementation of [`Transport::dial`] and [`QuicTransport::dial_0rtt`].
+    fn dial_with(
+        self,
+        addr: Multiaddr,
+        early_data: bool,
+    ) -> Result<<Self as Transport>::Dial, TransportError<Error>> {
+        let span = dial_span(&addr, false);
+        let result = span.in_scope(|| self.dial_with_inner(addr, early_data, span.clone()));
+        if result.is_err() {
+            record_outcome(&span, &result);
+        }
+        result
+    }
+
+    /// Implementation of [`QuicTransport::dial_with`], whose future reports its outcome in
+    /// `span`.
+    fn dial_with_inner(
+        self,
+        addr: Multiaddr,
+        early_data: bool,
+        span: tracing::Span,
+    ) -> Result<<Self as Transport>::Dial, TransportError<Error>> {
+        let (socket_addr, expected) = dial_socket_addr(addr.clone())?;
+        span.record("remote", &tracing::field::display(socket_addr));
+        if !self.0.can_dial() {
+            return Err(TransportError::Other(Error::ServerOnly));
+        }
+        if self.0.is_local_addr(&socket_addr) {
+            return Err(TransportError::Other(Error::DialToSelf));
+        }
+        let endpoint = self.0.dialer_endpoint(&socket_addr);
+        let handshake_timeout = endpoint.handshake_timeout();
+        let retry = endpoint.dial_retry();
+        let metrics = endpoint.muxer_config().metrics.clone();
+
+        let attempt = move || {
+            let endpoint = endpoint.clone();
+            let expected = expected.clone();
             async move {
-                let event = match endpoint.next_incoming().await {
-                    Left(connec) => {
-                        let remote_addr = socketaddr_to_multiaddr(&connec.remote_addr());
-                        Ok(ListenerEvent::Upgrade {
-                            upgrade: Upgrade::from_connection(connec),
-                            local_addr: addr.clone(), // TODO: hack
-                            remote_addr,
-                        })
+                let connection = endpoint.dial(socket_addr).await?;
+                if let Some(expected) = expected.as_ref().filter(|_| early_data) {
+                    if connection.has_0rtt() {
+                        if expected == endpoint.local_peer_id() {
+                            return Err(Error::DialToSelf);
+                        }
+                        let muxer = QuicMuxer::from_0rtt_connection(connection, expected.clone());
+                        return Ok((expected.clone(), muxer));
                     }
-                    Right(multiaddr) => Ok(ListenerEvent::NewAddress(multiaddr)),
-                };
-                Some((event, ()))
+                }
+                let (peer_id, muxer) = Upgrade::from_connection(connection).await?;
+                // The remote might be another process using our keypair, or might be ourselves
+                // behind an address we don't know of, such as the public address of a NAT.
+                if peer_id == *endpoint.local_peer_id() {
+                    return Err(Error::DialToSelf);
+                }
+                check_peer_id(expected, peer_id)?;
+                Ok((peer_id, muxer))
             }
-        })
-        .boxed())
+            .boxed()
+        };
+        let dial = with_retries(attempt, handshake_timeout, retry);
+        Ok(record_dial(dial, metrics, span).boxed())
     }
 
-    fn dial(self, addr: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
-        let socket_addr = if let Ok(socket_addr) = multiaddr_to_socketaddr(&addr) {
-            if socket_addr.port() == 0 || socket_addr.ip().is_unspecified() {
-                return Err(TransportError::MultiaddrNotSupported(addr));
+    /// Connects to `addr` as part of a simultaneous open, in order to traverse NATs.
+    ///
+    /// Instead of initiating the handshake, packets are sent to `addr` in order to open the
+    /// mappings of the NATs on the path, while waiting for the remote to dial us with
+    /// [`Transport::dial`] at the same time. The resulting connection is handed over to the
+    /// returned future rather than being reported to the listener. The remote must dial the
+    /// address that the local endpoint is seen at.
+    ///
+    /// Racing outgoing handshakes in both directions would leave each side free to pick a
+    /// different connection, which is why only one of the sides initiates the handshake.
+    ///
+    /// The future never resolves if the remote doesn't dial us, unless
+    /// [`Config::with_handshake_timeout`](crate::Config::with_handshake_timeout) is set.
+    pub fn dial_as_listener(
+        self,
+        addr: Multiaddr,
+    ) -> Result<<Self as Transport>::Dial, TransportError<Error>> {
+        let span = dial_span(&addr, true);
+        let result = span.in_scope(|| self.dial_as_listener_inner(addr, span.clone()));
+        if result.is_err() {
+            record_outcome(&span, &result);
+        }
+        result
+    }
+
+    /// Implementation of [`QuicTransport::dial_as_listener`], whose future reports its outcome
+    /// in `span`.
+    fn dial_as_listener_inner(
+        self,
+        addr: Multiaddr,
+        span: tracing::Span,
+    ) -> Result<<Self as Transport>::Dial, TransportError<Error>> {
+        let (socket_addr, expected) = dial_socket_addr(addr.clone())?;
+        span.record("remote", &tracing::field::display(socket_addr));
+        // The remote dials us, so the endpoint has to accept incoming connections.
+        if !self.0.can_listen() {
+            return Err(TransportError::Other(Error::ClientOnly));
+        }
+        let handshake_timeout = self.0.handshake_timeout();
+        let metrics = self.0.muxer_config().metrics.clone();
+
+        let dial = async move {
+            let mut incoming = self.0.expect_connection(socket_addr).await?;
+            let connection = loop {
+                self.0
+                    .send_udp_packet(socket_addr, hole_punch_packet())
+                    .await;
+                match future::select(incoming, futures_timer::Delay::new(HOLE_PUNCH_INTERVAL)).await
+                {
+                    future::Either::Left((Ok(connection), _)) => break connection,
+                    future::Either::Left((Err(_), _)) => return Err(Error::EndpointClosed),
+                    future::Either::Right(((), rx)) => incoming = rx,
+                }
+            };
+            let (peer_id, muxer) = Upgrade::from_connection(connection).await?;
+            if peer_id == *self.0.local_peer_id() {
+                return Err(Error::DialToSelf);
             }
-            socket_addr
-        } else {
-            return Err(TransportError::MultiaddrNotSupported(addr));
+            check_peer_id(expected, peer_id)?;
+            Ok((peer_id, muxer))
         };
+        Ok(record_dial(with_timeout(dial.boxed(), handshake_timeout), metrics, span).boxed())
+    }
+}
+
+/// Builds the span of a dial to `addr`. The remote socket address, the identity of the remote
+/// and the outcome are recorded in it as they become known.
+fn dial_span(addr: &Multiaddr, as_listener: bool) -> tracing::Span {
+    tracing::debug_span!(
+        "dial",
+        %addr,
+        as_listener,
+        remote = tracing::field::Empty,
+        peer = tracing::field::Empty,
+        outcome = tracing::field::Empty,
+        error = tracing::field::Empty,
+    )
+}
 
-        Ok(async move {
-            let connection = self.0.dial(socket_addr).await.map_err(Error::Reach)?;
-            let final_connec = Upgrade::from_connection(connection).await?;
-            Ok(final_connec)
+/// Records whether `result` is a success in the `outcome` field of `span`, along with the
+/// error in case of failure.
+fn record_outcome<T, E: fmt::Display>(span: &tracing::Span, result: &Result<T, E>) {
+    match result {
+        Ok(_) => {
+            span.record("outcome", &"success");
+        }
+        Err(err) => {
+            span.record("outcome", &"failure");
+            span.record("error", &tracing::field::display(err));
         }
-        .boxed())
     }
+    span.in_scope(|| tracing::debug!(success = result.is_ok(), "done"));
 }
 
-/// Tries to turn a QUIC multiaddress into a UDP [`SocketAddr`]. Returns an error if the format
-/// of the multiaddr is wrong.
-pub(crate) fn multiaddr_to_socketaddr(addr: &Multiaddr) -> Result<SocketAddr, ()> {
-    let mut iter = addr.iter();
-    let proto1 = iter.next().ok_or(())?;
-    let proto2 = iter.next().ok_or(())?;
-    let proto3 = iter.next().ok_or(())?;
+/// Interval at which [`QuicTransport::dial_as_listener`] sends packets to the remote.
+const HOLE_PUNCH_INTERVAL: Duration = Duration::from_millis(200);
 
-    if iter.next().is_some() {
-        return Err(());
+/// Builds a packet whose only purpose is to open the mappings of the NATs between us and a
+/// remote. It is too short to be processed by the QUIC implementation of the remote.
+fn hole_punch_packet() -> Vec<u8> {
+    let mut packet = vec![0; 8];
+    // The content of the packet doesn't matter, randomness just avoids making it recognizable.
+    let _ = ring::rand::SystemRandom::new().fill(&mut packet);
+    packet
+}
+
+/// Turns the address to dial into a UDP socket address, and the peer designated by its
+/// optional trailing `/p2p` component.
+fn dial_socket_addr(
+    addr: Multiaddr,
+) -> Result<(SocketAddr, Option<PeerId>), TransportError<Error>> {
+    let mut socket_multiaddr = addr.clone();
+    let expected = match socket_multiaddr.iter().last() {
+        Some(Protocol::P2p(multihash)) => {
+            socket_multiaddr.pop();
+            match PeerId::from_multihash(multihash) {
+                Ok(peer_id) => Some(peer_id),
+                Err(_) => return Err(TransportError::MultiaddrNotSupported(addr)),
+            }
+        }
+        _ => None,
+    };
+
+    // Addresses with an unspecified IP address or port 0 designate no remote in particular.
+    match multiaddr_to_socketaddr(&socket_multiaddr) {
+        Ok(socket_addr) if socket_addr.port() != 0 && !socket_addr.ip().is_unspecified() => {
+            Ok((socket_addr, expected))
+        }
+        _ => Err(TransportError::MultiaddrNotSupported(addr)),
+    }
+}
+
+/// Fails with [`Error::PeerIdMismatch`] if a peer was `expected` and `actual` is another one.
+fn check_peer_id(expected: Option<PeerId>, actual: PeerId) -> Result<(), Error> {
+    match expected {
+        Some(expected) if expected != actual => Err(Error::PeerIdMismatch { expected, actual }),
+        _ => Ok(()),
     }
+}
 
-    match (proto1, proto2, proto3) {
-        (Protocol::Ip4(ip), Protocol::Udp(port), Protocol::Quic) => {
-            Ok(SocketAddr::new(ip.into(), port))
+/// Fails with [`Error::HandshakeTimeout`] if `dial` doesn't complete within `timeout`.
+async fn with_timeout<T>(
+    dial: Pin<Box<dyn Future<Output = Result<T, Error>> + Send>>,
+    timeout: Option<Duration>,
+) -> Result<T, Error> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return dial.await,
+    };
+    match future::select(dial, futures_timer::Delay::new(timeout)).await {
+        future::Either::Left((result, _)) => result,
+        future::Either::Right(((), _)) => Err(Error::HandshakeTimeout),
+    }
+}
+
+/// Runs `attempt` with [`with_timeout`] until it succeeds, fails with an error other than a
+/// timeout, or `retry` allows no more attempts. As documented by
+/// [`Config::with_dial_retry`](crate::Config::with_dial_retry), [`Error::TimedOut`] only
+/// counts as a timeout if there is no handshake `timeout`.
+async fn with_retries<T>(
+    mut attempt: impl FnMut() -> Pin<Box<dyn Future<Output = Result<T, Error>> + Send>>,
+    timeout: Option<Duration>,
+    retry: Option<DialRetry>,
+) -> Result<T, Error> {
+    let mut delays = retry.into_iter().flat_map(DialRetry::delays);
+    loop {
+        let result = with_timeout(attempt(), timeout).await;
+        let timed_out = match result {
+            Err(Error::HandshakeTimeout) => true,
+            Err(Error::TimedOut) => timeout.is_none(),
+            _ => false,
+        };
+        match result {
+            Err(err) if timed_out => match delays.next() {
+                Some(delay) => {
+                    tracing::debug!(?delay, "dial timed out, attempting again: {}", err);
+                    futures_timer::Delay::new(delay).await;
+                }
+                None => return Err(err),
+            },
+            result => return result,
         }
-        (Protocol::Ip6(ip), Protocol::Udp(port), Protocol::Quic) => {
-            Ok(SocketAddr::new(ip.into(), port))
+    }
+}
+
+/// Reports the latency of `dial` to `metrics` if it succeeds, or its failure if it times out.
+/// Other failures of the handshake are reported by the [`Upgrade`]. The identity of the remote
+/// and the outcome are recorded in `span`.
+async fn record_dial(
+    dial: impl Future<Output = Result<(PeerId, QuicMuxer), Error>>,
+    metrics: Metrics,
+    span: tracing::Span,
+) -> Result<(PeerId, QuicMuxer), Error> {
+    let started = Instant::now();
+    let result = dial.await;
+    if let Ok((peer_id, _)) = &result {
+        span.record("peer", &tracing::field::display(peer_id));
+    }
+    record_outcome(&span, &result);
+    match &result {
+        Ok(_) => metrics.dial_succeeded(started.elapsed()),
+        Err(err @ Error::HandshakeTimeout) => metrics.handshake_failed(Direction::Outbound, err),
+        Err(_) => {}
+    }
+    result
+}
+
+/// Returns the items produced by the background task of `endpoint`. The stream ends once the
+/// background task has stopped.
+fn incoming(endpoint: Arc<Endpoint>) -> impl Stream<Item = Incoming> {
+    stream::unfold(endpoint, |endpoint| async move {
+        let incoming = endpoint.next_incoming().await?;
+        Some((incoming, endpoint))
+    })
+}
+
+/// Turns the items produced by an endpoint into listener events.
+///
+/// Errors are reported without ending the stream, which only ends alongside `incoming`.
+fn listener_events(
+    incoming: impl Stream<Item = Incoming>,
+    local_socket_addr: SocketAddr,
+) -> impl Stream<Item = Result<ListenerEvent<Upgrade, Error>, Error>> {
+    incoming.map(move |incoming| match incoming {
+        Incoming::Connection(connec) => {
+            let remote_socket_addr = unmap_ipv4(connec.remote_addr());
+            // Listening on a wildcard address must not report the wildcard address as the local
+            // address of the connections, as that would mislead address discovery.
+            let local_addr = endpoint::local_addr_towards(local_socket_addr, &remote_socket_addr)
+                .unwrap_or_else(|err| {
+                    tracing::warn!(
+                        "Failed to determine the local address towards {}: {}",
+                        remote_socket_addr,
+                        err
+                    );
+                    local_socket_addr
+                });
+            Ok(ListenerEvent::Upgrade {
+                upgrade: Upgrade::from_connection(connec),
+                local_addr: socketaddr_to_multiaddr(&local_addr),
+                remote_addr: socketaddr_to_multiaddr(&remote_socket_addr),
+            })
+        }
+        Incoming::NewAddress(multiaddr) => Ok(ListenerEvent::NewAddress(multiaddr)),
+        Incoming::AddressExpired(multiaddr) => Ok(ListenerEvent::AddressExpired(multiaddr)),
+        Incoming::Error(err) => Ok(ListenerEvent::Error(Error::Io(err))),
+        Incoming::DriverFailed => Err(Error::DriverFailed),
+    })
+}
+
+/// Turns an IPv4-mapped IPv6 address, as reported by a dual-stack socket for IPv4 remotes,
+/// into the corresponding IPv4 address. Other addresses are returned unchanged.
+fn unmap_ipv4(socket_addr: SocketAddr) -> SocketAddr {
+    if let SocketAddr::V6(addr) = socket_addr {
+        if let [0, 0, 0, 0, 0, 0xffff, high, low] = addr.ip().segments() {
+            let ip = Ipv4Addr::from((u32::from(high) << 16) | u32::from(low));
+            return SocketAddr::new(ip.into(), addr.port());
         }
-        _ => Err(()),
     }
+    socket_addr
 }
 
-/// Turns an IP address and port into the corresponding QUIC multiaddr.
-pub(crate) fn socketaddr_to_multiaddr(socket_addr: &SocketAddr) -> Multiaddr {
-    Multiaddr::empty()
-        .with(socket_addr.ip().into())
-        .with(Protocol::Udp(socket_addr.port()))
-        .with(Protocol::Quic)
+#[cfg(test)]
+#[test]
+fn ipv4_mapped_addresses_are_unmapped() {
+    let mapped: SocketAddr = "[::ffff:192.0.2.1]:4001".parse().unwrap();
+    assert_eq!(unmap_ipv4(mapped), "192.0.2.1:4001".parse().unwrap());
+    let ipv6: SocketAddr = "[2001:db8::1]:4001".parse().unwrap();
+    assert_eq!(unmap_ipv4(ipv6), ipv6);
+    let ipv4: SocketAddr = "192.0.2.1:4001".parse().unwrap();
+    assert_eq!(unmap_ipv4(ipv4), ipv4);
 }
 
 #[cfg(test)]
 #[test]
-fn multiaddr_to_udp_conversion() {
-    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+fn listener_survives_errors() {
+    let addr: Multiaddr = "/ip4/127.0.0.1/udp/1234/quic".parse().unwrap();
+    let incoming = stream::iter(vec![
+        Incoming::Error(io::Error::new(io::ErrorKind::Other, "transient")),
+        Incoming::NewAddress(addr.clone()),
+        Incoming::Error(io::Error::new(io::ErrorKind::Other, "transient")),
+        Incoming::AddressExpired(addr.clone()),
+        Incoming::DriverFailed,
+    ]);
+    let local_socket_addr = "127.0.0.1:1234".parse().unwrap();
+    let events =
+        futures::executor::block_on_stream(listener_events(incoming, local_socket_addr).boxed())
+            .collect::<Vec<_>>();
+    assert_eq!(events.len(), 5);
+    assert!(matches!(events[0], Ok(ListenerEvent::Error(Error::Io(_)))));
+    assert!(matches!(&events[1], Ok(ListenerEvent::NewAddress(a)) if *a == addr));
+    assert!(matches!(events[2], Ok(ListenerEvent::Error(Error::Io(_)))));
+    assert!(matches!(&events[3], Ok(ListenerEvent::AddressExpired(a)) if *a == addr));
+    assert!(matches!(events[4], Err(Error::DriverFailed)));
+}
 
-    assert!(
-        multiaddr_to_socketaddr(&"/ip4/127.0.0.1/udp/1234".parse::<Multiaddr>().unwrap()).is_err()
-    );
+#[cfg(test)]
+#[test]
+fn errors_convert_to_io_errors() {
+    let peer_id = || {
+        libp2p_core::identity::Keypair::generate_ed25519()
+            .public()
+            .into_peer_id()
+    };
+    let kind = |err: Error| io::Error::from(err).kind();
 
+    assert_eq!(kind(Error::HandshakeTimeout), io::ErrorKind::TimedOut);
+    assert_eq!(kind(Error::EndpointClosed), io::ErrorKind::NotConnected);
+    assert_eq!(kind(Error::DriverFailed), io::ErrorKind::Other);
+    assert_eq!(kind(Error::DialToSelf), io::ErrorKind::InvalidInput);
+    assert_eq!(kind(Error::ClientOnly), io::ErrorKind::InvalidInput);
+    assert_eq!(kind(Error::ServerOnly), io::ErrorKind::InvalidInput);
     assert_eq!(
-        multiaddr_to_socketaddr(
-            &"/ip4/127.0.0.1/udp/12345/quic"
-                .parse::<Multiaddr>()
-                .unwrap()
-        ),
-        Ok(SocketAddr::new(
-            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
-            12345,
-        ))
+        kind(Error::PeerIdMismatch {
+            expected: peer_id(),
+            actual: peer_id(),
+        }),
+        io::ErrorKind::InvalidData
     );
     assert_eq!(
-        multiaddr_to_socketaddr(
-            &"/ip4/255.255.255.255/udp/8080/quic"
-                .parse::<Multiaddr>()
-                .unwrap()
-        ),
-        Ok(SocketAddr::new(
-            IpAddr::V4(Ipv4Addr::new(255, 255, 255, 255)),
-            8080,
-        ))
+        kind(Error::Io(io::Error::from(io::ErrorKind::ConnectionReset))),
+        io::ErrorKind::ConnectionReset
     );
     assert_eq!(
-        multiaddr_to_socketaddr(&"/ip6/::1/udp/12345/quic".parse::<Multiaddr>().unwrap()),
-        Ok(SocketAddr::new(
-            IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)),
-            12345,
-        ))
+        kind(Error::Established(Libp2pQuicConnectionError::ClosedChannel)),
+        io::ErrorKind::ConnectionAborted
     );
     assert_eq!(
-        multiaddr_to_socketaddr(
-            &"/ip6/ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff/udp/8080/quic"
-                .parse::<Multiaddr>()
-                .unwrap()
-        ),
-        Ok(SocketAddr::new(
-            IpAddr::V6(Ipv6Addr::new(
-                65535, 65535, 65535, 65535, 65535, 65535, 65535, 65535,
-            )),
-            8080,
-        ))
+        kind(Error::Reach(ConnectError::TooManyConnections)),
+        io::ErrorKind::Other
     );
+    assert!(matches!(
+        Error::from(ConnectError::EndpointStopping),
+        Error::EndpointClosed
+    ));
+    assert!(matches!(
+        Error::from(Libp2pQuicConnectionError::Quinn(ConnectionError::TimedOut)),
+        Error::TimedOut
+    ));
+    assert!(matches!(
+        Error::from(Libp2pQuicConnectionError::Quinn(
+            ConnectionError::VersionMismatch
+        )),
+        Error::VersionNegotiationFailed { .. }
+    ));
+    match Error::from(Libp2pQuicConnectionError::VersionNegotiationFailed {
+        offered: vec![0xff00_001b],
+        peer_supported: vec![1],
+    }) {
+        Error::VersionNegotiationFailed {
+            offered,
+            peer_supported,
+        } => {
+            assert_eq!(offered, [0xff00_001b]);
+            assert_eq!(peer_supported, [1]);
+        }
+        err => panic!("unexpected error: {:?}", err),
+    }
+    assert!(matches!(
+        Error::from(Libp2pQuicConnectionError::Quinn(ConnectionError::Reset)),
+        Error::Established(_)
+    ));
+    assert_eq!(kind(Error::TimedOut), io::ErrorKind::TimedOut);
+}
+
+#[cfg(test)]
+#[test]
+fn dial_addresses() {
+    let peer_id = libp2p_core::identity::Keypair::generate_ed25519()
+        .public()
+        .into_peer_id();
+    let addr: Multiaddr = "/ip4/127.0.0.1/udp/1234/quic".parse().unwrap();
+    let socket_addr: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+
+    assert!(matches!(
+        dial_socket_addr(addr.clone()),
+        Ok((a, None)) if a == socket_addr
+    ));
+    let with_peer = addr.with(Protocol::P2p(peer_id.clone().into()));
+    assert!(matches!(
+        dial_socket_addr(with_peer),
+        Ok((a, Some(p))) if a == socket_addr && p == peer_id
+    ));
+
+    for addr in &[
+        "/ip4/0.0.0.0/udp/5/quic",
+        "/ip4/1.2.3.4/udp/0/quic",
+        "/ip4/0.0.0.0/udp/0/quic",
+        "/ip6/::/udp/0/quic",
+        "/ip4/127.0.0.1/udp/1234",
+        "/ip4/127.0.0.1/tcp/1234",
+    ] {
+        let addr: Multiaddr = addr.parse().unwrap();
+        match dial_socket_addr(addr.clone()) {
+            Err(TransportError::MultiaddrNotSupported(a)) => assert_eq!(a, addr),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn idle_timeouts_are_only_retried_without_handshake_timeout() {
+    let retry = DialRetry {
+        max_attempts: 3,
+        initial_delay: Duration::from_millis(0),
+        multiplier: 1,
+    };
+    let attempts = |timeout: Option<Duration>| {
+        let count = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let attempt = {
+            let count = count.clone();
+            move || {
+                count.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                future::ready(Err::<(), _>(Error::TimedOut)).boxed()
+            }
+        };
+        let result = futures::executor::block_on(with_retries(attempt, timeout, Some(retry)));
+        assert!(matches!(result, Err(Error::TimedOut)));
+        count.load(std::sync::atomic::Ordering::SeqCst)
+    };
+    assert_eq!(attempts(None), 3);
+    assert_eq!(attempts(Some(Duration::from_secs(10))), 1);
 }
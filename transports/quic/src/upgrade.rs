@@ -22,58 +22,160 @@
 
 use crate::{
     connection::{Connection, ConnectionEvent},
+    metrics::{Direction, Metrics, TransportMetrics},
     muxer::QuicMuxer,
-    transport, x509,
+    transport,
 };
 
 use futures::prelude::*;
 use libp2p_core::PeerId;
 use std::{
     fmt,
+    net::SocketAddr,
     pin::Pin,
     task::{Context, Poll},
+    time::Instant,
 };
 
 /// A QUIC connection currently being negotiated.
 pub struct Upgrade {
     connection: Option<Connection>,
+    /// Information about the handshake, if it is traced.
+    trace: Option<HandshakeTrace>,
+    /// Where to report a failure of the handshake, alongside the direction of the
+    /// connection.
+    metrics: (Metrics, Direction),
+    /// Span of the handshake, nested in the span of the connection, that its outcome is
+    /// recorded in.
+    span: tracing::Span,
+}
+
+/// State of the trace of a handshake. See [`crate::Config::with_handshake_trace`].
+struct HandshakeTrace {
+    side: quinn_proto::Side,
+    remote: SocketAddr,
+    started: Instant,
 }
 
 impl Upgrade {
     /// Builds an [`Upgrade`] that wraps around a [`Connection`].
     pub(crate) fn from_connection(connection: Connection) -> Self {
+        let trace = if connection.handshake_trace() {
+            Some(HandshakeTrace {
+                side: connection.side(),
+                remote: connection.remote_addr(),
+                started: Instant::now(),
+            })
+        } else {
+            None
+        };
+        milestone(&trace, "started");
+        let span = tracing::debug_span!(
+            parent: connection.span(),
+            "handshake",
+            outcome = tracing::field::Empty,
+            error = tracing::field::Empty,
+        );
+        let metrics = (
+            connection.metrics().clone(),
+            Direction::from_side(connection.side()),
+        );
         Upgrade {
             connection: Some(connection),
+            trace,
+            metrics,
+            span,
         }
     }
+
+    /// Reports that the handshake has failed with `err`, and returns `err`.
+    fn fail(&mut self, err: transport::Error) -> transport::Error {
+        milestone(&self.trace, "failed");
+        self.span.record("outcome", &"failure");
+        self.span.record("error", &tracing::field::display(&err));
+        tracing::debug!("handshake failed: {}", err);
+        let (metrics, direction) = &self.metrics;
+        metrics.handshake_failed(*direction, &err);
+        err
+    }
+
+    /// Returns `true` if the remote has acknowledged the connection, in other words if the
+    /// handshake has completed and, for inbound connections, the address of the remote has been
+    /// validated.
+    ///
+    /// This is `false` for as long as the handshake is in progress, and thus for most of the
+    /// lifetime of an `Upgrade`. Use [`QuicMuxer::is_remote_acknowledged`] once the upgrade has
+    /// succeeded.
+    ///
+    /// [`QuicMuxer::is_remote_acknowledged`]: libp2p_core::StreamMuxer::is_remote_acknowledged
+    pub fn is_remote_acknowledged(&self) -> bool {
+        self.connection
+            .as_ref()
+            .map_or(false, |c| !c.is_handshaking() && !c.is_closed())
+    }
+}
+
+/// Reports that the handshake has reached `step`, if the handshake is traced.
+fn milestone(trace: &Option<HandshakeTrace>, step: &'static str) {
+    if let Some(trace) = trace {
+        tracing::info!(
+            target: "libp2p_quic::handshake",
+            milestone = step,
+            side = ?trace.side,
+            remote = %trace.remote,
+            elapsed = ?trace.started.elapsed(),
+        );
+    }
 }
 
 impl Future for Upgrade {
     type Output = Result<(PeerId, QuicMuxer), transport::Error>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let connection = match self.connection.as_mut() {
+        let this = &mut *self;
+        let connection = match this.connection.as_mut() {
             Some(c) => c,
             None => panic!("Future polled after it has ended"),
         };
+        let span = this.span.clone();
+        let _enter = span.enter();
 
         loop {
             match Connection::poll_event(connection, cx) {
                 Poll::Pending => return Poll::Pending,
                 Poll::Ready(ConnectionEvent::Connected) => {
-                    let mut certificates = connection.peer_certificates().unwrap();
-                    let peer_id =
-                        x509::extract_peerid_or_panic(certificates.next().unwrap().as_der()); // TODO: bad API
-                    let muxer = QuicMuxer::from_connection(self.connection.take().unwrap());
-                    self.connection = None;
-                    return Poll::Ready(Ok((peer_id, muxer)));
+                    milestone(&this.trace, "connected");
+                    let result = connection.remote_peer_id();
+                    if !matches!(result, Err(transport::Error::MissingCertificate)) {
+                        milestone(&this.trace, "certificate_received");
+                    }
+                    let mut connection = this
+                        .connection
+                        .take()
+                        .expect("connection is only taken when the upgrade ends; qed");
+                    return Poll::Ready(match result {
+                        Ok(peer_id) => {
+                            milestone(&this.trace, "peer_identified");
+                            connection.record_peer_id(&peer_id);
+                            this.span.record("outcome", &"success");
+                            tracing::debug!("handshake succeeded");
+                            Ok((peer_id, QuicMuxer::from_connection(connection)))
+                        }
+                        Err(err) => Err(this.fail(err)),
+                    });
                 }
                 Poll::Ready(ConnectionEvent::ConnectionLost(err)) => {
-                    self.connection = None;
-                    return Poll::Ready(Err(transport::Error::Established(err)));
+                    this.connection = None;
+                    return Poll::Ready(Err(this.fail(transport::Error::from(err))));
                 }
+                // Substreams and datagrams are only picked up by the muxer, which queries the
+                // connection directly and therefore doesn't need these events.
                 Poll::Ready(ConnectionEvent::StreamOpened)
-                | Poll::Ready(ConnectionEvent::StreamReadable(_)) => continue,
+                | Poll::Ready(ConnectionEvent::StreamAvailable)
+                | Poll::Ready(ConnectionEvent::StreamReadable(_))
+                | Poll::Ready(ConnectionEvent::DatagramReceived) => continue,
+                // The connection is being closed, and `ConnectionLost` follows.
+                Poll::Ready(ConnectionEvent::MigrationRefused(_)) => continue,
                 // TODO: enumerate the items and explain how they can't happen
                 Poll::Ready(e) => unreachable!("{:?}", e),
             }
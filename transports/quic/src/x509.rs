@@ -26,15 +26,33 @@ mod verifier;
 use std::sync::Arc;
 use thiserror::Error;
 
-pub use verifier::extract_peerid_or_panic;
+pub(crate) use certificate::keypair_from_der;
+pub use certificate::{subject_public_key_info, CertificateParams, KeyType};
+pub use verifier::{
+    extract_peerid, extract_peerid_or_panic, verify_libp2p_certificate, ChainMode, PeerPolicy,
+    TimeMode, VerifiedCertificate, VerifierConfig, DEFAULT_MAX_CERTIFICATE_SIZE,
+};
 
 const LIBP2P_SIGNING_PREFIX: [u8; 21] = *b"libp2p-tls-handshake:";
 const LIBP2P_SIGNING_PREFIX_LENGTH: usize = LIBP2P_SIGNING_PREFIX.len();
 const LIBP2P_OID_BYTES: &[u8] = &[43, 6, 1, 4, 1, 131, 162, 90, 1, 1];
 
+/// Server name passed to rustls when dialing.
+///
+/// Remotes are authenticated by their libp2p extension rather than by a DNS name, but rustls
+/// requires a valid one. The same name is used for every remote, and isn't sent as SNI.
+pub(crate) const SERVER_NAME: &str = "libp2p";
+
+/// Number of sessions remembered by clients for resumption.
+const SESSION_CACHE_SIZE: usize = 256;
+
+/// The ALPN protocol identifier of libp2p, used when no other one is configured.
+pub(crate) const LIBP2P_ALPN: &[u8] = b"libp2p";
+
 /// Error creating a configuration
 // TODO: remove this; what is the user supposed to do with this error?
 #[derive(Debug, Error)]
+#[non_exhaustive]
 pub enum ConfigError {
     /// TLS private key or certificate rejected
     #[error("TLS private or certificate key rejected: {0}")]
@@ -45,45 +63,295 @@ pub enum ConfigError {
     /// Certificate generation error
     #[error("Certificate generation error: {0}")]
     RcgenError(#[from] rcgen::RcgenError),
+    /// Identity key rejected
+    #[error("Identity key rejected: {0}")]
+    KeyDecodingError(#[from] libp2p_core::identity::error::DecodingError),
+    /// PKCS#8 document of an identity key malformed
+    #[error("Malformed PKCS#8 document")]
+    MalformedPkcs8,
 }
 
-fn make_client_config(
+/// Returns the list of ALPN protocols to offer, in order of preference, defaulting to
+/// [`LIBP2P_ALPN`] if `alpn_protocols` is empty.
+fn alpn_protocols_or_default(alpn_protocols: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    if alpn_protocols.is_empty() {
+        vec![LIBP2P_ALPN.to_vec()]
+    } else {
+        alpn_protocols.to_vec()
+    }
+}
+
+/// Builds the TLS configuration used when dialing.
+///
+/// If `session_resumption` is true, the sessions are remembered in order to be resumed with
+/// the session tickets sent by the remotes. Otherwise, a full handshake is always performed.
+pub(crate) fn make_client_config(
     certificate: rustls::Certificate,
     key: rustls::PrivateKey,
-    verifier: Arc<verifier::Libp2pCertificateVerifier>,
+    verifier: VerifierConfig,
+    alpn_protocols: &[Vec<u8>],
+    session_resumption: bool,
 ) -> Result<rustls::ClientConfig, rustls::TLSError> {
+    let verifier = Arc::new(verifier::Libp2pCertificateVerifier::new(verifier));
     let mut crypto = rustls::ClientConfig::new();
     crypto.versions = vec![rustls::ProtocolVersion::TLSv1_3];
-    crypto.alpn_protocols = vec![b"libp2p".to_vec()];
+    crypto.alpn_protocols = alpn_protocols_or_default(alpn_protocols);
     crypto.enable_early_data = false;
+    crypto.enable_sni = false;
+    crypto.enable_tickets = session_resumption;
+    if session_resumption {
+        crypto.set_persistence(rustls::ClientSessionMemoryCache::new(SESSION_CACHE_SIZE));
+    } else {
+        crypto.set_persistence(Arc::new(rustls::NoClientSessionStorage {}));
+    }
     crypto.set_single_client_cert(vec![certificate], key)?;
     crypto.dangerous().set_certificate_verifier(verifier);
     Ok(crypto)
 }
 
-fn make_server_config(
+/// Builds the TLS configuration used when accepting connections.
+///
+/// If `session_resumption` is true, session tickets are sent to the remotes once the handshake
+/// has completed, which allows them to resume the session later on. Otherwise, sessions are
+/// never resumed.
+pub(crate) fn make_server_config(
     certificate: rustls::Certificate,
     key: rustls::PrivateKey,
-    verifier: Arc<verifier::Libp2pCertificateVerifier>,
+    verifier: VerifierConfig,
+    alpn_protocols: &[Vec<u8>],
+    session_resumption: bool,
 ) -> Result<rustls::ServerConfig, rustls::TLSError> {
+    let verifier = Arc::new(verifier::Libp2pCertificateVerifier::new(verifier));
     let mut crypto = rustls::ServerConfig::new(verifier);
     crypto.versions = vec![rustls::ProtocolVersion::TLSv1_3];
-    crypto.alpn_protocols = vec![b"libp2p".to_vec()];
+    crypto.alpn_protocols = alpn_protocols_or_default(alpn_protocols);
+    if session_resumption {
+        crypto.ticketer = rustls::Ticketer::new();
+    } else {
+        crypto.session_storage = Arc::new(rustls::NoServerSessionStorage {});
+    }
     crypto.set_single_cert(vec![certificate], key)?;
     Ok(crypto)
 }
 
+/// Generates a certificate for libp2p, and returns it alongside its private key.
+pub(crate) fn make_certificate(
+    keypair: &libp2p_core::identity::Keypair,
+    params: &CertificateParams,
+) -> Result<(rustls::Certificate, rustls::PrivateKey), ConfigError> {
+    let cert = certificate::make_cert(&keypair, params)?;
+    let private_key = cert.serialize_private_key_der();
+    Ok((
+        rustls::Certificate(cert.serialize_der()?),
+        rustls::PrivateKey(private_key),
+    ))
+}
+
+/// Generates a certificate for libp2p from a DER-encoded identity key, and returns it alongside
+/// its private key.
+///
+/// This is the certificate [`make_tls_config`] would generate for the identity keypair, for
+/// users who store it as bytes, for instance in a file. The libp2p extension of the certificate
+/// is signed by the identity key, while the returned private key is the one of the certificate,
+/// as expected by [`rustls::ClientConfig::set_single_client_cert`] and
+/// [`rustls::ServerConfig::set_single_cert`].
+pub fn make_certificate_from_der(
+    key_bytes: &[u8],
+    key_type: KeyType,
+    params: &CertificateParams,
+) -> Result<(rustls::Certificate, rustls::PrivateKey), ConfigError> {
+    let keypair = certificate::keypair_from_der(key_bytes, key_type)?;
+    make_certificate(&keypair, params)
+}
+
 /// Create TLS client and server configurations for libp2p.
 pub fn make_tls_config(
     keypair: &libp2p_core::identity::Keypair,
 ) -> Result<(rustls::ClientConfig, rustls::ServerConfig), ConfigError> {
-    let cert = certificate::make_cert(&keypair)?;
-    let private_key = cert.serialize_private_key_der();
-    let verifier = Arc::new(verifier::Libp2pCertificateVerifier);
-    let cert = rustls::Certificate(cert.serialize_der()?);
-    let key = rustls::PrivateKey(private_key);
+    make_tls_config_with_alpn(keypair, &[])
+}
+
+/// Similar to [`make_tls_config`], except that the given ALPN protocols are offered, in order
+/// of preference, instead of just `b"libp2p"`. An empty list means `b"libp2p"`.
+///
+/// Note that the protocol is selected by the server: it picks the first protocol of its own
+/// list that the client also supports.
+pub fn make_tls_config_with_alpn(
+    keypair: &libp2p_core::identity::Keypair,
+    alpn_protocols: &[Vec<u8>],
+) -> Result<(rustls::ClientConfig, rustls::ServerConfig), ConfigError> {
+    let (certificate, key) = make_certificate(keypair, &CertificateParams::default())?;
     Ok((
-        make_client_config(cert.clone(), key.clone(), verifier.clone())?,
-        make_server_config(cert, key, verifier)?,
+        make_client_config(
+            certificate.clone(),
+            key.clone(),
+            VerifierConfig::default(),
+            alpn_protocols,
+            false,
+        )?,
+        make_server_config(
+            certificate,
+            key,
+            VerifierConfig::default(),
+            alpn_protocols,
+            false,
+        )?,
     ))
 }
+
+/// Similar to [`make_tls_config`], except that the client and server configurations use
+/// distinct certificates, generated from `client_params` and `server_params` respectively.
+///
+/// Both certificates are signed by `keypair` and thus identify the same peer.
+pub fn make_tls_config_split(
+    keypair: &libp2p_core::identity::Keypair,
+    client_params: &CertificateParams,
+    server_params: &CertificateParams,
+) -> Result<(rustls::ClientConfig, rustls::ServerConfig), ConfigError> {
+    let (client_cert, client_key) = make_certificate(keypair, client_params)?;
+    let (server_cert, server_key) = make_certificate(keypair, server_params)?;
+    Ok((
+        make_client_config(
+            client_cert,
+            client_key,
+            VerifierConfig::default(),
+            &[],
+            false,
+        )?,
+        make_server_config(
+            server_cert,
+            server_key,
+            VerifierConfig::default(),
+            &[],
+            false,
+        )?,
+    ))
+}
+
+#[cfg(test)]
+#[test]
+fn split_certificates_share_peer_id() {
+    use std::time::{Duration, SystemTime};
+
+    let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+    let now = SystemTime::now();
+    let day = Duration::from_secs(24 * 3600);
+    let client_params = CertificateParams::default().with_validity(now - day, now + day);
+    let server_params = CertificateParams::default().with_validity(now + 10 * day, now + 20 * day);
+    make_tls_config_split(&keypair, &client_params, &server_params).unwrap();
+
+    let (client_cert, _) = make_certificate(&keypair, &client_params).unwrap();
+    let (server_cert, _) = make_certificate(&keypair, &server_params).unwrap();
+    let peer_id = keypair.public().into_peer_id();
+    assert_eq!(extract_peerid_or_panic(&client_cert.0), peer_id);
+    assert_eq!(extract_peerid_or_panic(&server_cert.0), peer_id);
+
+    // Only the client certificate is currently valid.
+    let check_time = VerifierConfig::default();
+    assert!(check_time.verify_presented_certs(&[client_cert]).is_ok());
+    assert!(check_time
+        .verify_presented_certs(&[server_cert.clone()])
+        .is_err());
+    let ignore_time = VerifierConfig::default().with_time_mode(TimeMode::Ignore);
+    assert!(ignore_time.verify_presented_certs(&[server_cert]).is_ok());
+}
+
+#[cfg(test)]
+#[test]
+fn handshakes_use_placeholder_server_name() {
+    use rustls::Session;
+
+    fn transfer(from: &mut dyn Session, to: &mut dyn Session) {
+        let mut buf = Vec::new();
+        while from.wants_write() {
+            from.write_tls(&mut buf).unwrap();
+        }
+        to.read_tls(&mut &buf[..]).unwrap();
+        to.process_new_packets().unwrap();
+    }
+
+    let (client, server) = make_tls_config(&libp2p_core::identity::Keypair::generate_ed25519())
+        .expect("the configuration is valid");
+    assert!(!client.enable_sni);
+    let server_name = webpki::DNSNameRef::try_from_ascii_str(SERVER_NAME).unwrap();
+    let mut client = rustls::ClientSession::new(&Arc::new(client), server_name);
+    let mut server = rustls::ServerSession::new(&Arc::new(server));
+    for _ in 0..10 {
+        if !client.is_handshaking() && !server.is_handshaking() {
+            break;
+        }
+        transfer(&mut client, &mut server);
+        transfer(&mut server, &mut client);
+    }
+    assert!(!client.is_handshaking() && !server.is_handshaking());
+    assert_eq!(server.get_sni_hostname(), None);
+}
+
+#[cfg(test)]
+#[test]
+fn alpn_protocols_default_to_libp2p() {
+    let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+    let (client, server) = make_tls_config(&keypair).unwrap();
+    assert_eq!(client.alpn_protocols, [LIBP2P_ALPN]);
+    assert_eq!(server.alpn_protocols, [LIBP2P_ALPN]);
+
+    let protocols = vec![b"libp2p/2".to_vec(), LIBP2P_ALPN.to_vec()];
+    let (client, server) = make_tls_config_with_alpn(&keypair, &protocols).unwrap();
+    assert_eq!(client.alpn_protocols, protocols);
+    assert_eq!(server.alpn_protocols, protocols);
+}
+
+#[cfg(test)]
+#[test]
+fn certificates_from_der_keys() {
+    use libp2p_core::identity::{ed25519, PublicKey};
+    use ring::signature::KeyPair;
+
+    let verify = |(certificate, _): (rustls::Certificate, _)| {
+        VerifierConfig::default()
+            .verify_presented_certs(&[certificate])
+            .unwrap()
+    };
+
+    // PKCS#8 version 2, which includes the public key.
+    let rng = ring::rand::SystemRandom::new();
+    let document = ring::signature::Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+    let ring_keypair = ring::signature::Ed25519KeyPair::from_pkcs8(document.as_ref()).unwrap();
+    let public = ed25519::PublicKey::decode(ring_keypair.public_key().as_ref()).unwrap();
+    let peer_id = PublicKey::Ed25519(public).into_peer_id();
+    let params = CertificateParams::default();
+    let certificate = make_certificate_from_der(document.as_ref(), KeyType::Ed25519, &params);
+    assert_eq!(verify(certificate.unwrap()), peer_id);
+
+    // PKCS#8 version 1, which only contains the seed.
+    let seed = [7; 32];
+    let mut document = vec![
+        0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04,
+        0x20,
+    ];
+    document.extend_from_slice(&seed);
+    let secret = ed25519::SecretKey::from_bytes(&mut seed.clone()).unwrap();
+    let peer_id = libp2p_core::identity::Keypair::Ed25519(secret.into())
+        .public()
+        .into_peer_id();
+    let certificate = make_certificate_from_der(&document, KeyType::Ed25519, &params);
+    assert_eq!(verify(certificate.unwrap()), peer_id);
+
+    let rsa = include_bytes!("../tests/test-rsa-private-key.pk8");
+    let peer_id = libp2p_core::identity::Keypair::rsa_from_pkcs8(&mut rsa.to_vec())
+        .unwrap()
+        .public()
+        .into_peer_id();
+    let certificate = make_certificate_from_der(rsa, KeyType::Rsa, &params);
+    assert_eq!(verify(certificate.unwrap()), peer_id);
+
+    // An RSA document isn't an Ed25519 one.
+    assert!(matches!(
+        make_certificate_from_der(rsa, KeyType::Ed25519, &params),
+        Err(ConfigError::MalformedPkcs8)
+    ));
+    assert!(matches!(
+        make_certificate_from_der(&document[..20], KeyType::Ed25519, &params),
+        Err(ConfigError::MalformedPkcs8)
+    ));
+}
@@ -22,13 +22,15 @@
 //!
 //! This handles generation, signing, and verification.
 //!
-//! This crate uses the `log` crate to emit log output.  Events that will occur
+//! This crate uses the `tracing` crate to emit log output.  Events that will occur
 //! normally are output at `trace` level, while “expected” error conditions
 //! (ones that can result during correct use of the library) are logged at
 //! `debug` level.
 
 use super::LIBP2P_SIGNING_PREFIX_LENGTH;
 use libp2p_core::identity;
+use ring::io::der;
+use std::time::SystemTime;
 
 const LIBP2P_OID: &[u64] = &[1, 3, 6, 1, 4, 1, 53594, 1, 1];
 const LIBP2P_SIGNATURE_ALGORITHM_PUBLIC_KEY_LENGTH: usize = 65;
@@ -38,11 +40,112 @@ static LIBP2P_SIGNATURE_ALGORITHM: &rcgen::SignatureAlgorithm = &rcgen::PKCS_ECD
 //static LIBP2P_SIGNATURE_ALGORITHM: &rcgen::SignatureAlgorithm =
 // &rcgen::PKCS_ED25519
 
+/// Parameters of a generated certificate.
+///
+/// The default parameters produce a certificate that is valid from 1975 to 4096.
+#[derive(Debug, Clone, Default)]
+pub struct CertificateParams {
+    not_before: Option<SystemTime>,
+    not_after: Option<SystemTime>,
+}
+
+impl CertificateParams {
+    /// Sets the validity period of the certificate.
+    pub fn with_validity(mut self, not_before: SystemTime, not_after: SystemTime) -> Self {
+        self.not_before = Some(not_before);
+        self.not_after = Some(not_after);
+        self
+    }
+}
+
+/// Type of a DER-encoded libp2p identity key. See [`make_certificate_from_der`].
+///
+/// [`make_certificate_from_der`]: crate::make_certificate_from_der
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum KeyType {
+    /// An Ed25519 key in a PKCS#8 document, as defined in RFC 8410.
+    Ed25519,
+    /// An RSA key in a PKCS#8 document.
+    Rsa,
+    /// A secp256k1 key in an `ECPrivateKey` structure, as defined in RFC 5915.
+    Secp256k1,
+}
+
+/// Decodes a libp2p identity keypair from its DER encoding.
+pub(crate) fn keypair_from_der(
+    key_bytes: &[u8],
+    key_type: KeyType,
+) -> Result<identity::Keypair, super::ConfigError> {
+    // The decoding functions erase the secret from the buffer they are given.
+    let mut key_bytes = key_bytes.to_vec();
+    Ok(match key_type {
+        KeyType::Ed25519 => {
+            let seed = ed25519_seed_from_pkcs8(&key_bytes);
+            key_bytes.iter_mut().for_each(|byte| *byte = 0);
+            let seed = seed.map_err(|_| super::ConfigError::MalformedPkcs8)?;
+            identity::Keypair::Ed25519(identity::ed25519::SecretKey::from_bytes(seed)?.into())
+        }
+        KeyType::Rsa => identity::Keypair::rsa_from_pkcs8(&mut key_bytes)?,
+        KeyType::Secp256k1 => identity::Keypair::Secp256k1(
+            identity::secp256k1::SecretKey::from_der(&mut key_bytes)?.into(),
+        ),
+    })
+}
+
+/// Extracts the 32-byte seed of an Ed25519 key from a PKCS#8 document, either version 1 or
+/// version 2.
+fn ed25519_seed_from_pkcs8(document: &[u8]) -> Result<Vec<u8>, ring::error::Unspecified> {
+    // 1.3.101.112
+    const ED25519_OID: &[u8] = &[0x2b, 0x65, 0x70];
+    let e = ring::error::Unspecified;
+
+    untrusted::Input::from(document).read_all(e, |input| {
+        der::nested(input, der::Tag::Sequence, e, |info| {
+            match der::small_nonnegative_integer(info) {
+                Ok(0) | Ok(1) => {}
+                _ => return Err(e),
+            }
+            der::nested(
+                info,
+                der::Tag::Sequence,
+                e,
+                |algorithm| match der::expect_tag_and_get_value(algorithm, der::Tag::OID) {
+                    Ok(oid) if oid.as_slice_less_safe() == ED25519_OID => Ok(()),
+                    _ => Err(e),
+                },
+            )?;
+            let seed = der::nested(info, der::Tag::OctetString, e, |private_key| {
+                der::expect_tag_and_get_value(private_key, der::Tag::OctetString)
+            })?;
+            // The optional attributes and public key are not needed.
+            info.skip_to_end();
+            Ok(seed.as_slice_less_safe().to_vec())
+        })
+    })
+}
+
 /// Generates a self-signed TLS certificate that includes a libp2p-specific
 /// certificate extension containing the public key of the given keypair.
+///
+/// Any type of libp2p identity `keypair` can be used, as it only signs the extension. The
+/// key of the certificate itself, which signs the TLS handshake, is always a new ECDSA P-256
+/// key: `rcgen` can't generate RSA keys, `rustls` 0.17 doesn't accept Ed25519 certificates,
+/// and secp256k1 isn't a TLS 1.3 signature scheme.
 pub(crate) fn make_cert(
     keypair: &identity::Keypair,
+    cert_params: &CertificateParams,
 ) -> Result<rcgen::Certificate, super::ConfigError> {
+    Ok(rcgen::Certificate::from_params(make_cert_params(
+        keypair,
+        cert_params,
+    )?)?)
+}
+
+/// Returns the `rcgen` parameters of the certificate built by [`make_cert`].
+pub(crate) fn make_cert_params(
+    keypair: &identity::Keypair,
+    cert_params: &CertificateParams,
+) -> Result<rcgen::CertificateParams, super::ConfigError> {
     // Keypair used to sign the certificate.
     let certif_keypair = rcgen::KeyPair::generate(&LIBP2P_SIGNATURE_ALGORITHM)?;
 
@@ -53,7 +156,7 @@ pub(crate) fn make_cert(
         assert_eq!(
             certif_pubkey.len(),
             LIBP2P_SIGNATURE_ALGORITHM_PUBLIC_KEY_LENGTH,
-            "ed25519 public keys are {} bytes",
+            "ECDSA P-256 public keys are {} bytes",
             LIBP2P_SIGNATURE_ALGORITHM_PUBLIC_KEY_LENGTH
         );
 
@@ -85,14 +188,185 @@ pub(crate) fn make_cert(
         ext
     };
 
-    let certificate = {
-        let mut params = rcgen::CertificateParams::new(vec![]);
-        params.distinguished_name = rcgen::DistinguishedName::new();
-        params.custom_extensions.push(libp2p_extension);
-        params.alg = &LIBP2P_SIGNATURE_ALGORITHM;
-        params.key_pair = Some(certif_keypair);
-        rcgen::Certificate::from_params(params)?
+    let mut params = rcgen::CertificateParams::new(vec![]);
+    params.distinguished_name = rcgen::DistinguishedName::new();
+    params.custom_extensions.push(libp2p_extension);
+    params.alg = &LIBP2P_SIGNATURE_ALGORITHM;
+    params.key_pair = Some(certif_keypair);
+    if let Some(not_before) = cert_params.not_before {
+        params.not_before = not_before.into();
+    }
+    if let Some(not_after) = cert_params.not_after {
+        params.not_after = not_after.into();
+    }
+    Ok(params)
+}
+
+/// Returns the DER-encoded SubjectPublicKeyInfo of a libp2p certificate.
+///
+/// This is the public key the TLS handshake is authenticated with, which is stable for as long
+/// as the certificate is, and can thus be published and pinned out of band.
+///
+/// Fails if `certificate` isn't a valid libp2p certificate. Its validity period and signature
+/// are not checked.
+pub fn subject_public_key_info(certificate: &[u8]) -> Result<Vec<u8>, webpki::Error> {
+    super::verifier::extract_peerid(certificate)?;
+    let fields = TbsFields::parse(certificate)?;
+    Ok(fields.spki.to_vec())
+}
+
+/// Returns the validity period of a certificate, as `(not_before, not_after)`.
+///
+/// Only the DER structure of `certificate` is checked.
+pub(crate) fn validity(certificate: &[u8]) -> Result<(SystemTime, SystemTime), webpki::Error> {
+    let e = webpki::Error::BadDER;
+    let fields = TbsFields::parse(certificate)?;
+    untrusted::Input::from(fields.validity).read_all(e, |validity| {
+        let not_before = read_time(validity)?;
+        let not_after = read_time(validity)?;
+        Ok((not_before, not_after))
+    })
+}
+
+/// Fields of the `tbsCertificate` of a certificate, as raw DER.
+pub(crate) struct TbsFields<'a> {
+    /// Content of the `issuer` field.
+    pub(crate) issuer: &'a [u8],
+    /// Content of the `validity` field.
+    pub(crate) validity: &'a [u8],
+    /// Content of the `subject` field.
+    pub(crate) subject: &'a [u8],
+    /// The whole `subjectPublicKeyInfo` field, tag and length included.
+    pub(crate) spki: &'a [u8],
+}
+
+impl<'a> TbsFields<'a> {
+    /// Extracts the fields of `certificate`. Only its DER structure is checked.
+    pub(crate) fn parse(certificate: &'a [u8]) -> Result<Self, webpki::Error> {
+        let e = webpki::Error::BadDER;
+        let sequence = |input: &mut untrusted::Reader<'a>| {
+            der::expect_tag_and_get_value(input, der::Tag::Sequence)
+                .map(|value| value.as_slice_less_safe())
+                .map_err(|_| e)
+        };
+        untrusted::Input::from(certificate).read_all(e, |input| {
+            der::nested(input, der::Tag::Sequence, e, |certificate| {
+                let fields = der::nested(certificate, der::Tag::Sequence, e, |tbs| {
+                    // The version is optional, and the serialNumber and signature are not
+                    // needed.
+                    if tbs.peek(der::Tag::ContextSpecificConstructed0 as u8) {
+                        der::expect_tag_and_get_value(tbs, der::Tag::ContextSpecificConstructed0)
+                            .map_err(|_| e)?;
+                    }
+                    der::expect_tag_and_get_value(tbs, der::Tag::Integer).map_err(|_| e)?;
+                    sequence(tbs)?;
+                    let issuer = sequence(tbs)?;
+                    let validity = sequence(tbs)?;
+                    let subject = sequence(tbs)?;
+                    let (spki, _) = tbs.read_partial(sequence)?;
+                    tbs.skip_to_end();
+                    Ok(TbsFields {
+                        issuer,
+                        validity,
+                        subject,
+                        spki: spki.as_slice_less_safe(),
+                    })
+                })?;
+                certificate.skip_to_end();
+                Ok(fields)
+            })
+        })
+    }
+}
+
+/// Reads an ASN.1 `Time`, which is either a `UTCTime` or a `GeneralizedTime` in UTC with a
+/// precision of one second, as required by RFC 5280.
+fn read_time(input: &mut untrusted::Reader<'_>) -> Result<SystemTime, webpki::Error> {
+    const UTC_TIME: u8 = der::Tag::UTCTime as u8;
+    const GENERALIZED_TIME: u8 = der::Tag::GeneralizedTime as u8;
+    let e = webpki::Error::BadDERTime;
+
+    let (tag, value) = der::read_tag_and_get_value(input).map_err(|_| e)?;
+    let value = value.as_slice_less_safe();
+    let (year, rest) = match (tag, value.len()) {
+        (UTC_TIME, 13) => {
+            let year = parse_digits(&value[..2])?;
+            (
+                if year < 50 { 2000 + year } else { 1900 + year },
+                &value[2..],
+            )
+        }
+        (GENERALIZED_TIME, 15) => (parse_digits(&value[..4])?, &value[4..]),
+        _ => return Err(e),
     };
+    if rest[10] != b'Z' {
+        return Err(e);
+    }
+    let (month, day) = (parse_digits(&rest[0..2])?, parse_digits(&rest[2..4])?);
+    let (hours, minutes) = (parse_digits(&rest[4..6])?, parse_digits(&rest[6..8])?);
+    let seconds = parse_digits(&rest[8..10])?;
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hours > 23
+        || minutes > 59
+        || seconds > 59
+    {
+        return Err(e);
+    }
+
+    // Number of days since 1970-01-01 in the proleptic Gregorian calendar.
+    let (y, m) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = y.div_euclid(400);
+    let year_of_era = y - era * 400;
+    let day_of_year = (153 * m + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    let timestamp = days * 86_400 + hours * 3_600 + minutes * 60 + seconds;
+    let offset = std::time::Duration::from_secs(timestamp.abs() as u64);
+    if timestamp >= 0 {
+        SystemTime::UNIX_EPOCH.checked_add(offset)
+    } else {
+        SystemTime::UNIX_EPOCH.checked_sub(offset)
+    }
+    .ok_or(e)
+}
+
+fn parse_digits(digits: &[u8]) -> Result<i64, webpki::Error> {
+    digits.iter().try_fold(0, |n, &digit| match digit {
+        b'0'..=b'9' => Ok(n * 10 + i64::from(digit - b'0')),
+        _ => Err(webpki::Error::BadDERTime),
+    })
+}
+
+#[cfg(test)]
+#[test]
+fn validity_matches_params() {
+    use std::time::Duration;
+
+    let keypair = identity::Keypair::generate_ed25519();
+    let not_before = SystemTime::UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+    let not_after = SystemTime::UNIX_EPOCH + Duration::from_secs(4_102_444_800);
+    let params = CertificateParams::default().with_validity(not_before, not_after);
+    let certificate = make_cert(&keypair, &params)
+        .unwrap()
+        .serialize_der()
+        .unwrap();
+    assert_eq!(validity(&certificate).unwrap(), (not_before, not_after));
 
-    Ok(certificate)
+    // The default validity period uses both encodings of times.
+    let certificate = make_cert(&keypair, &Default::default())
+        .unwrap()
+        .serialize_der()
+        .unwrap();
+    let (not_before, not_after) = validity(&certificate).unwrap();
+    assert_eq!(
+        not_before,
+        SystemTime::UNIX_EPOCH + Duration::from_secs(157_766_400)
+    );
+    assert!(not_after > SystemTime::UNIX_EPOCH + Duration::from_secs(2_000 * 365 * 86_400));
 }
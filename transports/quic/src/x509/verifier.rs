@@ -18,29 +18,452 @@
 // FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
 // DEALINGS IN THE SOFTWARE.
 
-use libp2p_core::identity::PublicKey;
-use ring::io::der;
+use libp2p_core::{identity::PublicKey, PeerId};
+use parking_lot::Mutex;
+use ring::{digest, io::der};
 use rustls::{
     internal::msgs::handshake::DigitallySignedStruct, Certificate, ClientCertVerified,
-    HandshakeSignatureValid, ServerCertVerified, TLSError,
+    HandshakeSignatureValid, ServerCertVerified, SignatureScheme, TLSError,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 use untrusted::{Input, Reader};
 use webpki::Error;
 
+/// Configuration of the verification of the certificates presented by remotes.
+///
+/// Regardless of the configuration, a certificate is only ever accepted if it is self-signed,
+/// which means that its issuer is identical to its subject and that it is signed with its own
+/// key, and has a valid libp2p extension. The options of this struct are orthogonal to each other
+/// and further restrict, or in the case of [`TimeMode::Ignore`] and [`ChainMode::Lenient`]
+/// relax, the set of accepted certificates.
+///
+/// The default configuration accepts any peer presenting exactly one certificate of at most
+/// [`DEFAULT_MAX_CERTIFICATE_SIZE`] bytes that is valid at the current time.
+#[derive(Clone)]
+pub struct VerifierConfig {
+    expected_peer: Option<PeerId>,
+    policy: PeerPolicy,
+    time_mode: TimeMode,
+    chain_mode: ChainMode,
+    accepted_signature_schemes: Option<Vec<SignatureScheme>>,
+    cache: Option<Arc<VerificationCache>>,
+    max_certificate_size: usize,
+}
+
+/// Default maximum size of the certificates accepted from remotes, in bytes. See
+/// [`VerifierConfig::with_max_certificate_size`].
+///
+/// The certificates generated by libp2p implementations are well below 1 KiB with Ed25519 and
+/// secp256k1 identities, and below 2 KiB with 4096-bit RSA identities.
+pub const DEFAULT_MAX_CERTIFICATE_SIZE: usize = 8 * 1024;
+
+/// Decides which peers are allowed to connect. See [`VerifierConfig::with_policy`].
+#[derive(Clone)]
+pub enum PeerPolicy {
+    /// All peers are accepted.
+    AcceptAll,
+    /// Only the peers in the set are accepted.
+    Allowlist(HashSet<PeerId>),
+    /// The peers for which the function returns `true` are accepted.
+    Custom(Arc<dyn Fn(&PeerId) -> bool + Send + Sync>),
+}
+
+/// Whether the validity period of certificates is checked. See
+/// [`VerifierConfig::with_time_mode`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TimeMode {
+    /// Certificates are rejected outside of their validity period.
+    Check,
+    /// The validity period of certificates is ignored.
+    Ignore,
+}
+
+/// How certificate chains are handled. See [`VerifierConfig::with_chain_mode`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ChainMode {
+    /// Exactly one certificate must be presented.
+    Strict,
+    /// The first certificate is verified, and any additional certificate is ignored.
+    Lenient,
+}
+
+impl VerifierConfig {
+    /// Only accepts remotes whose certificate corresponds to the given [`PeerId`].
+    pub fn with_expected_peer(mut self, peer: PeerId) -> Self {
+        self.expected_peer = Some(peer);
+        self
+    }
+
+    /// Sets the policy deciding which peers are accepted.
+    ///
+    /// Defaults to [`PeerPolicy::AcceptAll`].
+    pub fn with_policy(mut self, policy: PeerPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Sets whether the validity period of certificates is checked.
+    ///
+    /// Defaults to [`TimeMode::Check`].
+    pub fn with_time_mode(mut self, time_mode: TimeMode) -> Self {
+        self.time_mode = time_mode;
+        self
+    }
+
+    /// Sets how certificate chains are handled.
+    ///
+    /// Defaults to [`ChainMode::Strict`].
+    pub fn with_chain_mode(mut self, chain_mode: ChainMode) -> Self {
+        self.chain_mode = chain_mode;
+        self
+    }
+
+    /// Only accepts TLS 1.3 handshake signatures using one of the given schemes.
+    ///
+    /// By default, all the schemes supported by the certificate are accepted.
+    pub fn with_signature_schemes(mut self, schemes: Vec<SignatureScheme>) -> Self {
+        self.accepted_signature_schemes = Some(schemes);
+        self
+    }
+
+    /// Remembers the result of the verification of up to `capacity` certificates, so that
+    /// remotes presenting the same certificate again, for instance when reconnecting, are
+    /// verified without parsing the certificate and checking its signatures again.
+    ///
+    /// Certificates are identified by their SHA-256 digest. A certificate is verified again
+    /// once the current time is outside of its validity period, unless [`TimeMode::Ignore`] is
+    /// used. The least recently used certificate is forgotten when the cache is full. The
+    /// expected peer and the [`PeerPolicy`] are always checked.
+    ///
+    /// The cache is shared by the clones of this configuration. Defaults to no cache.
+    pub fn with_cache(mut self, capacity: usize) -> Self {
+        self.cache = Some(Arc::new(VerificationCache::new(capacity)));
+        self
+    }
+
+    /// Rejects certificates longer than `bytes` with [`Error::BadDER`], before parsing them.
+    ///
+    /// This bounds the work and the memory that a remote can make us spend on its certificate.
+    /// Only the certificate that is verified counts, additional certificates accepted with
+    /// [`ChainMode::Lenient`] are ignored altogether. Defaults to
+    /// [`DEFAULT_MAX_CERTIFICATE_SIZE`].
+    pub fn with_max_certificate_size(mut self, bytes: usize) -> Self {
+        self.max_certificate_size = bytes;
+        self
+    }
+
+    /// Checks the certificates presented by a remote, and returns the [`PeerId`] of the remote
+    /// if they are acceptable.
+    ///
+    /// This is what the TLS configurations built with this configuration do during the
+    /// handshake.
+    pub fn verify_presented_certs(
+        &self,
+        presented_certs: &[Certificate],
+    ) -> Result<PeerId, TLSError> {
+        let certificate = match (self.chain_mode, presented_certs) {
+            (_, [certificate]) => certificate,
+            (ChainMode::Lenient, [certificate, ..]) => certificate,
+            _ => return Err(TLSError::NoCertificatesPresented),
+        };
+        if certificate.as_ref().len() > self.max_certificate_size {
+            return Err(TLSError::WebPKIError(Error::BadDER));
+        }
+        let now = match self.time_mode {
+            TimeMode::Check => Some(SystemTime::now()),
+            TimeMode::Ignore => None,
+        };
+        let verified = match &self.cache {
+            Some(cache) => cache.verify(certificate.as_ref(), now),
+            None => verify_certificate(certificate.as_ref(), now),
+        }
+        .map_err(TLSError::WebPKIError)?;
+        tracing::debug!(
+            peer = %verified.peer_id,
+            not_before = ?verified.not_before,
+            not_after = ?verified.not_after,
+            "verified certificate"
+        );
+        let peer_id = verified.peer_id;
+
+        if self.expected_peer.as_ref().map_or(false, |p| *p != peer_id) {
+            return Err(TLSError::General(format!("unexpected peer {}", peer_id)));
+        }
+        if !self.policy.accepts(&peer_id) {
+            return Err(TLSError::General(format!(
+                "peer {} rejected by policy",
+                peer_id
+            )));
+        }
+        Ok(peer_id)
+    }
+
+    /// Checks a TLS 1.3 handshake signature made with the key of `cert`.
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &Certificate,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, TLSError> {
+        if let Some(schemes) = &self.accepted_signature_schemes {
+            if !schemes.contains(&dss.scheme) {
+                return Err(TLSError::PeerMisbehavedError(format!(
+                    "signature scheme {:?} not accepted",
+                    dss.scheme
+                )));
+            }
+        }
+        verify_tls13_signature(message, cert, dss)
+    }
+}
+
+/// A certificate accepted by [`verify_libp2p_certificate`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifiedCertificate {
+    /// The peer authenticated by the certificate.
+    pub peer_id: PeerId,
+    /// Start of the validity period of the certificate.
+    pub not_before: SystemTime,
+    /// End of the validity period of the certificate.
+    pub not_after: SystemTime,
+}
+
+/// Checks that the DER-encoded `certificate` is a valid libp2p certificate at `now_unix_secs`
+/// seconds since the Unix epoch, and returns the [`PeerId`] it authenticates along with its
+/// validity period.
+///
+/// This performs the same checks as the certificate verifiers used during the handshake with the
+/// default [`VerifierConfig`]: the certificate must be self-signed, valid at the given time, and
+/// carry a libp2p extension signed by the identity key of the peer. Certificates longer than
+/// [`DEFAULT_MAX_CERTIFICATE_SIZE`] are rejected with [`Error::BadDER`] without being parsed. It
+/// doesn't depend on rustls, and can thus be used to check certificates obtained by other means.
+pub fn verify_libp2p_certificate(
+    certificate: &[u8],
+    now_unix_secs: u64,
+) -> Result<VerifiedCertificate, Error> {
+    if certificate.len() > DEFAULT_MAX_CERTIFICATE_SIZE {
+        return Err(Error::BadDER);
+    }
+    verify_certificate(
+        certificate,
+        Some(UNIX_EPOCH + Duration::from_secs(now_unix_secs)),
+    )
+}
+
+/// Checks that `certificate` is a valid libp2p certificate, and returns the [`PeerId`] it
+/// authenticates. The validity period is only checked if `now` is set.
+fn verify_certificate(
+    certificate: &[u8],
+    now: Option<SystemTime>,
+) -> Result<VerifiedCertificate, Error> {
+    let fields = super::certificate::TbsFields::parse(certificate)?;
+    let (not_before, not_after) = super::certificate::validity(certificate)?;
+    if let Some(now) = now {
+        if now < not_before {
+            return Err(Error::CertNotValidYet);
+        }
+        if now > not_after {
+            return Err(Error::CertExpired);
+        }
+    }
+    let (certificate, extension) = parse_certificate(certificate)?;
+    // A certificate whose issuer differs from its subject would be part of a chain, even if it
+    // is signed with its own key.
+    if fields.issuer != fields.subject {
+        return Err(Error::UnknownIssuer);
+    }
+    certificate.check_self_issued()?;
+    verify_libp2p_signature(&extension, certificate.subject_public_key_info().key())?;
+    Ok(VerifiedCertificate {
+        peer_id: PeerId::from_public_key(extension.peer_key),
+        not_before,
+        not_after,
+    })
+}
+
+/// Certificates that have been successfully verified. See [`VerifierConfig::with_cache`].
+struct VerificationCache {
+    capacity: usize,
+    inner: Mutex<CacheInner>,
+}
+
+struct CacheInner {
+    /// Verified certificates, by SHA-256 digest.
+    entries: HashMap<[u8; 32], CacheEntry>,
+    /// Incremented on every access, to find the least recently used entry.
+    clock: u64,
+}
+
+struct CacheEntry {
+    certificate: VerifiedCertificate,
+    last_used: u64,
+}
+
+impl VerificationCache {
+    fn new(capacity: usize) -> Self {
+        VerificationCache {
+            capacity,
+            inner: Mutex::new(CacheInner {
+                entries: HashMap::with_capacity(capacity),
+                clock: 0,
+            }),
+        }
+    }
+
+    /// Same as [`verify_certificate`], except that the result is looked up in the cache first.
+    fn verify(
+        &self,
+        certificate: &[u8],
+        now: Option<SystemTime>,
+    ) -> Result<VerifiedCertificate, Error> {
+        let mut key = [0; 32];
+        key.copy_from_slice(digest::digest(&digest::SHA256, certificate).as_ref());
+
+        {
+            let mut inner = self.inner.lock();
+            inner.clock += 1;
+            let clock = inner.clock;
+            if let Some(entry) = inner.entries.get_mut(&key) {
+                let verified = &entry.certificate;
+                if now.map_or(true, |now| {
+                    verified.not_before <= now && now <= verified.not_after
+                }) {
+                    entry.last_used = clock;
+                    return Ok(entry.certificate.clone());
+                }
+                inner.entries.remove(&key);
+            }
+        }
+
+        // The lock is not held while verifying, as that's the expensive part.
+        let verified = verify_certificate(certificate, now)?;
+        if self.capacity == 0 {
+            return Ok(verified);
+        }
+        let mut inner = self.inner.lock();
+        if inner.entries.len() >= self.capacity && !inner.entries.contains_key(&key) {
+            let oldest = inner
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| *key)
+                .expect("the cache is full and its capacity isn't 0; qed");
+            inner.entries.remove(&oldest);
+        }
+        let last_used = inner.clock;
+        inner.entries.insert(
+            key,
+            CacheEntry {
+                certificate: verified.clone(),
+                last_used,
+            },
+        );
+        Ok(verified)
+    }
+
+    /// Returns the number of certificates in the cache.
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.inner.lock().entries.len()
+    }
+}
+
+impl fmt::Debug for VerifierConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("VerifierConfig")
+            .field("expected_peer", &self.expected_peer)
+            .field("policy", &self.policy)
+            .field("time_mode", &self.time_mode)
+            .field("chain_mode", &self.chain_mode)
+            .field(
+                "accepted_signature_schemes",
+                &self.accepted_signature_schemes,
+            )
+            .field("cache", &self.cache.as_ref().map(|cache| cache.capacity))
+            .field("max_certificate_size", &self.max_certificate_size)
+            .finish()
+    }
+}
+
+impl Default for VerifierConfig {
+    fn default() -> Self {
+        VerifierConfig {
+            expected_peer: None,
+            policy: Default::default(),
+            time_mode: Default::default(),
+            chain_mode: Default::default(),
+            accepted_signature_schemes: None,
+            cache: None,
+            max_certificate_size: DEFAULT_MAX_CERTIFICATE_SIZE,
+        }
+    }
+}
+
+impl PeerPolicy {
+    /// Returns `true` if `peer` is allowed to connect.
+    fn accepts(&self, peer: &PeerId) -> bool {
+        match self {
+            PeerPolicy::AcceptAll => true,
+            PeerPolicy::Allowlist(peers) => peers.contains(peer),
+            PeerPolicy::Custom(accept) => accept(peer),
+        }
+    }
+}
+
+impl Default for PeerPolicy {
+    fn default() -> Self {
+        PeerPolicy::AcceptAll
+    }
+}
+
+impl fmt::Debug for PeerPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PeerPolicy::AcceptAll => f.write_str("AcceptAll"),
+            PeerPolicy::Allowlist(peers) => f.debug_tuple("Allowlist").field(peers).finish(),
+            PeerPolicy::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+impl Default for TimeMode {
+    fn default() -> Self {
+        TimeMode::Check
+    }
+}
+
+impl Default for ChainMode {
+    fn default() -> Self {
+        ChainMode::Strict
+    }
+}
+
 /// Libp2p client and server certificate verifier.
-pub(crate) struct Libp2pCertificateVerifier;
+pub(crate) struct Libp2pCertificateVerifier {
+    config: VerifierConfig,
+}
+
+impl Libp2pCertificateVerifier {
+    pub(crate) fn new(config: VerifierConfig) -> Self {
+        Libp2pCertificateVerifier { config }
+    }
+}
 
 /// libp2p requires the following of X.509 server certificate chains:
 ///
-/// * Exactly one certificate must be presented.
-/// * The certificate must be self-signed.
+/// * Exactly one certificate must be presented, unless [`ChainMode::Lenient`] is used.
+/// * The certificate must be self-signed: its issuer must be byte-for-byte identical to its
+///   subject, and its signature must verify with its own public key.
 /// * The certificate must have a valid libp2p extension that includes a
 ///   signature of its public key.
 ///
-/// The check that the [`PeerId`] matches the expected [`PeerId`] must be done by
-/// the caller.
-///
-/// [`PeerId`]: libp2p_core::PeerId
+/// The [`PeerId`] must additionally satisfy the [`VerifierConfig`]. If no expected [`PeerId`]
+/// is configured, the check that it matches the dialed [`PeerId`] must be done by the caller.
 impl rustls::ServerCertVerifier for Libp2pCertificateVerifier {
     fn verify_server_cert(
         &self,
@@ -49,7 +472,9 @@ impl rustls::ServerCertVerifier for Libp2pCertificateVerifier {
         _dns_name: webpki::DNSNameRef<'_>,
         _ocsp_response: &[u8],
     ) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
-        verify_presented_certs(presented_certs).map(|()| ServerCertVerified::assertion())
+        self.config
+            .verify_presented_certs(presented_certs)
+            .map(|_| ServerCertVerified::assertion())
     }
 
     fn verify_tls12_signature(
@@ -67,22 +492,20 @@ impl rustls::ServerCertVerifier for Libp2pCertificateVerifier {
         cert: &Certificate,
         dss: &DigitallySignedStruct,
     ) -> Result<HandshakeSignatureValid, TLSError> {
-        verify_tls13_signature(message, cert, dss)
+        self.config.verify_tls13_signature(message, cert, dss)
     }
 }
 
 /// libp2p requires the following of X.509 client certificate chains:
 ///
-/// * Exactly one certificate must be presented. In particular, client
-///   authentication is mandatory in libp2p.
-/// * The certificate must be self-signed.
+/// * Exactly one certificate must be presented, unless [`ChainMode::Lenient`] is used. In
+///   particular, client authentication is mandatory in libp2p.
+/// * The certificate must be self-signed: its issuer must be byte-for-byte identical to its
+///   subject, and its signature must verify with its own public key.
 /// * The certificate must have a valid libp2p extension that includes a
 ///   signature of its public key.
 ///
-/// The check that the [`PeerId`] matches the expected [`PeerId`] must be done by
-/// the caller.
-///
-/// [`PeerId`]: libp2p_core::PeerId
+/// The [`PeerId`] must additionally satisfy the [`VerifierConfig`].
 impl rustls::ClientCertVerifier for Libp2pCertificateVerifier {
     fn offer_client_auth(&self) -> bool {
         true
@@ -100,7 +523,9 @@ impl rustls::ClientCertVerifier for Libp2pCertificateVerifier {
         presented_certs: &[Certificate],
         _dns_name: Option<&webpki::DNSName>,
     ) -> Result<ClientCertVerified, rustls::TLSError> {
-        verify_presented_certs(presented_certs).map(|()| ClientCertVerified::assertion())
+        self.config
+            .verify_presented_certs(presented_certs)
+            .map(|_| ClientCertVerified::assertion())
     }
 
     fn verify_tls12_signature(
@@ -118,11 +543,7 @@ impl rustls::ClientCertVerifier for Libp2pCertificateVerifier {
         cert: &Certificate,
         dss: &DigitallySignedStruct,
     ) -> Result<HandshakeSignatureValid, TLSError> {
-        x509_signature::parse_certificate(cert.as_ref())
-            .map_err(rustls::TLSError::WebPKIError)?
-            .check_tls13_signature(dss.scheme, message, dss.sig.0.as_ref())
-            .map_err(rustls::TLSError::WebPKIError)
-            .map(|()| rustls::HandshakeSignatureValid::assertion())
+        self.config.verify_tls13_signature(message, cert, dss)
     }
 }
 
@@ -155,6 +576,24 @@ fn verify_libp2p_signature(
     }
 }
 
+/// Extensions that are accepted even if they are marked critical, and are then ignored.
+///
+/// Certificates generated by generic TLS tooling commonly mark these standard extensions
+/// critical: RFC 5280 requires it for basicConstraints in CA certificates, which self-signed
+/// certificates often claim to be, and for subjectAltName when the subject is empty, and
+/// recommends it for keyUsage. None of them is relevant to libp2p, which authenticates remotes
+/// with the libp2p extension alone, so they are tolerated without being interpreted.
+const IGNORED_CRITICAL_EXTENSIONS: &[&[u8]] = &[
+    // basicConstraints (2.5.29.19)
+    &[85, 29, 19],
+    // keyUsage (2.5.29.15)
+    &[85, 29, 15],
+    // subjectAltName (2.5.29.17)
+    &[85, 29, 17],
+    // extKeyUsage (2.5.29.37)
+    &[85, 29, 37],
+];
+
 fn parse_certificate(
     certificate: &[u8],
 ) -> Result<(x509_signature::X509Certificate<'_>, Libp2pExtension<'_>), Error> {
@@ -169,7 +608,9 @@ fn parse_certificate(
                 super::LIBP2P_OID_BYTES => {
                     libp2p_extension = Some(parse_libp2p_extension(extension)?)
                 }
-                _ if critical => return Err(Error::UnsupportedCriticalExtension),
+                _ if critical && !IGNORED_CRITICAL_EXTENSIONS.contains(&oid) => {
+                    return Err(Error::UnsupportedCriticalExtension)
+                }
                 _ => {}
             })
         })?;
@@ -177,20 +618,6 @@ fn parse_certificate(
     Ok((parsed, libp2p_extension))
 }
 
-fn verify_presented_certs(presented_certs: &[Certificate]) -> Result<(), TLSError> {
-    if presented_certs.len() != 1 {
-        return Err(TLSError::NoCertificatesPresented);
-    }
-    let (certificate, extension) =
-        parse_certificate(presented_certs[0].as_ref()).map_err(TLSError::WebPKIError)?;
-    certificate.valid().map_err(TLSError::WebPKIError)?;
-    certificate
-        .check_self_issued()
-        .map_err(TLSError::WebPKIError)?;
-    verify_libp2p_signature(&extension, certificate.subject_public_key_info().key())
-        .map_err(TLSError::WebPKIError)
-}
-
 struct Libp2pExtension<'a> {
     peer_key: PublicKey,
     signature: &'a [u8],
@@ -217,6 +644,18 @@ fn parse_libp2p_extension<'a>(extension: Input<'a>) -> Result<Libp2pExtension<'a
     })
 }
 
+/// Extracts the [`PeerId`] from a certificate’s libp2p extension.
+///
+/// Fails if the certificate isn't a well-formed X.509 certificate with a libp2p extension. The
+/// signature of the extension isn't checked, which the certificate verifiers of this crate do
+/// during the handshake.
+///
+/// [`PeerId`]: libp2p_core::PeerId
+pub fn extract_peerid(certificate: &[u8]) -> Result<libp2p_core::PeerId, Error> {
+    let (_, extension) = parse_certificate(certificate)?;
+    Ok(libp2p_core::PeerId::from_public_key(extension.peer_key))
+}
+
 /// Extracts the [`PeerId`] from a certificate’s libp2p extension. It is erroneous
 /// to call this unless the certificate is known to be a well-formed X.509
 /// certificate with a valid libp2p extension. The certificate verifiers in this
@@ -228,7 +667,516 @@ fn parse_libp2p_extension<'a>(extension: Input<'a>) -> Result<Libp2pExtension<'a
 ///
 /// [`PeerId`]: libp2p_core::PeerId
 pub fn extract_peerid_or_panic(certificate: &[u8]) -> libp2p_core::PeerId {
-    let r = parse_certificate(certificate)
-        .expect("we already checked that the certificate was valid during the handshake; qed");
-    libp2p_core::PeerId::from_public_key(r.1.peer_key)
+    extract_peerid(certificate)
+        .expect("we already checked that the certificate was valid during the handshake; qed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p_core::identity::Keypair;
+    use ring::signature::{EcdsaKeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+
+    fn certificate(keypair: &Keypair) -> Certificate {
+        super::super::make_certificate(keypair, &Default::default())
+            .unwrap()
+            .0
+    }
+
+    fn verify(config: &VerifierConfig, certs: &[Certificate]) -> Result<PeerId, TLSError> {
+        config.verify_presented_certs(certs)
+    }
+
+    #[test]
+    fn default_accepts_one_certificate() {
+        let keypair = Keypair::generate_ed25519();
+        let config = VerifierConfig::default();
+        let peer_id = verify(&config, &[certificate(&keypair)]).unwrap();
+        assert_eq!(peer_id, keypair.public().into_peer_id());
+    }
+
+    #[test]
+    fn cache_remembers_verified_certificates() {
+        let (a, b) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let (cert_a, cert_b) = (certificate(&a), certificate(&b));
+        let config = VerifierConfig::default().with_cache(1);
+        let cache = config.cache.clone().unwrap();
+
+        assert_eq!(
+            verify(&config, &[cert_a.clone()]).unwrap(),
+            a.public().into_peer_id()
+        );
+        assert_eq!(cache.len(), 1);
+        assert_eq!(
+            verify(&config, &[cert_a.clone()]).unwrap(),
+            a.public().into_peer_id()
+        );
+        assert_eq!(cache.len(), 1);
+
+        // The least recently used certificate is evicted.
+        assert_eq!(
+            verify(&config, &[cert_b]).unwrap(),
+            b.public().into_peer_id()
+        );
+        assert_eq!(cache.len(), 1);
+
+        // The peer checks still apply to cached certificates.
+        let config = config.with_expected_peer(b.public().into_peer_id());
+        assert!(verify(&config, &[cert_a]).is_err());
+
+        // Invalid certificates are not remembered.
+        assert!(verify(&config, &[Certificate(vec![0x30, 0x00])]).is_err());
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn cache_verifies_lapsed_certificates_again() {
+        let keypair = Keypair::generate_ed25519();
+        let not_before = SystemTime::now() - Duration::from_secs(7200);
+        let not_after = SystemTime::now() - Duration::from_secs(3600);
+        let params =
+            super::super::CertificateParams::default().with_validity(not_before, not_after);
+        let expired = super::super::make_certificate(&keypair, &params).unwrap().0;
+
+        let ignore_time = VerifierConfig::default()
+            .with_time_mode(TimeMode::Ignore)
+            .with_cache(4);
+        let cache = ignore_time.cache.clone().unwrap();
+        assert!(verify(&ignore_time, &[expired.clone()]).is_ok());
+        assert!(verify(&ignore_time, &[expired.clone()]).is_ok());
+        assert_eq!(cache.len(), 1);
+
+        let check_time = ignore_time.with_time_mode(TimeMode::Check);
+        assert!(verify(&check_time, &[expired]).is_err());
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[test]
+    fn subject_public_key_info_round_trip() {
+        let keypair = Keypair::generate_ed25519();
+        let certificate = certificate(&keypair);
+        let spki = super::super::subject_public_key_info(&certificate.0).unwrap();
+        let (parsed, _) = parse_certificate(&certificate.0).unwrap();
+        assert!(spki.ends_with(parsed.subject_public_key_info().key()));
+        let other = super::super::subject_public_key_info(&certificate(&keypair).0).unwrap();
+        assert_ne!(spki, other, "each certificate has its own key");
+        assert!(super::super::subject_public_key_info(&[0x30, 0x00]).is_err());
+    }
+
+    #[test]
+    fn all_identity_key_types_are_supported() {
+        let mut rsa_key = include_bytes!("../../tests/test-rsa-private-key.pk8").to_vec();
+        let keypairs = vec![
+            Keypair::generate_ed25519(),
+            Keypair::generate_secp256k1(),
+            Keypair::rsa_from_pkcs8(&mut rsa_key).unwrap(),
+        ];
+        let config = VerifierConfig::default();
+        for keypair in keypairs {
+            let certificate = certificate(&keypair);
+            let peer_id = verify(&config, &[certificate.clone()]).unwrap();
+            assert_eq!(peer_id, keypair.public().into_peer_id());
+            assert_eq!(extract_peerid(&certificate.0).unwrap(), peer_id);
+        }
+    }
+
+    #[test]
+    fn invalid_certificates_are_always_rejected() {
+        let config = VerifierConfig::default()
+            .with_time_mode(TimeMode::Ignore)
+            .with_chain_mode(ChainMode::Lenient);
+        assert!(verify(&config, &[Certificate(vec![0x30, 0x00])]).is_err());
+        assert!(verify(&config, &[]).is_err());
+    }
+
+    #[test]
+    fn certificates_must_be_self_issued() {
+        let keypair = Keypair::generate_ed25519();
+        let certificate =
+            super::super::certificate::make_cert(&keypair, &Default::default()).unwrap();
+
+        // An issuer with another name, but the same key, so that the signature is valid.
+        let mut issuer = rcgen::CertificateParams::new(vec![]);
+        issuer
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, "issuer");
+        issuer.alg = &rcgen::PKCS_ECDSA_P256_SHA256;
+        let key = certificate.serialize_private_key_der();
+        issuer.key_pair = Some(rcgen::KeyPair::from_der(&key).unwrap());
+        let issuer = rcgen::Certificate::from_params(issuer).unwrap();
+        let chained = Certificate(certificate.serialize_der_with_signer(&issuer).unwrap());
+
+        let self_issued = Certificate(certificate.serialize_der().unwrap());
+        let config = VerifierConfig::default();
+        assert!(verify(&config, &[self_issued]).is_ok());
+        assert_eq!(
+            verify(&config, &[chained]).unwrap_err(),
+            TLSError::WebPKIError(Error::UnknownIssuer)
+        );
+    }
+
+    #[test]
+    fn known_critical_extensions_are_ignored() {
+        let keypair = Keypair::generate_ed25519();
+        let with_extension = |oid: &[u64], content: Vec<u8>| {
+            let mut params =
+                super::super::certificate::make_cert_params(&keypair, &Default::default()).unwrap();
+            let mut extension = rcgen::CustomExtension::from_oid_content(oid, content);
+            extension.set_criticality(true);
+            params.custom_extensions.push(extension);
+            let certificate = rcgen::Certificate::from_params(params).unwrap();
+            Certificate(certificate.serialize_der().unwrap())
+        };
+        let config = VerifierConfig::default();
+
+        // basicConstraints with cA set, and keyUsage with digitalSignature and keyCertSign.
+        let basic_constraints = with_extension(&[2, 5, 29, 19], vec![0x30, 0x03, 0x01, 0x01, 0xff]);
+        assert!(verify(&config, &[basic_constraints]).is_ok());
+        let key_usage = with_extension(&[2, 5, 29, 15], vec![0x03, 0x02, 0x02, 0x84]);
+        assert!(verify(&config, &[key_usage]).is_ok());
+
+        let unknown = with_extension(&[1, 3, 6, 1, 4, 1, 99999, 1], vec![0x05, 0x00]);
+        assert_eq!(
+            verify(&config, &[unknown]).unwrap_err(),
+            TLSError::WebPKIError(Error::UnsupportedCriticalExtension)
+        );
+    }
+
+    #[test]
+    fn strict_chain_mode() {
+        let (a, b) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let config = VerifierConfig::default().with_chain_mode(ChainMode::Strict);
+        assert!(verify(&config, &[]).is_err());
+        assert!(verify(&config, &[certificate(&a), certificate(&b)]).is_err());
+    }
+
+    #[test]
+    fn lenient_chain_mode() {
+        let (a, b) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let config = VerifierConfig::default().with_chain_mode(ChainMode::Lenient);
+        let peer_id = verify(&config, &[certificate(&a), certificate(&b)]).unwrap();
+        assert_eq!(peer_id, a.public().into_peer_id());
+        // Additional certificates are not even parsed.
+        let garbage = Certificate(vec![0x30, 0x00]);
+        assert!(verify(&config, &[certificate(&a), garbage.clone()]).is_ok());
+        assert!(verify(&config, &[garbage, certificate(&a)]).is_err());
+    }
+
+    #[test]
+    fn standalone_verification() {
+        let keypair = Keypair::generate_ed25519();
+        let not_before = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        let not_after = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let params =
+            super::super::CertificateParams::default().with_validity(not_before, not_after);
+        let certificate = super::super::make_certificate(&keypair, &params).unwrap().0;
+
+        assert_eq!(
+            verify_libp2p_certificate(&certificate.0, 1_550_000_000)
+                .unwrap()
+                .peer_id,
+            keypair.public().into_peer_id()
+        );
+        assert_eq!(
+            verify_libp2p_certificate(&certificate.0, 1_400_000_000),
+            Err(Error::CertNotValidYet)
+        );
+        assert_eq!(
+            verify_libp2p_certificate(&certificate.0, 1_700_000_000),
+            Err(Error::CertExpired)
+        );
+        assert!(verify_libp2p_certificate(&certificate.0[1..], 1_550_000_000).is_err());
+
+        let mut tampered = certificate.0.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(verify_libp2p_certificate(&tampered, 1_550_000_000).is_err());
+    }
+
+    #[test]
+    fn verification_reports_validity_period() {
+        let keypair = Keypair::generate_ed25519();
+        let not_before = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        let not_after = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let params =
+            super::super::CertificateParams::default().with_validity(not_before, not_after);
+        let certificate = super::super::make_certificate(&keypair, &params).unwrap().0;
+
+        let expected = VerifiedCertificate {
+            peer_id: keypair.public().into_peer_id(),
+            not_before,
+            not_after,
+        };
+        assert_eq!(
+            verify_libp2p_certificate(&certificate.0, 1_550_000_000),
+            Ok(expected.clone())
+        );
+        // The cache reports the same period.
+        let cache = VerificationCache::new(1);
+        assert_eq!(cache.verify(&certificate.0, None), Ok(expected.clone()));
+        assert_eq!(cache.verify(&certificate.0, None), Ok(expected));
+    }
+
+    #[test]
+    fn oversized_certificates_are_rejected_before_parsing() {
+        let keypair = Keypair::generate_ed25519();
+        let certificate = certificate(&keypair);
+        let len = certificate.0.len();
+        assert!(len <= DEFAULT_MAX_CERTIFICATE_SIZE);
+
+        let config = VerifierConfig::default().with_max_certificate_size(len);
+        assert!(verify(&config, &[certificate.clone()]).is_ok());
+        let config = VerifierConfig::default().with_max_certificate_size(len - 1);
+        match verify(&config, &[certificate.clone()]) {
+            Err(TLSError::WebPKIError(Error::BadDER)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        // The size alone decides, even for a certificate that would otherwise be acceptable.
+        let mut padded = certificate.0;
+        padded.resize(DEFAULT_MAX_CERTIFICATE_SIZE + 1, 0);
+        assert_eq!(
+            verify_libp2p_certificate(&padded, 1_600_000_000),
+            Err(Error::BadDER)
+        );
+        match verify(&VerifierConfig::default(), &[Certificate(padded)]) {
+            Err(TLSError::WebPKIError(Error::BadDER)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn time_mode() {
+        let keypair = Keypair::generate_ed25519();
+        let now = std::time::SystemTime::now();
+        let day = std::time::Duration::from_secs(24 * 3600);
+        let params =
+            super::super::CertificateParams::default().with_validity(now - 2 * day, now - day);
+        let expired = super::super::make_certificate(&keypair, &params).unwrap().0;
+
+        let config = VerifierConfig::default().with_time_mode(TimeMode::Check);
+        assert!(verify(&config, &[certificate(&keypair)]).is_ok());
+        assert!(verify(&config, &[expired.clone()]).is_err());
+
+        let config = VerifierConfig::default().with_time_mode(TimeMode::Ignore);
+        assert!(verify(&config, &[certificate(&keypair)]).is_ok());
+        assert!(verify(&config, &[expired]).is_ok());
+    }
+
+    #[test]
+    fn expected_peer() {
+        let (a, b) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let config = VerifierConfig::default().with_expected_peer(a.public().into_peer_id());
+        assert!(verify(&config, &[certificate(&a)]).is_ok());
+        assert!(verify(&config, &[certificate(&b)]).is_err());
+    }
+
+    #[test]
+    fn allowlist_policy() {
+        let (a, b) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let allowed = vec![a.public().into_peer_id()].into_iter().collect();
+        let config = VerifierConfig::default().with_policy(PeerPolicy::Allowlist(allowed));
+        assert!(verify(&config, &[certificate(&a)]).is_ok());
+        assert!(verify(&config, &[certificate(&b)]).is_err());
+
+        let config = VerifierConfig::default().with_policy(PeerPolicy::Allowlist(HashSet::new()));
+        assert!(verify(&config, &[certificate(&a)]).is_err());
+    }
+
+    #[test]
+    fn custom_policy() {
+        let (a, b) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let denied = b.public().into_peer_id();
+        let policy = PeerPolicy::Custom(Arc::new(move |peer| *peer != denied));
+        let config = VerifierConfig::default().with_policy(policy);
+        assert!(verify(&config, &[certificate(&a)]).is_ok());
+        assert!(verify(&config, &[certificate(&b)]).is_err());
+    }
+
+    #[test]
+    fn expected_peer_and_policy_must_both_accept() {
+        let (a, b) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let allowed = vec![b.public().into_peer_id()].into_iter().collect();
+        let config = VerifierConfig::default()
+            .with_expected_peer(a.public().into_peer_id())
+            .with_policy(PeerPolicy::Allowlist(allowed));
+        assert!(verify(&config, &[certificate(&a)]).is_err());
+        assert!(verify(&config, &[certificate(&b)]).is_err());
+
+        let config = config.with_policy(PeerPolicy::AcceptAll);
+        assert!(verify(&config, &[certificate(&a)]).is_ok());
+    }
+
+    #[test]
+    fn lenient_chain_mode_and_expected_peer_use_the_first_certificate() {
+        let (a, b) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let config = VerifierConfig::default()
+            .with_chain_mode(ChainMode::Lenient)
+            .with_expected_peer(b.public().into_peer_id());
+        assert!(verify(&config, &[certificate(&a), certificate(&b)]).is_err());
+        assert!(verify(&config, &[certificate(&b), certificate(&a)]).is_ok());
+    }
+
+    #[test]
+    fn signature_schemes() {
+        let keypair = Keypair::generate_ed25519();
+        let (cert, key) = super::super::make_certificate(&keypair, &Default::default()).unwrap();
+        let message = b"handshake transcript";
+        let signature = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &key.0)
+            .unwrap()
+            .sign(&ring::rand::SystemRandom::new(), message)
+            .unwrap();
+        let dss = DigitallySignedStruct::new(
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            signature.as_ref().to_vec(),
+        );
+
+        let config = VerifierConfig::default();
+        assert!(config.verify_tls13_signature(message, &cert, &dss).is_ok());
+        assert!(config
+            .verify_tls13_signature(b"something else", &cert, &dss)
+            .is_err());
+
+        let config = VerifierConfig::default()
+            .with_signature_schemes(vec![SignatureScheme::ECDSA_NISTP256_SHA256]);
+        assert!(config.verify_tls13_signature(message, &cert, &dss).is_ok());
+
+        let config =
+            VerifierConfig::default().with_signature_schemes(vec![SignatureScheme::ED25519]);
+        assert!(config.verify_tls13_signature(message, &cert, &dss).is_err());
+    }
+
+    /// DER-encodes a value with the given tag.
+    fn der_value(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut value = vec![tag];
+        match content.len() {
+            len @ 0..=0x7f => value.push(len as u8),
+            len @ 0x80..=0xff => value.extend_from_slice(&[0x81, len as u8]),
+            len => value.extend_from_slice(&[0x82, (len >> 8) as u8, len as u8]),
+        }
+        value.extend_from_slice(content);
+        value
+    }
+
+    fn bit_string(content: &[u8]) -> Vec<u8> {
+        der_value(0x03, &[&[0][..], content].concat())
+    }
+
+    fn extension(public_key: &[u8], signature: &[u8]) -> Vec<u8> {
+        der_value(
+            0x30,
+            &[bit_string(public_key), bit_string(signature)].concat(),
+        )
+    }
+
+    fn parse_extension(extension: &[u8]) -> Result<(), Error> {
+        parse_libp2p_extension(Input::from(extension)).map(drop)
+    }
+
+    #[test]
+    fn libp2p_extension_round_trip() {
+        let keypair = Keypair::generate_ed25519();
+        let public_key = keypair.public().into_protobuf_encoding();
+        let extension = extension(&public_key, &[1; 64]);
+        let parsed = parse_libp2p_extension(Input::from(&extension)).unwrap();
+        assert_eq!(parsed.peer_key, keypair.public());
+        assert_eq!(parsed.signature, &[1; 64][..]);
+    }
+
+    #[test]
+    fn libp2p_extension_rejects_malformed_der() {
+        let public_key = Keypair::generate_ed25519()
+            .public()
+            .into_protobuf_encoding();
+        let valid = extension(&public_key, &[1; 64]);
+        let invalid = Err(Error::ExtensionValueInvalid);
+
+        // Truncated sequences.
+        assert_eq!(parse_extension(&[]), invalid);
+        assert_eq!(parse_extension(&valid[..1]), invalid);
+        assert_eq!(parse_extension(&valid[..valid.len() - 1]), invalid);
+        assert_eq!(
+            parse_extension(&der_value(0x30, &bit_string(&public_key))),
+            invalid
+        );
+
+        // Extra trailing bytes, after or inside the sequence.
+        assert_eq!(parse_extension(&[&valid[..], &[0]].concat()), invalid);
+        let content = [
+            bit_string(&public_key),
+            bit_string(&[1; 64]),
+            bit_string(&[]),
+        ]
+        .concat();
+        assert_eq!(parse_extension(&der_value(0x30, &content)), invalid);
+
+        // Empty bit strings, with and without the unused bits octet.
+        assert_eq!(parse_extension(&extension(&[], &[1; 64])), invalid);
+        let content = [bit_string(&public_key), vec![0x03, 0x00]].concat();
+        assert_eq!(parse_extension(&der_value(0x30, &content)), invalid);
+
+        // Bit strings with unused bits.
+        let content = [bit_string(&public_key), vec![0x03, 0x02, 0x01, 0xfe]].concat();
+        assert_eq!(parse_extension(&der_value(0x30, &content)), invalid);
+
+        // Lengths exceeding the available data.
+        assert_eq!(
+            parse_extension(&[0x30, 0x82, 0xff, 0xff, 0x03, 0x00]),
+            invalid
+        );
+        let mut oversized = valid.clone();
+        oversized[3] = 0x7f;
+        assert_eq!(parse_extension(&oversized), invalid);
+
+        // Other types than a sequence of bit strings.
+        assert_eq!(parse_extension(&der_value(0x31, &valid[2..])), invalid);
+        let content = [der_value(0x04, &public_key), bit_string(&[1; 64])].concat();
+        assert_eq!(parse_extension(&der_value(0x30, &content)), invalid);
+
+        // Public keys that aren't protobuf-encoded libp2p keys.
+        assert_eq!(
+            parse_extension(&extension(b"not a public key", &[1; 64])),
+            invalid
+        );
+        let mut unknown_type = public_key.clone();
+        unknown_type[1] = 0x7f;
+        assert_eq!(
+            parse_extension(&extension(&unknown_type, &[1; 64])),
+            invalid
+        );
+        assert_eq!(
+            parse_extension(&extension(&public_key[..public_key.len() - 1], &[1; 64])),
+            invalid
+        );
+    }
+
+    #[test]
+    fn libp2p_extension_never_panics() {
+        let public_key = Keypair::generate_secp256k1()
+            .public()
+            .into_protobuf_encoding();
+        let valid = extension(&public_key, &[1; 72]);
+        for len in 0..valid.len() {
+            assert!(parse_extension(&valid[..len]).is_err());
+        }
+        for i in 0..valid.len() {
+            for bit in 0..8 {
+                let mut mutated = valid.clone();
+                mutated[i] ^= 1 << bit;
+                let _ = parse_extension(&mutated);
+            }
+        }
+    }
+
+    #[test]
+    fn extract_peerid_rejects_malformed_certificates() {
+        let keypair = Keypair::generate_ed25519();
+        let cert = certificate(&keypair);
+        assert_eq!(
+            extract_peerid(&cert.0).unwrap(),
+            keypair.public().into_peer_id()
+        );
+        assert!(extract_peerid(&[]).is_err());
+        assert!(extract_peerid(&cert.0[..cert.0.len() / 2]).is_err());
+    }
 }
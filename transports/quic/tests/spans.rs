@@ -0,0 +1,226 @@
+// Copyright 2020 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Checks the spans that the transport emits its events in. The spans of a connection are
+//! created by the background task of its endpoint, which is why these tests install a global
+//! subscriber and live in a test binary of their own.
+
+use futures::prelude::*;
+use libp2p_core::{
+    muxing::StreamMuxer,
+    transport::{ListenerEvent, Transport},
+};
+use libp2p_quic::{Config, Endpoint, QuicTransport};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
+use tracing::{
+    field::{Field, Visit},
+    span::{Attributes, Id, Record},
+    Subscriber,
+};
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+
+/// A span that has been created, and the fields recorded in it so far.
+#[derive(Debug)]
+struct RecordedSpan {
+    name: &'static str,
+    /// Index of the parent span in [`Spans::spans`], if it has been recorded.
+    parent: Option<usize>,
+    fields: HashMap<&'static str, String>,
+}
+
+/// Records all the spans that are created, in order.
+#[derive(Default)]
+struct Spans {
+    spans: Vec<RecordedSpan>,
+    /// Index in `spans` of the live spans. Identifiers are reused once spans are closed.
+    by_id: HashMap<Id, usize>,
+}
+
+#[derive(Clone, Default)]
+struct SpanRecorder(Arc<Mutex<Spans>>);
+
+struct FieldVisitor<'a>(&'a mut HashMap<&'static str, String>);
+
+impl Visit for FieldVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.insert(field.name(), format!("{:?}", value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name(), value.to_owned());
+    }
+}
+
+impl<S: Subscriber> Layer<S> for SpanRecorder {
+    fn new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut spans = self.0.lock().unwrap();
+        let parent = attrs
+            .parent()
+            .cloned()
+            .or_else(|| ctx.current_span().id().cloned())
+            .and_then(|parent| spans.by_id.get(&parent).copied());
+        let mut fields = HashMap::new();
+        attrs.record(&mut FieldVisitor(&mut fields));
+        let index = spans.spans.len();
+        spans.spans.push(RecordedSpan {
+            name: attrs.metadata().name(),
+            parent,
+            fields,
+        });
+        spans.by_id.insert(id.clone(), index);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, _: Context<'_, S>) {
+        let mut spans = self.0.lock().unwrap();
+        if let Some(&index) = spans.by_id.get(id) {
+            values.record(&mut FieldVisitor(&mut spans.spans[index].fields));
+        }
+    }
+}
+
+fn config(keypair: &libp2p_core::identity::Keypair) -> Config {
+    Config::new(keypair, "/ip4/127.0.0.1/udp/0/quic".parse().unwrap()).unwrap()
+}
+
+#[test]
+fn connections_and_substreams_have_spans() {
+    let recorder = SpanRecorder::default();
+    tracing::subscriber::set_global_default(tracing_subscriber::registry().with(recorder.clone()))
+        .unwrap();
+
+    let listener_keypair = libp2p_core::identity::Keypair::generate_ed25519();
+    let dialer_keypair = libp2p_core::identity::Keypair::generate_ed25519();
+    let listen_addr = async_std::task::block_on(async {
+        let mut listener = QuicTransport(Endpoint::new(config(&listener_keypair)).unwrap())
+            .listen_on("/ip4/127.0.0.1/udp/0/quic".parse().unwrap())
+            .unwrap();
+        let listen_addr = match listener.next().await.unwrap().unwrap() {
+            ListenerEvent::NewAddress(listen_addr) => listen_addr,
+            _ => panic!("expected a listen address"),
+        };
+        let dialer = QuicTransport(Endpoint::new(config(&dialer_keypair)).unwrap());
+        let dial = async_std::task::spawn(dialer.dial(listen_addr.clone()).unwrap());
+        let listener_muxer = loop {
+            match listener.next().await.unwrap().unwrap() {
+                ListenerEvent::Upgrade { upgrade, .. } => break upgrade.await.unwrap().1,
+                _ => continue,
+            }
+        };
+        let (_, dialer_muxer) = dial.await.unwrap();
+
+        let mut outbound = dialer_muxer.open_outbound();
+        let mut substream = future::poll_fn(|cx| dialer_muxer.poll_outbound(cx, &mut outbound))
+            .await
+            .unwrap();
+        future::poll_fn(|cx| dialer_muxer.write_substream(cx, &mut substream, b"hello"))
+            .await
+            .unwrap();
+        let inbound = future::poll_fn(|cx| listener_muxer.poll_inbound(cx))
+            .await
+            .unwrap();
+        listener_muxer.destroy_substream(inbound);
+        dialer_muxer.destroy_substream(substream);
+        listen_addr
+    });
+
+    let spans = recorder.0.lock().unwrap();
+    let connection = |direction: &str| {
+        let mut connections =
+            spans.spans.iter().enumerate().filter(|(_, span)| {
+                span.name == "connection" && span.fields["direction"] == direction
+            });
+        let connection = connections.next().expect("no connection span");
+        assert!(connections.next().is_none());
+        connection
+    };
+
+    let (inbound_index, inbound) = connection("Inbound");
+    assert_eq!(
+        inbound.fields["peer"],
+        dialer_keypair.public().into_peer_id().to_string()
+    );
+    assert!(inbound.fields["remote"].starts_with("127.0.0.1:"));
+
+    let (outbound_index, outbound) = connection("Outbound");
+    assert_eq!(
+        outbound.fields["peer"],
+        listener_keypair.public().into_peer_id().to_string()
+    );
+    let listen_port = match listen_addr.iter().nth(1) {
+        Some(libp2p_core::multiaddr::Protocol::Udp(port)) => port,
+        _ => panic!("unexpected listen address {}", listen_addr),
+    };
+    assert_eq!(
+        outbound.fields["remote"],
+        format!("127.0.0.1:{}", listen_port)
+    );
+
+    // Both ends report the outcome of the handshake in a span nested in the span of their
+    // connection.
+    for &connection in &[inbound_index, outbound_index] {
+        let mut handshakes = spans
+            .spans
+            .iter()
+            .filter(|span| span.name == "handshake" && span.parent == Some(connection));
+        let handshake = handshakes.next().expect("no handshake span");
+        assert!(handshakes.next().is_none());
+        assert_eq!(handshake.fields["outcome"], "success");
+    }
+
+    let listen = spans
+        .spans
+        .iter()
+        .find(|span| span.name == "listen_on")
+        .expect("no listen_on span");
+    assert_eq!(listen.fields["addr"], "/ip4/127.0.0.1/udp/0/quic");
+    assert_eq!(listen.fields["local"], format!("127.0.0.1:{}", listen_port));
+    assert_eq!(listen.fields["outcome"], "success");
+
+    let dial = spans
+        .spans
+        .iter()
+        .find(|span| span.name == "dial")
+        .expect("no dial span");
+    assert_eq!(dial.fields["addr"], listen_addr.to_string());
+    assert_eq!(dial.fields["remote"], format!("127.0.0.1:{}", listen_port));
+    assert_eq!(
+        dial.fields["peer"],
+        listener_keypair.public().into_peer_id().to_string()
+    );
+    assert_eq!(dial.fields["outcome"], "success");
+    assert!(!dial.fields.contains_key("error"));
+
+    // Both ends report the substream in a span nested in the span of their connection.
+    for &connection in &[inbound_index, outbound_index] {
+        let substreams = spans
+            .spans
+            .iter()
+            .filter(|span| span.name == "substream" && span.parent == Some(connection))
+            .collect::<Vec<_>>();
+        assert!(!substreams.is_empty());
+        assert!(substreams
+            .iter()
+            .all(|span| span.fields["id"] == substreams[0].fields["id"]));
+    }
+}
@@ -0,0 +1,204 @@
+// Copyright 2017-2020 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Stress tests, which are too slow to run by default. Run them with
+//! `cargo test --release --test stress -- --ignored`.
+
+use futures::prelude::*;
+use libp2p_core::{
+    muxing::StreamMuxer,
+    transport::{ListenerEvent, Transport},
+};
+use libp2p_quic::{Config, Endpoint, QuicMuxer, QuicTransport, Substream};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// Allocator keeping track of the number of bytes allocated, in order to detect memory growth.
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(allocated, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Connects a dialer to a listener, the latter accepting up to `max_substreams` concurrent
+/// substreams, and returns the listener's and the dialer's muxers, in that order.
+async fn connected_pair(max_substreams: u64) -> (QuicMuxer, QuicMuxer) {
+    let config = || {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        Config::new(&keypair, "/ip4/127.0.0.1/udp/0/quic".parse().unwrap()).unwrap()
+    };
+    let listener_config = config().with_max_concurrent_bidi_streams(max_substreams);
+    let mut listener = QuicTransport(Endpoint::new(listener_config).unwrap())
+        .listen_on("/ip4/127.0.0.1/udp/0/quic".parse().unwrap())
+        .unwrap();
+    let listen_addr = match listener.next().await.unwrap().unwrap() {
+        ListenerEvent::NewAddress(listen_addr) => listen_addr,
+        _ => panic!("expected a listen address"),
+    };
+    let dial = QuicTransport(Endpoint::new(config()).unwrap())
+        .dial(listen_addr)
+        .unwrap();
+    let dial = async_std::task::spawn(dial);
+    let listener_muxer = loop {
+        if let ListenerEvent::Upgrade { upgrade, .. } = listener.next().await.unwrap().unwrap() {
+            break upgrade.await.unwrap().1;
+        }
+    };
+    let (_, dialer_muxer) = dial.await.unwrap();
+    (listener_muxer, dialer_muxer)
+}
+
+/// Writes `buf` to `substream`, then finishes it.
+async fn write_and_finish(muxer: &QuicMuxer, substream: &mut Substream, mut buf: &[u8]) {
+    while !buf.is_empty() {
+        let written = future::poll_fn(|cx| muxer.write_substream(cx, substream, buf))
+            .await
+            .unwrap();
+        buf = &buf[written..];
+    }
+    future::poll_fn(|cx| muxer.shutdown_substream(cx, substream))
+        .await
+        .unwrap();
+}
+
+/// Reads `substream` until the remote finishes it.
+async fn read_to_end(muxer: &QuicMuxer, substream: &mut Substream) -> Vec<u8> {
+    let (mut data, mut buf) = (Vec::new(), [0; 64]);
+    loop {
+        match future::poll_fn(|cx| muxer.read_substream(cx, substream, &mut buf))
+            .await
+            .unwrap()
+        {
+            0 => return data,
+            read => data.extend_from_slice(&buf[..read]),
+        }
+    }
+}
+
+/// Opens `count` substreams from `dialer` to `listener` at the same time, and exchanges a small
+/// payload and its echo on each of them before closing them.
+async fn echo_round(listener: &Arc<QuicMuxer>, dialer: &Arc<QuicMuxer>, count: usize) {
+    const PAYLOAD: &[u8] = b"stress";
+
+    let server = {
+        let listener = listener.clone();
+        async_std::task::spawn(async move {
+            let mut echoes = Vec::with_capacity(count);
+            for _ in 0..count {
+                let mut substream = future::poll_fn(|cx| listener.poll_inbound(cx))
+                    .await
+                    .unwrap();
+                let listener = listener.clone();
+                echoes.push(async_std::task::spawn(async move {
+                    let data = read_to_end(&listener, &mut substream).await;
+                    write_and_finish(&listener, &mut substream, &data).await;
+                    listener.destroy_substream(substream);
+                }));
+            }
+            future::join_all(echoes).await;
+        })
+    };
+
+    let mut exchanges = Vec::with_capacity(count);
+    for _ in 0..count {
+        let mut substream = future::poll_fn(|cx| dialer.poll_outbound(cx, &mut ()))
+            .await
+            .unwrap();
+        let dialer = dialer.clone();
+        exchanges.push(async_std::task::spawn(async move {
+            write_and_finish(&dialer, &mut substream, PAYLOAD).await;
+            assert_eq!(read_to_end(&dialer, &mut substream).await, PAYLOAD);
+            dialer.destroy_substream(substream);
+        }));
+    }
+    future::join_all(exchanges).await;
+    server.await;
+}
+
+/// Opens 5,000 concurrent substreams on a single connection, as Kademlia and gossipsub may do,
+/// and checks that they all complete and that the memory they used is released.
+#[test]
+#[ignore]
+fn many_concurrent_substreams() {
+    const SUBSTREAMS: usize = 5_000;
+    const TIMEOUT: Duration = Duration::from_secs(60);
+    // Delay for the acknowledgements of the closed substreams to be exchanged, after which
+    // `quinn_proto` forgets them.
+    const SETTLE: Duration = Duration::from_millis(500);
+
+    async_std::task::block_on(async {
+        let (listener, dialer) = connected_pair(SUBSTREAMS as u64).await;
+        let (listener, dialer) = (Arc::new(listener), Arc::new(dialer));
+        async_std::task::sleep(SETTLE).await;
+        let baseline = ALLOCATED.load(Ordering::SeqCst);
+        PEAK.store(baseline, Ordering::SeqCst);
+
+        // The first round grows the collections of the connections to their final capacity,
+        // which isn't given back afterwards.
+        async_std::future::timeout(TIMEOUT, echo_round(&listener, &dialer, SUBSTREAMS))
+            .await
+            .expect("the substreams didn't complete in time");
+        async_std::task::sleep(SETTLE).await;
+        let after_first = ALLOCATED.load(Ordering::SeqCst);
+        let peak = PEAK.load(Ordering::SeqCst);
+        assert!(
+            after_first.saturating_sub(baseline) < (peak - baseline) / 2,
+            "{} bytes of the {} used by the substreams haven't been released",
+            after_first.saturating_sub(baseline),
+            peak - baseline
+        );
+
+        // Past the first round, memory must return to where it was.
+        async_std::future::timeout(TIMEOUT, echo_round(&listener, &dialer, SUBSTREAMS))
+            .await
+            .expect("the substreams didn't complete in time");
+        async_std::task::sleep(SETTLE).await;
+        let after_second = ALLOCATED.load(Ordering::SeqCst);
+        assert!(
+            after_second <= after_first + 64 * 1024,
+            "memory grew by {} bytes over a round of substreams",
+            after_second.saturating_sub(after_first)
+        );
+    })
+}
@@ -24,14 +24,26 @@ use libp2p_core::{
     muxing::StreamMuxer,
     transport::ListenerEvent,
     transport::Transport,
+    PeerId,
+};
+use libp2p_quic::{
+    Config, DialRetry, Direction, Endpoint, QuicMuxer, QuicTransport, TransportMetrics,
+    VerifierConfig,
 };
-use libp2p_quic::{Config, Endpoint, QuicMuxer, QuicTransport};
 
+use async_std::net::UdpSocket;
+use futures::channel::{mpsc, oneshot};
 use std::{
+    collections::HashSet,
     io::Result,
+    net::SocketAddr,
     pin::Pin,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicIsize, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
     task::{Context, Poll},
+    time::Duration,
 };
 use tracing::{debug, error, info, trace};
 
@@ -42,6 +54,14 @@ struct QuicStream<'a> {
     shutdown: bool,
 }
 
+impl<'a> QuicStream<'a> {
+    /// Abruptly stops sending on the substream with the given error code.
+    fn reset(&mut self, error_code: u32) {
+        self.muxer
+            .reset_substream(self.id.as_mut().unwrap(), error_code);
+    }
+}
+
 impl<'a> AsyncWrite for QuicStream<'a> {
     fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
         assert!(!self.shutdown, "written after close");
@@ -52,6 +72,19 @@ impl<'a> AsyncWrite for QuicStream<'a> {
             .map_err(From::from)
     }
 
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> Poll<Result<usize>> {
+        assert!(!self.shutdown, "written after close");
+        let Self { muxer, id, .. } = self.get_mut();
+        let _ = muxer.poll_inbound(cx).is_pending();
+        muxer
+            .write_substream_vectored(cx, id.as_mut().unwrap(), bufs)
+            .map_err(From::from)
+    }
+
     fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
         self.shutdown = true;
         let Self { muxer, id, .. } = self.get_mut();
@@ -82,6 +115,18 @@ impl<'a> AsyncRead for QuicStream<'a> {
             .read_substream(cx, id.as_mut().unwrap(), buf)
             .map_err(From::from)
     }
+
+    fn poll_read_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &mut [std::io::IoSliceMut<'_>],
+    ) -> Poll<Result<usize>> {
+        let Self { id, muxer, .. } = self.get_mut();
+        let _ = muxer.poll_inbound(cx).is_pending();
+        muxer
+            .read_substream_vectored(cx, id.as_mut().unwrap(), bufs)
+            .map_err(From::from)
+    }
 }
 
 impl<'a> Drop for QuicStream<'a> {
@@ -133,6 +178,355 @@ fn init() {
         .try_init();
 }
 
+/// Starts listening with the given configuration and returns the first reported listen address
+/// alongside the listener.
+async fn listen(config: Config) -> (Multiaddr, <QuicTransport as Transport>::Listener) {
+    let addr = "/ip4/127.0.0.1/udp/0/quic".parse().unwrap();
+    let mut listener = QuicTransport(Endpoint::new(config).unwrap())
+        .listen_on(addr)
+        .unwrap();
+    match listener.next().await.unwrap().unwrap() {
+        ListenerEvent::NewAddress(listen_addr) => (listen_addr, listener),
+        _ => panic!("expected a listen address"),
+    }
+}
+
+/// Builds a configuration listening on an ephemeral port on the IPv4 loopback.
+fn config(keypair: &libp2p_core::identity::Keypair) -> Config {
+    Config::new(keypair, "/ip4/127.0.0.1/udp/0/quic".parse().unwrap()).unwrap()
+}
+
+/// Builds a configuration like [`config`], with a keypair of its own. Dialers need one, as
+/// dialing a remote that has our own `PeerId` fails.
+fn new_peer_config() -> Config {
+    config(&libp2p_core::identity::Keypair::generate_ed25519())
+}
+
+/// Connects a dialer built from `dialer_config` to a listener built from `listener_config` and
+/// returns the listener's and the dialer's muxers, in that order.
+async fn connected_pair(listener_config: Config, dialer_config: Config) -> (QuicMuxer, QuicMuxer) {
+    let (listen_addr, mut listener) = listen(listener_config).await;
+    let dialer = QuicTransport(Endpoint::new(dialer_config).unwrap());
+    let dial = async_std::task::spawn(dialer.dial(listen_addr).unwrap());
+    let (_, listener_muxer) = accept(&mut listener).await;
+    let (_, dialer_muxer) = dial.await.unwrap();
+    (listener_muxer, dialer_muxer)
+}
+
+/// Executor that runs each task to completion on a dedicated thread, without any runtime.
+struct ThreadExecutor;
+
+impl libp2p_core::Executor for ThreadExecutor {
+    fn exec(&self, future: Pin<Box<dyn Future<Output = ()> + Send>>) {
+        std::thread::spawn(move || futures::executor::block_on(future));
+    }
+}
+
+/// Executor that runs the first task like [`ThreadExecutor`], and drops all the others.
+struct FirstTaskExecutor(std::sync::atomic::AtomicBool);
+
+impl libp2p_core::Executor for FirstTaskExecutor {
+    fn exec(&self, future: Pin<Box<dyn Future<Output = ()> + Send>>) {
+        if !self.0.swap(true, std::sync::atomic::Ordering::SeqCst) {
+            libp2p_core::Executor::exec(&ThreadExecutor, future);
+        }
+    }
+}
+
+/// Asks `muxer` to close its connection, without waiting for the closure to complete.
+fn start_close(muxer: &QuicMuxer) {
+    let waker = futures::task::noop_waker();
+    let _ = muxer.close(&mut Context::from_waker(&waker));
+}
+
+/// Waits for the next incoming connection on `listener` and performs its handshake.
+async fn accept(listener: &mut <QuicTransport as Transport>::Listener) -> (PeerId, QuicMuxer) {
+    loop {
+        match listener.next().await.unwrap().unwrap() {
+            ListenerEvent::Upgrade { upgrade, .. } => {
+                return upgrade.await.expect("upgrade failed")
+            }
+            ListenerEvent::NewAddress(_) => {}
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Sends data from the dialer to the listener and back over a new substream, after which each
+/// side has processed what the other sent before.
+async fn round_trip(dialer_muxer: &QuicMuxer, listener_muxer: &QuicMuxer) {
+    let mut outbound = Outbound(dialer_muxer).await.unwrap();
+    outbound.write_all(b"ping").await.unwrap();
+    let mut inbound = Inbound(listener_muxer).next().await.unwrap();
+    let mut buf = [0u8; 4];
+    inbound.read_exact(&mut buf).await.unwrap();
+    inbound.write_all(b"pong").await.unwrap();
+    outbound.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"pong");
+}
+
+/// Drives `muxer` until it reports that its connection is gone, and returns the reason.
+async fn wait_closed(muxer: &QuicMuxer) -> libp2p_quic::Error {
+    loop {
+        match future::poll_fn(|cx| muxer.poll_inbound(cx)).await {
+            Ok(substream) => muxer.destroy_substream(substream),
+            Err(err) => return err,
+        }
+    }
+}
+
+/// Turns a QUIC multiaddr into the UDP socket address it designates.
+fn socket_addr(addr: &Multiaddr) -> SocketAddr {
+    let mut iter = addr.iter();
+    let ip = match iter.next() {
+        Some(Protocol::Ip4(ip)) => ip.into(),
+        Some(Protocol::Ip6(ip)) => ip.into(),
+        other => panic!("unexpected protocol: {:?}", other),
+    };
+    match iter.next() {
+        Some(Protocol::Udp(port)) => SocketAddr::new(ip, port),
+        other => panic!("unexpected protocol: {:?}", other),
+    }
+}
+
+/// Turns a UDP socket address into a QUIC multiaddr.
+fn quic_addr(addr: SocketAddr) -> Multiaddr {
+    Multiaddr::empty()
+        .with(addr.ip().into())
+        .with(Protocol::Udp(addr.port()))
+        .with(Protocol::Quic)
+}
+
+/// UDP relay sitting between a dialer and a listener.
+///
+/// The listener only ever sees the relay's outgoing socket. Calling [`UdpRelay::rebind`] swaps
+/// this socket for a new one, which looks to the listener as if the dialer moved to a new
+/// network path. Calling [`UdpRelay::spoof`] makes a third party send a forged packet to the
+/// listener instead.
+struct UdpRelay {
+    /// Address to dial in order to go through the relay.
+    addr: Multiaddr,
+    /// Sends commands to the relay.
+    commands: mpsc::UnboundedSender<RelayCommand>,
+}
+
+/// Command sent to a [`UdpRelay`].
+enum RelayCommand {
+    /// Switch to a new outgoing socket.
+    Rebind,
+    /// Send a packet with the destination connection ID of the last short header packet of the
+    /// dialer, but with a garbage payload, to the listener from a socket of its own, then
+    /// notify the sender.
+    Spoof(oneshot::Sender<()>),
+}
+
+impl UdpRelay {
+    async fn spawn(target: SocketAddr) -> Self {
+        enum Event {
+            FromDialer(Result<(usize, SocketAddr)>),
+            FromListener(Result<(usize, SocketAddr)>),
+            Command(Option<RelayCommand>),
+        }
+
+        /// Length of the connection IDs that `quinn_proto` picks by default.
+        const CID_LEN: usize = 8;
+
+        let front = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = quic_addr(front.local_addr().unwrap());
+        let (commands, mut commands_rx) = mpsc::unbounded();
+
+        async_std::task::spawn(async move {
+            let mut back = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let mut dialer = None;
+            let mut last_short_header = None;
+            let mut front_buf = vec![0; 65536];
+            let mut back_buf = vec![0; 65536];
+            loop {
+                let event = futures::select! {
+                    r = front.recv_from(&mut front_buf).fuse() => Event::FromDialer(r),
+                    r = back.recv_from(&mut back_buf).fuse() => Event::FromListener(r),
+                    r = commands_rx.next() => Event::Command(r),
+                };
+                match event {
+                    Event::FromDialer(Ok((n, from))) => {
+                        dialer = Some(from);
+                        let packet = &front_buf[..n];
+                        if packet[0] & 0x80 == 0 && n > 1 + CID_LEN {
+                            last_short_header = Some(packet.to_vec());
+                        }
+                        let _ = back.send_to(packet, target).await;
+                    }
+                    Event::FromListener(Ok((n, _))) => {
+                        if let Some(dialer) = dialer {
+                            let _ = front.send_to(&back_buf[..n], dialer).await;
+                        }
+                    }
+                    Event::FromDialer(Err(_)) | Event::FromListener(Err(_)) => {}
+                    Event::Command(Some(RelayCommand::Rebind)) => {
+                        back = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+                    }
+                    Event::Command(Some(RelayCommand::Spoof(sent))) => {
+                        let mut forged = last_short_header.clone().expect("no packet to forge");
+                        for byte in &mut forged[1 + CID_LEN..] {
+                            *byte = !*byte;
+                        }
+                        let third_party = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+                        let _ = third_party.send_to(&forged, target).await;
+                        let _ = sent.send(());
+                    }
+                    Event::Command(None) => return,
+                }
+            }
+        });
+
+        UdpRelay { addr, commands }
+    }
+
+    fn rebind(&self) {
+        self.commands.unbounded_send(RelayCommand::Rebind).unwrap();
+    }
+
+    /// Resolves once the forged packet has been sent. The packets the listener receives
+    /// afterwards are processed after it.
+    async fn spoof(&self) {
+        let (sent, rx) = oneshot::channel();
+        self.commands
+            .unbounded_send(RelayCommand::Spoof(sent))
+            .unwrap();
+        rx.await.unwrap();
+    }
+}
+
+/// Returns the destination connection ID of a long header packet.
+fn long_header_dcid(packet: &[u8]) -> Option<&[u8]> {
+    if packet.first()? & 0x80 == 0 {
+        return None;
+    }
+    let len = usize::from(*packet.get(5)?);
+    packet.get(6..6 + len)
+}
+
+/// UDP relay between a dialer and a listener that drops the packets of the first connection
+/// attempt of the dialer, in other words the long header packets carrying the first destination
+/// connection ID it picked.
+struct FirstAttemptDropper {
+    /// Address to dial in order to go through the relay.
+    addr: Multiaddr,
+}
+
+impl FirstAttemptDropper {
+    async fn spawn(target: SocketAddr) -> Self {
+        enum Event {
+            FromDialer(Result<(usize, SocketAddr)>),
+            FromListener(Result<(usize, SocketAddr)>),
+        }
+
+        let front = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = quic_addr(front.local_addr().unwrap());
+
+        async_std::task::spawn(async move {
+            let back = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let mut dialer = None;
+            let mut first_dcid = None;
+            let mut front_buf = vec![0; 65536];
+            let mut back_buf = vec![0; 65536];
+            loop {
+                let event = futures::select! {
+                    r = front.recv_from(&mut front_buf).fuse() => Event::FromDialer(r),
+                    r = back.recv_from(&mut back_buf).fuse() => Event::FromListener(r),
+                };
+                match event {
+                    Event::FromDialer(Ok((n, from))) => {
+                        dialer = Some(from);
+                        let packet = &front_buf[..n];
+                        if let Some(dcid) = long_header_dcid(packet) {
+                            let first_dcid = first_dcid.get_or_insert_with(|| dcid.to_vec());
+                            if dcid == &first_dcid[..] {
+                                continue;
+                            }
+                        }
+                        let _ = back.send_to(packet, target).await;
+                    }
+                    Event::FromListener(Ok((n, _))) => {
+                        if let Some(dialer) = dialer {
+                            let _ = front.send_to(&back_buf[..n], dialer).await;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        });
+
+        FirstAttemptDropper { addr }
+    }
+}
+
+/// UDP forwarder standing in for a port-restricted NAT in front of a single host.
+///
+/// Packets that the host sends to [`PortRestrictedNat::inner`] are forwarded to the remote from
+/// [`PortRestrictedNat::outer`]. Packets that the remote sends to the outer address are only
+/// forwarded to the host once the host has sent a packet to the remote.
+struct PortRestrictedNat {
+    /// Address at which the host reaches the remote.
+    inner: SocketAddr,
+    /// Address at which the remote reaches the host.
+    outer: SocketAddr,
+    /// Notified once the host has sent a packet to the remote.
+    punched: oneshot::Receiver<()>,
+}
+
+impl PortRestrictedNat {
+    async fn spawn(host: SocketAddr, remote: SocketAddr) -> Self {
+        enum Event {
+            FromHost(Result<(usize, SocketAddr)>),
+            FromRemote(Result<(usize, SocketAddr)>),
+        }
+
+        let inner_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let outer_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let inner = inner_socket.local_addr().unwrap();
+        let outer = outer_socket.local_addr().unwrap();
+        let (punched_tx, punched_rx) = oneshot::channel();
+
+        async_std::task::spawn(async move {
+            let mut punched = false;
+            let mut punched_tx = Some(punched_tx);
+            let mut inner_buf = vec![0; 65536];
+            let mut outer_buf = vec![0; 65536];
+            loop {
+                let event = futures::select! {
+                    r = inner_socket.recv_from(&mut inner_buf).fuse() => Event::FromHost(r),
+                    r = outer_socket.recv_from(&mut outer_buf).fuse() => Event::FromRemote(r),
+                };
+                match event {
+                    Event::FromHost(Ok((n, from))) if from == host => {
+                        punched = true;
+                        if let Some(punched_tx) = punched_tx.take() {
+                            let _ = punched_tx.send(());
+                        }
+                        let _ = outer_socket.send_to(&inner_buf[..n], remote).await;
+                    }
+                    Event::FromRemote(Ok((n, from))) if from == remote && punched => {
+                        let _ = inner_socket.send_to(&outer_buf[..n], host).await;
+                    }
+                    _ => {}
+                }
+            }
+        });
+
+        PortRestrictedNat {
+            inner,
+            outer,
+            punched: punched_rx,
+        }
+    }
+
+    /// Resolves once the host has punched a hole in the NAT.
+    async fn wait_punched(&mut self) {
+        (&mut self.punched).await.unwrap();
+    }
+}
+
 struct Closer(Arc<QuicMuxer>);
 
 impl Future for Closer {
@@ -143,6 +537,230 @@ impl Future for Closer {
     }
 }
 
+#[test]
+fn dual_stack_listener_reports_ipv4_remotes() {
+    init();
+    async_std::task::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let addr: Multiaddr = "/ip6/::/udp/0/quic".parse().unwrap();
+        let listener_config = Config::new(&keypair, addr.clone())
+            .unwrap()
+            .with_ipv6_only(false);
+        let mut listener = QuicTransport(Endpoint::new(listener_config).unwrap())
+            .listen_on(addr)
+            .unwrap();
+        let port = match listener.next().await.unwrap().unwrap() {
+            ListenerEvent::NewAddress(listen_addr) => match listen_addr.iter().nth(1) {
+                Some(Protocol::Udp(port)) => port,
+                _ => panic!("expected a UDP port"),
+            },
+            _ => panic!("expected a listen address"),
+        };
+
+        let dialer = QuicTransport(Endpoint::new(new_peer_config()).unwrap());
+        let dial_addr = format!("/ip4/127.0.0.1/udp/{}/quic", port).parse().unwrap();
+        let _dial = async_std::task::spawn(dialer.dial(dial_addr).unwrap());
+        loop {
+            match listener.next().await.unwrap().unwrap() {
+                ListenerEvent::Upgrade {
+                    local_addr,
+                    remote_addr,
+                    ..
+                } => {
+                    let mut iter = remote_addr.iter();
+                    assert_eq!(iter.next(), Some(Protocol::Ip4([127, 0, 0, 1].into())));
+                    let expected = format!("/ip4/127.0.0.1/udp/{}/quic", port);
+                    assert_eq!(local_addr, expected.parse().unwrap());
+                    break;
+                }
+                ListenerEvent::NewAddress(_) => {}
+                ListenerEvent::AddressExpired(_) => panic!("unexpected expired address"),
+            }
+        }
+    });
+}
+
+#[test]
+fn listeners_share_transport() {
+    init();
+    async_std::task::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let transport = QuicTransport(Endpoint::new(config(&keypair)).unwrap());
+
+        let mut listen_addrs = Vec::new();
+        let mut listeners = Vec::new();
+        for addr in &["/ip4/127.0.0.1/udp/0/quic", "/ip6/::1/udp/0/quic"] {
+            let mut listener = transport.clone().listen_on(addr.parse().unwrap()).unwrap();
+            match listener.next().await.unwrap().unwrap() {
+                ListenerEvent::NewAddress(listen_addr) => listen_addrs.push(listen_addr),
+                _ => panic!("expected a listen address"),
+            }
+            listeners.push(listener);
+        }
+        assert!(socket_addr(&listen_addrs[0]).is_ipv4());
+        assert!(socket_addr(&listen_addrs[1]).is_ipv6());
+
+        // Listening again on an address that is in use fails.
+        for listen_addr in &listen_addrs {
+            match transport.clone().listen_on(listen_addr.clone()) {
+                Err(libp2p_core::transport::TransportError::Other(
+                    libp2p_quic::transport::Error::Io(err),
+                )) => {
+                    assert_eq!(err.kind(), std::io::ErrorKind::AddrInUse)
+                }
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+
+        // The dialer needs an IPv6 socket as well in order to reach the IPv6 listener.
+        let dialer = QuicTransport(Endpoint::new(new_peer_config()).unwrap());
+        let _dialer_listener = dialer
+            .clone()
+            .listen_on("/ip6/::1/udp/0/quic".parse().unwrap())
+            .unwrap();
+        for (listen_addr, listener) in listen_addrs.into_iter().zip(&mut listeners) {
+            let dial = dialer.clone().dial(listen_addr).unwrap();
+            let ((_, _listener_muxer), dialed) = future::join(accept(listener), dial).await;
+            dialed.unwrap();
+        }
+    });
+}
+
+#[test]
+fn listen_addrs_match_listener_events() {
+    init();
+    async_std::task::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let endpoint = Endpoint::new(config(&keypair)).unwrap();
+        let transport = QuicTransport(endpoint.clone());
+        assert!(endpoint.listen_addrs().is_empty());
+
+        let addr = "/ip4/127.0.0.1/udp/0/quic".parse().unwrap();
+        let mut listener = transport.clone().listen_on(addr).unwrap();
+        let listen_addr = match listener.next().await.unwrap().unwrap() {
+            ListenerEvent::NewAddress(listen_addr) => listen_addr,
+            _ => panic!("expected a listen address"),
+        };
+        assert_ne!(socket_addr(&listen_addr).port(), 0);
+        assert_eq!(endpoint.listen_addrs(), vec![listen_addr.clone()]);
+
+        // All the addresses of a wildcard listener are reported before anything else.
+        let addr = "/ip4/0.0.0.0/udp/0/quic".parse().unwrap();
+        let mut wildcard_listener = transport.listen_on(addr).unwrap();
+        let wildcard_addrs = endpoint.listen_addrs().split_off(1);
+        for expected in &wildcard_addrs {
+            match wildcard_listener.next().await.unwrap().unwrap() {
+                ListenerEvent::NewAddress(a) => assert_eq!(a, *expected),
+                _ => panic!("expected a listen address"),
+            }
+            assert_ne!(socket_addr(expected).port(), 0);
+            assert_eq!(expected.iter().last(), Some(Protocol::Quic));
+        }
+
+        drop(wildcard_listener);
+        assert_eq!(endpoint.listen_addrs(), vec![listen_addr]);
+        drop(listener);
+        assert!(endpoint.listen_addrs().is_empty());
+    });
+}
+
+#[test]
+fn wildcard_listener_reports_local_addr() {
+    init();
+    async_std::task::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let addr: Multiaddr = "/ip4/0.0.0.0/udp/0/quic".parse().unwrap();
+        let listener_config = Config::new(&keypair, addr.clone()).unwrap();
+        let mut listener = QuicTransport(Endpoint::new(listener_config).unwrap())
+            .listen_on(addr)
+            .unwrap();
+        let port = match listener.next().await.unwrap().unwrap() {
+            ListenerEvent::NewAddress(listen_addr) => socket_addr(&listen_addr).port(),
+            _ => panic!("expected a listen address"),
+        };
+
+        let dialer = QuicTransport(Endpoint::new(new_peer_config()).unwrap());
+        let dial_addr = format!("/ip4/127.0.0.1/udp/{}/quic", port).parse().unwrap();
+        let _dial = async_std::task::spawn(dialer.dial(dial_addr).unwrap());
+        loop {
+            match listener.next().await.unwrap().unwrap() {
+                ListenerEvent::Upgrade { local_addr, .. } => {
+                    let local_addr = socket_addr(&local_addr);
+                    assert!(!local_addr.ip().is_unspecified(), "{}", local_addr);
+                    assert_eq!(local_addr, SocketAddr::from(([127, 0, 0, 1], port)));
+                    break;
+                }
+                ListenerEvent::NewAddress(_) => {}
+                ListenerEvent::AddressExpired(_) => panic!("unexpected expired address"),
+            }
+        }
+    });
+}
+
+#[test]
+fn dual_stack_wildcard_expansion() {
+    /// Listens on all the interfaces of both address families, and returns the reported
+    /// addresses alongside the expected ones.
+    fn listen_addrs(loopback: bool) -> (HashSet<Multiaddr>, HashSet<Multiaddr>) {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let addr: Multiaddr = "/ip6/::/udp/0/quic".parse().unwrap();
+        let config = Config::new(&keypair, addr.clone())
+            .unwrap()
+            .with_ipv6_only(false)
+            .with_loopback_addresses(loopback);
+        let mut listener = QuicTransport(Endpoint::new(config).unwrap())
+            .listen_on(addr)
+            .unwrap();
+
+        futures::executor::block_on(async {
+            let mut reported = HashSet::new();
+            let mut expected = None;
+            loop {
+                match listener.next().await.unwrap().unwrap() {
+                    ListenerEvent::NewAddress(a) => {
+                        let expected = expected.get_or_insert_with(|| {
+                            let suffixes = [Protocol::Udp(socket_addr(&a).port()), Protocol::Quic];
+                            libp2p_core::multiaddr::host_addresses(&suffixes)
+                                .unwrap()
+                                .into_iter()
+                                .filter(|(ip, ..)| loopback || !ip.is_loopback())
+                                .map(|(_, _, addr)| addr)
+                                .collect::<HashSet<_>>()
+                        });
+                        assert!(reported.insert(a), "address reported twice");
+                        if reported.len() == expected.len() {
+                            return (reported, expected.clone());
+                        }
+                    }
+                    _ => panic!("expected a listen address"),
+                }
+            }
+        })
+    }
+
+    init();
+    let (reported, expected) = listen_addrs(true);
+    assert_eq!(reported, expected);
+    let has_family = |ipv4: bool| {
+        reported
+            .iter()
+            .any(|a| socket_addr(a).is_ipv4() == ipv4 && !socket_addr(a).ip().is_unspecified())
+    };
+    assert!(has_family(true), "no IPv4 address in {:?}", reported);
+    assert!(has_family(false), "no IPv6 address in {:?}", reported);
+
+    // Without loopback addresses, nothing at all might be reported.
+    let has_other_interfaces = libp2p_core::multiaddr::host_addresses(&[])
+        .unwrap()
+        .iter()
+        .any(|(ip, ..)| !ip.is_loopback());
+    if has_other_interfaces {
+        let (reported, expected) = listen_addrs(false);
+        assert_eq!(reported, expected);
+        assert!(reported.iter().all(|a| !socket_addr(a).ip().is_loopback()));
+    }
+}
+
 #[test]
 fn wildcard_expansion() {
     init();
@@ -151,11 +769,22 @@ fn wildcard_expansion() {
     let listener =
         QuicTransport(Endpoint::new(Config::new(&keypair, addr.clone()).unwrap()).unwrap());
     let mut incoming = listener.listen_on(addr).unwrap();
+
+    // One address is expected for each IPv4 interface of the host.
+    let suffixes = [Protocol::Udp(1234), Protocol::Quic];
+    let expected = libp2p_core::multiaddr::host_addresses(&suffixes)
+        .unwrap()
+        .into_iter()
+        .filter(|(ip, ..)| ip.is_ipv4())
+        .map(|(_, _, addr)| addr)
+        .collect::<HashSet<_>>();
+
     // Process all initial `NewAddress` events and make sure they
     // do not contain wildcard address or port.
     futures::executor::block_on(async move {
-        while let Some(event) = incoming.next().await.map(|e| e.unwrap()) {
-            match event {
+        let mut reported = HashSet::new();
+        while reported.len() < expected.len() {
+            match incoming.next().await.unwrap().unwrap() {
                 ListenerEvent::NewAddress(a) => {
                     let mut iter = a.iter();
                     match iter.next().expect("ip address") {
@@ -170,13 +799,14 @@ fn wildcard_expansion() {
                     }
                     assert_eq!(iter.next(), Some(Protocol::Quic));
                     assert_eq!(iter.next(), None);
+                    assert!(reported.insert(a), "address reported twice");
                 }
                 ListenerEvent::Upgrade { .. } => panic!(),
                 ListenerEvent::AddressExpired { .. } => panic!(),
                 ListenerEvent::Error { .. } => panic!(),
             }
-            break;
         }
+        assert_eq!(reported, expected);
         drop(incoming);
     });
 }
@@ -194,7 +824,9 @@ fn do_test(_i: u32) {
     let (ready_tx, ready_rx) = futures::channel::oneshot::channel();
     let mut ready_tx = Some(ready_tx);
     let keypair = libp2p_core::identity::Keypair::generate_ed25519();
-    let keypair2 = keypair.clone();
+    let keypair2 = libp2p_core::identity::Keypair::generate_ed25519();
+    let dialer_id = keypair.public().into_peer_id();
+    let listener_id = keypair2.public().into_peer_id();
     let addr: Multiaddr = "/ip4/127.0.0.1/udp/0/quic".parse().expect("bad address?");
     let quic_config = Config::new(&keypair2, addr.clone()).unwrap();
     let quic_endpoint = Endpoint::new(quic_config).unwrap();
@@ -281,62 +913,2678 @@ fn do_test(_i: u32) {
         debug!("awaiting handle");
         peer_id
     });
-    assert_eq!(
-        async_std::task::block_on(handle),
-        async_std::task::block_on(second_handle)
-    );
+    assert_eq!(async_std::task::block_on(handle), dialer_id);
+    assert_eq!(async_std::task::block_on(second_handle), listener_id);
 }
 
-#[test]
-fn replace_port_0_in_returned_multiaddr_ipv4() {
-    init();
-    let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+/// Counts the events reported to it.
+#[derive(Default)]
+struct CountingMetrics {
+    connections_opened: AtomicUsize,
+    connections_closed: AtomicUsize,
+    handshake_failures: AtomicUsize,
+    open_substreams: AtomicIsize,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    dials: AtomicUsize,
+    established: Mutex<Vec<(PeerId, Multiaddr, Direction)>>,
+    /// Remotes of the terminated connections, and whether a reason was reported.
+    terminated: Mutex<Vec<(PeerId, bool)>>,
+}
 
-    let addr = "/ip4/127.0.0.1/udp/0/quic".parse::<Multiaddr>().unwrap();
-    assert!(addr.to_string().ends_with("udp/0/quic"));
+impl TransportMetrics for CountingMetrics {
+    fn connection_opened(&self, _: Direction) {
+        self.connections_opened.fetch_add(1, Ordering::SeqCst);
+    }
 
-    let config = Config::new(&keypair, addr.clone()).unwrap();
-    let quic = QuicTransport(Endpoint::new(config).expect("no error"));
+    fn connection_closed(&self, _: Direction) {
+        self.connections_closed.fetch_add(1, Ordering::SeqCst);
+    }
 
-    let new_addr = futures::executor::block_on_stream(quic.listen_on(addr).unwrap())
-        .next()
-        .expect("some event")
-        .expect("no error")
-        .into_new_address()
-        .expect("listen address");
+    fn connection_established(&self, peer: &PeerId, remote_addr: &Multiaddr, direction: Direction) {
+        self.established
+            .lock()
+            .unwrap()
+            .push((peer.clone(), remote_addr.clone(), direction));
+    }
 
-    if new_addr.to_string().contains("udp/0") {
-        panic!("failed to expand address ― got {}", new_addr);
+    fn connection_terminated(&self, peer: &PeerId, reason: Option<&libp2p_quic::transport::Error>) {
+        self.terminated
+            .lock()
+            .unwrap()
+            .push((peer.clone(), reason.is_some()));
+    }
+
+    fn handshake_failed(&self, direction: Direction, _: &libp2p_quic::transport::Error) {
+        assert_eq!(direction, Direction::Outbound);
+        self.handshake_failures.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn substream_opened(&self) {
+        self.open_substreams.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn substream_closed(&self) {
+        self.open_substreams.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    fn bytes_sent(&self, bytes: u64) {
+        self.bytes_sent.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    fn bytes_received(&self, bytes: u64) {
+        self.bytes_received.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    fn dial_succeeded(&self, _: Duration) {
+        self.dials.fetch_add(1, Ordering::SeqCst);
     }
 }
 
 #[test]
-fn replace_port_0_in_returned_multiaddr_ipv6() {
+fn metrics_follow_connections_and_substreams() {
     init();
-    let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+    async_std::task::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let listener_metrics = Arc::new(CountingMetrics::default());
+        let dialer_metrics = Arc::new(CountingMetrics::default());
+        let (listener_muxer, dialer_muxer) = connected_pair(
+            config(&keypair).with_metrics(listener_metrics.clone()),
+            new_peer_config().with_metrics(dialer_metrics.clone()),
+        )
+        .await;
+        for metrics in &[&listener_metrics, &dialer_metrics] {
+            assert_eq!(metrics.connections_opened.load(Ordering::SeqCst), 1);
+            assert_eq!(metrics.connections_closed.load(Ordering::SeqCst), 0);
+        }
+        assert_eq!(dialer_metrics.dials.load(Ordering::SeqCst), 1);
+        assert_eq!(listener_metrics.dials.load(Ordering::SeqCst), 0);
 
-    let addr: Multiaddr = "/ip6/::1/udp/0/quic".parse().unwrap();
-    assert!(addr.to_string().contains("udp/0/quic"));
-    let config = Config::new(&keypair, addr.clone()).unwrap();
-    let quic = QuicTransport(Endpoint::new(config).expect("no error"));
+        let listener_id = keypair.public().into_peer_id();
+        let dialer_id = {
+            let established = listener_metrics.established.lock().unwrap();
+            assert_eq!(established.len(), 1);
+            let (dialer_id, remote_addr, direction) = &established[0];
+            assert_ne!(*dialer_id, listener_id);
+            assert_eq!(*direction, Direction::Inbound);
+            assert!(remote_addr.to_string().starts_with("/ip4/127.0.0.1/udp/"));
+            dialer_id.clone()
+        };
+        {
+            let established = dialer_metrics.established.lock().unwrap();
+            assert_eq!(established.len(), 1);
+            assert_eq!(established[0].0, listener_id);
+            assert_eq!(established[0].2, Direction::Outbound);
+        }
 
-    let new_addr = futures::executor::block_on_stream(quic.listen_on(addr).unwrap())
-        .next()
-        .expect("some event")
-        .expect("no error")
-        .into_new_address()
-        .expect("listen address");
+        let mut outbound = Outbound(&dialer_muxer).await.unwrap();
+        outbound.write_all(b"hello").await.unwrap();
+        outbound.close().await.unwrap();
+        let mut inbound = Inbound(&listener_muxer).next().await.unwrap();
+        let mut buf = Vec::new();
+        inbound.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"hello");
+        assert_eq!(dialer_metrics.bytes_sent.load(Ordering::SeqCst), 5);
+        assert_eq!(listener_metrics.bytes_received.load(Ordering::SeqCst), 5);
+        for metrics in &[&listener_metrics, &dialer_metrics] {
+            assert_eq!(metrics.open_substreams.load(Ordering::SeqCst), 1);
+        }
+        drop(inbound);
+        drop(outbound);
+        for metrics in &[&listener_metrics, &dialer_metrics] {
+            assert_eq!(metrics.open_substreams.load(Ordering::SeqCst), 0);
+        }
 
-    assert!(!new_addr.to_string().contains("udp/0"));
+        start_close(&dialer_muxer);
+        wait_closed(&listener_muxer).await;
+        dialer_muxer.join_driver().await.unwrap();
+        listener_muxer.join_driver().await.unwrap();
+        drop((listener_muxer, dialer_muxer));
+        for metrics in &[&listener_metrics, &dialer_metrics] {
+            assert_eq!(metrics.connections_closed.load(Ordering::SeqCst), 1);
+        }
+        let terminated = listener_metrics.terminated.lock().unwrap().clone();
+        // The listener has been closed by the dialer, which is reported as the reason.
+        assert_eq!(terminated, vec![(dialer_id, true)]);
+        let terminated = dialer_metrics.terminated.lock().unwrap();
+        assert_eq!(terminated.len(), 1);
+        assert_eq!(terminated[0].0, listener_id);
+
+        // The dialer rejects the certificate of the listener.
+        let (listen_addr, _listener) = listen(config(&keypair)).await;
+        let verifier = VerifierConfig::default().with_expected_peer(PeerId::random());
+        let failing_metrics = Arc::new(CountingMetrics::default());
+        let dialer_config = new_peer_config()
+            .with_verifier(verifier)
+            .with_metrics(failing_metrics.clone());
+        let dialer = QuicTransport(Endpoint::new(dialer_config).unwrap());
+        assert!(dialer.dial(listen_addr).unwrap().await.is_err());
+        assert_eq!(failing_metrics.handshake_failures.load(Ordering::SeqCst), 1);
+        assert_eq!(failing_metrics.connections_opened.load(Ordering::SeqCst), 0);
+        assert_eq!(failing_metrics.dials.load(Ordering::SeqCst), 0);
+        assert!(failing_metrics.established.lock().unwrap().is_empty());
+    });
 }
 
 #[test]
-fn larger_addr_denied() {
+fn identity_key_types() {
     init();
-    let keypair = libp2p_core::identity::Keypair::generate_ed25519();
-    let addr = "/ip4/127.0.0.1/tcp/12345/tcp/12345"
-        .parse::<Multiaddr>()
-        .unwrap();
-    let config = Config::new(&keypair, addr).unwrap();
-    assert!(Endpoint::new(config).is_err())
+    let mut rsa_key = include_bytes!("test-rsa-private-key.pk8").to_vec();
+    let rsa = libp2p_core::identity::Keypair::rsa_from_pkcs8(&mut rsa_key).unwrap();
+    let secp256k1 = libp2p_core::identity::Keypair::generate_secp256k1();
+    async_std::task::block_on(async {
+        let (listen_addr, mut listener) = listen(config(&rsa)).await;
+        let dialer = QuicTransport(Endpoint::new(config(&secp256k1)).unwrap());
+        let (accepted, dialed) =
+            future::join(accept(&mut listener), dialer.dial(listen_addr).unwrap()).await;
+        assert_eq!(accepted.0, secp256k1.public().into_peer_id());
+        assert_eq!(dialed.unwrap().0, rsa.public().into_peer_id());
+    });
+}
+
+#[test]
+fn vectored_writes() {
+    init();
+    async_std::task::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let (listener_muxer, dialer_muxer) =
+            connected_pair(config(&keypair), new_peer_config()).await;
+        // Large enough for the writes to be cut short by the flow control.
+        let large = vec![7u8; 4 * 1024 * 1024];
+
+        let dialer = async {
+            let mut stream = Outbound(&dialer_muxer).await.unwrap();
+            let bufs = [
+                std::io::IoSlice::new(b"hello"),
+                std::io::IoSlice::new(b""),
+                std::io::IoSlice::new(b", "),
+                std::io::IoSlice::new(b"world"),
+            ];
+            assert_eq!(stream.write_vectored(&bufs).await.unwrap(), 12);
+            let (first, second) = large.split_at(large.len() / 2);
+            let mut written = 0;
+            while written < large.len() {
+                let bufs = [
+                    std::io::IoSlice::new(&first[written.min(first.len())..]),
+                    std::io::IoSlice::new(&second[written.saturating_sub(first.len())..]),
+                ];
+                let n = stream.write_vectored(&bufs).await.unwrap();
+                assert!(n > 0);
+                written += n;
+            }
+            stream.close().await.unwrap();
+        };
+
+        let listener = async {
+            let mut socket = Inbound(&listener_muxer).next().await.unwrap();
+            let mut received = Vec::new();
+            socket.read_to_end(&mut received).await.unwrap();
+            assert_eq!(&received[..12], b"hello, world");
+            assert!(received[12..] == large[..]);
+        };
+
+        future::join(dialer, listener).await;
+    });
+}
+
+#[test]
+fn chunk_and_vectored_reads() {
+    init();
+    async_std::task::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let (listener_muxer, dialer_muxer) =
+            connected_pair(config(&keypair), new_peer_config()).await;
+        let data = (0..64 * 1024).map(|i| i as u8).collect::<Vec<_>>();
+
+        let mut stream = Outbound(&dialer_muxer).await.unwrap();
+        stream.write_all(&data).await.unwrap();
+        stream.close().await.unwrap();
+
+        // Alternate between chunk reads, regular reads and vectored reads, which must all
+        // resume where the previous one stopped.
+        let mut socket = Inbound(&listener_muxer).next().await.unwrap();
+        let mut received = Vec::new();
+        for round in 0.. {
+            match round % 3 {
+                0 => {
+                    let id = socket.id.as_mut().unwrap();
+                    match future::poll_fn(|cx| listener_muxer.read_chunk(cx, id, 1000))
+                        .await
+                        .unwrap()
+                    {
+                        Some(chunk) => {
+                            assert!(!chunk.is_empty() && chunk.len() <= 1000);
+                            received.extend_from_slice(&chunk);
+                        }
+                        None => break,
+                    }
+                }
+                1 => {
+                    let mut buf = [0u8; 700];
+                    let n = socket.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    received.extend_from_slice(&buf[..n]);
+                }
+                _ => {
+                    let (mut a, mut b) = ([0u8; 300], [0u8; 500]);
+                    let mut bufs = [
+                        std::io::IoSliceMut::new(&mut a),
+                        std::io::IoSliceMut::new(&mut b),
+                    ];
+                    let n = socket.read_vectored(&mut bufs).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    let both = a.iter().chain(b.iter()).copied().collect::<Vec<_>>();
+                    received.extend_from_slice(&both[..n]);
+                }
+            }
+        }
+        assert!(received == data);
+
+        // The end of the substream keeps being reported.
+        let id = socket.id.as_mut().unwrap();
+        let chunk = future::poll_fn(|cx| listener_muxer.read_chunk(cx, id, 1000)).await;
+        assert!(chunk.unwrap().is_none());
+
+        // Chunks are bounded whatever the requested length.
+        let mut stream = Outbound(&dialer_muxer).await.unwrap();
+        stream.write_all(&data).await.unwrap();
+        stream.close().await.unwrap();
+        let mut socket = Inbound(&listener_muxer).next().await.unwrap();
+        let id = socket.id.as_mut().unwrap();
+        let chunk = future::poll_fn(|cx| listener_muxer.read_chunk(cx, id, usize::MAX))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!chunk.is_empty() && chunk.len() <= data.len());
+        assert_eq!(chunk[..], data[..chunk.len()]);
+    });
+}
+
+#[test]
+fn half_close() {
+    init();
+    async_std::task::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let (listener_muxer, dialer_muxer) =
+            connected_pair(config(&keypair), new_peer_config()).await;
+
+        let listener = async {
+            let mut socket = Inbound(&listener_muxer).next().await.unwrap();
+            // The end of the request is signalled by the dialer closing its side.
+            let mut request = Vec::new();
+            socket.read_to_end(&mut request).await.unwrap();
+            assert_eq!(request, [4, 5, 6]);
+            socket.write_all(&[1, 2, 3]).await.unwrap();
+            socket.close().await.unwrap();
+        };
+
+        let dialer = async {
+            let mut stream = Outbound(&dialer_muxer).await.unwrap();
+            stream.write_all(&[4, 5, 6]).await.unwrap();
+            stream.close().await.unwrap();
+
+            let waker = futures::task::noop_waker();
+            let mut cx = Context::from_waker(&waker);
+            match dialer_muxer.write_substream(&mut cx, stream.id.as_mut().unwrap(), &[7]) {
+                Poll::Ready(Err(libp2p_quic::Error::WriteAfterClose)) => {}
+                other => panic!("unexpected result: {:?}", other),
+            }
+
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).await.unwrap();
+            assert_eq!(response, [1, 2, 3]);
+        };
+
+        future::join(listener, dialer).await;
+    });
+}
+
+#[test]
+fn listen_on_given_socket() {
+    use libp2p_core::transport::TransportError;
+
+    init();
+    async_std::task::block_on(async {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let bound = socket.local_addr().unwrap();
+        let addr = quic_addr(bound);
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        // Port 0 in the multiaddr of the configuration matches the port of the socket.
+        let endpoint = Endpoint::from_socket(config(&keypair), socket).unwrap();
+        let mut listener = QuicTransport(endpoint).listen_on(addr.clone()).unwrap();
+        match listener.next().await.unwrap().unwrap() {
+            ListenerEvent::NewAddress(listen_addr) => {
+                assert_eq!(socket_addr(&listen_addr), bound)
+            }
+            _ => panic!("expected a listen address"),
+        }
+
+        let dialer = QuicTransport(Endpoint::new(new_peer_config()).unwrap());
+        let (_, dial) = future::join(accept(&mut listener), dialer.dial(addr).unwrap()).await;
+        assert_eq!(dial.unwrap().0, keypair.public().into_peer_id());
+    });
+
+    // Connected sockets are rejected.
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.connect("127.0.0.1:4001").unwrap();
+    match Endpoint::from_socket(new_peer_config(), socket) {
+        Err(TransportError::Other(err)) => {
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+            assert!(err.to_string().contains("connected"));
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+
+    // So are sockets bound to another address than the multiaddr of the configuration.
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let bound = socket.local_addr().unwrap();
+    let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+    let unspecified: Multiaddr = format!("/ip4/0.0.0.0/udp/{}/quic", bound.port())
+        .parse()
+        .unwrap();
+    let exact = Config::new(&keypair, quic_addr(bound)).unwrap();
+    Endpoint::from_socket(exact, socket.try_clone().unwrap()).unwrap();
+    let unspecified = Config::new(&keypair, unspecified).unwrap();
+    Endpoint::from_socket(unspecified, socket.try_clone().unwrap()).unwrap();
+    let other_port = SocketAddr::new(bound.ip(), bound.port().checked_add(1).unwrap_or(1));
+    for addr in vec![
+        "/ip6/::1/udp/0/quic".parse().unwrap(),
+        "/ip6/::/udp/0/quic".parse().unwrap(),
+        quic_addr(other_port),
+    ] {
+        let config = Config::new(&keypair, addr).unwrap();
+        match Endpoint::from_socket(config, socket.try_clone().unwrap()) {
+            Err(TransportError::Other(err)) => {
+                assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+                assert!(err.to_string().contains("doesn't match"));
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn replace_port_0_in_returned_multiaddr_ipv4() {
+    init();
+    let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+
+    let addr = "/ip4/127.0.0.1/udp/0/quic".parse::<Multiaddr>().unwrap();
+    assert!(addr.to_string().ends_with("udp/0/quic"));
+
+    let config = Config::new(&keypair, addr.clone()).unwrap();
+    let quic = QuicTransport(Endpoint::new(config).expect("no error"));
+
+    let new_addr = futures::executor::block_on_stream(quic.listen_on(addr).unwrap())
+        .next()
+        .expect("some event")
+        .expect("no error")
+        .into_new_address()
+        .expect("listen address");
+
+    if new_addr.to_string().contains("udp/0") {
+        panic!("failed to expand address ― got {}", new_addr);
+    }
+}
+
+#[test]
+fn replace_port_0_in_returned_multiaddr_ipv6() {
+    init();
+    let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+
+    let addr: Multiaddr = "/ip6/::1/udp/0/quic".parse().unwrap();
+    assert!(addr.to_string().contains("udp/0/quic"));
+    let config = Config::new(&keypair, addr.clone()).unwrap();
+    let quic = QuicTransport(Endpoint::new(config).expect("no error"));
+
+    let new_addr = futures::executor::block_on_stream(quic.listen_on(addr).unwrap())
+        .next()
+        .expect("some event")
+        .expect("no error")
+        .into_new_address()
+        .expect("listen address");
+
+    assert!(!new_addr.to_string().contains("udp/0"));
+}
+
+#[test]
+fn dialing_listen_only_addresses_fails() {
+    use libp2p_core::transport::TransportError;
+
+    init();
+    let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+    let listen_addr: Multiaddr = "/ip4/0.0.0.0/udp/0/quic".parse().unwrap();
+    let config = Config::new(&keypair, listen_addr.clone()).unwrap();
+    let transport = QuicTransport(Endpoint::new(config).unwrap());
+    for addr in &[
+        "/ip4/0.0.0.0/udp/0/quic",
+        "/ip4/0.0.0.0/udp/1234/quic",
+        "/ip4/127.0.0.1/udp/0/quic",
+        "/ip4/1.2.3.4/udp/0/quic",
+        "/ip4/127.0.0.1/tcp/1234",
+    ] {
+        let addr: Multiaddr = addr.parse().unwrap();
+        match transport.clone().dial(addr.clone()) {
+            Err(TransportError::MultiaddrNotSupported(a)) => assert_eq!(a, addr),
+            Err(other) => panic!("unexpected error for {}: {:?}", addr, other),
+            Ok(_) => panic!("dialing {} succeeded", addr),
+        }
+    }
+
+    assert!(transport.listen_on(listen_addr).is_ok());
+}
+
+#[test]
+fn config_errors_can_be_handled() {
+    let addr: Multiaddr = "/ip4/127.0.0.1/udp/0/quic".parse().unwrap();
+    let from_der = |key_type| Config::from_der(b"not a key", key_type, addr.clone());
+
+    match from_der(libp2p_quic::KeyType::Ed25519) {
+        Err(libp2p_quic::ConfigError::MalformedPkcs8) => {}
+        other => panic!("unexpected result: {:?}", other),
+    }
+    match from_der(libp2p_quic::KeyType::Rsa) {
+        Err(libp2p_quic::ConfigError::KeyDecodingError(_)) => {}
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn larger_addr_denied() {
+    init();
+    let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+    let addr = "/ip4/127.0.0.1/tcp/12345/tcp/12345"
+        .parse::<Multiaddr>()
+        .unwrap();
+    let config = Config::new(&keypair, addr).unwrap();
+    assert!(Endpoint::new(config).is_err())
+}
+
+#[test]
+fn initial_window() {
+    init();
+    let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+    assert!(Endpoint::new(config(&keypair).with_initial_window(0)).is_err());
+
+    async_std::task::block_on(async {
+        let listener_config = config(&keypair);
+        let dialer_config = new_peer_config().with_initial_window(64 * 1024);
+        let (listener_muxer, dialer_muxer) = connected_pair(listener_config, dialer_config).await;
+        assert!(listener_muxer.is_remote_acknowledged());
+        assert!(dialer_muxer.is_remote_acknowledged());
+    });
+}
+
+#[test]
+fn custom_executor() {
+    init();
+    futures::executor::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let (listen_addr, mut listener) =
+            listen(config(&keypair).with_executor(ThreadExecutor)).await;
+        let dialer_config = new_peer_config().with_executor_fn(|future| {
+            std::thread::spawn(move || futures::executor::block_on(future));
+        });
+        let dialer_endpoint = Endpoint::new(dialer_config).unwrap();
+        let dialer = QuicTransport(dialer_endpoint.clone());
+        let ((_, listener_muxer), dial) =
+            future::join(accept(&mut listener), dialer.dial(listen_addr).unwrap()).await;
+        let (_, dialer_muxer) = dial.unwrap();
+
+        let mut stream = Outbound(&dialer_muxer).await.unwrap();
+        stream.write_all(&[1, 2, 3]).await.unwrap();
+        let mut socket = Inbound(&listener_muxer).next().await.unwrap();
+        let mut buf = [0u8; 3];
+        socket.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [1, 2, 3]);
+
+        dialer_endpoint.close_now().await;
+    });
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn tokio_runtime() {
+    init();
+    let mut runtime = tokio_crate::runtime::Builder::new()
+        .basic_scheduler()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let (listen_addr, mut listener) = listen(config(&keypair)).await;
+        let dialer = QuicTransport(Endpoint::new(new_peer_config()).unwrap());
+        let ((_, listener_muxer), dial) =
+            future::join(accept(&mut listener), dialer.dial(listen_addr).unwrap()).await;
+        let (_, dialer_muxer) = dial.unwrap();
+
+        let mut stream = Outbound(&dialer_muxer).await.unwrap();
+        stream.write_all(&[1, 2, 3]).await.unwrap();
+        let mut socket = Inbound(&listener_muxer).next().await.unwrap();
+        let mut buf = [0u8; 3];
+        socket.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [1, 2, 3]);
+    });
+}
+
+#[test]
+fn endpoint_driver_spawned_by_caller() {
+    init();
+    async_std::task::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let (listen_addr, mut listener) = listen(config(&keypair)).await;
+        let (dialer_endpoint, driver) = Endpoint::new_with_driver(new_peer_config()).unwrap();
+        let driver = async_std::task::spawn(driver);
+
+        let dialer = QuicTransport(dialer_endpoint.clone());
+        let dial = async_std::task::spawn(dialer.dial(listen_addr).unwrap());
+        let (_, listener_muxer) = accept(&mut listener).await;
+        let (_, dialer_muxer) = dial.await.unwrap();
+        let mut stream = Outbound(&dialer_muxer).await.unwrap();
+        stream.write_all(&[1, 2, 3]).await.unwrap();
+        let mut socket = Inbound(&listener_muxer).next().await.unwrap();
+        let mut buf = [0u8; 3];
+        socket.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [1, 2, 3]);
+
+        // The driver resolves once the endpoint is closed.
+        dialer_endpoint.close_now().await;
+        driver.await;
+    });
+}
+
+#[test]
+fn join_driver() {
+    init();
+    async_std::task::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let (listener_muxer, dialer_muxer) =
+            connected_pair(config(&keypair), new_peer_config()).await;
+        start_close(&dialer_muxer);
+        wait_closed(&listener_muxer).await;
+        dialer_muxer.join_driver().await.unwrap();
+        listener_muxer.join_driver().await.unwrap();
+
+        // The executor of the dialer only runs the background task of the endpoint, and drops
+        // the one of the connection.
+        let executor = FirstTaskExecutor(Default::default());
+        let dialer_config = new_peer_config().with_executor(executor);
+        let (_listener_muxer, dialer_muxer) = connected_pair(config(&keypair), dialer_config).await;
+        match dialer_muxer.join_driver().await {
+            Err(libp2p_quic::Error::DriverFailed) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    });
+}
+
+#[test]
+fn migration_disabled_terminates_connection() {
+    init();
+    async_std::task::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        // The listener's idle timeout is long enough that only the migration attempt can close
+        // its connection in time.
+        let listener_config = config(&keypair)
+            .with_migration(false)
+            .with_idle_timeout(Some(Duration::from_secs(30)));
+        let (listen_addr, mut listener) = listen(listener_config).await;
+        let relay = UdpRelay::spawn(socket_addr(&listen_addr)).await;
+
+        let dialer_config = new_peer_config().with_idle_timeout(Some(Duration::from_secs(1)));
+        let dialer = QuicTransport(Endpoint::new(dialer_config).unwrap());
+        let dial = async_std::task::spawn(dialer.dial(relay.addr.clone()).unwrap());
+        let (_, listener_muxer) = accept(&mut listener).await;
+        let (_, dialer_muxer) = dial.await.unwrap();
+
+        let mut stream = Outbound(&dialer_muxer).await.unwrap();
+        stream.write_all(&[1, 2, 3]).await.unwrap();
+        let mut socket = Inbound(&listener_muxer).next().await.unwrap();
+        let mut buf = [0u8; 3];
+        socket.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [1, 2, 3]);
+
+        // From now on, the listener receives the dialer's packets from a different address.
+        relay.rebind();
+        let _ = stream.write_all(&[4, 5, 6]).await;
+
+        async_std::future::timeout(Duration::from_secs(5), wait_closed(&listener_muxer))
+            .await
+            .expect("connection migrated instead of being terminated");
+        assert!(listener_muxer
+            .recent_errors()
+            .iter()
+            .any(|e| matches!(e, libp2p_quic::ErrorEvent::MigrationRefused { .. })));
+        async_std::future::timeout(Duration::from_secs(5), wait_closed(&dialer_muxer))
+            .await
+            .expect("dialer didn't notice the termination");
+    });
+}
+
+#[test]
+fn spoofed_path_change_does_not_terminate_connection() {
+    init();
+    async_std::task::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let listener_config = config(&keypair).with_migration(false);
+        let (listen_addr, mut listener) = listen(listener_config).await;
+        let relay = UdpRelay::spawn(socket_addr(&listen_addr)).await;
+
+        let dialer = QuicTransport(Endpoint::new(new_peer_config()).unwrap());
+        let dial = async_std::task::spawn(dialer.dial(relay.addr.clone()).unwrap());
+        let (_, listener_muxer) = accept(&mut listener).await;
+        let (_, dialer_muxer) = dial.await.unwrap();
+
+        let mut stream = Outbound(&dialer_muxer).await.unwrap();
+        stream.write_all(&[1, 2, 3]).await.unwrap();
+        let mut socket = Inbound(&listener_muxer).next().await.unwrap();
+        let mut buf = [0u8; 3];
+        socket.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [1, 2, 3]);
+
+        // A packet carrying the connection ID of the connection arrives from another address,
+        // but can't be authenticated.
+        relay.spoof().await;
+
+        stream.write_all(&[4, 5, 6]).await.unwrap();
+        socket.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [4, 5, 6]);
+        assert!(!listener_muxer
+            .recent_errors()
+            .iter()
+            .any(|e| matches!(e, libp2p_quic::ErrorEvent::MigrationRefused { .. })));
+    });
+}
+
+#[test]
+fn remote_acknowledged_tracks_connection_state() {
+    init();
+    async_std::task::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let (listen_addr, mut listener) = listen(config(&keypair)).await;
+        let dialer = QuicTransport(Endpoint::new(new_peer_config()).unwrap());
+        let dial = async_std::task::spawn(dialer.dial(listen_addr).unwrap());
+        let upgrade = loop {
+            match listener.next().await.unwrap().unwrap() {
+                ListenerEvent::Upgrade { upgrade, .. } => break upgrade,
+                ListenerEvent::NewAddress(_) => {}
+                _ => unreachable!(),
+            }
+        };
+        assert!(!upgrade.is_remote_acknowledged());
+        let (_, listener_muxer) = upgrade.await.unwrap();
+        assert!(listener_muxer.is_remote_acknowledged());
+        let (_, dialer_muxer) = dial.await.unwrap();
+        assert!(dialer_muxer.is_remote_acknowledged());
+    });
+
+    async_std::task::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let (listener_muxer, dialer_muxer) =
+            connected_pair(config(&keypair), new_peer_config()).await;
+        assert!(listener_muxer.is_remote_acknowledged());
+        assert!(dialer_muxer.is_remote_acknowledged());
+
+        start_close(&dialer_muxer);
+        assert!(!dialer_muxer.is_remote_acknowledged());
+        wait_closed(&listener_muxer).await;
+        assert!(!listener_muxer.is_remote_acknowledged());
+    });
+}
+
+#[test]
+fn dropping_endpoint_releases_port() {
+    init();
+    let port = std::net::UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let addr: Multiaddr = format!("/ip4/127.0.0.1/udp/{}/quic", port).parse().unwrap();
+    let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+    let config = Config::new(&keypair, addr).unwrap();
+    // Dropping the endpoint releases the port right away, whether its background task is
+    // running or not.
+    for _ in 0..200 {
+        let endpoint = Endpoint::new(config.clone()).expect("port is still in use");
+        drop(endpoint);
+    }
+    for _ in 0..200 {
+        let (endpoint, _driver) =
+            Endpoint::new_with_driver(config.clone()).expect("port is still in use");
+        drop(endpoint);
+    }
+
+    async_std::task::block_on(async {
+        // Closing explicitly releases the port as well.
+        for _ in 0..200 {
+            let endpoint = Endpoint::new(config.clone()).expect("port is still in use");
+            endpoint.close_now().await;
+        }
+
+        // Every caller waits for the port to be released, not only the first one.
+        for _ in 0..200 {
+            let endpoint = Endpoint::new(config.clone()).expect("port is still in use");
+            let first = endpoint.close_now();
+            futures::pin_mut!(first);
+            let _ = futures::poll!(first.as_mut());
+            endpoint.close_now().await;
+            std::net::UdpSocket::bind(("127.0.0.1", port)).expect("port is still in use");
+        }
+    });
+}
+
+#[test]
+fn endpoint_close_drains_connections() {
+    init();
+    async_std::task::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let listener_endpoint = Endpoint::new(config(&keypair)).unwrap();
+        let listener_transport = QuicTransport(listener_endpoint.clone());
+        let mut listener = listener_transport
+            .clone()
+            .listen_on("/ip4/127.0.0.1/udp/0/quic".parse().unwrap())
+            .unwrap();
+        let listen_addr = match listener.next().await.unwrap().unwrap() {
+            ListenerEvent::NewAddress(listen_addr) => listen_addr,
+            _ => panic!("expected a listen address"),
+        };
+        let dialer = QuicTransport(Endpoint::new(new_peer_config()).unwrap());
+        let dial = async_std::task::spawn(dialer.dial(listen_addr.clone()).unwrap());
+        let (_, listener_muxer) = accept(&mut listener).await;
+        let (_, dialer_muxer) = dial.await.unwrap();
+        let dialer_muxer = Arc::new(dialer_muxer);
+
+        // Keep a transfer going until the connection is closed under its feet.
+        let writer = {
+            let muxer = dialer_muxer.clone();
+            async_std::task::spawn(async move {
+                let mut substream = future::poll_fn(|cx| muxer.poll_outbound(cx, &mut ()))
+                    .await
+                    .unwrap();
+                let data = [0u8; 1024];
+                while future::poll_fn(|cx| muxer.write_substream(cx, &mut substream, &data))
+                    .await
+                    .is_ok()
+                {}
+            })
+        };
+        let mut inbound = future::poll_fn(|cx| listener_muxer.poll_inbound(cx))
+            .await
+            .unwrap();
+        let mut buf = [0u8; 1024];
+        future::poll_fn(|cx| listener_muxer.read_substream(cx, &mut inbound, &mut buf))
+            .await
+            .unwrap();
+
+        listener_endpoint.close(42, b"shutting down").await.unwrap();
+        match wait_closed(&dialer_muxer).await {
+            libp2p_quic::Error::ConnectionError(
+                quinn_proto::ConnectionError::ApplicationClosed(close),
+            ) => {
+                assert_eq!(close.error_code, quinn_proto::VarInt::from_u32(42));
+                assert_eq!(&close.reason[..], b"shutting down");
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+        writer.await;
+
+        match listener_transport.dial(listen_addr).unwrap().await {
+            Err(libp2p_quic::transport::Error::EndpointClosed) => {}
+            Err(err) => panic!("unexpected error: {:?}", err),
+            Ok(_) => panic!("dialing from a closed endpoint succeeded"),
+        }
+    });
+}
+
+#[test]
+fn close_all_closes_every_connection() {
+    init();
+    async_std::task::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let listener_endpoint = Endpoint::new(config(&keypair)).unwrap();
+        let mut listener = QuicTransport(listener_endpoint.clone())
+            .listen_on("/ip4/127.0.0.1/udp/0/quic".parse().unwrap())
+            .unwrap();
+        let listen_addr = match listener.next().await.unwrap().unwrap() {
+            ListenerEvent::NewAddress(listen_addr) => listen_addr,
+            _ => panic!("expected a listen address"),
+        };
+
+        let mut muxers = Vec::new();
+        for _ in 0..3 {
+            let dialer = QuicTransport(Endpoint::new(new_peer_config()).unwrap());
+            let (accepted, dial) = future::join(
+                accept(&mut listener),
+                dialer.dial(listen_addr.clone()).unwrap(),
+            )
+            .await;
+            muxers.push((accepted.1, dial.unwrap().1));
+        }
+
+        listener_endpoint.close_all(7, b"bye").await.unwrap();
+        for (_, dialer_muxer) in &muxers {
+            match wait_closed(dialer_muxer).await {
+                libp2p_quic::Error::ConnectionError(
+                    quinn_proto::ConnectionError::ApplicationClosed(close),
+                ) => assert_eq!(close.error_code, quinn_proto::VarInt::from_u32(7)),
+                other => panic!("unexpected error: {:?}", other),
+            }
+        }
+
+        // The endpoint still accepts connections.
+        let dialer = QuicTransport(Endpoint::new(new_peer_config()).unwrap());
+        let (_, dial) =
+            future::join(accept(&mut listener), dialer.dial(listen_addr).unwrap()).await;
+        dial.unwrap();
+    });
+}
+
+#[test]
+fn pending_connections_are_limited() {
+    const LIMIT: usize = 10;
+    const DIALS: usize = 100;
+
+    init();
+    let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+    assert!(Endpoint::new(config(&keypair).with_max_pending_connections(0)).is_err());
+
+    async_std::task::block_on(async {
+        let listener_config = config(&keypair).with_max_pending_connections(LIMIT as u32);
+        let listener_endpoint = Endpoint::new(listener_config).unwrap();
+        let mut listener = QuicTransport(listener_endpoint.clone())
+            .listen_on("/ip4/127.0.0.1/udp/0/quic".parse().unwrap())
+            .unwrap();
+        let listen_addr = match listener.next().await.unwrap().unwrap() {
+            ListenerEvent::NewAddress(listen_addr) => listen_addr,
+            _ => panic!("expected a listen address"),
+        };
+
+        let dialer = QuicTransport(Endpoint::new(new_peer_config()).unwrap());
+        let mut dials = (0..DIALS)
+            .map(|_| async_std::task::spawn(dialer.clone().dial(listen_addr.clone()).unwrap()))
+            .collect::<stream::FuturesUnordered<_>>();
+
+        // The application is slow: it takes the upgrades from the listener one at a time, and
+        // doesn't drive them until all the other dials have been refused. The handshakes of
+        // the upgrades can't complete in the meantime.
+        let mut upgrades = Vec::new();
+        while upgrades.len() < LIMIT {
+            if let ListenerEvent::Upgrade { upgrade, .. } = listener.next().await.unwrap().unwrap()
+            {
+                upgrades.push(upgrade);
+            }
+            assert!(listener_endpoint.pending_connections() <= LIMIT);
+        }
+        for _ in LIMIT..DIALS {
+            assert!(dials.next().await.unwrap().is_err());
+        }
+        assert_eq!(listener_endpoint.pending_connections(), LIMIT);
+        assert!(listener.next().now_or_never().is_none());
+
+        let _muxers = future::try_join_all(upgrades).await.unwrap();
+        let established = dials.collect::<Vec<_>>().await;
+        assert!(established.iter().all(|result| result.is_ok()));
+
+        // Completed handshakes free their slot.
+        assert_eq!(listener_endpoint.pending_connections(), 0);
+        let dialer = QuicTransport(Endpoint::new(new_peer_config()).unwrap());
+        let (_, dial) =
+            future::join(accept(&mut listener), dialer.dial(listen_addr).unwrap()).await;
+        dial.unwrap();
+    });
+}
+
+#[test]
+fn listener_close_releases_port() {
+    init();
+    async_std::task::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let (listen_addr, remote) = listen(config(&keypair)).await;
+
+        let transport = QuicTransport(Endpoint::new(config(&keypair)).unwrap());
+        let mut listener = transport
+            .clone()
+            .listen_on("/ip4/127.0.0.1/udp/0/quic".parse().unwrap())
+            .unwrap();
+        let addr = match listener.next().await.unwrap().unwrap() {
+            ListenerEvent::NewAddress(addr) => addr,
+            _ => panic!("expected a listen address"),
+        };
+        listener.close().await;
+        std::net::UdpSocket::bind(socket_addr(&addr)).expect("port is still in use");
+
+        match transport.dial(listen_addr).unwrap().await {
+            Err(libp2p_quic::transport::Error::EndpointClosed) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        drop(remote);
+    });
+}
+
+#[test]
+fn close_with_reports_error_code() {
+    init();
+    async_std::task::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let (listener_muxer, dialer_muxer) =
+            connected_pair(config(&keypair), new_peer_config()).await;
+
+        let waker = futures::task::noop_waker();
+        let _ = dialer_muxer.close_with(&mut Context::from_waker(&waker), 42, b"bye");
+
+        match wait_closed(&listener_muxer).await {
+            libp2p_quic::Error::ConnectionError(
+                quinn_proto::ConnectionError::ApplicationClosed(close),
+            ) => {
+                assert_eq!(close.error_code, quinn_proto::VarInt::from_u32(42));
+                assert_eq!(&close.reason[..], b"bye");
+            }
+            err => panic!("unexpected error: {:?}", err),
+        }
+    });
+}
+
+#[test]
+fn oversized_close_reason_is_rejected() {
+    use libp2p_quic::muxer::MAX_CLOSE_REASON_LEN;
+
+    init();
+    async_std::task::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let (listener_muxer, dialer_muxer) =
+            connected_pair(config(&keypair), new_peer_config()).await;
+
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let reason = vec![b'x'; MAX_CLOSE_REASON_LEN + 1];
+        match dialer_muxer.close_with(&mut cx, 42, &reason) {
+            Poll::Ready(Err(libp2p_quic::Error::CloseReasonTooLong(len))) => {
+                assert_eq!(len, reason.len())
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        // The connection is still open, and the longest allowed reason is delivered as is.
+        let _ = dialer_muxer.close_with(&mut cx, 42, &reason[..MAX_CLOSE_REASON_LEN]);
+        match wait_closed(&listener_muxer).await {
+            libp2p_quic::Error::ConnectionError(
+                quinn_proto::ConnectionError::ApplicationClosed(close),
+            ) => assert_eq!(&close.reason[..], &reason[..MAX_CLOSE_REASON_LEN]),
+            err => panic!("unexpected error: {:?}", err),
+        }
+    });
+}
+
+#[test]
+fn flow_control_stalls_are_recorded() {
+    init();
+    async_std::task::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let (_listener_muxer, dialer_muxer) =
+            connected_pair(config(&keypair), new_peer_config()).await;
+        assert!(dialer_muxer.recent_errors().is_empty());
+
+        // Nobody reads on the listener side, so writing eventually runs out of credit.
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let data = vec![0u8; 64 * 1024];
+        let mut substreams = Vec::new();
+        for _ in 0..2 {
+            let mut substream = Outbound(&dialer_muxer).await.unwrap().id.take().unwrap();
+            while let Poll::Ready(result) =
+                dialer_muxer.write_substream(&mut cx, &mut substream, &data)
+            {
+                assert_ne!(result.unwrap(), 0);
+            }
+            substreams.push(substream);
+        }
+
+        let expected = substreams
+            .iter()
+            .map(|&substream| libp2p_quic::ErrorEvent::FlowControlBlocked { substream })
+            .collect::<Vec<_>>();
+        assert_eq!(dialer_muxer.recent_errors(), expected);
+    });
+}
+
+#[test]
+fn unread_data_is_capped_per_connection() {
+    const WINDOW: usize = 64 * 1024;
+
+    init();
+    async_std::task::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let listener_config = config(&keypair)
+            .with_stream_receive_window(WINDOW as u64 / 4)
+            .with_receive_window(WINDOW as u64)
+            .with_datagrams(true);
+        let dialer_config = new_peer_config().with_datagrams(true);
+        let (listener_muxer, dialer_muxer) = connected_pair(listener_config, dialer_config).await;
+
+        // The listener never reads, while the dialer dribbles data over many substreams, which
+        // combined could hold far more than the connection window.
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut substreams = Vec::new();
+        for _ in 0..32 {
+            substreams.push(Outbound(&dialer_muxer).await.unwrap().id.take().unwrap());
+        }
+        let mut written = 0;
+        for _ in 0..3 {
+            for substream in &mut substreams {
+                while let Poll::Ready(result) =
+                    dialer_muxer.write_substream(&mut cx, substream, &[0x5a; 1024])
+                {
+                    written += result.unwrap();
+                }
+            }
+            // Datagrams aren't subject to flow control. Once the listener has answered one, it
+            // has processed the data sent before, and would have raised the limit already.
+            dialer_muxer.send_datagram(b"sync".to_vec()).unwrap();
+            future::poll_fn(|cx| listener_muxer.poll_datagram(cx))
+                .await
+                .unwrap();
+            listener_muxer.send_datagram(b"sync".to_vec()).unwrap();
+            future::poll_fn(|cx| dialer_muxer.poll_datagram(cx))
+                .await
+                .unwrap();
+        }
+        assert!(written > 0);
+        assert!(
+            written <= WINDOW,
+            "{} bytes written with a receive window of {}",
+            written,
+            WINDOW
+        );
+    });
+}
+
+#[test]
+fn keep_alive() {
+    init();
+    let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+    let invalid = config(&keypair)
+        .with_idle_timeout(Some(Duration::from_secs(1)))
+        .with_keep_alive_interval(Some(Duration::from_secs(1)));
+    assert!(Endpoint::new(invalid).is_err());
+
+    async_std::task::block_on(async {
+        let idle_timeout = Duration::from_millis(500);
+        let with_keep_alive = |config: Config, keep_alive_interval| {
+            config
+                .with_idle_timeout(Some(idle_timeout))
+                .with_keep_alive_interval(keep_alive_interval)
+        };
+
+        let keep_alive_interval = Some(Duration::from_millis(100));
+        let (listener_muxer, dialer_muxer) = connected_pair(
+            with_keep_alive(config(&keypair), keep_alive_interval),
+            with_keep_alive(new_peer_config(), keep_alive_interval),
+        )
+        .await;
+        let closed = future::select(
+            wait_closed(&listener_muxer).boxed(),
+            wait_closed(&dialer_muxer).boxed(),
+        );
+        let result = async_std::future::timeout(4 * idle_timeout, closed).await;
+        assert!(result.is_err(), "connection closed despite keep-alive");
+        assert!(listener_muxer.is_remote_acknowledged());
+        assert!(dialer_muxer.is_remote_acknowledged());
+
+        let (listener_muxer, dialer_muxer) = connected_pair(
+            with_keep_alive(config(&keypair), None),
+            with_keep_alive(new_peer_config(), None),
+        )
+        .await;
+        async_std::future::timeout(4 * idle_timeout, async {
+            future::join(wait_closed(&dialer_muxer), wait_closed(&listener_muxer)).await
+        })
+        .await
+        .expect("idle connection wasn't closed");
+    });
+}
+
+#[test]
+fn receive_windows() {
+    const LEN: usize = 256 * 1024;
+
+    /// Sends `LEN` bytes to a listener built from `listener_config`, and returns the number of
+    /// times writing was blocked by flow control.
+    async fn transfer(listener_config: Config, dialer_config: Config) -> usize {
+        let (listener_muxer, dialer_muxer) = connected_pair(listener_config, dialer_config).await;
+        let write = async {
+            let mut stream = Outbound(&dialer_muxer).await.unwrap();
+            stream.write_all(&vec![0x5a; LEN]).await.unwrap();
+            stream.close().await.unwrap();
+        };
+        let read = async {
+            let mut stream = Inbound(&listener_muxer).next().await.unwrap();
+            let mut buf = Vec::new();
+            stream.read_to_end(&mut buf).await.unwrap();
+            buf
+        };
+        let ((), received) = future::join(write, read).await;
+        assert_eq!(received, vec![0x5a; LEN]);
+        dialer_muxer.recent_errors().len()
+    }
+
+    init();
+    let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+    for &window in &[0, 1 << 62] {
+        assert!(Endpoint::new(config(&keypair).with_stream_receive_window(window)).is_err());
+        assert!(Endpoint::new(config(&keypair).with_receive_window(window)).is_err());
+    }
+
+    async_std::task::block_on(async {
+        let large = config(&keypair)
+            .with_stream_receive_window(4 * LEN as u64)
+            .with_receive_window(4 * LEN as u64);
+        let large_stalls = transfer(large, new_peer_config()).await;
+
+        let tiny = config(&keypair)
+            .with_stream_receive_window(1024)
+            .with_receive_window(2048);
+        let tiny_stalls = transfer(tiny, new_peer_config()).await;
+
+        assert!(
+            tiny_stalls > large_stalls,
+            "{} stalls with tiny windows, {} with large ones",
+            tiny_stalls,
+            large_stalls
+        );
+    });
+}
+
+#[test]
+fn bulk_transfer_through_small_window() {
+    const LEN: usize = 10 * 1024 * 1024;
+
+    init();
+    let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+    let listener_config = config(&keypair)
+        .with_stream_receive_window(4 * 1024)
+        .with_receive_window(16 * 1024);
+    async_std::task::block_on(async {
+        let (listener_muxer, dialer_muxer) =
+            connected_pair(listener_config, new_peer_config()).await;
+        let data = (0..LEN).map(|i| i as u8).collect::<Vec<_>>();
+        let write = async {
+            let mut stream = Outbound(&dialer_muxer).await.unwrap();
+            stream.write_all(&data).await.unwrap();
+            stream.close().await.unwrap();
+        };
+        let read = async {
+            let mut stream = Inbound(&listener_muxer).next().await.unwrap();
+            let mut buf = Vec::with_capacity(LEN);
+            stream.read_to_end(&mut buf).await.unwrap();
+            buf
+        };
+        let transfer = future::join(write, read);
+        let ((), received) = async_std::future::timeout(Duration::from_secs(60), transfer)
+            .await
+            .expect("the transfer stalled");
+        assert!(received == data, "received data differs from the sent data");
+        assert!(dialer_muxer
+            .recent_errors()
+            .iter()
+            .any(|e| matches!(e, libp2p_quic::ErrorEvent::FlowControlBlocked { .. })));
+    });
+}
+
+#[test]
+fn bulk_transfer_alongside_substream_flood() {
+    const LEN: usize = 4 * 1024 * 1024;
+    // Maximum number of flood substreams opened and accepted between two reads on the bulk
+    // substream.
+    const MAX_FLOOD_PER_READ: usize = 1000;
+
+    init();
+    let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+    async_std::task::block_on(async {
+        let (listener_muxer, dialer_muxer) =
+            connected_pair(config(&keypair), new_peer_config()).await;
+        let (listener_muxer, dialer_muxer) = (Arc::new(listener_muxer), Arc::new(dialer_muxer));
+
+        // The bulk substream is the first one that the listener accepts.
+        let mut sender = future::poll_fn(|cx| dialer_muxer.poll_outbound(cx, &mut ()))
+            .await
+            .unwrap();
+        future::poll_fn(|cx| dialer_muxer.write_substream(cx, &mut sender, &[0]))
+            .await
+            .unwrap();
+        let mut receiver = future::poll_fn(|cx| listener_muxer.poll_inbound(cx))
+            .await
+            .unwrap();
+
+        // During the transfer, the dialer opens short-lived substreams as fast as the listener
+        // accepts them, from tasks of their own.
+        let flooded = Arc::new(AtomicUsize::new(0));
+        let (flood, stop_flood) = future::abortable({
+            let (dialer_muxer, flooded) = (dialer_muxer.clone(), flooded.clone());
+            async move {
+                loop {
+                    let mut substream =
+                        future::poll_fn(|cx| dialer_muxer.poll_outbound(cx, &mut ()))
+                            .await
+                            .unwrap();
+                    future::poll_fn(|cx| {
+                        dialer_muxer.write_substream(cx, &mut substream, b"flood")
+                    })
+                    .await
+                    .unwrap();
+                    future::poll_fn(|cx| dialer_muxer.shutdown_substream(cx, &mut substream))
+                        .await
+                        .unwrap();
+                    dialer_muxer.destroy_substream(substream);
+                    flooded.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        });
+        let (accept, stop_accept) = future::abortable({
+            let listener_muxer = listener_muxer.clone();
+            async move {
+                loop {
+                    let mut substream = future::poll_fn(|cx| listener_muxer.poll_inbound(cx))
+                        .await
+                        .unwrap();
+                    let mut buf = [0; 16];
+                    while future::poll_fn(|cx| {
+                        listener_muxer.read_substream(cx, &mut substream, &mut buf)
+                    })
+                    .await
+                    .unwrap()
+                        != 0
+                    {}
+                    listener_muxer.destroy_substream(substream);
+                }
+            }
+        });
+        async_std::task::spawn(flood);
+        async_std::task::spawn(accept);
+
+        let data = vec![0x42; LEN];
+        let send = async {
+            let mut sent = 0;
+            while sent < data.len() {
+                sent += future::poll_fn(|cx| {
+                    dialer_muxer.write_substream(cx, &mut sender, &data[sent..])
+                })
+                .await
+                .unwrap();
+            }
+            future::poll_fn(|cx| dialer_muxer.shutdown_substream(cx, &mut sender))
+                .await
+                .unwrap();
+        };
+        // Measures the progress of the transfer against the one of the flood, which doesn't
+        // depend on the speed of the machine running the test.
+        let receive = async {
+            let mut buf = vec![0; 64 * 1024];
+            let mut received = 0;
+            let mut last_flooded = flooded.load(Ordering::SeqCst);
+            let mut max_flood_per_read = 0;
+            loop {
+                match future::poll_fn(|cx| {
+                    listener_muxer.read_substream(cx, &mut receiver, &mut buf)
+                })
+                .await
+                .unwrap()
+                {
+                    0 => break (received, max_flood_per_read),
+                    n => received += n,
+                }
+                let now_flooded = flooded.load(Ordering::SeqCst);
+                max_flood_per_read = max_flood_per_read.max(now_flooded - last_flooded);
+                last_flooded = now_flooded;
+            }
+        };
+
+        let ((), (received, max_flood_per_read)) =
+            async_std::future::timeout(Duration::from_secs(60), future::join(send, receive))
+                .await
+                .expect("the transfer stalled");
+        stop_flood.abort();
+        stop_accept.abort();
+
+        assert_eq!(received, LEN + 1);
+        assert!(
+            max_flood_per_read <= MAX_FLOOD_PER_READ,
+            "{} flood substreams between two reads",
+            max_flood_per_read
+        );
+        assert!(flooded.load(Ordering::SeqCst) > 0);
+    });
+}
+
+#[test]
+fn concurrent_writers() {
+    const WRITERS: usize = 32;
+    const LEN: usize = 64 * 1024;
+
+    init();
+    async_std::task::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let (listener_muxer, dialer_muxer) =
+            connected_pair(config(&keypair), new_peer_config()).await;
+        let (listener_muxer, dialer_muxer) = (Arc::new(listener_muxer), Arc::new(dialer_muxer));
+
+        // Each writer sends its own index over a substream of the shared connection.
+        let writers = (0..WRITERS)
+            .map(|i| {
+                let muxer = dialer_muxer.clone();
+                async_std::task::spawn(async move {
+                    let mut substream = future::poll_fn(|cx| muxer.poll_outbound(cx, &mut ()))
+                        .await
+                        .unwrap();
+                    let data = vec![i as u8; LEN];
+                    let mut buf = &data[..];
+                    while !buf.is_empty() {
+                        let n =
+                            future::poll_fn(|cx| muxer.write_substream(cx, &mut substream, buf))
+                                .await
+                                .unwrap();
+                        buf = &buf[n..];
+                    }
+                    future::poll_fn(|cx| muxer.shutdown_substream(cx, &mut substream))
+                        .await
+                        .unwrap();
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut readers = Vec::with_capacity(WRITERS);
+        for _ in 0..WRITERS {
+            let mut substream = future::poll_fn(|cx| listener_muxer.poll_inbound(cx))
+                .await
+                .unwrap();
+            let muxer = listener_muxer.clone();
+            readers.push(async_std::task::spawn(async move {
+                let mut received = Vec::with_capacity(LEN);
+                let mut buf = [0u8; 4096];
+                loop {
+                    let n =
+                        future::poll_fn(|cx| muxer.read_substream(cx, &mut substream, &mut buf))
+                            .await
+                            .unwrap();
+                    if n == 0 {
+                        return received;
+                    }
+                    received.extend_from_slice(&buf[..n]);
+                }
+            }));
+        }
+
+        let mut senders = Vec::with_capacity(WRITERS);
+        for received in future::join_all(readers).await {
+            assert_eq!(received.len(), LEN);
+            assert!(received.iter().all(|&b| b == received[0]));
+            senders.push(received[0] as usize);
+        }
+        senders.sort();
+        assert_eq!(senders, (0..WRITERS).collect::<Vec<_>>());
+        future::join_all(writers).await;
+    });
+}
+
+#[test]
+fn verifier_rejects_unexpected_peer() {
+    init();
+    async_std::task::block_on(async {
+        let listener_keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let dialer_keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let (listen_addr, mut listener) = listen(config(&listener_keypair)).await;
+        async_std::task::spawn(async move {
+            while let Some(Ok(event)) = listener.next().await {
+                if let ListenerEvent::Upgrade { upgrade, .. } = event {
+                    let _ = upgrade.await;
+                }
+            }
+        });
+
+        // The dialer expects to reach itself, which the listener can't prove.
+        let expected = dialer_keypair.public().into_peer_id();
+        let verifier = libp2p_quic::VerifierConfig::default().with_expected_peer(expected);
+        let dialer_config = config(&dialer_keypair).with_verifier(verifier);
+        let dialer = QuicTransport(Endpoint::new(dialer_config).unwrap());
+        assert!(dialer.dial(listen_addr).unwrap().await.is_err());
+    });
+}
+
+/// Records the `milestone` field of the events emitted with the handshake trace target.
+#[derive(Clone, Default)]
+struct HandshakeMilestones(Arc<std::sync::Mutex<Vec<String>>>);
+
+impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for HandshakeMilestones {
+    fn on_event(&self, event: &tracing::Event<'_>, _: tracing_subscriber::layer::Context<'_, S>) {
+        struct Visitor<'a>(&'a mut Vec<String>);
+        impl tracing::field::Visit for Visitor<'_> {
+            fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+                if field.name() == "milestone" {
+                    self.0.push(value.to_owned());
+                }
+            }
+            fn record_debug(&mut self, _: &tracing::field::Field, _: &dyn std::fmt::Debug) {}
+        }
+
+        if event.metadata().target() == "libp2p_quic::handshake" {
+            event.record(&mut Visitor(&mut self.0.lock().unwrap()));
+        }
+    }
+}
+
+#[test]
+fn handshake_trace() {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    let milestones = HandshakeMilestones::default();
+    let subscriber = tracing_subscriber::registry().with(milestones.clone());
+    tracing::subscriber::with_default(subscriber, || {
+        async_std::task::block_on(async {
+            let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+            // Only the listener, whose handshake is driven by the current thread, is traced.
+            let listener_config = config(&keypair).with_handshake_trace(true);
+            let (_listener_muxer, _dialer_muxer) =
+                connected_pair(listener_config, new_peer_config()).await;
+        })
+    });
+
+    assert_eq!(
+        *milestones.0.lock().unwrap(),
+        [
+            "started",
+            "connected",
+            "certificate_received",
+            "peer_identified"
+        ]
+    );
+}
+
+#[test]
+fn from_keypair_just_works() {
+    init();
+    async_std::task::block_on(async {
+        let listener_keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let dialer_keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let listener_config = Config::from_keypair(&listener_keypair).unwrap();
+        let mut listener = QuicTransport(Endpoint::new(listener_config).unwrap())
+            .listen_on("/ip4/0.0.0.0/udp/0/quic".parse().unwrap())
+            .unwrap();
+
+        // The listener reports an address for each interface, including the loopback one.
+        let listen_addr = loop {
+            match listener.next().await.unwrap().unwrap() {
+                ListenerEvent::NewAddress(addr) => {
+                    if addr.iter().next() == Some(Protocol::Ip4([127, 0, 0, 1].into())) {
+                        break addr;
+                    }
+                }
+                _ => panic!("expected a listen address"),
+            }
+        };
+
+        let dialer_config = Config::from_keypair(&dialer_keypair).unwrap();
+        let dialer = QuicTransport(Endpoint::new(dialer_config).unwrap());
+        let dial = async_std::task::spawn(dialer.dial(listen_addr).unwrap());
+        let (dialer_id, listener_muxer) = accept(&mut listener).await;
+        let (listener_id, dialer_muxer) = dial.await.unwrap();
+        assert_eq!(dialer_id, dialer_keypair.public().into_peer_id());
+        assert_eq!(listener_id, listener_keypair.public().into_peer_id());
+
+        let mut outbound = future::poll_fn(|cx| dialer_muxer.poll_outbound(cx, &mut ()))
+            .await
+            .unwrap();
+        future::poll_fn(|cx| dialer_muxer.write_substream(cx, &mut outbound, b"ping"))
+            .await
+            .unwrap();
+        future::poll_fn(|cx| dialer_muxer.shutdown_substream(cx, &mut outbound))
+            .await
+            .unwrap();
+
+        let mut inbound = future::poll_fn(|cx| listener_muxer.poll_inbound(cx))
+            .await
+            .unwrap();
+        let mut received = Vec::new();
+        let mut buf = [0u8; 16];
+        loop {
+            let n = future::poll_fn(|cx| listener_muxer.read_substream(cx, &mut inbound, &mut buf))
+                .await
+                .unwrap();
+            if n == 0 {
+                break;
+            }
+            received.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(received, b"ping");
+    });
+}
+
+#[test]
+fn simultaneous_open_through_nat() {
+    init();
+    async_std::task::block_on(async {
+        async fn endpoint(config: Config) -> (QuicTransport, SocketAddr) {
+            let transport = QuicTransport(Endpoint::new(config).unwrap());
+            let mut listener = transport
+                .clone()
+                .listen_on("/ip4/127.0.0.1/udp/0/quic".parse().unwrap())
+                .unwrap();
+            match listener.next().await.unwrap().unwrap() {
+                ListenerEvent::NewAddress(addr) => (transport, socket_addr(&addr)),
+                _ => panic!("expected a listen address"),
+            }
+        }
+
+        // The first peer is behind a NAT, and the second one has to reach it through the NAT.
+        let first_keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let second_keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let (first, first_addr) = endpoint(config(&first_keypair)).await;
+        let second_config = config(&second_keypair).with_handshake_timeout(Duration::from_secs(2));
+        let (second, second_addr) = endpoint(second_config).await;
+        let mut nat = PortRestrictedNat::spawn(first_addr, second_addr).await;
+
+        // Without the first peer punching a hole, the NAT drops the packets of the second one.
+        match second.clone().dial(quic_addr(nat.outer)).unwrap().await {
+            Err(libp2p_quic::transport::Error::HandshakeTimeout) => {}
+            Err(err) => panic!("unexpected error: {:?}", err),
+            Ok(_) => panic!("unexpected success"),
+        }
+
+        let punch = async_std::task::spawn(first.dial_as_listener(quic_addr(nat.inner)).unwrap());
+        nat.wait_punched().await;
+        let (first_id, _second_muxer) = second.dial(quic_addr(nat.outer)).unwrap().await.unwrap();
+        let (second_id, _first_muxer) = punch.await.unwrap();
+        assert_eq!(first_id, first_keypair.public().into_peer_id());
+        assert_eq!(second_id, second_keypair.public().into_peer_id());
+    });
+}
+
+#[test]
+fn handshake_with_retry() {
+    init();
+    async_std::task::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let listener_config = config(&keypair).with_retry(true);
+        let (listener_muxer, dialer_muxer) =
+            connected_pair(listener_config, new_peer_config()).await;
+        assert!(listener_muxer.stats().rtt > Duration::from_secs(0));
+        assert!(dialer_muxer.stats().rtt > Duration::from_secs(0));
+    });
+}
+
+#[test]
+fn dialing_unexpected_peer_fails() {
+    init();
+    async_std::task::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let (listen_addr, mut listener) = listen(config(&keypair)).await;
+        async_std::task::spawn(async move {
+            while let Some(Ok(event)) = listener.next().await {
+                if let ListenerEvent::Upgrade { upgrade, .. } = event {
+                    let _ = upgrade.await;
+                }
+            }
+        });
+
+        let other = libp2p_core::identity::Keypair::generate_ed25519()
+            .public()
+            .into_peer_id();
+        let dialer = QuicTransport(Endpoint::new(new_peer_config()).unwrap());
+        let addr = listen_addr.with(Protocol::P2p(other.clone().into()));
+        match dialer.dial(addr).unwrap().await {
+            Err(libp2p_quic::transport::Error::PeerIdMismatch { expected, actual }) => {
+                assert_eq!(expected, other);
+                assert_eq!(actual, keypair.public().into_peer_id());
+            }
+            Err(err) => panic!("unexpected error: {:?}", err),
+            Ok(_) => panic!("unexpected success"),
+        }
+    });
+}
+
+#[test]
+fn dialing_self_fails() {
+    init();
+    async_std::task::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let transport = QuicTransport(Endpoint::new(config(&keypair)).unwrap());
+        let mut listener = transport
+            .clone()
+            .listen_on("/ip4/127.0.0.1/udp/0/quic".parse().unwrap())
+            .unwrap();
+        let listen_addr = match listener.next().await.unwrap().unwrap() {
+            ListenerEvent::NewAddress(addr) => addr,
+            _ => panic!("expected a listen address"),
+        };
+        match transport.clone().dial(listen_addr.clone()) {
+            Err(libp2p_core::transport::TransportError::Other(
+                libp2p_quic::transport::Error::DialToSelf,
+            )) => {}
+            Err(err) => panic!("unexpected error: {:?}", err),
+            Ok(_) => panic!("unexpected success"),
+        }
+
+        // Another endpoint with the same keypair is only found out after the handshake.
+        async_std::task::spawn(async move {
+            while let Some(Ok(event)) = listener.next().await {
+                if let ListenerEvent::Upgrade { upgrade, .. } = event {
+                    let _ = upgrade.await;
+                }
+            }
+        });
+        let dialer = QuicTransport(Endpoint::new(config(&keypair)).unwrap());
+        match dialer.dial(listen_addr.clone()).unwrap().await {
+            Err(libp2p_quic::transport::Error::DialToSelf) => {}
+            Err(err) => panic!("unexpected error: {:?}", err),
+            Ok(_) => panic!("unexpected success"),
+        }
+
+        // The same goes for dialing as the listener.
+        let puncher = QuicTransport(Endpoint::new(config(&keypair)).unwrap());
+        let mut puncher_listener = puncher
+            .clone()
+            .listen_on("/ip4/127.0.0.1/udp/0/quic".parse().unwrap())
+            .unwrap();
+        let puncher_addr = match puncher_listener.next().await.unwrap().unwrap() {
+            ListenerEvent::NewAddress(addr) => addr,
+            _ => panic!("expected a listen address"),
+        };
+        let punch = async_std::task::spawn(puncher.dial_as_listener(listen_addr).unwrap());
+        let _ = transport.dial(puncher_addr).unwrap().await;
+        match punch.await {
+            Err(libp2p_quic::transport::Error::DialToSelf) => {}
+            Err(err) => panic!("unexpected error: {:?}", err),
+            Ok(_) => panic!("unexpected success"),
+        }
+    });
+}
+
+#[test]
+fn client_and_server_only_endpoints() {
+    init();
+    async_std::task::block_on(async {
+        let addr: Multiaddr = "/ip4/127.0.0.1/udp/0/quic".parse().unwrap();
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let client = QuicTransport(
+            Endpoint::new(Config::client_only(&keypair, addr.clone()).unwrap()).unwrap(),
+        );
+        match client.clone().listen_on(addr.clone()) {
+            Err(libp2p_core::transport::TransportError::Other(
+                libp2p_quic::transport::Error::ClientOnly,
+            )) => {}
+            Err(err) => panic!("unexpected error: {:?}", err),
+            Ok(_) => panic!("unexpected success"),
+        }
+        let tcp_addr: Multiaddr = "/ip4/127.0.0.1/tcp/1234".parse().unwrap();
+        match client.clone().listen_on(tcp_addr.clone()) {
+            Err(libp2p_core::transport::TransportError::MultiaddrNotSupported(a)) => {
+                assert_eq!(a, tcp_addr)
+            }
+            Err(err) => panic!("unexpected error: {:?}", err),
+            Ok(_) => panic!("unexpected success"),
+        }
+
+        let server_keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let server = QuicTransport(
+            Endpoint::new(Config::server_only(&server_keypair, addr.clone()).unwrap()).unwrap(),
+        );
+        let mut listener = server.clone().listen_on(addr.clone()).unwrap();
+        let listen_addr = match listener.next().await.unwrap().unwrap() {
+            ListenerEvent::NewAddress(addr) => addr,
+            _ => panic!("expected a listen address"),
+        };
+        match server.dial(listen_addr.clone()) {
+            Err(libp2p_core::transport::TransportError::Other(
+                libp2p_quic::transport::Error::ServerOnly,
+            )) => {}
+            Err(err) => panic!("unexpected error: {:?}", err),
+            Ok(_) => panic!("unexpected success"),
+        }
+
+        let ((client_id, _), dial) =
+            future::join(accept(&mut listener), client.dial(listen_addr).unwrap()).await;
+        let (server_id, _) = dial.unwrap();
+        assert_eq!(client_id, keypair.public().into_peer_id());
+        assert_eq!(server_id, server_keypair.public().into_peer_id());
+    });
+}
+
+#[test]
+fn client_endpoint_ignores_incoming_handshakes() {
+    init();
+    async_std::task::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let (listen_addr, mut listener) = listen(config(&keypair)).await;
+
+        let client_keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let client_addr = "/ip4/127.0.0.1/udp/4001/quic".parse().unwrap();
+        let client_config = Config::new(&client_keypair, client_addr).unwrap();
+        let client = QuicTransport(Endpoint::client(client_config).unwrap());
+        match client
+            .clone()
+            .listen_on("/ip4/127.0.0.1/udp/0/quic".parse().unwrap())
+        {
+            Err(libp2p_core::transport::TransportError::Other(
+                libp2p_quic::transport::Error::ClientOnly,
+            )) => {}
+            Err(err) => panic!("unexpected error: {:?}", err),
+            Ok(_) => panic!("unexpected success"),
+        }
+
+        let (accepted, dial) = future::join(
+            async {
+                loop {
+                    if let ListenerEvent::Upgrade {
+                        upgrade,
+                        remote_addr,
+                        ..
+                    } = listener.next().await.unwrap().unwrap()
+                    {
+                        break (upgrade.await.unwrap(), remote_addr);
+                    }
+                }
+            },
+            client.dial(listen_addr).unwrap(),
+        )
+        .await;
+        assert_eq!(dial.unwrap().0, keypair.public().into_peer_id());
+        let (_, client_addr) = accepted;
+        assert_ne!(socket_addr(&client_addr).port(), 4001);
+
+        // Nothing answers handshakes sent to the socket of the client.
+        let dialer_config = new_peer_config().with_handshake_timeout(Duration::from_millis(500));
+        let dialer = QuicTransport(Endpoint::new(dialer_config).unwrap());
+        assert!(dialer.dial(client_addr).unwrap().await.is_err());
+    });
+}
+
+#[test]
+fn resumed_connections_send_0rtt_data() {
+    init();
+    async_std::task::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let listener_id = keypair.public().into_peer_id();
+        let (listen_addr, mut listener) = listen(config(&keypair).with_0rtt_unchecked(true)).await;
+        let addr = listen_addr.with(Protocol::P2p(listener_id.clone().into()));
+        let dialer =
+            QuicTransport(Endpoint::new(new_peer_config().with_0rtt_unchecked(true)).unwrap());
+
+        // The first connection provides the session ticket that 0-RTT relies on. The ticket is
+        // sent after the handshake, before the listener answers any data.
+        let ((_, first_listener_muxer), dial) = future::join(
+            accept(&mut listener),
+            dialer.clone().dial(addr.clone()).unwrap(),
+        )
+        .await;
+        let (_, first_muxer) = dial.unwrap();
+        assert!(!first_muxer.accepted_0rtt());
+        round_trip(&first_muxer, &first_listener_muxer).await;
+
+        // The second connection is usable before its handshake has completed.
+        let (peer_id, dialer_muxer) = dialer.dial_0rtt(addr).unwrap().await.unwrap();
+        assert_eq!(peer_id, listener_id);
+        let mut stream = Outbound(&dialer_muxer).await.unwrap();
+        stream.write_all(b"early").await.unwrap();
+
+        let (_, listener_muxer) = accept(&mut listener).await;
+        let mut socket = Inbound(&listener_muxer).next().await.unwrap();
+        let mut buf = [0u8; 5];
+        socket.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"early");
+        assert!(dialer_muxer.accepted_0rtt());
+    });
+}
+
+#[test]
+fn resumed_sessions_skip_certificate_exchange() {
+    init();
+    async_std::task::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let listener_id = keypair.public().into_peer_id();
+        let (listen_addr, mut listener) =
+            listen(config(&keypair).with_session_resumption(true)).await;
+        let dialer =
+            QuicTransport(Endpoint::new(new_peer_config().with_session_resumption(true)).unwrap());
+
+        let mut sent = Vec::new();
+        for _ in 0..2 {
+            let ((_, listener_muxer), dial) = future::join(
+                accept(&mut listener),
+                dialer.clone().dial(listen_addr.clone()).unwrap(),
+            )
+            .await;
+            let (peer_id, muxer) = dial.unwrap();
+            assert_eq!(peer_id, listener_id);
+            sent.push(muxer.stats().udp_bytes_sent);
+            // The session ticket is sent after the handshake, before the listener answers any
+            // data.
+            round_trip(&muxer, &listener_muxer).await;
+        }
+
+        // During a full handshake, the dialer sends its certificate alongside a signature, which
+        // takes several hundred bytes. Resumed handshakes skip both.
+        assert!(
+            sent[1] + 256 < sent[0],
+            "resumed handshake sent {} bytes, full handshake {} bytes",
+            sent[1],
+            sent[0]
+        );
+    });
+}
+
+/// Client certificate resolver that never presents a certificate.
+struct NoClientCertificate;
+
+impl rustls::ResolvesClientCert for NoClientCertificate {
+    fn resolve(
+        &self,
+        _: &[&[u8]],
+        _: &[rustls::SignatureScheme],
+    ) -> Option<rustls::sign::CertifiedKey> {
+        None
+    }
+
+    fn has_certs(&self) -> bool {
+        false
+    }
+}
+
+#[test]
+fn failed_incoming_handshakes_are_reported() {
+    init();
+    async_std::task::block_on(async {
+        let (listen_addr, mut listener) = listen(new_peer_config()).await;
+
+        let (mut client, server) =
+            libp2p_quic::make_tls_config(&libp2p_core::identity::Keypair::generate_ed25519())
+                .unwrap();
+        client.client_auth_cert_resolver = Arc::new(NoClientCertificate);
+        let anonymous =
+            QuicTransport(Endpoint::new(new_peer_config().with_tls(client, server)).unwrap());
+        let _dial = async_std::task::spawn(anonymous.dial(listen_addr.clone()).unwrap());
+
+        // The failure is reported by the upgrade only, with its typed error.
+        let upgrade = match listener.next().await.unwrap().unwrap() {
+            ListenerEvent::Upgrade { upgrade, .. } => upgrade,
+            other => panic!("unexpected event: {:?}", other),
+        };
+        match upgrade.await {
+            Err(libp2p_quic::transport::Error::TransportError { .. })
+            | Err(libp2p_quic::transport::Error::MissingCertificate) => {}
+            Err(err) => panic!("unexpected error: {:?}", err),
+            Ok(_) => panic!("unexpected success"),
+        }
+
+        // The listener keeps accepting well-behaved clients, without reporting the failure.
+        let dialer = QuicTransport(Endpoint::new(new_peer_config()).unwrap());
+        let (_, dial) =
+            future::join(accept(&mut listener), dialer.dial(listen_addr).unwrap()).await;
+        dial.unwrap();
+    });
+}
+
+#[test]
+fn dialing_from_closed_endpoint_fails() {
+    init();
+    async_std::task::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let endpoint = Endpoint::new(config(&keypair)).unwrap();
+        endpoint.close_now().await;
+        let addr = "/ip4/127.0.0.1/udp/1234/quic".parse().unwrap();
+        match QuicTransport(endpoint).dial(addr).unwrap().await {
+            Err(libp2p_quic::transport::Error::EndpointClosed) => {}
+            Err(err) => panic!("unexpected error: {:?}", err),
+            Ok(_) => panic!("unexpected success"),
+        }
+    });
+}
+
+#[test]
+fn handshake_timeout() {
+    init();
+    async_std::task::block_on(async {
+        // Nobody ever answers on this socket.
+        let black_hole = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = quic_addr(black_hole.local_addr().unwrap());
+
+        let dialer_config = new_peer_config().with_handshake_timeout(Duration::from_millis(500));
+        let dialer = QuicTransport(Endpoint::new(dialer_config).unwrap());
+        let result = async_std::future::timeout(Duration::from_secs(5), dialer.dial(addr).unwrap())
+            .await
+            .expect("the handshake timeout should have fired");
+        match result {
+            Err(libp2p_quic::transport::Error::HandshakeTimeout) => {}
+            Err(err) => panic!("unexpected error: {:?}", err),
+            Ok(_) => panic!("unexpected success"),
+        }
+    });
+}
+
+#[test]
+fn dial_retry() {
+    init();
+    async_std::task::block_on(async {
+        let retry = DialRetry {
+            max_attempts: 3,
+            initial_delay: Duration::from_millis(50),
+            multiplier: 2,
+        };
+
+        // The packets of the first attempt are lost, and the second attempt succeeds.
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let (listen_addr, mut listener) = listen(config(&keypair)).await;
+        let relay = FirstAttemptDropper::spawn(socket_addr(&listen_addr)).await;
+        let dialer_metrics = Arc::new(CountingMetrics::default());
+        let dialer_config = new_peer_config()
+            .with_handshake_timeout(Duration::from_millis(500))
+            .with_dial_retry(retry)
+            .with_metrics(dialer_metrics.clone());
+        let dialer = QuicTransport(Endpoint::new(dialer_config).unwrap());
+        let (_, dial) = future::join(
+            accept(&mut listener),
+            dialer.clone().dial(relay.addr.clone()).unwrap(),
+        )
+        .await;
+        assert_eq!(dial.unwrap().0, keypair.public().into_peer_id());
+        assert_eq!(dialer_metrics.connections_opened.load(Ordering::SeqCst), 1);
+
+        // All the attempts time out. Each of them picks a destination connection ID of its own.
+        let black_hole = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        match dialer
+            .clone()
+            .dial(quic_addr(black_hole.local_addr().unwrap()))
+            .unwrap()
+            .await
+        {
+            Err(libp2p_quic::transport::Error::HandshakeTimeout) => {}
+            Err(err) => panic!("unexpected error: {:?}", err),
+            Ok(_) => panic!("unexpected success"),
+        }
+        black_hole.set_nonblocking(true).unwrap();
+        let mut attempts = HashSet::new();
+        let mut packet = [0; 65536];
+        while let Ok(n) = black_hole.recv(&mut packet) {
+            if let Some(dcid) = long_header_dcid(&packet[..n]) {
+                attempts.insert(dcid.to_vec());
+            }
+        }
+        assert_eq!(attempts.len(), 3);
+
+        // Other errors aren't retried.
+        async_std::task::spawn(async move {
+            while let Some(Ok(event)) = listener.next().await {
+                if let ListenerEvent::Upgrade { upgrade, .. } = event {
+                    let _ = upgrade.await;
+                }
+            }
+        });
+        let wrong_addr = listen_addr.with(Protocol::P2p(PeerId::random().into()));
+        match dialer.dial(wrong_addr).unwrap().await {
+            Err(libp2p_quic::transport::Error::PeerIdMismatch { .. }) => {}
+            Err(err) => panic!("unexpected error: {:?}", err),
+            Ok(_) => panic!("unexpected success"),
+        }
+        assert_eq!(dialer_metrics.connections_opened.load(Ordering::SeqCst), 2);
+    });
+}
+
+#[test]
+fn connection_stats() {
+    init();
+    async_std::task::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let (listener_muxer, dialer_muxer) =
+            connected_pair(config(&keypair), new_peer_config()).await;
+
+        let mut outbound = future::poll_fn(|cx| dialer_muxer.poll_outbound(cx, &mut ()))
+            .await
+            .unwrap();
+        let data = b"hello world";
+        let mut buf = &data[..];
+        while !buf.is_empty() {
+            let n = future::poll_fn(|cx| dialer_muxer.write_substream(cx, &mut outbound, buf))
+                .await
+                .unwrap();
+            buf = &buf[n..];
+        }
+        future::poll_fn(|cx| dialer_muxer.shutdown_substream(cx, &mut outbound))
+            .await
+            .unwrap();
+
+        let mut inbound = future::poll_fn(|cx| listener_muxer.poll_inbound(cx))
+            .await
+            .unwrap();
+        let mut received = Vec::new();
+        let mut buf = [0u8; 64];
+        loop {
+            let n = future::poll_fn(|cx| listener_muxer.read_substream(cx, &mut inbound, &mut buf))
+                .await
+                .unwrap();
+            if n == 0 {
+                break;
+            }
+            received.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(received, data);
+
+        dialer_muxer.ping();
+        let dialer_stats = dialer_muxer.stats();
+        let listener_stats = listener_muxer.stats();
+        assert!(dialer_stats.rtt > Duration::from_secs(0));
+        assert!(listener_stats.rtt > Duration::from_secs(0));
+        assert_eq!(dialer_stats.bytes_sent, data.len() as u64);
+        assert_eq!(listener_stats.bytes_received, data.len() as u64);
+        assert!(dialer_stats.datagrams_sent > 0);
+        assert!(dialer_stats.udp_bytes_sent > dialer_stats.bytes_sent);
+    });
+}
+
+#[test]
+fn negotiated_alpn_protocol() {
+    init();
+    async_std::task::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let protocols = vec![b"libp2p/2".to_vec(), b"libp2p".to_vec()];
+
+        // The dialer only supports the second protocol of the listener.
+        let listener_config = config(&keypair).with_alpn_protocols(protocols.clone());
+        let (listener_muxer, dialer_muxer) =
+            connected_pair(listener_config.clone(), new_peer_config()).await;
+        assert_eq!(listener_muxer.alpn_protocol(), Some(b"libp2p".to_vec()));
+        assert_eq!(dialer_muxer.alpn_protocol(), Some(b"libp2p".to_vec()));
+
+        let dialer_config = new_peer_config().with_alpn_protocols(protocols);
+        let (listener_muxer, dialer_muxer) = connected_pair(listener_config, dialer_config).await;
+        assert_eq!(listener_muxer.alpn_protocol(), Some(b"libp2p/2".to_vec()));
+        assert_eq!(dialer_muxer.alpn_protocol(), Some(b"libp2p/2".to_vec()));
+    });
+}
+
+#[test]
+fn version_negotiation_failure() {
+    init();
+    async_std::task::block_on(async {
+        // Versions of the form 0x?a?a?a?a are reserved to exercise version negotiation, and are
+        // therefore never spoken by the dialer.
+        const PEER_SUPPORTED: [u32; 2] = [0x1a2a_3a4a, 0x5a6a_7a8a];
+
+        // The remote answers the initial packet of the dialer with a version negotiation
+        // packet.
+        let remote = async_std::net::UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let remote_addr = remote.local_addr().unwrap();
+        let responder = async_std::task::spawn(async move {
+            let mut buf = vec![0; 65536];
+            let (len, from) = remote.recv_from(&mut buf).await.unwrap();
+            let initial = &buf[..len];
+            assert_ne!(initial[0] & 0x80, 0, "expected a long header");
+            let offered = u32::from_be_bytes([initial[1], initial[2], initial[3], initial[4]]);
+            let dst_cid_end = 6 + initial[5] as usize;
+            let dst_cid = &initial[6..dst_cid_end];
+            let src_cid = &initial[dst_cid_end + 1..][..initial[dst_cid_end] as usize];
+
+            // The connection IDs are swapped in the answer.
+            let mut negotiation = vec![0xc0, 0, 0, 0, 0];
+            negotiation.push(src_cid.len() as u8);
+            negotiation.extend_from_slice(src_cid);
+            negotiation.push(dst_cid.len() as u8);
+            negotiation.extend_from_slice(dst_cid);
+            for version in &PEER_SUPPORTED {
+                negotiation.extend_from_slice(&version.to_be_bytes());
+            }
+            remote.send_to(&negotiation, from).await.unwrap();
+            offered
+        });
+
+        let transport = QuicTransport(Endpoint::new(new_peer_config()).unwrap());
+        let result = transport.dial(quic_addr(remote_addr)).unwrap().await;
+        let offered = responder.await;
+        match result {
+            Err(libp2p_quic::transport::Error::VersionNegotiationFailed {
+                offered: reported,
+                peer_supported,
+            }) => {
+                assert_eq!(reported, [offered]);
+                assert_eq!(peer_supported, PEER_SUPPORTED);
+            }
+            Err(err) => panic!("unexpected error: {:?}", err),
+            Ok(_) => panic!("unexpected success"),
+        }
+    });
+}
+
+#[test]
+fn handshake_info() {
+    init();
+    async_std::task::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let (listener_muxer, dialer_muxer) =
+            connected_pair(config(&keypair), new_peer_config()).await;
+        let tls13_suites = [
+            rustls::CipherSuite::TLS13_AES_128_GCM_SHA256,
+            rustls::CipherSuite::TLS13_AES_256_GCM_SHA384,
+            rustls::CipherSuite::TLS13_CHACHA20_POLY1305_SHA256,
+        ];
+        for muxer in &[&listener_muxer, &dialer_muxer] {
+            let info = muxer.handshake_info();
+            assert_eq!(info.alpn_protocol, Some(b"libp2p".to_vec()));
+            assert!(tls13_suites.contains(&info.cipher_suite.unwrap()));
+            assert!(!info.accepted_0rtt);
+            assert!(info.quic_version.is_some());
+        }
+        assert_eq!(
+            listener_muxer.handshake_info().quic_version,
+            dialer_muxer.handshake_info().quic_version
+        );
+        assert_eq!(
+            listener_muxer.handshake_info().cipher_suite,
+            dialer_muxer.handshake_info().cipher_suite
+        );
+    });
+}
+
+#[test]
+fn datagrams() {
+    init();
+    async_std::task::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let listener_config = config(&keypair).with_datagrams(true);
+
+        // The dialer doesn't accept datagrams, but the listener does.
+        let (listener_muxer, dialer_muxer) =
+            connected_pair(listener_config.clone(), new_peer_config()).await;
+        assert!(!listener_muxer.datagrams_supported());
+        assert!(dialer_muxer.datagrams_supported());
+        match listener_muxer.send_datagram(b"hello".to_vec()) {
+            Err(libp2p_quic::Error::SendDatagram(
+                quinn_proto::SendDatagramError::UnsupportedByPeer,
+            )) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        dialer_muxer.send_datagram(b"hello".to_vec()).unwrap();
+        let received = future::poll_fn(|cx| listener_muxer.poll_datagram(cx))
+            .await
+            .unwrap();
+        assert_eq!(received, b"hello");
+
+        // Both sides accept datagrams.
+        let dialer_config = new_peer_config().with_datagrams(true);
+        let (listener_muxer, dialer_muxer) = connected_pair(listener_config, dialer_config).await;
+        assert!(listener_muxer.datagrams_supported());
+        listener_muxer.send_datagram(b"world".to_vec()).unwrap();
+        let received = future::poll_fn(|cx| dialer_muxer.poll_datagram(cx))
+            .await
+            .unwrap();
+        assert_eq!(received, b"world");
+    });
+}
+
+#[test]
+fn custom_tls_configs_are_used() {
+    init();
+    async_std::task::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let tls_keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let (client, server) = libp2p_quic::make_tls_config(&tls_keypair).unwrap();
+        let listener_config = config(&keypair).with_tls(client, server);
+        let (listen_addr, mut listener) = listen(listener_config).await;
+
+        // Offering other protocols is fine as long as `libp2p` is among them.
+        let (mut client, server) = libp2p_quic::make_tls_config(&keypair).unwrap();
+        client.alpn_protocols.insert(0, b"other".to_vec());
+        let dialer_config = config(&keypair).with_tls(client, server);
+        let dialer = QuicTransport(Endpoint::new(dialer_config).unwrap());
+        let dial = async_std::task::spawn(dialer.dial(listen_addr).unwrap());
+        let (dialer_id, _listener_muxer) = accept(&mut listener).await;
+        let (listener_id, _dialer_muxer) = dial.await.unwrap();
+
+        // The listener is identified by the certificate of its custom configuration.
+        assert_eq!(listener_id, tls_keypair.public().into_peer_id());
+        assert_eq!(dialer_id, keypair.public().into_peer_id());
+    });
+}
+
+#[test]
+fn resource_limits_bound_buffered_data() {
+    const RECEIVE_WINDOW: u64 = 4096;
+
+    init();
+    let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+    let listener_config = config(&keypair)
+        .with_max_concurrent_bidi_streams(4)
+        .with_stream_receive_window(1024)
+        .with_receive_window(RECEIVE_WINDOW);
+    assert!(Endpoint::new(config(&keypair).with_datagram_receive_buffer_size(0)).is_err());
+
+    async_std::task::block_on(async {
+        // The listener never accepts nor reads any substream.
+        let (_listener_muxer, dialer_muxer) =
+            connected_pair(listener_config, new_peer_config()).await;
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        let dialer = &dialer_muxer;
+        let open = || future::poll_fn(move |cx| dialer.poll_outbound(cx, &mut ()));
+        let mut substreams = Vec::new();
+        for _ in 0..4 {
+            substreams.push(open().await.unwrap());
+        }
+        assert!(dialer_muxer.poll_outbound(&mut cx, &mut ()).is_pending());
+
+        // Writing stalls once the windows are used up, which bounds what the listener buffers.
+        let data = vec![0u8; 512];
+        let mut written = 0;
+        for substream in &mut substreams {
+            while let Poll::Ready(result) = dialer_muxer.write_substream(&mut cx, substream, &data)
+            {
+                written += result.unwrap() as u64;
+            }
+        }
+        assert!(written > 0);
+        assert!(written <= RECEIVE_WINDOW, "{} bytes buffered", written);
+    });
+}
+
+#[test]
+fn outbound_substreams_wait_for_stream_credit() {
+    init();
+    async_std::task::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let listener_config = config(&keypair).with_max_concurrent_bidi_streams(2);
+        let (listener_muxer, dialer_muxer) =
+            connected_pair(listener_config, new_peer_config()).await;
+
+        // The listener reads every substream until the end, then closes it.
+        let listener_muxer = Arc::new(listener_muxer);
+        async_std::task::spawn(async move {
+            let muxer = listener_muxer;
+            while let Ok(mut substream) = future::poll_fn(|cx| muxer.poll_inbound(cx)).await {
+                let mut buf = [0u8; 16];
+                while future::poll_fn(|cx| muxer.read_substream(cx, &mut substream, &mut buf))
+                    .await
+                    .unwrap()
+                    != 0
+                {}
+                future::poll_fn(|cx| muxer.shutdown_substream(cx, &mut substream))
+                    .await
+                    .unwrap();
+                muxer.destroy_substream(substream);
+            }
+        });
+
+        let dialer = &dialer_muxer;
+        let open = || future::poll_fn(move |cx| dialer.poll_outbound(cx, &mut ()));
+        let mut substreams = vec![open().await.unwrap(), open().await.unwrap()];
+        assert!(!dialer_muxer.is_stream_limit_reached());
+
+        // The third substream has to wait for one of the first two to be closed.
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        assert!(dialer_muxer.poll_outbound(&mut cx, &mut ()).is_pending());
+        assert!(dialer_muxer.is_stream_limit_reached());
+
+        for i in 0..10u8 {
+            let mut substream = match substreams.pop() {
+                Some(substream) => substream,
+                None => open().await.unwrap(),
+            };
+            let written =
+                future::poll_fn(|cx| dialer_muxer.write_substream(cx, &mut substream, &[i]))
+                    .await
+                    .unwrap();
+            assert_eq!(written, 1);
+            future::poll_fn(|cx| dialer_muxer.shutdown_substream(cx, &mut substream))
+                .await
+                .unwrap();
+            let mut buf = [0u8; 16];
+            let read =
+                future::poll_fn(|cx| dialer_muxer.read_substream(cx, &mut substream, &mut buf))
+                    .await
+                    .unwrap();
+            assert_eq!(read, 0);
+            dialer_muxer.destroy_substream(substream);
+        }
+        assert!(!dialer_muxer.is_stream_limit_reached());
+    });
+}
+
+#[test]
+fn reset_error_code_is_reported() {
+    init();
+    async_std::task::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let (listener_muxer, dialer_muxer) =
+            connected_pair(config(&keypair), new_peer_config()).await;
+
+        let mut stream = Outbound(&dialer_muxer).await.unwrap();
+        stream.write_all(&[1, 2, 3]).await.unwrap();
+        let mut socket = Inbound(&listener_muxer).next().await.unwrap();
+        let mut buf = [0u8; 3];
+        socket.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [1, 2, 3]);
+
+        stream.reset(42);
+        assert!(stream.write_all(&[4]).await.is_err());
+
+        let result = future::poll_fn(|cx| {
+            listener_muxer.read_substream(cx, socket.id.as_mut().unwrap(), &mut buf)
+        })
+        .await;
+        match result {
+            Err(libp2p_quic::Error::Reset(code)) => {
+                assert_eq!(code, quinn_proto::VarInt::from_u32(42))
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    });
+}
+
+#[test]
+fn read_after_end_of_substream() {
+    init();
+    async_std::task::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let (listener_muxer, dialer_muxer) =
+            connected_pair(config(&keypair), new_peer_config()).await;
+        let mut buf = [0u8; 16];
+
+        // Finished substream.
+        let mut stream = Outbound(&dialer_muxer).await.unwrap();
+        stream.write_all(&[1, 2, 3]).await.unwrap();
+        stream.close().await.unwrap();
+        let mut socket = Inbound(&listener_muxer).next().await.unwrap();
+        let mut received = Vec::new();
+        socket.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, [1, 2, 3]);
+        for _ in 0..3 {
+            assert_eq!(socket.read(&mut buf).await.unwrap(), 0);
+        }
+
+        // Reset substream.
+        let mut stream = Outbound(&dialer_muxer).await.unwrap();
+        stream.write_all(&[1, 2, 3]).await.unwrap();
+        let mut socket = Inbound(&listener_muxer).next().await.unwrap();
+        socket.read_exact(&mut buf[..3]).await.unwrap();
+        stream.reset(7);
+        for _ in 0..3 {
+            let result = future::poll_fn(|cx| {
+                listener_muxer.read_substream(cx, socket.id.as_mut().unwrap(), &mut buf)
+            })
+            .await;
+            match result {
+                Err(libp2p_quic::Error::Reset(code)) => {
+                    assert_eq!(code, quinn_proto::VarInt::from_u32(7))
+                }
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+    });
+}
+
+#[test]
+fn io_error_kinds() {
+    use std::io::ErrorKind;
+
+    init();
+    let kind = |err: libp2p_quic::Error| std::io::Error::from(err).kind();
+    async_std::task::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let (listener_muxer, dialer_muxer) =
+            connected_pair(config(&keypair), new_peer_config()).await;
+
+        // Reset substream.
+        let mut stream = Outbound(&dialer_muxer).await.unwrap();
+        stream.write_all(&[1]).await.unwrap();
+        let mut socket = Inbound(&listener_muxer).next().await.unwrap();
+        stream.reset(3);
+        let err = socket.read_to_end(&mut Vec::new()).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+        match err
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<libp2p_quic::Error>())
+        {
+            Some(libp2p_quic::Error::Reset(code)) => {
+                assert_eq!(*code, quinn_proto::VarInt::from_u32(3))
+            }
+            other => panic!("unexpected source: {:?}", other),
+        }
+
+        // Substream that the remote stopped reading.
+        let mut stream = Outbound(&dialer_muxer).await.unwrap();
+        stream.write_all(&[1]).await.unwrap();
+        drop(Inbound(&listener_muxer).next().await.unwrap());
+        let err = loop {
+            if let Err(err) = stream.write_all(&[0; 1024]).await {
+                break err;
+            }
+        };
+        assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+
+        // Connection closed by the dialer.
+        let waker = futures::task::noop_waker();
+        let _ = dialer_muxer.close_with(&mut Context::from_waker(&waker), 1, b"");
+        assert_eq!(
+            kind(wait_closed(&listener_muxer).await),
+            ErrorKind::ConnectionReset
+        );
+        assert_eq!(
+            kind(wait_closed(&dialer_muxer).await),
+            ErrorKind::ConnectionAborted
+        );
+
+        // Idle connection.
+        let idle = |config: Config| {
+            config
+                .with_idle_timeout(Some(Duration::from_millis(500)))
+                .with_keep_alive_interval(None)
+        };
+        let (listener_muxer, _dialer_muxer) =
+            connected_pair(idle(config(&keypair)), idle(new_peer_config())).await;
+        let err = wait_closed(&listener_muxer).await;
+        assert!(
+            matches!(
+                err,
+                libp2p_quic::Error::ConnectionError(quinn_proto::ConnectionError::TimedOut)
+            ),
+            "{:?}",
+            err
+        );
+        assert_eq!(kind(err), ErrorKind::TimedOut);
+    });
+}
+
+#[test]
+fn destroyed_substreams_are_released() {
+    init();
+    async_std::task::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let listener_config = config(&keypair).with_max_concurrent_bidi_streams(4);
+        let (listener_muxer, dialer_muxer) =
+            connected_pair(listener_config, new_peer_config()).await;
+
+        // Each substream is abandoned by both sides without being closed.
+        let dialer = async {
+            for _ in 0..100 {
+                let mut stream = Outbound(&dialer_muxer).await.unwrap();
+                stream.write_all(&[1, 2, 3]).await.unwrap();
+            }
+        };
+        let dialer = async_std::future::timeout(Duration::from_secs(30), dialer);
+        match future::select(dialer.boxed(), wait_closed(&listener_muxer).boxed()).await {
+            future::Either::Left((result, _)) => {
+                result.expect("destroyed substreams weren't released")
+            }
+            future::Either::Right((err, _)) => panic!("connection closed: {:?}", err),
+        }
+    });
+}
+
+#[test]
+fn inbound_substreams_respect_limit() {
+    init();
+    async_std::task::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let listener_config = config(&keypair).with_max_concurrent_bidi_streams(1);
+        let (listener_muxer, dialer_muxer) =
+            connected_pair(listener_config, new_peer_config()).await;
+
+        // The dialer sends its index over each of two substreams, then waits for the listener
+        // to close them. It reports when opening the second substream has to wait.
+        let (blocked_tx, mut blocked_rx) = oneshot::channel();
+        let dialer = async_std::task::spawn(async move {
+            let muxer = dialer_muxer;
+            let mut blocked_tx = Some(blocked_tx);
+            let mut substreams = Vec::new();
+            for i in 0..2u8 {
+                let mut substream = future::poll_fn(|cx| {
+                    let result = muxer.poll_outbound(cx, &mut ());
+                    if i == 1 && result.is_pending() {
+                        if let Some(blocked_tx) = blocked_tx.take() {
+                            let _ = blocked_tx.send(());
+                        }
+                    }
+                    result
+                })
+                .await
+                .unwrap();
+                future::poll_fn(|cx| muxer.write_substream(cx, &mut substream, &[i]))
+                    .await
+                    .unwrap();
+                future::poll_fn(|cx| muxer.shutdown_substream(cx, &mut substream))
+                    .await
+                    .unwrap();
+                substreams.push(substream);
+            }
+            for mut substream in substreams {
+                let mut buf = [0u8; 16];
+                while future::poll_fn(|cx| muxer.read_substream(cx, &mut substream, &mut buf))
+                    .await
+                    .unwrap()
+                    != 0
+                {}
+                muxer.destroy_substream(substream);
+            }
+            muxer
+        });
+
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        for i in 0..2u8 {
+            let mut substream = future::poll_fn(|cx| listener_muxer.poll_inbound(cx))
+                .await
+                .unwrap();
+            let mut received = Vec::new();
+            let mut buf = [0u8; 16];
+            loop {
+                let n = future::poll_fn(|cx| {
+                    listener_muxer.read_substream(cx, &mut substream, &mut buf)
+                })
+                .await
+                .unwrap();
+                if n == 0 {
+                    break;
+                }
+                received.extend_from_slice(&buf[..n]);
+            }
+            assert_eq!(received, [i]);
+
+            // The second substream isn't delivered as long as the first one is open.
+            if i == 0 {
+                (&mut blocked_rx).await.unwrap();
+                assert!(listener_muxer.poll_inbound(&mut cx).is_pending());
+            }
+
+            future::poll_fn(|cx| listener_muxer.shutdown_substream(cx, &mut substream))
+                .await
+                .unwrap();
+            listener_muxer.destroy_substream(substream);
+        }
+        let _dialer_muxer = dialer.await;
+    });
+}
+
+#[test]
+fn outbound_substreams_fail_fast_without_stream_credit() {
+    init();
+    async_std::task::block_on(async {
+        let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+        let listener_config = config(&keypair).with_max_concurrent_bidi_streams(2);
+        let dialer_config = new_peer_config().with_outbound_queueing(false);
+        let (_listener_muxer, dialer_muxer) = connected_pair(listener_config, dialer_config).await;
+
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        for _ in 0..2 {
+            match dialer_muxer.poll_outbound(&mut cx, &mut ()) {
+                Poll::Ready(Ok(_)) => {}
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+        match dialer_muxer.poll_outbound(&mut cx, &mut ()) {
+            Poll::Ready(Err(libp2p_quic::Error::StreamsExhausted)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(dialer_muxer.is_stream_limit_reached());
+    });
+}
+
+#[test]
+fn validate_listen_accepts_valid_addresses() {
+    let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+    let config = config(&keypair);
+
+    // Find a port that is currently free, then make sure that validating doesn't take it.
+    let port = std::net::UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let addr = quic_addr(SocketAddr::from(([127, 0, 0, 1], port)));
+    for _ in 0..2 {
+        assert_eq!(
+            config.validate_listen(&addr).unwrap(),
+            SocketAddr::from(([127, 0, 0, 1], port))
+        );
+    }
+    let _socket = std::net::UdpSocket::bind(("127.0.0.1", port)).unwrap();
+    assert!(config.validate_listen(&addr).is_ok());
+
+    let addr = "/ip6/::1/udp/0/quic".parse().unwrap();
+    assert_eq!(
+        config.validate_listen(&addr).unwrap(),
+        "[::1]:0".parse().unwrap()
+    );
+}
+
+#[test]
+fn validate_listen_rejects_invalid_addresses() {
+    use libp2p_core::transport::TransportError;
+
+    let keypair = libp2p_core::identity::Keypair::generate_ed25519();
+    let config = config(&keypair);
+    for addr in &[
+        "/ip4/127.0.0.1/udp/1234",
+        "/ip4/127.0.0.1/tcp/1234/quic",
+        "/ip4/127.0.0.1/udp/1234/quic/p2p/QmcgpsyWgH8Y8ajJz1Cu72KnS5uo2Aa2LpzU7kinSupNKC",
+        "/dns4/localhost/udp/1234/quic",
+    ] {
+        let addr: Multiaddr = addr.parse().unwrap();
+        match config.validate_listen(&addr) {
+            Err(TransportError::MultiaddrNotSupported(a)) => assert_eq!(a, addr),
+            other => panic!("unexpected result for {}: {:?}", addr, other),
+        }
+    }
+
+    let config = config.with_max_concurrent_bidi_streams(u64::max_value());
+    let addr = "/ip4/127.0.0.1/udp/1234/quic".parse().unwrap();
+    match config.validate_listen(&addr) {
+        Err(TransportError::Other(err)) => {
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput)
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
 }